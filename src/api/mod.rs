@@ -21,9 +21,11 @@ pub(crate) async fn start(router: Router, stats_sender: StatsSender) {
     let app = AxumRouter::new()
         .route("/api/health", get(Api::health_check))
         .route("/api/pool/info", get(Api::get_pool_info))
+        .route("/api/pool/latency", get(Api::get_pool_latency))
         .route("/api/stats/miners", get(Api::get_downstream_stats))
         .route("/api/stats/aggregate", get(Api::get_aggregate_stats))
         .route("/api/stats/system", get(Api::system_stats))
+        .route("/api/stats/admission", get(Api::get_admission_stats))
         .with_state(state);
 
     let api_server_addr = format!("0.0.0.0:{}", *API_SERVER_PORT);
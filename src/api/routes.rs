@@ -1,5 +1,5 @@
 use super::{utils::get_cpu_and_memory_usage, AppState};
-use crate::proxy_state::ProxyState;
+use crate::{proxy_state::ProxyState, shared::hashrate::HashrateAverages};
 use axum::{extract::State, http::StatusCode, response::IntoResponse, Json};
 use serde::Serialize;
 
@@ -42,28 +42,66 @@ impl Api {
                 );
             }
         };
+        // Shares of miners that disconnected stay in the windows
+        let aggregate_hashrate_averages = state
+            .stats_sender
+            .collect_aggregate_hashrate()
+            .await
+            .unwrap_or_default();
+        let aggregate_channel_dropped_shares = state
+            .stats_sender
+            .collect_channel_dropped_shares()
+            .await
+            .unwrap_or_default();
         let mut total_connected_device = 0;
         let mut total_accepted_shares = 0;
         let mut total_rejected_shares = 0;
+        let mut total_dropped_shares = 0;
+        let mut total_stale_shares = 0;
+        let mut total_duplicate_shares = 0;
+        let mut total_late_shares = 0;
         let mut total_hashrate = 0.0;
         let mut total_diff = 0.0;
         for (_, downstream) in stats {
             total_connected_device += 1;
             total_accepted_shares += downstream.accepted_shares;
             total_rejected_shares += downstream.rejected_shares;
+            total_dropped_shares += downstream.dropped_shares;
+            total_stale_shares += downstream.stale_shares;
+            total_duplicate_shares += downstream.duplicate_shares;
+            total_late_shares += downstream.late_shares;
             total_hashrate += downstream.hashrate as f64;
             total_diff += downstream.current_difficulty as f64
         }
         let result = AggregateStates {
             total_connected_device,
             aggregate_hashrate: total_hashrate,
+            aggregate_hashrate_averages,
             aggregate_accepted_shares: total_accepted_shares,
             aggregate_rejected_shares: total_rejected_shares,
+            aggregate_dropped_shares: total_dropped_shares,
+            aggregate_channel_dropped_shares,
+            aggregate_stale_shares: total_stale_shares,
+            aggregate_duplicate_shares: total_duplicate_shares,
+            aggregate_late_shares: total_late_shares,
             aggregate_diff: total_diff,
         };
         (StatusCode::OK, Json(APIResponse::success(Some(result))))
     }
 
+    // Returns the number of admitted miners and the connections refused by admission control
+    pub async fn get_admission_stats() -> impl IntoResponse {
+        match crate::ingress::admission::stats() {
+            Some(stats) => (StatusCode::OK, Json(APIResponse::success(Some(stats)))),
+            None => (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(APIResponse::error(Some(
+                    "Failed to collect admission stats".to_string(),
+                ))),
+            ),
+        }
+    }
+
     // Retrieves the current pool information
     pub async fn get_pool_info(State(state): State<AppState>) -> impl IntoResponse {
         let current_pool_address = state.router.current_pool;
@@ -71,9 +109,21 @@ impl Api {
 
         match (current_pool_address, latency) {
             (Some(address), Some(latency)) => {
+                let samples = crate::router::latency::samples(address).unwrap_or_default();
                 let response_data = serde_json::json!({
                     "address": address.to_string(),
-                    "latency": latency.as_millis().to_string()
+                    "latency": latency.as_millis().to_string(),
+                    "share_rtt": samples.share_rtt().map(|l| l.as_millis().to_string()),
+                    "prev_hash_delay": samples.prev_hash_delay().map(|l| l.as_millis().to_string()),
+                    "share_ack_latency": {
+                        "count": samples.share_ack().count(),
+                        "mean_ms": samples.share_ack().mean().map(|l| l.as_millis().to_string()),
+                        "buckets": samples
+                            .share_ack()
+                            .buckets()
+                            .map(|(le_ms, count)| serde_json::json!({ "le_ms": le_ms, "count": count }))
+                            .collect::<Vec<_>>(),
+                    },
                 });
                 (
                     StatusCode::OK,
@@ -89,6 +139,22 @@ impl Api {
         }
     }
 
+    // Returns the latency history of every pool, the pool switches and why they happened
+    pub async fn get_pool_latency(State(state): State<AppState>) -> impl IntoResponse {
+        let router = &state.router;
+        let response_data = serde_json::json!({
+            "current_pool": router.current_pool.map(|address| address.to_string()),
+            "connected_secs": router.connected_for().map(|d| d.as_secs()),
+            "strategy": crate::config::Configuration::pool_selection().strategy,
+            "pools": router.pool_reports(),
+            "switches": router.switch_history(),
+        });
+        (
+            StatusCode::OK,
+            Json(APIResponse::success(Some(response_data))),
+        )
+    }
+
     // Returns the status of the Proxy
     pub async fn health_check() -> impl IntoResponse {
         match ProxyState::is_proxy_down() {
@@ -112,8 +178,15 @@ impl Api {
 struct AggregateStates {
     total_connected_device: u32,
     aggregate_hashrate: f64, // f64 is used here to avoid overflow
+    aggregate_hashrate_averages: HashrateAverages,
     aggregate_accepted_shares: u64,
     aggregate_rejected_shares: u64,
+    aggregate_dropped_shares: u64,
+    /// Shares of every miner not sent because of the share budget of the upstream channel.
+    aggregate_channel_dropped_shares: u64,
+    aggregate_stale_shares: u64,
+    aggregate_duplicate_shares: u64,
+    aggregate_late_shares: u64,
     aggregate_diff: f64,
 }
 
@@ -1,27 +1,55 @@
+use crate::{
+    shared::hashrate::{HashrateAverages, HashrateMeter, HashrateWindow},
+    translator::MinerState,
+};
 use serde::Serialize;
-use std::collections::HashMap;
+use std::{collections::HashMap, time::Instant};
 use tokio::sync::{mpsc, oneshot};
 use tracing::warn;
 
 #[derive(Debug)]
 enum StatsCommand {
     SetupStats(u32),
-    UpdateHashrate(u32, f32),
+    UpdateEstimatedHashrate(u32, f32),
     UpdateDiff(u32, f32),
-    UpdateAcceptedShares(u32),
+    UpdateAcceptedShares(u32, f32),
     UpdateRejectedShares(u32),
+    UpdateDroppedShares(u32),
+    UpdateChannelDroppedShares,
+    UpdateStaleShares(u32),
+    UpdateDuplicateShares(u32),
+    UpdateLateShares(u32),
     UpdateDeviceName(u32, String),
+    UpdateState(u32, MinerState),
     RemoveStats(u32),
     GetStats(oneshot::Sender<HashMap<u32, DownstreamConnectionStats>>),
+    GetAggregateHashrate(oneshot::Sender<HashrateAverages>),
+    GetChannelDroppedShares(oneshot::Sender<u64>),
 }
 
 #[derive(Debug, Clone, Serialize)]
 pub struct DownstreamConnectionStats {
     pub device_name: Option<String>,
+    /// Measured over the last 5 minutes, the estimated one during the first minute.
     pub hashrate: f32,
+    /// Hashrate at which the difficulty of the miner sends the shares per minute of its port.
+    pub estimated_hashrate: f32,
+    /// Measured from the difficulty of the accepted shares.
+    pub hashrate_averages: HashrateAverages,
     pub accepted_shares: u64,
     pub rejected_shares: u64,
+    /// Valid shares rejected by the rate limiter of the miner, not included in `rejected_shares`.
+    pub dropped_shares: u64,
+    /// Rejected shares of jobs built on a previous block, included in `rejected_shares`.
+    pub stale_shares: u64,
+    /// Rejected shares already submitted, included in `rejected_shares`.
+    pub duplicate_shares: u64,
+    /// Accepted shares of a job that was no longer the latest one, included in `accepted_shares`.
+    pub late_shares: u64,
     pub current_difficulty: f32,
+    pub state: MinerState,
+    #[serde(skip)]
+    meter: HashrateMeter,
 }
 
 impl DownstreamConnectionStats {
@@ -29,11 +57,28 @@ impl DownstreamConnectionStats {
         Self {
             device_name: None,
             hashrate: 0.0,
+            estimated_hashrate: 0.0,
+            hashrate_averages: HashrateAverages::default(),
             accepted_shares: 0,
             rejected_shares: 0,
+            dropped_shares: 0,
+            stale_shares: 0,
+            duplicate_shares: 0,
+            late_shares: 0,
             current_difficulty: 0.0,
+            state: MinerState::Active,
+            meter: HashrateMeter::default(),
         }
     }
+
+    /// Updates the measured hashrates before the stats are read.
+    fn measure(&mut self, now: Instant) {
+        self.hashrate_averages = self.meter.averages(now);
+        self.hashrate = self
+            .meter
+            .hashrate(now, HashrateWindow::FiveMinutes)
+            .unwrap_or(self.estimated_hashrate);
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -58,26 +103,57 @@ impl StatsSender {
         self.send(StatsCommand::SetupStats(connection_id));
     }
 
-    pub fn update_hashrate(&self, connection_id: u32, hashrate: f32) {
-        self.send(StatsCommand::UpdateHashrate(connection_id, hashrate));
+    pub fn update_estimated_hashrate(&self, connection_id: u32, hashrate: f32) {
+        self.send(StatsCommand::UpdateEstimatedHashrate(
+            connection_id,
+            hashrate,
+        ));
     }
 
     pub fn update_diff(&self, connection_id: u32, diff: f32) {
         self.send(StatsCommand::UpdateDiff(connection_id, diff));
     }
 
-    pub fn update_accepted_shares(&self, connection_id: u32) {
-        self.send(StatsCommand::UpdateAcceptedShares(connection_id));
+    pub fn update_accepted_shares(&self, connection_id: u32, difficulty: f32) {
+        self.send(StatsCommand::UpdateAcceptedShares(
+            connection_id,
+            difficulty,
+        ));
     }
 
     pub fn update_rejected_shares(&self, connection_id: u32) {
         self.send(StatsCommand::UpdateRejectedShares(connection_id));
     }
 
+    pub fn update_dropped_shares(&self, connection_id: u32) {
+        self.send(StatsCommand::UpdateDroppedShares(connection_id));
+    }
+
+    /// Valid share that did not fit in the share budget of the upstream channel.
+    pub fn update_channel_dropped_shares(&self) {
+        self.send(StatsCommand::UpdateChannelDroppedShares);
+    }
+
+    pub fn update_stale_shares(&self, connection_id: u32) {
+        self.send(StatsCommand::UpdateStaleShares(connection_id));
+    }
+
+    pub fn update_duplicate_shares(&self, connection_id: u32) {
+        self.send(StatsCommand::UpdateDuplicateShares(connection_id));
+    }
+
+    pub fn update_late_shares(&self, connection_id: u32) {
+        self.send(StatsCommand::UpdateLateShares(connection_id));
+    }
+
     pub fn update_device_name(&self, connection_id: u32, name: String) {
         self.send(StatsCommand::UpdateDeviceName(connection_id, name));
     }
 
+    pub fn update_state(&self, connection_id: u32, state: MinerState) {
+        self.send(StatsCommand::UpdateState(connection_id, state));
+    }
+
     pub fn remove_stats(&self, connection_id: u32) {
         self.send(StatsCommand::RemoveStats(connection_id));
     }
@@ -90,10 +166,28 @@ impl StatsSender {
             Err(e) => Err(e.to_string()),
         }
     }
+
+    /// Hashrate of every miner together, including the ones that disconnected within a window.
+    pub async fn collect_aggregate_hashrate(&self) -> Result<HashrateAverages, String> {
+        let (tx, rx) = oneshot::channel();
+        self.send(StatsCommand::GetAggregateHashrate(tx));
+        rx.await.map_err(|e| e.to_string())
+    }
+
+    /// Shares not sent to the pool because the upstream channel was over its share budget.
+    pub async fn collect_channel_dropped_shares(&self) -> Result<u64, String> {
+        let (tx, rx) = oneshot::channel();
+        self.send(StatsCommand::GetChannelDroppedShares(tx));
+        rx.await.map_err(|e| e.to_string())
+    }
 }
 
 struct StatsManager {
     stats: HashMap<u32, DownstreamConnectionStats>,
+    /// Accepted shares of every miner.
+    aggregate: HashrateMeter,
+    /// Shares dropped by the share budget of the upstream channels, for every miner.
+    channel_dropped_shares: u64,
     receiver: mpsc::Receiver<StatsCommand>,
 }
 
@@ -101,6 +195,8 @@ impl StatsManager {
     fn new(receiver: mpsc::Receiver<StatsCommand>) -> Self {
         Self {
             stats: HashMap::new(),
+            aggregate: HashrateMeter::default(),
+            channel_dropped_shares: 0,
             receiver,
         }
     }
@@ -111,9 +207,9 @@ impl StatsManager {
                 StatsCommand::SetupStats(id) => {
                     self.stats.insert(id, DownstreamConnectionStats::new());
                 }
-                StatsCommand::UpdateHashrate(id, hashrate) => {
+                StatsCommand::UpdateEstimatedHashrate(id, hashrate) => {
                     if let Some(stats) = self.stats.get_mut(&id) {
-                        stats.hashrate = hashrate
+                        stats.estimated_hashrate = hashrate
                     }
                 }
                 StatsCommand::UpdateDiff(id, diff) => {
@@ -121,9 +217,12 @@ impl StatsManager {
                         stats.current_difficulty = diff
                     }
                 }
-                StatsCommand::UpdateAcceptedShares(id) => {
+                StatsCommand::UpdateAcceptedShares(id, difficulty) => {
+                    let now = Instant::now();
+                    self.aggregate.add_share(now, difficulty);
                     if let Some(stats) = self.stats.get_mut(&id) {
-                        stats.accepted_shares += 1
+                        stats.accepted_shares += 1;
+                        stats.meter.add_share(now, difficulty);
                     }
                 }
                 StatsCommand::UpdateRejectedShares(id) => {
@@ -131,17 +230,53 @@ impl StatsManager {
                         stats.rejected_shares += 1
                     }
                 }
+                StatsCommand::UpdateDroppedShares(id) => {
+                    if let Some(stats) = self.stats.get_mut(&id) {
+                        stats.dropped_shares += 1
+                    }
+                }
+                StatsCommand::UpdateChannelDroppedShares => self.channel_dropped_shares += 1,
+                StatsCommand::UpdateStaleShares(id) => {
+                    if let Some(stats) = self.stats.get_mut(&id) {
+                        stats.stale_shares += 1
+                    }
+                }
+                StatsCommand::UpdateDuplicateShares(id) => {
+                    if let Some(stats) = self.stats.get_mut(&id) {
+                        stats.duplicate_shares += 1
+                    }
+                }
+                StatsCommand::UpdateLateShares(id) => {
+                    if let Some(stats) = self.stats.get_mut(&id) {
+                        stats.late_shares += 1
+                    }
+                }
                 StatsCommand::UpdateDeviceName(id, name) => {
                     if let Some(stats) = self.stats.get_mut(&id) {
                         stats.device_name = Some(name)
                     }
                 }
+                StatsCommand::UpdateState(id, state) => {
+                    if let Some(stats) = self.stats.get_mut(&id) {
+                        stats.state = state
+                    }
+                }
                 StatsCommand::RemoveStats(id) => {
                     self.stats.remove(&id);
                 }
                 StatsCommand::GetStats(tx) => {
+                    let now = Instant::now();
+                    for stats in self.stats.values_mut() {
+                        stats.measure(now);
+                    }
                     let _ = tx.send(self.stats.clone());
                 }
+                StatsCommand::GetAggregateHashrate(tx) => {
+                    let _ = tx.send(self.aggregate.averages(Instant::now()));
+                }
+                StatsCommand::GetChannelDroppedShares(tx) => {
+                    let _ = tx.send(self.channel_dropped_shares);
+                }
             }
         }
     }
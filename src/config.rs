@@ -2,16 +2,15 @@ use clap::Parser;
 use lazy_static::lazy_static;
 use serde::{Deserialize, Serialize};
 use serde_json::json;
-use std::{
-    net::{SocketAddr, ToSocketAddrs},
-    path::PathBuf,
-    time::Duration,
-};
-use tracing::{debug, error, info};
+use std::{path::PathBuf, time::Duration};
+use tracing::{debug, error, info, warn};
 
 use crate::{
-    shared::error::Error, HashUnit, DEFAULT_SV1_HASHPOWER, PRODUCTION_URL, STAGING_URL,
-    TESTNET3_URL,
+    ingress::admission::AdmissionRules,
+    router::strategy::{PoolSelectionConfig, StrategyKind},
+    shared::{error::Error, version_rolling},
+    translator::{ShareRateLimiter, VardiffConfig, RETAINED_JOBS},
+    HashUnit, DEFAULT_SV1_HASHPOWER, PRODUCTION_URL, STAGING_URL, TESTNET3_URL,
 };
 lazy_static! {
     pub static ref CONFIG: Configuration = Configuration::load_config();
@@ -52,6 +51,13 @@ struct Args {
     auto_update: bool,
     #[clap(long)]
     signature: Option<String>,
+    #[clap(long = "pool-strategy")]
+    pool_strategy: Option<StrategyKind>,
+    #[clap(long, value_delimiter = ',')]
+    pools: Option<Vec<String>>,
+    /// Runs the vardiff simulation described in this file and exits.
+    #[clap(long = "simulate-vardiff")]
+    simulate_vardiff: Option<PathBuf>,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -71,6 +77,481 @@ struct ConfigFile {
     api_server_port: Option<String>,
     monitor: Option<bool>,
     auto_update: Option<bool>,
+    pool_selection: Option<PoolSelectionConfig>,
+    pools: Option<Vec<String>>,
+    pools_mode: Option<PoolsMode>,
+    pool_cache: Option<PathBuf>,
+    tls: Option<TlsConfig>,
+    listeners: Option<Vec<ListenerConfig>>,
+    admission: Option<AdmissionConfig>,
+    firmware: Option<Vec<FirmwareQuirksConfig>>,
+    sv2: Option<Sv2ListenerConfig>,
+    min_difficulty: Option<f32>,
+    max_difficulty: Option<f32>,
+    workers: Option<Vec<WorkerConfig>>,
+    share_rate_limit: Option<ShareRateLimitConfig>,
+    vardiff: Option<VardiffConfig>,
+    share_ack: Option<ShareAckConfig>,
+    job_retention: Option<JobRetentionConfig>,
+    /// Version bits miners may roll, in hex.
+    version_rolling_mask: Option<String>,
+    worker_channels: Option<WorkerChannelMode>,
+    extranonce_fallback: Option<ExtranonceFallback>,
+    /// Miners that send no valid share for this long are disconnected. Unset or 0 never
+    /// disconnects them, listeners can set their own.
+    idle_timeout_secs: Option<u64>,
+}
+
+/// `[share_rate_limit]` section of the config file. Every miner has its own budget of shares,
+/// the shares sent to the pool on the upstream channel have another one. Shares over the budget of
+/// a miner are rejected, shares over the budget of the channel are not sent to the pool. The
+/// bursts default to one minute of shares.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ShareRateLimitConfig {
+    pub miner_shares_per_min: f32,
+    pub miner_burst: Option<f32>,
+    pub channel_shares_per_min: f32,
+    pub channel_burst: Option<f32>,
+}
+
+impl Default for ShareRateLimitConfig {
+    fn default() -> Self {
+        Self {
+            miner_shares_per_min: 70.0,
+            miner_burst: None,
+            channel_shares_per_min: 600.0,
+            channel_burst: None,
+        }
+    }
+}
+
+impl ShareRateLimitConfig {
+    fn from_env() -> Self {
+        let default = Self::default();
+        let limit = |var: &str| std::env::var(var).ok().and_then(|s| s.parse().ok());
+        Self {
+            miner_shares_per_min: limit("MINER_SHARES_PER_MIN")
+                .unwrap_or(default.miner_shares_per_min),
+            miner_burst: limit("MINER_SHARES_BURST"),
+            channel_shares_per_min: limit("CHANNEL_SHARES_PER_MIN")
+                .unwrap_or(default.channel_shares_per_min),
+            channel_burst: limit("CHANNEL_SHARES_BURST"),
+        }
+    }
+
+    /// A budget that is not positive would drop every share.
+    fn validate(&self) -> Result<(), String> {
+        let limits = [
+            ("miner_shares_per_min", Some(self.miner_shares_per_min)),
+            ("miner_burst", self.miner_burst),
+            ("channel_shares_per_min", Some(self.channel_shares_per_min)),
+            ("channel_burst", self.channel_burst),
+        ];
+        for (name, limit) in limits {
+            if let Some(limit) = limit {
+                if limit.is_nan() || limit <= 0.0 {
+                    return Err(format!("{} must be greater than 0, got {}", name, limit));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Limiter of the shares of a single miner.
+    pub fn miner_limiter(&self) -> ShareRateLimiter {
+        ShareRateLimiter::new(
+            self.miner_shares_per_min,
+            self.miner_burst.unwrap_or(self.miner_shares_per_min),
+        )
+    }
+
+    /// Limiter of the shares sent on the upstream channel.
+    pub fn channel_limiter(&self) -> ShareRateLimiter {
+        ShareRateLimiter::new(
+            self.channel_shares_per_min,
+            self.channel_burst.unwrap_or(self.channel_shares_per_min),
+        )
+    }
+}
+
+/// `[share_ack]` section of the config file. In strict mode a miner is answered for a share that
+/// meets the pool target only once the pool accepted or rejected it, so that its counters match
+/// what the pool credited. Without a verdict within `timeout_secs` the share is answered as
+/// `on_timeout` says.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ShareAckConfig {
+    pub strict: bool,
+    pub timeout_secs: u64,
+    pub on_timeout: AckFallback,
+}
+
+impl Default for ShareAckConfig {
+    fn default() -> Self {
+        Self {
+            strict: false,
+            timeout_secs: 10,
+            on_timeout: AckFallback::Accept,
+        }
+    }
+}
+
+impl ShareAckConfig {
+    fn from_env() -> Self {
+        let default = Self::default();
+        Self {
+            strict: std::env::var("STRICT_SHARE_ACK").is_ok(),
+            timeout_secs: std::env::var("SHARE_ACK_TIMEOUT_SECS")
+                .ok()
+                .and_then(|s| s.parse().ok())
+                .unwrap_or(default.timeout_secs),
+            on_timeout: std::env::var("SHARE_ACK_ON_TIMEOUT")
+                .ok()
+                .and_then(|s| match s.to_lowercase().as_str() {
+                    "accept" => Some(AckFallback::Accept),
+                    "reject" => Some(AckFallback::Reject),
+                    _ => None,
+                })
+                .unwrap_or(default.on_timeout),
+        }
+    }
+}
+
+/// `[job_retention]` section of the config file. Jobs a miner may still submit shares for, by
+/// count and by age, for the jobs built on the current prevhash and for the ones built on earlier
+/// ones. The latest job is always kept. Shares of a kept job of the current prevhash that is not
+/// the latest are accepted and counted as late, the ones of an earlier prevhash are rejected as
+/// stale. No more jobs of the current prevhash are kept than the channel factory of the `Bridge`
+/// can still translate shares for.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct JobRetentionConfig {
+    pub current_max_jobs: usize,
+    pub current_max_age_secs: u64,
+    pub previous_max_jobs: usize,
+    pub previous_max_age_secs: u64,
+}
+
+impl Default for JobRetentionConfig {
+    fn default() -> Self {
+        Self {
+            current_max_jobs: RETAINED_JOBS,
+            current_max_age_secs: 300,
+            previous_max_jobs: 3,
+            previous_max_age_secs: 60,
+        }
+    }
+}
+
+impl JobRetentionConfig {
+    fn from_env() -> Self {
+        let default = Self::default();
+        let var = |name: &str| std::env::var(name).ok().and_then(|s| s.parse().ok());
+        Self {
+            current_max_jobs: var("CURRENT_MAX_JOBS").unwrap_or(default.current_max_jobs),
+            current_max_age_secs: var("CURRENT_MAX_JOB_AGE_SECS")
+                .unwrap_or(default.current_max_age_secs),
+            previous_max_jobs: var("PREVIOUS_MAX_JOBS").unwrap_or(default.previous_max_jobs),
+            previous_max_age_secs: var("PREVIOUS_MAX_JOB_AGE_SECS")
+                .unwrap_or(default.previous_max_age_secs),
+        }
+    }
+
+    /// Jobs kept of the current prevhash, the latest included, or of the earlier ones.
+    pub fn max_jobs(&self, current: bool) -> usize {
+        if current {
+            self.current_max_jobs.min(RETAINED_JOBS)
+        } else {
+            self.previous_max_jobs
+        }
+    }
+
+    pub fn max_age(&self, current: bool) -> Duration {
+        Duration::from_secs(if current {
+            self.current_max_age_secs
+        } else {
+            self.previous_max_age_secs
+        })
+    }
+}
+
+/// Answer to a miner whose share got no verdict from the pool.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AckFallback {
+    Accept,
+    Reject,
+}
+
+/// Channels opened with the pool for the SV1 miners. With `worker` every authorized worker name
+/// gets its own extended channel, with `group` the workers that only differ in the part of the
+/// name after the last dot share one. The name is the `user_identity` of the channel, so that the
+/// pool accounts hashrate and rejects to it, and the pool `SetTarget` of the channel only applies
+/// to its miners.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum WorkerChannelMode {
+    /// Every miner is on the channel opened by the translator.
+    #[default]
+    Shared,
+    Worker,
+    Group,
+}
+
+/// What happens to a miner that can not be sent `mining.set_extranonce` when its extranonce
+/// changes, after an upstream switch or a `SetExtranoncePrefix` of the pool. Its extranonce1 is
+/// made of the prefix of the pool, so it can not keep mining on the new one and has to get a new
+/// extranonce1 from a new `mining.subscribe`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ExtranonceFallback {
+    /// The connection is closed, the miner reconnects after its own timeout.
+    #[default]
+    Disconnect,
+    /// The miner is sent `client.reconnect` to the same host and port before the connection is
+    /// closed, so that firmwares that handle it reconnect right away.
+    Reconnect,
+}
+
+impl WorkerChannelMode {
+    /// `user_identity` of the channel of a worker, `None` when it mines on the shared channel.
+    pub fn identity(&self, worker_name: &str) -> Option<String> {
+        match self {
+            Self::Shared => None,
+            Self::Worker => Some(worker_name.to_string()),
+            Self::Group => Some(
+                worker_name
+                    .rsplit_once('.')
+                    .map_or(worker_name, |(group, _)| group)
+                    .to_string(),
+            ),
+        }
+    }
+}
+
+/// `[[workers]]` entry of the config file: starting difficulty of a worker, it wins over the
+/// `mining.suggest_difficulty` and password hints of the miner.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct WorkerConfig {
+    /// Full user name of `mining.authorize`, or the worker part after the last dot.
+    pub name: String,
+    pub difficulty: Option<f32>,
+    /// Expected hashrate of the worker, e.g. "10T".
+    pub hashrate: Option<String>,
+}
+
+/// `[[firmware]]` entry of the config file. Changes the quirks of the firmware `name` (e.g.
+/// `luxminer`, or `generic` for the unknown ones) or adds a firmware matched on `agent`.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct FirmwareQuirksConfig {
+    pub name: String,
+    /// Case insensitive substring of the `mining.subscribe` agent signature.
+    pub agent: Option<String>,
+    pub null_id: Option<bool>,
+    pub integer_difficulty: Option<bool>,
+    pub notify_after_difficulty: Option<bool>,
+    pub set_extranonce: Option<bool>,
+    pub version_rolling: Option<bool>,
+}
+
+/// `[admission]` section of the config file. Limits which miners can connect to the SV1
+/// listeners. `allow` and `deny` take IPs or CIDR ranges, `deny` wins over `allow` and an empty
+/// `allow` lets everyone in.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AdmissionConfig {
+    pub allow: Vec<String>,
+    pub deny: Vec<String>,
+    pub max_miners: Option<usize>,
+    pub max_per_ip: Option<usize>,
+    /// New connections accepted from a single IP in a minute.
+    pub max_connections_per_minute: Option<usize>,
+}
+
+impl AdmissionConfig {
+    fn from_env() -> Self {
+        let list = |var: &str| -> Vec<String> {
+            std::env::var(var)
+                .map(|s| {
+                    s.split(',')
+                        .map(|n| n.trim().to_string())
+                        .filter(|n| !n.is_empty())
+                        .collect()
+                })
+                .unwrap_or_default()
+        };
+        let limit = |var: &str| std::env::var(var).ok().and_then(|s| s.parse().ok());
+        Self {
+            allow: list("ADMISSION_ALLOW"),
+            deny: list("ADMISSION_DENY"),
+            max_miners: limit("MAX_MINERS"),
+            max_per_ip: limit("MAX_CONNECTIONS_PER_IP"),
+            max_connections_per_minute: limit("MAX_CONNECTIONS_PER_MINUTE"),
+        }
+    }
+}
+
+/// `[[listeners]]` entry of the config file: an additional SV1 port with its own profile.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ListenerConfig {
+    pub listening_addr: String,
+    #[serde(flatten)]
+    pub overrides: ProfileOverrides,
+}
+
+/// Profile fields of a listener section. Fields that are not set take the value of the main
+/// listener.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct ProfileOverrides {
+    /// Expected hashrate of the miners on this port, e.g. "1T" or "120T".
+    pub downstream_hashrate: Option<String>,
+    pub share_per_min: Option<f32>,
+    pub min_difficulty: Option<f32>,
+    pub max_difficulty: Option<f32>,
+    /// Prepended to the worker names of the miners on this port.
+    pub worker_prefix: Option<String>,
+    pub vardiff: Option<VardiffConfig>,
+    pub idle_timeout_secs: Option<u64>,
+}
+
+/// Settings that drive the difficulty of the miners connected on a given SV1 port.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PortProfile {
+    pub expected_hashrate: f32,
+    pub share_per_min: f32,
+    /// Bounds of the difficulty, also applied to the difficulty hints of the miners.
+    pub min_difficulty: Option<f32>,
+    pub max_difficulty: Option<f32>,
+    pub worker_prefix: Option<String>,
+    pub vardiff: VardiffConfig,
+    /// Miners that send no share for this long are disconnected.
+    pub idle_timeout: Option<Duration>,
+}
+
+impl PortProfile {
+    /// Profile of the main listener, from `downstream_hashrate`, `SHARE_PER_MIN`, the difficulty
+    /// bounds and the vardiff of the config.
+    pub fn main() -> Self {
+        Self {
+            expected_hashrate: *crate::EXPECTED_SV1_HASHPOWER,
+            share_per_min: *crate::SHARE_PER_MIN,
+            min_difficulty: CONFIG.min_difficulty,
+            max_difficulty: CONFIG.max_difficulty,
+            worker_prefix: None,
+            vardiff: CONFIG.vardiff.clone(),
+            idle_timeout: CONFIG.idle_timeout,
+        }
+    }
+
+    pub fn clamp_difficulty(&self, difficulty: f32) -> f32 {
+        let difficulty = self
+            .min_difficulty
+            .map_or(difficulty, |min| difficulty.max(min));
+        self.max_difficulty
+            .map_or(difficulty, |max| difficulty.min(max))
+    }
+
+    pub fn worker_name(&self, name: &str) -> String {
+        match &self.worker_prefix {
+            Some(prefix) => format!("{}{}", prefix, name),
+            None => name.to_string(),
+        }
+    }
+}
+
+impl ListenerConfig {
+    pub fn profile(&self) -> PortProfile {
+        self.overrides.profile()
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        self.listening_addr
+            .parse::<std::net::SocketAddr>()
+            .map_err(|e| format!("invalid listening_addr: {}", e))?;
+        self.overrides.validate()
+    }
+}
+
+impl ProfileOverrides {
+    /// A listener must not silently fall back to the hashrate of the main one.
+    fn validate(&self) -> Result<(), String> {
+        if let Some(hashrate) = &self.downstream_hashrate {
+            parse_hashrate(hashrate)?;
+        }
+        Ok(())
+    }
+
+    pub fn profile(&self) -> PortProfile {
+        let main = PortProfile::main();
+        PortProfile {
+            // Checked by `validate` when the config is loaded
+            expected_hashrate: self
+                .downstream_hashrate
+                .as_deref()
+                .and_then(|h| parse_hashrate(h).ok())
+                .unwrap_or(main.expected_hashrate),
+            share_per_min: self.share_per_min.unwrap_or(main.share_per_min),
+            min_difficulty: self.min_difficulty.or(main.min_difficulty),
+            max_difficulty: self.max_difficulty.or(main.max_difficulty),
+            worker_prefix: self.worker_prefix.clone(),
+            vardiff: self.vardiff.clone().unwrap_or(main.vardiff),
+            idle_timeout: self
+                .idle_timeout_secs
+                .map_or(main.idle_timeout, idle_timeout),
+        }
+    }
+}
+
+/// `[tls]` section of the config file. When present, miners can also connect with stratum+ssl.
+/// The profile fields are the same as the ones of `[[listeners]]`.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TlsConfig {
+    pub listening_addr: String,
+    /// PEM certificate chain, reloaded when the file changes.
+    pub cert: PathBuf,
+    /// PEM private key, reloaded when the file changes.
+    pub key: PathBuf,
+    #[serde(flatten)]
+    pub overrides: ProfileOverrides,
+}
+
+impl TlsConfig {
+    pub fn profile(&self) -> PortProfile {
+        self.overrides.profile()
+    }
+}
+
+/// `[sv2]` section of the config file. When present, SV2 miners and proxies can connect with
+/// Noise encrypted connections and open standard or extended channels.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Sv2ListenerConfig {
+    pub listening_addr: String,
+    /// Authority public key, in the base58 format used by the pools.
+    pub public_key: String,
+    /// Authority secret key, in the base58 format used by the pools.
+    pub secret_key: String,
+    /// Validity of the certificate sent in the handshake, in seconds.
+    pub cert_validity_sec: Option<u64>,
+    #[serde(flatten)]
+    pub overrides: ProfileOverrides,
+}
+
+impl Sv2ListenerConfig {
+    pub fn profile(&self) -> PortProfile {
+        self.overrides.profile()
+    }
+}
+
+/// How the `pools` list of the config file is combined with the pools from the dashboard.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PoolsMode {
+    /// Only the configured pools are used, the dashboard is not queried.
+    #[default]
+    Replace,
+    /// The configured pools come first, followed by the discovered ones.
+    Add,
 }
 
 impl ConfigFile {
@@ -91,6 +572,26 @@ impl ConfigFile {
             api_server_port: None,
             monitor: None,
             auto_update: None,
+            pool_selection: None,
+            pools: None,
+            pools_mode: None,
+            pool_cache: None,
+            tls: None,
+            listeners: None,
+            admission: None,
+            firmware: None,
+            sv2: None,
+            min_difficulty: None,
+            max_difficulty: None,
+            workers: None,
+            share_rate_limit: None,
+            vardiff: None,
+            share_ack: None,
+            job_retention: None,
+            version_rolling_mask: None,
+            worker_channels: None,
+            extranonce_fallback: None,
+            idle_timeout_secs: None,
         }
     }
 }
@@ -112,6 +613,27 @@ pub struct Configuration {
     monitor: bool,
     auto_update: bool,
     signature: String,
+    pool_selection: PoolSelectionConfig,
+    pools: Vec<String>,
+    pools_mode: PoolsMode,
+    pool_cache: PathBuf,
+    tls: Option<TlsConfig>,
+    listeners: Vec<ListenerConfig>,
+    admission: AdmissionRules,
+    firmware: Vec<FirmwareQuirksConfig>,
+    sv2: Option<Sv2ListenerConfig>,
+    min_difficulty: Option<f32>,
+    max_difficulty: Option<f32>,
+    workers: Vec<WorkerConfig>,
+    share_rate_limit: ShareRateLimitConfig,
+    vardiff: VardiffConfig,
+    simulate_vardiff: Option<PathBuf>,
+    share_ack: ShareAckConfig,
+    job_retention: JobRetentionConfig,
+    version_rolling_mask: u32,
+    worker_channels: WorkerChannelMode,
+    extranonce_fallback: ExtranonceFallback,
+    idle_timeout: Option<Duration>,
 }
 impl Configuration {
     pub fn token() -> Option<String> {
@@ -122,14 +644,38 @@ impl Configuration {
         CONFIG.tp_address.clone()
     }
 
-    pub async fn pool_address() -> Option<Vec<SocketAddr>> {
-        match fetch_pool_urls().await {
-            Ok(addresses) => Some(addresses),
+    /// Returns the pools as `host:port`. Hostnames are resolved by the `Router` every time it
+    /// selects a pool. When the dashboard can not be reached, the pools it returned last time
+    /// are read from the pool cache.
+    pub async fn pool_address() -> Option<Vec<String>> {
+        let mut pools = CONFIG.pools.clone();
+        if !pools.is_empty() && CONFIG.pools_mode == PoolsMode::Replace {
+            return Some(pools);
+        }
+        let discovered = match fetch_pool_urls().await {
+            Ok(addresses) if !addresses.is_empty() => {
+                save_pool_cache(&CONFIG.pool_cache, &addresses);
+                Some(addresses)
+            }
+            Ok(_) => {
+                error!("Dashboard returned no pool addresses");
+                load_pool_cache(&CONFIG.pool_cache)
+            }
             Err(e) => {
                 error!("Failed to fetch pool addresses: {}", e);
-                None
+                load_pool_cache(&CONFIG.pool_cache)
+            }
+        };
+        for pool in discovered.into_iter().flatten() {
+            if !pools.contains(&pool) {
+                pools.push(pool);
             }
         }
+        if pools.is_empty() {
+            None
+        } else {
+            Some(pools)
+        }
     }
 
     pub fn adjustment_interval() -> u64 {
@@ -148,6 +694,63 @@ impl Configuration {
         CONFIG.listening_addr.clone()
     }
 
+    pub fn tls() -> Option<TlsConfig> {
+        CONFIG.tls.clone()
+    }
+
+    /// SV1 listeners in addition to the main one.
+    pub fn listeners() -> &'static [ListenerConfig] {
+        &CONFIG.listeners
+    }
+
+    pub fn admission() -> &'static AdmissionRules {
+        &CONFIG.admission
+    }
+
+    /// SV2 listener for miners that speak SV2 directly.
+    pub fn sv2_listener() -> Option<Sv2ListenerConfig> {
+        CONFIG.sv2.clone()
+    }
+
+    /// Firmware quirks that change or add to the built in ones.
+    pub fn firmware_quirks() -> &'static [FirmwareQuirksConfig] {
+        &CONFIG.firmware
+    }
+
+    pub fn simulate_vardiff() -> Option<&'static PathBuf> {
+        CONFIG.simulate_vardiff.as_ref()
+    }
+
+    pub fn share_rate_limit() -> &'static ShareRateLimitConfig {
+        &CONFIG.share_rate_limit
+    }
+
+    pub fn share_ack() -> &'static ShareAckConfig {
+        &CONFIG.share_ack
+    }
+
+    pub fn job_retention() -> &'static JobRetentionConfig {
+        &CONFIG.job_retention
+    }
+
+    /// Version bits miners may roll, if the pool allows it.
+    pub fn version_rolling_mask() -> u32 {
+        CONFIG.version_rolling_mask
+    }
+
+    pub fn worker_channels() -> WorkerChannelMode {
+        CONFIG.worker_channels
+    }
+
+    pub fn extranonce_fallback() -> ExtranonceFallback {
+        CONFIG.extranonce_fallback
+    }
+
+    /// Starting difficulties of single workers.
+    pub fn workers() -> &'static [WorkerConfig] {
+        &CONFIG.workers
+    }
+
     pub fn api_server_port() -> String {
         CONFIG.api_server_port.clone()
     }
@@ -220,6 +823,10 @@ impl Configuration {
         CONFIG.signature.clone()
     }
 
+    pub fn pool_selection() -> &'static PoolSelectionConfig {
+        &CONFIG.pool_selection
+    }
+
     // Loads config from CLI, file, or env vars with precedence: CLI > file > env.
     fn load_config() -> Self {
         let args = Args::parse();
@@ -339,6 +946,165 @@ impl Configuration {
             || config.auto_update.unwrap_or(true)
             || std::env::var("AUTO_UPDATE").is_ok();
 
+        let mut pool_selection = config
+            .pool_selection
+            .unwrap_or_else(|| PoolSelectionConfig {
+                strategy: std::env::var("POOL_STRATEGY")
+                    .ok()
+                    .and_then(|s| s.parse().ok())
+                    .unwrap_or_default(),
+                ..Default::default()
+            });
+        if let Some(strategy) = args.pool_strategy {
+            pool_selection.strategy = strategy;
+        }
+
+        let pools = args
+            .pools
+            .or(config.pools)
+            .or_else(|| {
+                std::env::var("POOLS")
+                    .ok()
+                    .map(|s| s.split(',').map(|p| p.trim().to_string()).collect())
+            })
+            .unwrap_or_default()
+            .into_iter()
+            .filter(|p| !p.is_empty())
+            .collect();
+        let pools_mode = config
+            .pools_mode
+            .or_else(|| {
+                std::env::var("POOLS_MODE")
+                    .ok()
+                    .and_then(|s| match s.to_lowercase().as_str() {
+                        "replace" => Some(PoolsMode::Replace),
+                        "add" => Some(PoolsMode::Add),
+                        _ => None,
+                    })
+            })
+            .unwrap_or_default();
+        let pool_cache = config
+            .pool_cache
+            .or_else(|| std::env::var("POOL_CACHE").ok().map(PathBuf::from))
+            .unwrap_or("pool_cache.json".into());
+
+        let tls = config.tls.or_else(|| {
+            Some(TlsConfig {
+                listening_addr: std::env::var("TLS_LISTENING_ADDR").ok()?,
+                cert: std::env::var("TLS_CERT").ok()?.into(),
+                key: std::env::var("TLS_KEY").ok()?.into(),
+                overrides: ProfileOverrides::default(),
+            })
+        });
+
+        let listeners = config.listeners.unwrap_or_default();
+        for listener in &listeners {
+            listener
+                .validate()
+                .unwrap_or_else(|e| panic!("Invalid listener {}: {}", listener.listening_addr, e));
+        }
+        if let Some(tls) = &tls {
+            tls.overrides
+                .validate()
+                .unwrap_or_else(|e| panic!("Invalid tls listener: {}", e));
+        }
+
+        let sv2 = config.sv2.or_else(|| {
+            Some(Sv2ListenerConfig {
+                listening_addr: std::env::var("SV2_LISTENING_ADDR").ok()?,
+                public_key: std::env::var("SV2_PUBLIC_KEY").ok()?,
+                secret_key: std::env::var("SV2_SECRET_KEY").ok()?,
+                cert_validity_sec: std::env::var("SV2_CERT_VALIDITY_SEC")
+                    .ok()
+                    .and_then(|s| s.parse().ok()),
+                overrides: ProfileOverrides::default(),
+            })
+        });
+        if let Some(sv2) = &sv2 {
+            sv2.overrides
+                .validate()
+                .unwrap_or_else(|e| panic!("Invalid sv2 listener: {}", e));
+        }
+
+        let difficulty_bound = |value: Option<f32>, var: &str| {
+            value.or_else(|| std::env::var(var).ok().and_then(|s| s.parse().ok()))
+        };
+        let min_difficulty = difficulty_bound(config.min_difficulty, "MIN_DIFFICULTY");
+        let max_difficulty = difficulty_bound(config.max_difficulty, "MAX_DIFFICULTY");
+
+        let vardiff = config.vardiff.unwrap_or_else(|| VardiffConfig {
+            algorithm: std::env::var("VARDIFF")
+                .ok()
+                .and_then(|s| s.parse().ok())
+                .unwrap_or_default(),
+            ..Default::default()
+        });
+
+        let version_rolling_mask = config
+            .version_rolling_mask
+            .or_else(|| std::env::var("VERSION_ROLLING_MASK").ok())
+            .map(|mask| version_rolling::parse_mask(&mask).unwrap_or_else(|e| panic!("{}", e)))
+            .unwrap_or(version_rolling::DEFAULT_MASK);
+
+        let worker_channels = config
+            .worker_channels
+            .or_else(|| {
+                std::env::var("WORKER_CHANNELS").ok().and_then(|s| {
+                    match s.to_lowercase().as_str() {
+                        "shared" => Some(WorkerChannelMode::Shared),
+                        "worker" => Some(WorkerChannelMode::Worker),
+                        "group" => Some(WorkerChannelMode::Group),
+                        _ => None,
+                    }
+                })
+            })
+            .unwrap_or_default();
+
+        let extranonce_fallback = config
+            .extranonce_fallback
+            .or_else(|| {
+                std::env::var("EXTRANONCE_FALLBACK").ok().and_then(|s| {
+                    match s.to_lowercase().as_str() {
+                        "disconnect" => Some(ExtranonceFallback::Disconnect),
+                        "reconnect" => Some(ExtranonceFallback::Reconnect),
+                        _ => None,
+                    }
+                })
+            })
+            .unwrap_or_default();
+
+        // Off unless set, here or on the listeners
+        let idle_timeout = config
+            .idle_timeout_secs
+            .or_else(|| {
+                std::env::var("IDLE_TIMEOUT_SECS")
+                    .ok()
+                    .and_then(|s| s.parse().ok())
+            })
+            .and_then(idle_timeout);
+
+        let job_retention = config
+            .job_retention
+            .unwrap_or_else(JobRetentionConfig::from_env);
+        if job_retention.current_max_jobs > RETAINED_JOBS {
+            warn!(
+                "job_retention.current_max_jobs is {}, only the last {} jobs are kept",
+                job_retention.current_max_jobs, RETAINED_JOBS
+            );
+        }
+
+        let share_rate_limit = config
+            .share_rate_limit
+            .unwrap_or_else(ShareRateLimitConfig::from_env);
+        share_rate_limit
+            .validate()
+            .unwrap_or_else(|e| panic!("Invalid share_rate_limit config: {}", e));
+
+        // A typo in a deny list must not silently let everyone in
+        let admission = config.admission.unwrap_or_else(AdmissionConfig::from_env);
+        let admission = AdmissionRules::try_from(&admission)
+            .unwrap_or_else(|e| panic!("Invalid admission config: {}", e));
+
         Configuration {
             token,
             tp_address,
@@ -356,12 +1122,37 @@ impl Configuration {
             monitor,
             auto_update,
             signature,
+            pool_selection,
+            pools,
+            pools_mode,
+            pool_cache,
+            tls,
+            listeners,
+            admission,
+            firmware: config.firmware.unwrap_or_default(),
+            sv2,
+            min_difficulty,
+            max_difficulty,
+            workers: config.workers.unwrap_or_default(),
+            share_rate_limit,
+            vardiff,
+            simulate_vardiff: args.simulate_vardiff,
+            share_ack: config.share_ack.unwrap_or_else(ShareAckConfig::from_env),
+            job_retention,
+            version_rolling_mask,
+            worker_channels,
+            extranonce_fallback,
+            idle_timeout,
         }
     }
 }
 
+fn idle_timeout(secs: u64) -> Option<Duration> {
+    Some(Duration::from_secs(secs)).filter(|timeout| !timeout.is_zero())
+}
+
 /// Parses a hashrate string (e.g., "10T", "2.5P", "500E") into an f32 value in h/s.
-fn parse_hashrate(hashrate_str: &str) -> Result<f32, String> {
+pub(crate) fn parse_hashrate(hashrate_str: &str) -> Result<f32, String> {
     info!("Received hashrate: '{}'", hashrate_str);
     let hashrate_str = hashrate_str.trim();
     if hashrate_str.is_empty() {
@@ -394,29 +1185,11 @@ fn parse_hashrate(hashrate_str: &str) -> Result<f32, String> {
     Ok(hashrate)
 }
 
-fn parse_address(addr: String) -> Option<SocketAddr> {
-    match addr.to_socket_addrs() {
-        Ok(mut addrs) => match addrs.next() {
-            Some(socket_addr) => Some(socket_addr),
-            None => {
-                error!("Failed to parse address: {}", addr);
-                None
-            }
-        },
-        Err(e) => {
-            error!("Failed to parse address '{}': {}", addr, e);
-            None
-        }
-    }
-}
-
 /// Fetches pool URLs from the server based on the environment.
-async fn fetch_pool_urls() -> Result<Vec<SocketAddr>, Error> {
+async fn fetch_pool_urls() -> Result<Vec<String>, Error> {
     if CONFIG.local {
         info!("Running in local mode, using hardcoded address 127.0.0.1:20000");
-        return Ok(vec![
-            parse_address("127.0.0.1:20000".to_string()).expect("Invalid local address")
-        ]);
+        return Ok(vec!["127.0.0.1:20000".to_string()]);
     };
     let url = if CONFIG.staging {
         STAGING_URL
@@ -460,17 +1233,13 @@ async fn fetch_pool_urls() -> Result<Vec<SocketAddr>, Error> {
         }
     };
 
-    // Parse the addresses into SocketAddr
-    let socket_addrs: Vec<SocketAddr> = addresses
+    let addresses: Vec<String> = addresses
         .into_iter()
-        .filter_map(|addr| {
-            let address = format!("{}:{}", addr.host, addr.port);
-            parse_address(address)
-        }) // Filter out any None values, i.e., invalid addresses
+        .map(|addr| format!("{}:{}", addr.host, addr.port))
         .collect();
-    info!("Found {} pool addresses", socket_addrs.len());
-    info!("Pool addresses: {:?}", &socket_addrs);
-    Ok(socket_addrs)
+    info!("Found {} pool addresses", addresses.len());
+    info!("Pool addresses: {:?}", &addresses);
+    Ok(addresses)
 }
 
 #[derive(Debug, Deserialize)]
@@ -478,3 +1247,38 @@ struct PoolAddress {
     host: String,
     port: u16,
 }
+
+/// Pools returned by the dashboard the last time it was reachable.
+#[derive(Serialize, Deserialize)]
+struct PoolCache {
+    environment: String,
+    pools: Vec<String>,
+}
+
+fn save_pool_cache(path: &PathBuf, pools: &[String]) {
+    let cache = PoolCache {
+        environment: Configuration::environment(),
+        pools: pools.to_vec(),
+    };
+    let result = serde_json::to_string(&cache)
+        .map_err(|e| e.to_string())
+        .and_then(|content| std::fs::write(path, content).map_err(|e| e.to_string()));
+    if let Err(e) = result {
+        error!("Failed to write pool cache {}: {}", path.display(), e);
+    }
+}
+
+fn load_pool_cache(path: &PathBuf) -> Option<Vec<String>> {
+    let cache: PoolCache = std::fs::read_to_string(path)
+        .ok()
+        .and_then(|content| serde_json::from_str(&content).ok())?;
+    if cache.environment != Configuration::environment() || cache.pools.is_empty() {
+        return None;
+    }
+    info!(
+        "Using pool addresses cached in {}: {:?}",
+        path.display(),
+        cache.pools
+    );
+    Some(cache.pools)
+}
@@ -0,0 +1,350 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    net::IpAddr,
+    str::FromStr,
+    time::{Duration, Instant},
+};
+
+use lazy_static::lazy_static;
+use roles_logic_sv2::utils::Mutex;
+use serde::Serialize;
+use tracing::{error, warn};
+
+use crate::config::{AdmissionConfig, Configuration};
+
+// Window of the per IP connection rate limit
+const RATE_WINDOW: Duration = Duration::from_secs(60);
+
+lazy_static! {
+    static ref ADMISSION: Mutex<Admission> =
+        Mutex::new(Admission::new(Configuration::admission().clone()));
+}
+
+/// An IP address or a CIDR range, e.g. `192.168.1.10` or `10.0.0.0/8`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct IpNet {
+    network: IpAddr,
+    prefix: u8,
+}
+
+impl IpNet {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.network, ip.to_canonical()) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                mask_eq(u32::from(network), u32::from(ip), self.prefix, 32)
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                mask_eq(u128::from(network), u128::from(ip), self.prefix, 128)
+            }
+            _ => false,
+        }
+    }
+}
+
+fn mask_eq<T>(network: T, ip: T, prefix: u8, bits: u8) -> bool
+where
+    T: Copy + Eq + std::ops::Shr<u8, Output = T>,
+{
+    if prefix == 0 {
+        return true;
+    }
+    let shift = bits - prefix;
+    (network >> shift) == (ip >> shift)
+}
+
+impl FromStr for IpNet {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let (address, prefix) = match s.split_once('/') {
+            Some((address, prefix)) => (address, Some(prefix)),
+            None => (s, None),
+        };
+        let network = IpAddr::from_str(address)
+            .map_err(|e| format!("Invalid address {}: {}", s, e))?
+            .to_canonical();
+        let max_prefix = if network.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix
+                .parse::<u8>()
+                .ok()
+                .filter(|p| *p <= max_prefix)
+                .ok_or(format!("Invalid prefix length in {}", s))?,
+            None => max_prefix,
+        };
+        Ok(Self { network, prefix })
+    }
+}
+
+/// Parsed `[admission]` section of the config file.
+#[derive(Clone, Debug, Default)]
+pub struct AdmissionRules {
+    allow: Vec<IpNet>,
+    deny: Vec<IpNet>,
+    max_miners: Option<usize>,
+    max_per_ip: Option<usize>,
+    max_connections_per_minute: Option<usize>,
+}
+
+impl TryFrom<&AdmissionConfig> for AdmissionRules {
+    type Error = String;
+
+    fn try_from(config: &AdmissionConfig) -> Result<Self, Self::Error> {
+        let parse = |nets: &[String]| {
+            nets.iter()
+                .map(|n| n.parse())
+                .collect::<Result<Vec<IpNet>, _>>()
+        };
+        Ok(Self {
+            allow: parse(&config.allow)?,
+            deny: parse(&config.deny)?,
+            max_miners: config.max_miners,
+            max_per_ip: config.max_per_ip,
+            max_connections_per_minute: config.max_connections_per_minute,
+        })
+    }
+}
+
+/// Why a connection was refused.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Rejection {
+    /// The IP is in the deny list.
+    Denied,
+    /// There is an allow list and the IP is not in it.
+    NotAllowed,
+    MaxMiners,
+    MaxPerIp,
+    RateLimited,
+}
+
+/// Connections refused since the proxy started, by reason.
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct RejectionCounts {
+    pub denied: u64,
+    pub not_allowed: u64,
+    pub max_miners: u64,
+    pub max_per_ip: u64,
+    pub rate_limited: u64,
+}
+
+impl RejectionCounts {
+    fn add(&mut self, rejection: Rejection) {
+        let count = match rejection {
+            Rejection::Denied => &mut self.denied,
+            Rejection::NotAllowed => &mut self.not_allowed,
+            Rejection::MaxMiners => &mut self.max_miners,
+            Rejection::MaxPerIp => &mut self.max_per_ip,
+            Rejection::RateLimited => &mut self.rate_limited,
+        };
+        *count += 1;
+    }
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct AdmissionStats {
+    pub connected: usize,
+    pub rejected: RejectionCounts,
+}
+
+#[derive(Default)]
+struct PerIp {
+    connected: usize,
+    /// When the connections admitted in the last `RATE_WINDOW` were opened.
+    admitted_at: VecDeque<Instant>,
+}
+
+struct Admission {
+    rules: AdmissionRules,
+    connected: usize,
+    per_ip: HashMap<IpAddr, PerIp>,
+    rejected: RejectionCounts,
+}
+
+impl Admission {
+    fn new(rules: AdmissionRules) -> Self {
+        Self {
+            rules,
+            connected: 0,
+            per_ip: HashMap::new(),
+            rejected: RejectionCounts::default(),
+        }
+    }
+
+    fn admit(&mut self, ip: IpAddr, now: Instant) -> Result<(), Rejection> {
+        let result = self.check(ip, now);
+        match result {
+            Ok(()) => {
+                self.connected += 1;
+                let per_ip = self.per_ip.entry(ip).or_default();
+                per_ip.connected += 1;
+                per_ip.admitted_at.push_back(now);
+            }
+            Err(rejection) => self.rejected.add(rejection),
+        }
+        result
+    }
+
+    fn check(&mut self, ip: IpAddr, now: Instant) -> Result<(), Rejection> {
+        let rules = &self.rules;
+        if rules.deny.iter().any(|net| net.contains(ip)) {
+            return Err(Rejection::Denied);
+        }
+        if !rules.allow.is_empty() && !rules.allow.iter().any(|net| net.contains(ip)) {
+            return Err(Rejection::NotAllowed);
+        }
+        if rules.max_miners.is_some_and(|max| self.connected >= max) {
+            return Err(Rejection::MaxMiners);
+        }
+        let Some(per_ip) = self.per_ip.get_mut(&ip) else {
+            return Ok(());
+        };
+        while per_ip
+            .admitted_at
+            .front()
+            .is_some_and(|t| now.saturating_duration_since(*t) >= RATE_WINDOW)
+        {
+            per_ip.admitted_at.pop_front();
+        }
+        if rules.max_per_ip.is_some_and(|max| per_ip.connected >= max) {
+            return Err(Rejection::MaxPerIp);
+        }
+        if rules
+            .max_connections_per_minute
+            .is_some_and(|max| per_ip.admitted_at.len() >= max)
+        {
+            return Err(Rejection::RateLimited);
+        }
+        Ok(())
+    }
+
+    fn release(&mut self, ip: IpAddr, now: Instant) {
+        self.connected = self.connected.saturating_sub(1);
+        if let Some(per_ip) = self.per_ip.get_mut(&ip) {
+            per_ip.connected = per_ip.connected.saturating_sub(1);
+        }
+        // IPs with no connection are kept only while they count for the rate limit
+        self.per_ip.retain(|_, per_ip| {
+            per_ip.connected > 0
+                || per_ip
+                    .admitted_at
+                    .back()
+                    .is_some_and(|t| now.saturating_duration_since(*t) < RATE_WINDOW)
+        });
+    }
+}
+
+/// Held for as long as an admitted miner is connected, the slot is released on drop.
+#[derive(Debug)]
+pub struct AdmissionGuard {
+    ip: IpAddr,
+}
+
+impl Drop for AdmissionGuard {
+    fn drop(&mut self) {
+        if ADMISSION
+            .safe_lock(|a| a.release(self.ip, Instant::now()))
+            .is_err()
+        {
+            error!("Admission Mutex corrupted");
+        }
+    }
+}
+
+/// Checks a new connection from `ip` against the `[admission]` rules. Returns `None` when the
+/// connection must be closed.
+pub fn admit(ip: IpAddr) -> Option<AdmissionGuard> {
+    let ip = ip.to_canonical();
+    match ADMISSION.safe_lock(|a| a.admit(ip, Instant::now())) {
+        Ok(Ok(())) => Some(AdmissionGuard { ip }),
+        Ok(Err(rejection)) => {
+            warn!("Connection from {} rejected: {:?}", ip, rejection);
+            None
+        }
+        Err(e) => {
+            error!("Admission Mutex corrupted: {e}");
+            None
+        }
+    }
+}
+
+pub fn stats() -> Option<AdmissionStats> {
+    ADMISSION
+        .safe_lock(|a| AdmissionStats {
+            connected: a.connected,
+            rejected: a.rejected.clone(),
+        })
+        .ok()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn parses_and_matches_cidrs() {
+        let lan: IpNet = "192.168.1.0/24".parse().unwrap();
+        assert!(lan.contains(ip("192.168.1.42")));
+        assert!(lan.contains(ip("::ffff:192.168.1.42")));
+        assert!(!lan.contains(ip("192.168.2.1")));
+        let single: IpNet = "10.0.0.1".parse().unwrap();
+        assert!(single.contains(ip("10.0.0.1")));
+        assert!(!single.contains(ip("10.0.0.2")));
+        let any: IpNet = "0.0.0.0/0".parse().unwrap();
+        assert!(any.contains(ip("8.8.8.8")));
+        assert!(!any.contains(ip("fe80::1")));
+        let v6: IpNet = "fd00::/8".parse().unwrap();
+        assert!(v6.contains(ip("fd12::1")));
+        assert!("10.0.0.0/33".parse::<IpNet>().is_err());
+        assert!("not an ip".parse::<IpNet>().is_err());
+    }
+
+    #[test]
+    fn applies_rules_and_limits() {
+        let config = AdmissionConfig {
+            allow: vec!["10.0.0.0/8".into()],
+            deny: vec!["10.0.0.66".into()],
+            max_miners: Some(3),
+            max_per_ip: Some(2),
+            max_connections_per_minute: Some(3),
+        };
+        let mut admission = Admission::new((&config).try_into().unwrap());
+        let now = Instant::now();
+        let miner = ip("10.0.0.1");
+
+        assert_eq!(
+            admission.admit(ip("10.0.0.66"), now),
+            Err(Rejection::Denied)
+        );
+        assert_eq!(
+            admission.admit(ip("192.168.1.1"), now),
+            Err(Rejection::NotAllowed)
+        );
+        assert_eq!(admission.admit(miner, now), Ok(()));
+        assert_eq!(admission.admit(miner, now), Ok(()));
+        assert_eq!(admission.admit(miner, now), Err(Rejection::MaxPerIp));
+        admission.release(miner, now);
+        assert_eq!(admission.admit(miner, now), Ok(()));
+        admission.release(miner, now);
+        // Three connections in the last minute
+        assert_eq!(admission.admit(miner, now), Err(Rejection::RateLimited));
+        let later = now + RATE_WINDOW;
+        assert_eq!(admission.admit(miner, later), Ok(()));
+        assert_eq!(admission.admit(ip("10.0.0.2"), later), Ok(()));
+        assert_eq!(
+            admission.admit(ip("10.0.0.3"), later),
+            Err(Rejection::MaxMiners)
+        );
+
+        assert_eq!(admission.connected, 3);
+        assert_eq!(admission.rejected.denied, 1);
+        assert_eq!(admission.rejected.not_allowed, 1);
+        assert_eq!(admission.rejected.max_per_ip, 1);
+        assert_eq!(admission.rejected.rate_limited, 1);
+        assert_eq!(admission.rejected.max_miners, 1);
+    }
+}
@@ -1,3 +1,6 @@
+pub mod admission;
 pub mod sv1_ingress;
+pub mod sv1_tls_ingress;
+pub mod sv2_ingress;
 //pub mod sv2_up_connection;
 //pub mod task_manager;
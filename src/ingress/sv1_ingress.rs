@@ -1,11 +1,16 @@
 use std::{
     net::{IpAddr, SocketAddr},
     sync::Arc,
+    time::Duration,
 };
 
 use crate::{
-    config::Configuration,
-    shared::{error::Sv1IngressError, utils::AbortOnDrop},
+    config::{Configuration, PortProfile},
+    shared::{
+        error::Sv1IngressError,
+        firmware::{self, FirmwareQuirks, MessageRewriter},
+        utils::AbortOnDrop,
+    },
 };
 use futures::{
     stream::{SplitSink, SplitStream},
@@ -13,70 +18,113 @@ use futures::{
 };
 use roles_logic_sv2::utils::Mutex;
 use tokio::{
-    net::{TcpListener, TcpStream},
+    io::{AsyncRead, AsyncWrite},
+    net::TcpListener,
     sync::mpsc::{channel, Receiver, Sender},
 };
-use tokio_util::codec::{Framed, LinesCodec};
+use tokio_util::codec::{Framed, LinesCodec, LinesCodecError};
 use tracing::{error, info, warn};
 
+use super::admission::{self, AdmissionGuard};
+
+// Time between two attempts to start a listener
+const RETRY_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Listens for SV1 miners on `down_addr`. Every miner connected on it gets `profile`.
 pub fn start_listen_for_downstream(
-    downstreams: Sender<(Sender<String>, Receiver<String>, IpAddr)>,
+    downstreams: Sender<(Sender<String>, Receiver<String>, IpAddr, Arc<PortProfile>)>,
+    down_addr: String,
+    profile: Arc<PortProfile>,
 ) -> AbortOnDrop {
     tokio::task::spawn(async move {
-        let down_addr: String = crate::SV1_DOWN_LISTEN_ADDR.to_string();
-        let downstream_addr: SocketAddr = down_addr.parse().expect("Invalid listen address");
-        info!(
-            "Trying to bind to address {} for downstream(miner) connections",
-            downstream_addr
-        );
-        let downstream_listener = TcpListener::bind(downstream_addr)
-            .await
-            .expect("impossible to bind downstream");
-        info!(
-            "Listening for downstream connections on {:?}",
-            downstream_addr
-        );
+        // A busy port must not stop the other listeners, so wait for it to be free
+        let downstream_listener = loop {
+            match bind(&down_addr).await {
+                Ok(listener) => break listener,
+                Err(e) => {
+                    error!(
+                        "Impossible to start SV1 listener, retrying in {}s: {}",
+                        RETRY_INTERVAL.as_secs(),
+                        e
+                    );
+                    tokio::time::sleep(RETRY_INTERVAL).await;
+                }
+            }
+        };
         while let Ok((stream, addr)) = downstream_listener.accept().await {
             info!("Try to connect {:#?}", addr);
+            // Dropping the stream closes the connection
+            let Some(admission) = admission::admit(addr.ip()) else {
+                continue;
+            };
             Downstream::initialize(
                 stream,
                 crate::MAX_LEN_DOWN_MSG,
                 addr.ip(),
+                profile.clone(),
                 downstreams.clone(),
+                admission,
             );
         }
     })
     .into()
 }
-struct Downstream {}
+async fn bind(down_addr: &str) -> Result<TcpListener, String> {
+    let downstream_addr: SocketAddr = down_addr
+        .parse()
+        .map_err(|e| format!("Invalid listen address {}: {}", down_addr, e))?;
+    info!(
+        "Trying to bind to address {} for downstream(miner) connections",
+        downstream_addr
+    );
+    let downstream_listener = TcpListener::bind(downstream_addr)
+        .await
+        .map_err(|e| format!("Impossible to bind {}: {}", downstream_addr, e))?;
+    info!(
+        "Listening for downstream connections on {:?}",
+        downstream_addr
+    );
+    Ok(downstream_listener)
+}
+
+pub(super) struct Downstream {}
 
 impl Downstream {
-    pub fn initialize(
-        stream: TcpStream,
+    /// Relays the lines of `stream` to the translator. `stream` is either a plain TCP stream or a
+    /// TLS one. The admission slot of the miner is released when the connection ends.
+    pub fn initialize<S: AsyncRead + AsyncWrite + Unpin + Send + 'static>(
+        stream: S,
         max_len_for_downstream_messages: u32,
         address: IpAddr,
-        downstreams: Sender<(Sender<String>, Receiver<String>, IpAddr)>,
+        profile: Arc<PortProfile>,
+        downstreams: Sender<(Sender<String>, Receiver<String>, IpAddr, Arc<PortProfile>)>,
+        admission: AdmissionGuard,
     ) {
         tokio::spawn(async move {
+            let _admission = admission;
             info!("spawning downstream");
             let (send_to_upstream, recv) = channel(10);
             let (send, recv_from_upstream) = channel(10);
-            downstreams
-                .send((send, recv, address))
+            if downstreams
+                .send((send, recv, address, profile))
                 .await
-                .expect("Translator busy");
+                .is_err()
+            {
+                error!("Translator dropped, closing connection with {}", address);
+                return;
+            }
             let codec = LinesCodec::new_with_max_length(max_len_for_downstream_messages as usize);
             let framed = Framed::new(stream, codec);
             Self::start(framed, recv_from_upstream, send_to_upstream).await
         });
     }
-    async fn start(
-        framed: Framed<TcpStream, LinesCodec>,
+    async fn start<S: AsyncRead + AsyncWrite + Unpin + Send + 'static>(
+        framed: Framed<S, LinesCodec>,
         receiver: Receiver<String>,
         sender: Sender<String>,
     ) {
         let (writer, reader) = framed.split();
-        let firmware = Arc::new(Mutex::new(Firmware::Uninitialized));
+        let firmware = Arc::new(Mutex::new(None));
         let result = tokio::select! {
             result1 = Self::receive_from_downstream_and_relay_up(reader, sender, firmware.clone()) => result1,
             result2 = Self::receive_from_upstream_and_relay_down(writer, receiver, firmware.clone()) => result2,
@@ -88,23 +136,38 @@ impl Downstream {
             Sv1IngressError::TaskFailed => (),
         }
     }
-    async fn receive_from_downstream_and_relay_up(
-        mut recv: SplitStream<Framed<TcpStream, LinesCodec>>,
+    async fn receive_from_downstream_and_relay_up<
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    >(
+        mut recv: SplitStream<Framed<S, LinesCodec>>,
         send: Sender<String>,
-        firmware: Arc<Mutex<Firmware>>,
+        firmware: Arc<Mutex<Option<&'static FirmwareQuirks>>>,
     ) -> Sv1IngressError {
         let mut is_subscribed = false;
         let task = tokio::spawn(async move {
-            while let Some(Ok(message)) = recv.next().await {
+            while let Some(message) = recv.next().await {
+                let message = match message {
+                    Ok(message) => message,
+                    // The codec drops the rest of the line and goes on with the next one
+                    Err(LinesCodecError::MaxLineLengthExceeded) => {
+                        warn!("Downstream sent a line longer than the limit, dropped");
+                        continue;
+                    }
+                    Err(e) => {
+                        warn!("Failed to read from downstream: {e}");
+                        break;
+                    }
+                };
                 if Configuration::sv1_ingress_log() {
                     info!("Sending msg to upstream: {}", message);
                 }
                 if !is_subscribed && message.contains("mining.subscribe") {
                     is_subscribed = true;
-                    if message.contains("LUXminer") {
-                        firmware.safe_lock(|f| *f = Firmware::Luxor).unwrap();
-                    } else {
-                        firmware.safe_lock(|f| *f = Firmware::Other).unwrap();
+                    let quirks = firmware::quirks_for(&subscribe_agent(&message));
+                    info!("Downstream firmware: {}", quirks.name);
+                    if firmware.safe_lock(|f| *f = Some(quirks)).is_err() {
+                        error!("Firmware Mutex corrupt");
+                        return Sv1IngressError::TaskFailed;
                     }
                 }
                 if send.send(message).await.is_err() {
@@ -121,29 +184,39 @@ impl Downstream {
             Err(_) => Sv1IngressError::TaskFailed,
         }
     }
-    async fn receive_from_upstream_and_relay_down(
-        mut send: SplitSink<Framed<TcpStream, LinesCodec>, String>,
+    async fn receive_from_upstream_and_relay_down<
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    >(
+        mut send: SplitSink<Framed<S, LinesCodec>, String>,
         mut recv: Receiver<String>,
-        firmware_: Arc<Mutex<Firmware>>,
+        firmware: Arc<Mutex<Option<&'static FirmwareQuirks>>>,
     ) -> Sv1IngressError {
-        let mut firmware = Firmware::Uninitialized;
+        let mut rewriter: Option<MessageRewriter> = None;
         let task = tokio::spawn(async move {
             while let Some(message) = recv.recv().await {
-                let mut message = message.replace(['\n', '\r'], "");
-                if !firmware.is_initialized() {
-                    firmware = firmware_.safe_lock(|f| *f).unwrap();
-                } else if firmware.is_luxor() && !message.contains("\"id\"") {
-                    if let Some(pos) = message.find('{') {
-                        message.insert_str(pos + 1, r#""id":null,"#);
+                let message = message.replace(['\n', '\r'], "");
+                if rewriter.is_none() {
+                    match firmware.safe_lock(|f| *f) {
+                        Ok(quirks) => rewriter = quirks.map(MessageRewriter::new),
+                        Err(_) => {
+                            error!("Firmware Mutex corrupt");
+                            return Sv1IngressError::TaskFailed;
+                        }
                     }
                 }
-                if Configuration::sv1_ingress_log() {
-                    info!("Sending msg to downstream_: {}", message);
-                }
-                if send.send(message).await.is_err() {
-                    warn!("Downstream dropped while trying to send message down");
-                    return Sv1IngressError::DownstreamDropped;
+                let messages = match rewriter.as_mut() {
+                    Some(rewriter) => rewriter.rewrite(message),
+                    None => vec![message],
                 };
+                for message in messages {
+                    if Configuration::sv1_ingress_log() {
+                        info!("Sending msg to downstream_: {}", message);
+                    }
+                    if send.send(message).await.is_err() {
+                        warn!("Downstream dropped while trying to send message down");
+                        return Sv1IngressError::DownstreamDropped;
+                    };
+                }
             }
             if send.close().await.is_err() {
                 error!("Failed to close connection");
@@ -159,18 +232,10 @@ impl Downstream {
     }
 }
 
-#[derive(Debug, Clone, Copy)]
-enum Firmware {
-    Luxor,
-    Other,
-    Uninitialized,
-}
-
-impl Firmware {
-    fn is_initialized(&self) -> bool {
-        !matches!(self, Firmware::Uninitialized)
-    }
-    fn is_luxor(&self) -> bool {
-        matches!(self, Firmware::Luxor)
-    }
+/// Agent signature of a `mining.subscribe` request, empty when the miner does not send one.
+fn subscribe_agent(message: &str) -> String {
+    serde_json::from_str::<serde_json::Value>(message)
+        .ok()
+        .and_then(|m| m.get("params")?.get(0)?.as_str().map(|a| a.to_string()))
+        .unwrap_or_default()
 }
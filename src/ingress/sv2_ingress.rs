@@ -0,0 +1,264 @@
+use std::{
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+    time::Duration,
+};
+
+use crate::{
+    config::{PortProfile, Sv2ListenerConfig},
+    shared::utils::AbortOnDrop,
+};
+use codec_sv2::{HandshakeRole, StandardEitherFrame, StandardSv2Frame};
+use demand_sv2_connection::noise_connection_tokio::Connection;
+use key_utils::{Secp256k1PublicKey, Secp256k1SecretKey};
+use noise_sv2::Responder;
+use roles_logic_sv2::{
+    common_messages_sv2::{
+        Protocol, SetupConnection, SetupConnectionError, SetupConnectionSuccess,
+    },
+    parsers::{CommonMessages, Mining, MiningDeviceMessages},
+};
+use tokio::{
+    net::{TcpListener, TcpStream},
+    sync::mpsc::{channel, Receiver, Sender},
+};
+use tracing::{error, info, warn};
+
+use super::admission::{self, AdmissionGuard};
+
+pub type Message = MiningDeviceMessages<'static>;
+pub type StdFrame = StandardSv2Frame<Message>;
+pub type EitherFrame = StandardEitherFrame<Message>;
+
+// Time between two attempts to start the listener
+const RETRY_INTERVAL: Duration = Duration::from_secs(30);
+// Max time a miner has to complete the noise handshake and send `SetupConnection`
+const SETUP_TIMEOUT: Duration = Duration::from_secs(10);
+// Validity of the certificate when not set in the config
+const DEFAULT_CERT_VALIDITY: Duration = Duration::from_secs(3600);
+// Only version of the mining protocol that is supported
+const MINING_PROTOCOL_VERSION: u16 = 2;
+
+/// Listens for SV2 miners and proxies. Every connection completes the noise handshake and a
+/// `SetupConnection` for the mining protocol, then its mining messages are relayed to the
+/// translator through `downstreams`.
+pub fn start_listen_for_sv2_downstream(
+    downstreams: Sender<(
+        Sender<Mining<'static>>,
+        Receiver<Mining<'static>>,
+        IpAddr,
+        Arc<PortProfile>,
+    )>,
+    config: Sv2ListenerConfig,
+    profile: Arc<PortProfile>,
+) -> AbortOnDrop {
+    tokio::task::spawn(async move {
+        // A wrong key must not stop the SV1 listeners, so wait for the config to be fixed
+        let (downstream_listener, keys) = loop {
+            match bind(&config).await {
+                Ok(bound) => break bound,
+                Err(e) => {
+                    error!(
+                        "Impossible to start SV2 listener, retrying in {}s: {}",
+                        RETRY_INTERVAL.as_secs(),
+                        e
+                    );
+                    tokio::time::sleep(RETRY_INTERVAL).await;
+                }
+            }
+        };
+        let cert_validity = config
+            .cert_validity_sec
+            .map(Duration::from_secs)
+            .unwrap_or(DEFAULT_CERT_VALIDITY);
+        while let Ok((stream, addr)) = downstream_listener.accept().await {
+            info!("Try to connect with SV2 {:#?}", addr);
+            let Some(admission) = admission::admit(addr.ip()) else {
+                continue;
+            };
+            let responder = match Responder::from_authority_kp(&keys.0, &keys.1, cert_validity) {
+                Ok(responder) => responder,
+                Err(e) => {
+                    error!("Impossible to create noise responder: {:?}", e);
+                    break;
+                }
+            };
+            let downstreams = downstreams.clone();
+            let profile = profile.clone();
+            tokio::spawn(async move {
+                match tokio::time::timeout(SETUP_TIMEOUT, setup_connection(stream, responder)).await
+                {
+                    Ok(Ok((receiver, sender))) => {
+                        info!("SV2 downstream {} connected", addr);
+                        start_relays(receiver, sender, addr.ip(), downstreams, profile, admission)
+                            .await
+                    }
+                    Ok(Err(e)) => warn!("SV2 connection with {} failed: {}", addr, e),
+                    Err(_) => warn!("SV2 connection with {} timed out", addr),
+                }
+            });
+        }
+    })
+    .into()
+}
+
+async fn bind(config: &Sv2ListenerConfig) -> Result<(TcpListener, ([u8; 32], [u8; 32])), String> {
+    let keys = parse_keys(config)?;
+    let downstream_addr: SocketAddr = config.listening_addr.parse().map_err(|e| {
+        format!(
+            "Invalid SV2 listen address {}: {}",
+            config.listening_addr, e
+        )
+    })?;
+    let downstream_listener = TcpListener::bind(downstream_addr)
+        .await
+        .map_err(|e| format!("Impossible to bind {}: {}", downstream_addr, e))?;
+    info!(
+        "Listening for SV2 downstream connections on {:?}",
+        downstream_addr
+    );
+    Ok((downstream_listener, keys))
+}
+
+fn parse_keys(config: &Sv2ListenerConfig) -> Result<([u8; 32], [u8; 32]), String> {
+    let public_key: Secp256k1PublicKey = config
+        .public_key
+        .parse()
+        .map_err(|e| format!("Invalid SV2 public key: {:?}", e))?;
+    let secret_key: Secp256k1SecretKey = config
+        .secret_key
+        .parse()
+        .map_err(|e| format!("Invalid SV2 secret key: {:?}", e))?;
+    Ok((public_key.into_bytes(), secret_key.into_bytes()))
+}
+
+/// Performs the noise handshake and answers the `SetupConnection` of the downstream.
+async fn setup_connection(
+    stream: TcpStream,
+    responder: Box<Responder>,
+) -> Result<(Receiver<EitherFrame>, Sender<EitherFrame>), String> {
+    let (mut receiver, sender, _, _) = Connection::new(stream, HandshakeRole::Responder(responder))
+        .await
+        .map_err(|e| format!("noise handshake failed: {:?}", e))?;
+    let frame = receiver
+        .recv()
+        .await
+        .ok_or("closed before SetupConnection")?;
+    let mut frame: StdFrame = frame
+        .try_into()
+        .map_err(|e| format!("invalid frame: {:?}", e))?;
+    let header = frame.get_header().ok_or("frame without header")?;
+    let message: CommonMessages<'_> = (header.msg_type(), frame.payload())
+        .try_into()
+        .map_err(|e| format!("expected SetupConnection: {:?}", e))?;
+    let CommonMessages::SetupConnection(setup) = message else {
+        return Err(format!("expected SetupConnection, received {:?}", message));
+    };
+    let response = match check_setup_connection(&setup) {
+        Ok(()) => CommonMessages::SetupConnectionSuccess(SetupConnectionSuccess {
+            used_version: MINING_PROTOCOL_VERSION,
+            flags: 0,
+        }),
+        Err(error_code) => CommonMessages::SetupConnectionError(SetupConnectionError {
+            flags: 0,
+            error_code: error_code.to_string().try_into().expect("Internal error: this operation can not fail because the error codes can always be converted into Inner"),
+        }),
+    };
+    let accepted = matches!(response, CommonMessages::SetupConnectionSuccess(_));
+    let frame: StdFrame = MiningDeviceMessages::Common(response)
+        .try_into()
+        .map_err(|e| format!("impossible to encode SetupConnection response: {:?}", e))?;
+    sender
+        .send(frame.into())
+        .await
+        .map_err(|_| "closed before SetupConnection response")?;
+    if !accepted {
+        return Err("SetupConnection rejected".to_string());
+    }
+    Ok((receiver, sender))
+}
+
+fn check_setup_connection(setup: &SetupConnection) -> Result<(), &'static str> {
+    if setup.protocol != Protocol::MiningProtocol {
+        return Err("unsupported-protocol");
+    }
+    if setup.min_version > MINING_PROTOCOL_VERSION || setup.max_version < MINING_PROTOCOL_VERSION {
+        return Err("protocol-version-mismatch");
+    }
+    Ok(())
+}
+
+/// Relays the mining messages between the connection and the translator until either side
+/// closes. The admission slot is released when the connection ends.
+async fn start_relays(
+    mut receiver: Receiver<EitherFrame>,
+    sender: Sender<EitherFrame>,
+    address: IpAddr,
+    downstreams: Sender<(
+        Sender<Mining<'static>>,
+        Receiver<Mining<'static>>,
+        IpAddr,
+        Arc<PortProfile>,
+    )>,
+    profile: Arc<PortProfile>,
+    admission: AdmissionGuard,
+) {
+    let _admission = admission;
+    let (send_to_translator, recv_from_down) = channel(crate::TRANSLATOR_BUFFER_SIZE);
+    let (send_to_down, mut recv_from_translator) = channel(crate::TRANSLATOR_BUFFER_SIZE);
+    if downstreams
+        .send((send_to_down, recv_from_down, address, profile))
+        .await
+        .is_err()
+    {
+        error!(
+            "Translator not available, dropping SV2 downstream {}",
+            address
+        );
+        return;
+    }
+    loop {
+        tokio::select! {
+            frame = receiver.recv() => {
+                let Some(frame) = frame else { break };
+                match parse_mining(frame) {
+                    Ok(message) => {
+                        if send_to_translator.send(message).await.is_err() {
+                            break;
+                        }
+                    }
+                    Err(e) => {
+                        warn!("SV2 downstream {} sent an invalid message: {}", address, e);
+                        break;
+                    }
+                }
+            }
+            message = recv_from_translator.recv() => {
+                // The translator closed the connection
+                let Some(message) = message else { break };
+                let frame: StdFrame = match MiningDeviceMessages::Mining(message).try_into() {
+                    Ok(frame) => frame,
+                    Err(e) => {
+                        error!("Impossible to encode message for SV2 downstream: {:?}", e);
+                        break;
+                    }
+                };
+                if sender.send(frame.into()).await.is_err() {
+                    break;
+                }
+            }
+        }
+    }
+    info!("SV2 downstream {} disconnected", address);
+}
+
+fn parse_mining(frame: EitherFrame) -> Result<Mining<'static>, String> {
+    let mut frame: StdFrame = frame
+        .try_into()
+        .map_err(|e| format!("invalid frame: {:?}", e))?;
+    let header = frame.get_header().ok_or("frame without header")?;
+    let message: Mining<'_> = (header.msg_type(), frame.payload())
+        .try_into()
+        .map_err(|e| format!("not a mining message: {:?}", e))?;
+    Ok(message.into_static())
+}
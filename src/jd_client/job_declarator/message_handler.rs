@@ -15,6 +15,9 @@ impl ParseServerJobDeclarationMessages for JobDeclarator {
         &mut self,
         message: AllocateMiningJobTokenSuccess,
     ) -> Result<SendTo, Error> {
+        if let Some(requested_at) = self.token_requested_at.take() {
+            crate::router::latency::on_mining_token(requested_at.elapsed());
+        }
         self.allocated_tokens.push(message.into_static());
 
         Ok(SendTo::None(None))
@@ -12,7 +12,7 @@ use roles_logic_sv2::{
     template_distribution_sv2::SetNewPrevHash,
     utils::Mutex,
 };
-use std::{collections::HashMap, convert::TryInto};
+use std::{collections::HashMap, convert::TryInto, time::Instant};
 use task_manager::TaskManager;
 use tokio::sync::mpsc::{Receiver as TReceiver, Sender as TSender};
 use tracing::{error, info};
@@ -75,6 +75,8 @@ pub struct JobDeclarator {
     pub coinbase_tx_prefix: B064K<'static>,
     pub coinbase_tx_suffix: B064K<'static>,
     pub task_manager: Arc<Mutex<TaskManager>>,
+    /// When the first mining token was requested, until it is allocated.
+    token_requested_at: Option<Instant>,
 }
 
 impl JobDeclarator {
@@ -84,6 +86,7 @@ impl JobDeclarator {
         up: Arc<Mutex<Upstream>>,
         should_log_when_connected: bool,
     ) -> Result<(Arc<Mutex<Self>>, AbortOnDrop), Error> {
+        let connect_timer = Instant::now();
         let stream = tokio::net::TcpStream::connect(address).await?;
         let initiator = Initiator::from_raw_k(authority_public_key)?;
         let (mut receiver, mut sender, _, _) =
@@ -92,6 +95,7 @@ impl JobDeclarator {
                 .map_err(|_| Error::Unrecoverable)?;
 
         SetupConnectionHandler::setup(&mut receiver, &mut sender, address).await?;
+        crate::router::latency::on_jd_connected(connect_timer.elapsed());
 
         if should_log_when_connected {
             info!("JD CONNECTED");
@@ -117,6 +121,7 @@ impl JobDeclarator {
             coinbase_tx_suffix: vec![].try_into().expect("Internal error: this operation can not fail because Vec can always be converted into Inner"),
             set_new_prev_hash_counter: 0,
             task_manager,
+            token_requested_at: Some(Instant::now()),
         }));
 
         Self::allocate_tokens(&self_, 2).await;
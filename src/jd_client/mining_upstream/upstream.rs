@@ -101,6 +101,10 @@ pub struct Upstream {
     /// set by the SV2 Upstream via the SV2 `OpenExtendedMiningChannelSuccess` message.
     pub min_extranonce_size: u16,
     pub upstream_extranonce1_size: usize,
+    /// Extranonce size and target of the channel, kept to replicate it again in the channel
+    /// factory when the pool changes the extranonce prefix.
+    extranonce_size: u16,
+    target: Option<U256<'static>>,
     /// Receives messages from the SV2 Upstream role
     //pub receiver: TReceiver<EitherFrame>,
     /// Sends messages to the SV2 Upstream role
@@ -137,6 +141,8 @@ impl Upstream {
             channel_id: None,
             min_extranonce_size,
             upstream_extranonce1_size: 16, // 16 is the default since that is the only value the pool supports currently
+            extranonce_size: 0,
+            target: None,
             sender,
             downstream: None,
             channel_factory: None,
@@ -426,6 +432,9 @@ impl ParseUpstreamMiningMessages<Downstream, NullDownstreamMiningSelector, NoRou
             .try_into()
             .expect("Internal error: this operation can not fail because extranonce_pref can always be converted to Vec<u8>");
         self.channel_id = Some(m.channel_id);
+        self.upstream_extranonce1_size = prefix_len;
+        self.extranonce_size = m.extranonce_size;
+        self.target = Some(m.target.clone().into_static());
         channel_factory
             .replicate_upstream_extended_channel_only_jd(
                 m.target.into_static(),
@@ -495,10 +504,12 @@ impl ParseUpstreamMiningMessages<Downstream, NullDownstreamMiningSelector, NoRou
         Ok(SendTo::RelaySameMessageToRemote(downstream))
     }
 
-    /// Handles the SV2 `SetExtranoncePrefix` message (TODO).
+    /// Handles the SV2 `SetExtranoncePrefix` message. The channel replicated in the factory that
+    /// checks the shares gets the new prefix, then the message is relayed to the translator which
+    /// moves its miners to it.
     fn handle_set_extranonce_prefix(
         &mut self,
-        _: roles_logic_sv2::mining_sv2::SetExtranoncePrefix,
+        m: roles_logic_sv2::mining_sv2::SetExtranoncePrefix,
     ) -> Result<roles_logic_sv2::handlers::mining::SendTo<Downstream>, RolesLogicError> {
         let downstream = match self.downstream.as_ref() {
             Some(downstream) => downstream.clone(),
@@ -507,6 +518,61 @@ impl ParseUpstreamMiningMessages<Downstream, NullDownstreamMiningSelector, NoRou
                 return Err(RolesLogicError::DownstreamDown);
             }
         };
+        if self.channel_id != Some(m.channel_id) {
+            warn!(
+                "SetExtranoncePrefix for unknown Channel Id {}, ignoring it",
+                m.channel_id
+            );
+            return Ok(SendTo::None(None));
+        }
+        // The ranges of the factory are set when the channel is opened
+        let prefix_len = m.extranonce_prefix.to_vec().len();
+        if prefix_len != self.upstream_extranonce1_size {
+            error!(
+                "Extranonce prefix of Channel Id {} changed size from {} to {} bytes",
+                m.channel_id, self.upstream_extranonce1_size, prefix_len
+            );
+            return Err(RolesLogicError::InvalidExtranonceSize(
+                self.upstream_extranonce1_size as u16,
+                prefix_len as u16,
+            ));
+        }
+        let target = self
+            .target
+            .clone()
+            .ok_or(RolesLogicError::NotFoundChannelId)?;
+        let extranonce: Extranonce = m.extranonce_prefix.to_vec().try_into().map_err(|_| {
+            RolesLogicError::InvalidExtranonceSize(
+                self.upstream_extranonce1_size as u16,
+                prefix_len as u16,
+            )
+        })?;
+        let (channel_id, extranonce_size) = (m.channel_id, self.extranonce_size);
+        info!("New extranonce prefix for Channel Id {}", channel_id);
+        match self.channel_factory.as_mut() {
+            Some(factory) => {
+                factory.replicate_upstream_extended_channel_only_jd(
+                    target,
+                    extranonce,
+                    channel_id,
+                    extranonce_size,
+                )?;
+            }
+            // The factory has already been handed to the downstream
+            None => downstream
+                .safe_lock(|d| {
+                    d.status
+                        .get_channel()?
+                        .replicate_upstream_extended_channel_only_jd(
+                            target,
+                            extranonce,
+                            channel_id,
+                            extranonce_size,
+                        )
+                        .map(|_| ())
+                })
+                .map_err(|e| RolesLogicError::PoisonLock(e.to_string()))??,
+        };
 
         Ok(SendTo::RelaySameMessageToRemote(downstream))
     }
@@ -435,6 +435,9 @@ impl TemplateRx {
                                                 }
                                             }
                                             Some(TemplateDistribution::SetNewPrevHash(m)) => {
+                                                crate::router::latency::on_tp_prev_hash(
+                                                    m.prev_hash.to_vec(),
+                                                );
                                                 super::IS_NEW_PHASH_ARRIVED.store(
                                                     true,
                                                     std::sync::atomic::Ordering::Release,
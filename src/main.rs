@@ -7,12 +7,12 @@ use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt, Layer};
 static GLOBAL: Jemalloc = Jemalloc;
 
 use crate::{monitor::logs::SendLogLayer, shared::utils::AbortOnDrop};
-use config::Configuration;
+use config::{Configuration, PortProfile};
 use key_utils::Secp256k1PublicKey;
 use lazy_static::lazy_static;
 use proxy_state::{PoolState, ProxyState, TpState, TranslatorState};
 use self_update::{backends, cargo_crate_version, update::UpdateStatus, TempDir};
-use std::{net::SocketAddr, time::Duration};
+use std::{net::SocketAddr, sync::Arc, time::Duration};
 use tokio::sync::mpsc::channel;
 use tracing::{debug, error, info, warn};
 mod api;
@@ -93,6 +93,14 @@ async fn main() {
         .with(remote_layer)
         .init();
 
+    if let Some(path) = Configuration::simulate_vardiff() {
+        if let Err(e) = translator::vardiff_sim::run(path) {
+            error!("Vardiff simulation failed: {}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+
     Configuration::token().expect("TOKEN is not set");
 
     //`self_update` performs synchronous I/O so spawn_blocking is needed
@@ -115,27 +123,31 @@ async fn main() {
 
     let auth_pub_k: Secp256k1PublicKey = AUTH_PUB_KEY.parse().expect("Invalid public key");
 
-    let pool_addresses = Configuration::pool_address()
-        .await
-        .filter(|p| !p.is_empty())
-        .unwrap_or_else(|| match Configuration::environment().as_str() {
-            "staging" => panic!("Staging pool address is missing"),
-            "testnet3" => panic!("Testnet3 pool address is missing"),
-            "local" => panic!("Local pool address is missing"),
-            "production" => panic!("Pool address is missing"),
-            _ => unreachable!(),
-        });
-
-    let mut router = router::Router::new(pool_addresses, auth_pub_k, None, None);
-    let epsilon = Duration::from_millis(30_000);
+    // Without configured pools, a cached discovery or a reachable dashboard there is nothing to
+    // connect to, keep trying instead of exiting.
+    let pool_addresses = loop {
+        match Configuration::pool_address().await {
+            Some(pools) => break pools,
+            None => {
+                error!(
+                    "{} pool address is missing. Set `pools` in the config file or check the connection with the dashboard. Retrying in 30 seconds...",
+                    Configuration::environment()
+                );
+                tokio::time::sleep(Duration::from_secs(30)).await;
+            }
+        }
+    };
+
+    let mut router = router::Router::new(
+        pool_addresses,
+        auth_pub_k,
+        None,
+        None,
+        Configuration::pool_selection(),
+    );
     let best_upstream = router.select_pool_connect().await;
-    initialize_proxy(
-        &mut router,
-        best_upstream,
-        epsilon,
-        Configuration::signature(),
-    )
-    .await;
+    let _standby_abortable = router.start_standby();
+    initialize_proxy(&mut router, best_upstream, Configuration::signature()).await;
     info!("exiting");
     tokio::time::sleep(tokio::time::Duration::from_secs(60)).await;
 }
@@ -143,11 +155,29 @@ async fn main() {
 async fn initialize_proxy(
     router: &mut Router,
     mut pool_addr: Option<std::net::SocketAddr>,
-    epsilon: Duration,
     signature: String,
 ) {
+    let stats_sender = api::stats::StatsSender::new();
+    // The SV1 side is started once and kept across upstream changes, so that miners are not
+    // disconnected every time the pool or the TP changes.
+    let (mut sessions, mut sv1_abort_handles) = match start_sv1_side(stats_sender.clone()).await {
+        Some(sv1_side) => sv1_side,
+        None => return,
+    };
     loop {
-        let stats_sender = api::stats::StatsSender::new();
+        if sv1_abort_handles
+            .iter()
+            .any(|(handle, _name)| handle.is_finished())
+        {
+            warn!("SV1 downstream side stopped, restarting it");
+            // Drop the old listener before binding the address again
+            sv1_abort_handles.clear();
+            tokio::time::sleep(tokio::time::Duration::from_millis(10)).await;
+            (sessions, sv1_abort_handles) = match start_sv1_side(stats_sender.clone()).await {
+                Some(sv1_side) => sv1_side,
+                None => return,
+            };
+        }
         let (send_to_pool, recv_from_pool, pool_connection_abortable) =
             match router.connect_pool(pool_addr).await {
                 Ok(connection) => connection,
@@ -163,27 +193,18 @@ async fn initialize_proxy(
                 }
             };
 
-        let (downs_sv1_tx, downs_sv1_rx) = channel(10);
-        let sv1_ingress_abortable = ingress::sv1_ingress::start_listen_for_downstream(downs_sv1_tx);
-
         let (translator_up_tx, mut translator_up_rx) = channel(10);
-        let translator_abortable = match translator::start(
-            downs_sv1_rx,
-            translator_up_tx,
-            stats_sender.clone(),
-            signature.clone(),
-        )
-        .await
-        {
-            Ok(abortable) => abortable,
-            Err(e) => {
-                error!("Impossible to initialize translator: {e}");
-                // Impossible to start the proxy so we restart proxy
-                ProxyState::update_translator_state(TranslatorState::Down);
-                ProxyState::update_tp_state(TpState::Down);
-                return;
-            }
-        };
+        let translator_abortable =
+            match translator::start(sessions.clone(), translator_up_tx, signature.clone()).await {
+                Ok(abortable) => abortable,
+                Err(e) => {
+                    error!("Impossible to initialize translator: {e}");
+                    // Impossible to start the proxy so we restart proxy
+                    ProxyState::update_translator_state(TranslatorState::Down);
+                    ProxyState::update_tp_state(TpState::Down);
+                    return;
+                }
+            };
 
         let (from_jdc_to_share_accounter_send, from_jdc_to_share_accounter_recv) = channel(10);
         let (from_share_accounter_to_jdc_send, from_share_accounter_to_jdc_recv) = channel(10);
@@ -249,16 +270,15 @@ async fn initialize_proxy(
         // Collecting all abort handles
         let mut abort_handles = vec![
             (pool_connection_abortable, "pool_connection".to_string()),
-            (sv1_ingress_abortable, "sv1_ingress".to_string()),
             (translator_abortable, "translator".to_string()),
             (share_accounter_abortable, "share_accounter".to_string()),
         ];
         if let Some(jdc_handle) = jdc_abortable {
             abort_handles.push((jdc_handle, "jdc".to_string()));
         }
-        let server_handle = tokio::spawn(api::start(router.clone(), stats_sender));
+        let server_handle = tokio::spawn(api::start(router.clone(), stats_sender.clone()));
         abort_handles.push((server_handle.into(), "api_server".to_string()));
-        match monitor(router, abort_handles, epsilon).await {
+        match monitor(router, abort_handles, &sv1_abort_handles).await {
             Reconnect::NewUpstream(new_pool_addr) => {
                 ProxyState::update_proxy_state_up();
                 pool_addr = Some(new_pool_addr);
@@ -269,14 +289,79 @@ async fn initialize_proxy(
                 pool_addr = None;
                 continue;
             }
+            Reconnect::Sv1Side => {
+                // The translator is bound to the SV1 sessions so it is started again with them,
+                // but on the same pool: this is not a failover and the standby is left alone.
+                pool_addr = router.current_pool;
+                continue;
+            }
         };
     }
 }
 
+/// Starts the SV1 listener and the translator downstream sessions.
+async fn start_sv1_side(
+    stats_sender: api::stats::StatsSender,
+) -> Option<(translator::DownstreamSessions, Vec<(AbortOnDrop, String)>)> {
+    let (downs_sv1_tx, downs_sv1_rx) = channel(10);
+    let (downs_sv2_tx, downs_sv2_rx) = channel(10);
+    let main_profile = Arc::new(PortProfile::main());
+    let mut abortables = vec![];
+    if let Some(tls) = Configuration::tls() {
+        let profile = Arc::new(tls.profile());
+        abortables.push((
+            ingress::sv1_tls_ingress::start_listen_for_tls_downstream(
+                downs_sv1_tx.clone(),
+                tls,
+                profile,
+            ),
+            "sv1_tls_ingress".to_string(),
+        ));
+    }
+    if let Some(sv2) = Configuration::sv2_listener() {
+        let profile = Arc::new(sv2.profile());
+        abortables.push((
+            ingress::sv2_ingress::start_listen_for_sv2_downstream(downs_sv2_tx, sv2, profile),
+            "sv2_ingress".to_string(),
+        ));
+    }
+    for listener in Configuration::listeners() {
+        abortables.push((
+            ingress::sv1_ingress::start_listen_for_downstream(
+                downs_sv1_tx.clone(),
+                listener.listening_addr.clone(),
+                Arc::new(listener.profile()),
+            ),
+            format!("sv1_ingress_{}", listener.listening_addr),
+        ));
+    }
+    let sv1_ingress_abortable = ingress::sv1_ingress::start_listen_for_downstream(
+        downs_sv1_tx,
+        SV1_DOWN_LISTEN_ADDR.to_string(),
+        main_profile,
+    );
+    let (sessions, sessions_abortable) =
+        match translator::DownstreamSessions::start(downs_sv1_rx, downs_sv2_rx, stats_sender).await
+        {
+            Ok(sessions) => sessions,
+            Err(e) => {
+                error!("Impossible to initialize translator downstreams: {e}");
+                ProxyState::update_translator_state(TranslatorState::Down);
+                return None;
+            }
+        };
+    abortables.push((sv1_ingress_abortable, "sv1_ingress".to_string()));
+    abortables.push((sessions_abortable, "translator_downstreams".to_string()));
+    Some((sessions, abortables))
+}
+
+/// Watches the tasks started for the current upstream. The SV1 side tasks are only checked, they
+/// are not dropped here since they must survive an upstream change. When one of them ends only
+/// the SV1 side is restarted, see `Reconnect::Sv1Side`.
 async fn monitor(
     router: &mut Router,
     abort_handles: Vec<(AbortOnDrop, std::string::String)>,
-    epsilon: Duration,
+    sv1_abort_handles: &[(AbortOnDrop, std::string::String)],
 ) -> Reconnect {
     let mut should_check_upstreams_latency = 0;
     loop {
@@ -284,8 +369,8 @@ async fn monitor(
             // Check if a better upstream exist every 100 seconds
             if should_check_upstreams_latency == 10 * 100 {
                 should_check_upstreams_latency = 0;
-                if let Some(new_upstream) = router.monitor_upstream(epsilon).await {
-                    info!("Faster upstream detected. Reinitializing proxy...");
+                if let Some(new_upstream) = router.monitor_upstream().await {
+                    info!("Better upstream detected. Reinitializing proxy...");
                     drop(abort_handles);
 
                     // Needs a little to time to drop
@@ -296,6 +381,16 @@ async fn monitor(
             should_check_upstreams_latency += 1;
         }
 
+        if let Some((_handle, name)) = sv1_abort_handles
+            .iter()
+            .find(|(handle, _name)| handle.is_finished())
+        {
+            error!("Task {:?} finished, restarting the SV1 side", name);
+            drop(abort_handles);
+            tokio::time::sleep(tokio::time::Duration::from_millis(10)).await;
+            return Reconnect::Sv1Side;
+        }
+
         // Monitor finished tasks
         if let Some((_handle, name)) = abort_handles
             .iter()
@@ -458,6 +553,7 @@ fn check_update_proxy() {
 pub enum Reconnect {
     NewUpstream(std::net::SocketAddr), // Reconnecting with a new upstream
     NoUpstream,                        // Reconnecting without upstream
+    Sv1Side,                           // SV1 listeners or sessions ended, the pool is kept
 }
 
 enum HashUnit {
@@ -57,7 +57,46 @@ pub async fn connect_pool(
             }
         }
     };
+    let (receiver, sender) = setup_pool_connection(
+        socket,
+        address,
+        authority_public_key,
+        setup_connection_msg,
+        timer,
+    )
+    .await?;
+    start_relays(receiver, sender).await
+}
+
+/// Opens a connection to the pool without retrying and without starting the relays, so that it
+/// can be kept aside and promoted later with `start_relays`.
+pub async fn open_pool_connection(
+    address: SocketAddr,
+    authority_public_key: Secp256k1PublicKey,
+    setup_connection_msg: Option<SetupConnection<'static>>,
+    timer: Option<std::time::Duration>,
+) -> Result<(Receiver<EitherFrame>, Sender<EitherFrame>), Error> {
+    let socket = tokio::time::timeout(timer.unwrap_or(DEFAULT_TIMER), TcpStream::connect(address))
+        .await
+        .map_err(|_| Error::Timeout)??;
+    setup_pool_connection(
+        socket,
+        address,
+        authority_public_key,
+        setup_connection_msg,
+        timer,
+    )
+    .await
+}
 
+/// Performs the noise handshake and the `SetupConnection` on an open socket.
+async fn setup_pool_connection(
+    socket: TcpStream,
+    address: SocketAddr,
+    authority_public_key: Secp256k1PublicKey,
+    setup_connection_msg: Option<SetupConnection<'static>>,
+    timer: Option<std::time::Duration>,
+) -> Result<(Receiver<EitherFrame>, Sender<EitherFrame>), Error> {
     info!("Performing SV2 Handshake with Pool at {}", address);
     let initiator =
         Initiator::from_raw_k(authority_public_key.into_bytes()).expect("Invalid authority key");
@@ -73,36 +112,47 @@ pub async fn connect_pool(
     info!("Sending SetupConnection message to Pool at {}", address);
     let setup_connection_msg =
         setup_connection_msg.unwrap_or(get_mining_setup_connection_msg(true));
-    match mining_setup_connection(
+    mining_setup_connection(
         &mut receiver,
         &mut sender,
         setup_connection_msg,
         timer.unwrap_or(DEFAULT_TIMER),
     )
-    .await
-    {
-        Ok(_) => {
-            let task_manager = TaskManager::initialize();
-            let abortable = task_manager
-                .safe_lock(|t| t.get_aborter())
-                .map_err(|_| Error::MiningPoolMutexCorrupted)?
-                .ok_or(Error::MiningPoolTaskManagerFailed)?;
-
-            let (send_to_down, recv_from_down) = tokio::sync::mpsc::channel(10);
-            let (send_from_down, recv_to_up) = tokio::sync::mpsc::channel(10);
-            let relay_up_task = relay_up(recv_to_up, sender);
-            TaskManager::add_sv2_relay_up(task_manager.clone(), relay_up_task)
-                .await
-                .map_err(|_| Error::MiningPoolTaskManagerFailed)?;
-
-            let relay_down_task = relay_down(receiver, send_to_down);
-            TaskManager::add_sv2_relay_down(task_manager.clone(), relay_down_task)
-                .await
-                .map_err(|_| Error::MiningPoolTaskManagerFailed)?;
-            Ok((send_from_down, recv_from_down, abortable))
-        }
-        Err(e) => Err(e),
-    }
+    .await?;
+    Ok((receiver, sender))
+}
+
+/// Starts relaying messages between the rest of the proxy and a pool connection that already
+/// completed the `SetupConnection`. From here on a failure of the connection sets the pool down.
+pub async fn start_relays(
+    receiver: Receiver<EitherFrame>,
+    sender: Sender<EitherFrame>,
+) -> Result<
+    (
+        Sender<PoolExtMessages<'static>>,
+        Receiver<PoolExtMessages<'static>>,
+        AbortOnDrop,
+    ),
+    Error,
+> {
+    let task_manager = TaskManager::initialize();
+    let abortable = task_manager
+        .safe_lock(|t| t.get_aborter())
+        .map_err(|_| Error::MiningPoolMutexCorrupted)?
+        .ok_or(Error::MiningPoolTaskManagerFailed)?;
+
+    let (send_to_down, recv_from_down) = tokio::sync::mpsc::channel(10);
+    let (send_from_down, recv_to_up) = tokio::sync::mpsc::channel(10);
+    let relay_up_task = relay_up(recv_to_up, sender);
+    TaskManager::add_sv2_relay_up(task_manager.clone(), relay_up_task)
+        .await
+        .map_err(|_| Error::MiningPoolTaskManagerFailed)?;
+
+    let relay_down_task = relay_down(receiver, send_to_down);
+    TaskManager::add_sv2_relay_down(task_manager.clone(), relay_down_task)
+        .await
+        .map_err(|_| Error::MiningPoolTaskManagerFailed)?;
+    Ok((send_from_down, recv_from_down, abortable))
 }
 
 pub fn relay_up(
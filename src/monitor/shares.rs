@@ -118,6 +118,29 @@ pub enum RejectionReason {
     InvalidShare,
     InvalidJobIdFormat,
     DifficultyMismatch,
+    RateLimited,
+    /// The job was built on a previous block.
+    Stale,
+    Duplicate,
+    /// Rejected by the pool for another reason, with its SV2 error code.
+    Pool(String),
+    /// The pool did not answer in time, see `ShareAckConfig`.
+    NotAcknowledged,
+    /// A field of the `mining.submit` has the wrong format or is out of range.
+    Malformed(String),
+}
+
+impl RejectionReason {
+    /// Reason of a SV2 `SubmitSharesError`.
+    pub fn from_error_code(error_code: &str) -> Self {
+        match error_code {
+            "stale-share" => Self::Stale,
+            "duplicate-share" => Self::Duplicate,
+            "difficulty-too-low" | "invalid-share" => Self::InvalidShare,
+            "invalid-job-id" => Self::JobIdNotFound,
+            _ => Self::Pool(error_code.to_string()),
+        }
+    }
 }
 
 impl std::fmt::Display for RejectionReason {
@@ -127,6 +150,12 @@ impl std::fmt::Display for RejectionReason {
             RejectionReason::InvalidShare => write!(f, "Invalid share"),
             RejectionReason::InvalidJobIdFormat => write!(f, "Invalid job ID format"),
             RejectionReason::DifficultyMismatch => write!(f, "Difficulty mismatch"),
+            RejectionReason::RateLimited => write!(f, "Rate limited"),
+            RejectionReason::Stale => write!(f, "Stale share"),
+            RejectionReason::Duplicate => write!(f, "Duplicate share"),
+            RejectionReason::Pool(error_code) => write!(f, "Rejected by pool: {}", error_code),
+            RejectionReason::NotAcknowledged => write!(f, "Not acknowledged by pool"),
+            RejectionReason::Malformed(field) => write!(f, "Malformed share: {}", field),
         }
     }
 }
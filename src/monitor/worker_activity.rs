@@ -11,6 +11,9 @@ pub struct WorkerActivity {
     user_agent: String,
     worker_name: String,
     activity: WorkerActivityType,
+    /// Why the proxy disconnected the worker, if it did.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reason: Option<String>,
 }
 
 impl WorkerActivity {
@@ -19,9 +22,15 @@ impl WorkerActivity {
             user_agent,
             worker_name,
             activity,
+            reason: None,
         }
     }
 
+    pub fn with_reason(mut self, reason: &str) -> Self {
+        self.reason = Some(reason.to_string());
+        self
+    }
+
     pub fn monitor_api(&self) -> MonitorAPI {
         MonitorAPI::new(worker_activity_server_endpoint())
     }
@@ -0,0 +1,241 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    net::SocketAddr,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use serde::Serialize;
+
+use super::{latency, PoolLatency};
+
+// Number of probes kept for every pool
+const MAX_PROBES: usize = 50;
+// Number of pool switches kept
+const MAX_SWITCHES: usize = 50;
+
+pub(super) fn unix_timestamp() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+fn as_millis(duration: Option<Duration>) -> Option<u128> {
+    duration.map(|d| d.as_millis())
+}
+
+/// Returns the sample at `percentile` (0 to 100) of `samples`.
+pub fn percentile(samples: impl Iterator<Item = Duration>, percentile: f64) -> Option<Duration> {
+    let mut sorted: Vec<Duration> = samples.collect();
+    if sorted.is_empty() {
+        return None;
+    }
+    sorted.sort();
+    let index = ((percentile / 100.0) * sorted.len() as f64) as usize;
+    sorted.get(index.min(sorted.len() - 1)).copied()
+}
+
+/// One probe of a pool, with the time spent in every stage. Stages after a failure are `None`.
+#[derive(Clone, Debug, Serialize)]
+pub struct ProbeRecord {
+    pub timestamp: u64,
+    /// TCP connect.
+    pub open_sv2_mining_connection_ms: Option<u128>,
+    pub noise_handshake_ms: Option<u128>,
+    /// `SetupConnection` round trip.
+    pub setup_a_channel_ms: Option<u128>,
+    /// Latency used to compare pools, `None` when the probe failed.
+    pub latency_ms: Option<u128>,
+}
+
+#[derive(Clone, Copy, Debug, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SwitchReason {
+    /// First pool picked when the proxy started.
+    Initial,
+    /// The strategy preferred another pool while connected.
+    Strategy,
+    /// The connection with the pool (or the proxy) went down.
+    Failover,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct SwitchRecord {
+    pub timestamp: u64,
+    pub from: Option<SocketAddr>,
+    pub to: SocketAddr,
+    pub reason: SwitchReason,
+    /// Whether the warm standby connection was promoted.
+    pub standby: bool,
+}
+
+/// Time spent in every stage of a connection with a pool. The first two come from the last
+/// successful probe, the others from the last live connection since the probes do not open a
+/// channel nor a JD connection.
+#[derive(Debug, Default, Serialize)]
+pub struct StageBreakdown {
+    pub open_sv2_mining_connection_ms: Option<u128>,
+    pub setup_a_channel_ms: Option<u128>,
+    pub receive_first_job_ms: Option<u128>,
+    pub receive_first_set_new_prev_hash_ms: Option<u128>,
+    pub open_sv2_jd_connection_ms: Option<u128>,
+    pub get_a_mining_token_ms: Option<u128>,
+}
+
+/// Latency summary of a pool as returned by the API.
+#[derive(Debug, Serialize)]
+pub struct PoolReport {
+    pub address: SocketAddr,
+    pub probes: usize,
+    pub failed_probes: usize,
+    pub p50_ms: Option<u128>,
+    pub p90_ms: Option<u128>,
+    pub p99_ms: Option<u128>,
+    pub last_probe: Option<ProbeRecord>,
+    pub stages: StageBreakdown,
+    /// Median share round trip measured on the live connection.
+    pub share_rtt_ms: Option<u128>,
+    /// Median delay of the pool `SetNewPrevHash` compared with the TP.
+    pub prev_hash_delay_ms: Option<u128>,
+}
+
+/// Rolling history of the probes of every pool and of the pool switches.
+#[derive(Default)]
+pub struct PoolHistory {
+    probes: HashMap<SocketAddr, VecDeque<ProbeRecord>>,
+    switches: VecDeque<SwitchRecord>,
+}
+
+impl PoolHistory {
+    pub(super) fn add_probe(&mut self, probe: &PoolLatency, latency: Option<Duration>) {
+        let probes = self.probes.entry(probe.pool).or_default();
+        if probes.len() == MAX_PROBES {
+            probes.pop_front();
+        }
+        probes.push_back(ProbeRecord {
+            timestamp: unix_timestamp(),
+            open_sv2_mining_connection_ms: as_millis(probe.open_sv2_mining_connection),
+            noise_handshake_ms: as_millis(probe.noise_handshake),
+            setup_a_channel_ms: as_millis(probe.setup_a_channel),
+            latency_ms: as_millis(latency),
+        });
+    }
+
+    /// Reconnecting to the pool we were on is not a switch and is not recorded.
+    pub(super) fn add_switch(&mut self, switch: SwitchRecord) {
+        if switch.from == Some(switch.to) {
+            return;
+        }
+        if self.switches.len() == MAX_SWITCHES {
+            self.switches.pop_front();
+        }
+        self.switches.push_back(switch);
+    }
+
+    pub fn switches(&self) -> Vec<SwitchRecord> {
+        self.switches.iter().cloned().collect()
+    }
+
+    pub fn report(&self, pool: SocketAddr) -> PoolReport {
+        let probes = self.probes.get(&pool);
+        let latencies = || {
+            probes
+                .into_iter()
+                .flatten()
+                .filter_map(|p| p.latency_ms)
+                .map(|ms| Duration::from_millis(ms as u64))
+        };
+        let samples = latency::samples(pool).unwrap_or_default();
+        let last_success = probes.and_then(|p| p.iter().rev().find(|p| p.latency_ms.is_some()));
+        let live = samples.stages();
+        let stages = StageBreakdown {
+            open_sv2_mining_connection_ms: last_success
+                .and_then(|p| p.open_sv2_mining_connection_ms),
+            setup_a_channel_ms: last_success.and_then(|p| p.setup_a_channel_ms),
+            receive_first_job_ms: as_millis(live.receive_first_job),
+            receive_first_set_new_prev_hash_ms: as_millis(live.receive_first_set_new_prev_hash),
+            open_sv2_jd_connection_ms: as_millis(live.open_sv2_jd_connection),
+            get_a_mining_token_ms: as_millis(live.get_a_mining_token),
+        };
+        PoolReport {
+            address: pool,
+            probes: probes.map_or(0, |p| p.len()),
+            failed_probes: probes.map_or(0, |p| {
+                p.iter().filter(|probe| probe.latency_ms.is_none()).count()
+            }),
+            p50_ms: as_millis(percentile(latencies(), 50.0)),
+            p90_ms: as_millis(percentile(latencies(), 90.0)),
+            p99_ms: as_millis(percentile(latencies(), 99.0)),
+            last_probe: probes.and_then(|p| p.back().cloned()),
+            stages,
+            share_rtt_ms: as_millis(samples.share_rtt()),
+            prev_hash_delay_ms: as_millis(samples.prev_hash_delay()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn percentiles() {
+        let samples = || (1..=100).map(Duration::from_millis);
+        assert_eq!(percentile(samples(), 50.0), Some(Duration::from_millis(51)));
+        assert_eq!(
+            percentile(samples(), 99.0),
+            Some(Duration::from_millis(100))
+        );
+        assert_eq!(
+            percentile(samples(), 100.0),
+            Some(Duration::from_millis(100))
+        );
+        assert_eq!(percentile(std::iter::empty(), 50.0), None);
+    }
+
+    #[test]
+    fn keeps_a_rolling_window_of_probes() {
+        let pool = SocketAddr::from(([127, 0, 0, 1], 1));
+        let mut history = PoolHistory::default();
+        let mut probe = PoolLatency::new(pool);
+        for ms in 0..(MAX_PROBES as u64 + 10) {
+            probe.setup_a_channel = Some(Duration::from_millis(ms));
+            history.add_probe(&probe, probe.setup_a_channel);
+        }
+        probe.setup_a_channel = None;
+        history.add_probe(&probe, None);
+
+        let report = history.report(pool);
+        assert_eq!(report.probes, MAX_PROBES);
+        assert_eq!(report.failed_probes, 1);
+        assert_eq!(report.p50_ms, Some(35));
+        assert_eq!(report.last_probe.unwrap().latency_ms, None);
+        // The breakdown comes from the last probe that succeeded
+        assert_eq!(
+            report.stages.setup_a_channel_ms,
+            Some(MAX_PROBES as u128 + 9)
+        );
+    }
+
+    #[test]
+    fn reconnecting_to_the_same_pool_is_not_a_switch() {
+        let first = SocketAddr::from(([127, 0, 0, 1], 1));
+        let second = SocketAddr::from(([127, 0, 0, 1], 2));
+        let mut history = PoolHistory::default();
+        let switch = |from, to| SwitchRecord {
+            timestamp: 0,
+            from,
+            to,
+            reason: SwitchReason::Failover,
+            standby: false,
+        };
+        history.add_switch(switch(None, first));
+        history.add_switch(switch(Some(first), first));
+        history.add_switch(switch(Some(first), second));
+
+        let switches = history.switches();
+        assert_eq!(switches.len(), 2);
+        assert_eq!(switches[1].from, Some(first));
+        assert_eq!(switches[1].to, second);
+    }
+}
@@ -0,0 +1,386 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+use lazy_static::lazy_static;
+use roles_logic_sv2::utils::Mutex;
+use tracing::error;
+
+// Number of samples kept per pool and per measurement
+const MAX_SAMPLES: usize = 100;
+// Prev hashes seen only on one side (TP or pool) that are kept while waiting for the other side
+const MAX_PENDING_PREV_HASHES: usize = 8;
+// Upper bounds of the buckets of `LatencyHistogram`, the last bucket has no bound
+const HISTOGRAM_BOUNDS_MS: [u64; 10] = [10, 25, 50, 100, 250, 500, 1_000, 2_500, 5_000, 10_000];
+
+lazy_static! {
+    static ref PASSIVE_LATENCY: Mutex<PassiveLatency> = Mutex::new(PassiveLatency::default());
+}
+
+/// Latency samples measured on the live connection with a pool.
+#[derive(Clone, Debug, Default)]
+pub struct PoolSamples {
+    /// Time between sending a share and receiving the pool `ShareOk` for it.
+    share_rtt: VecDeque<Duration>,
+    /// How late the pool `SetNewPrevHash` arrived compared with the one sent by the TP. Zero when
+    /// the pool was first.
+    prev_hash_delay: VecDeque<Duration>,
+    /// Time between sending a share and receiving the pool verdict, accepted or rejected, since
+    /// the proxy started.
+    share_ack: LatencyHistogram,
+    /// Stages of the last live connection that the handshake-only probe can not measure.
+    stages: LiveStages,
+}
+
+/// Stages of a connection with a pool measured on the live connection, they need a channel or a
+/// JD connection that the probes do not open.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct LiveStages {
+    /// From `OpenExtendedMiningChannel` to the first `NewExtendedMiningJob`.
+    pub receive_first_job: Option<Duration>,
+    /// From `OpenExtendedMiningChannel` to the first `SetNewPrevHash`.
+    pub receive_first_set_new_prev_hash: Option<Duration>,
+    /// TCP connect, noise handshake and `SetupConnection` of the JD connection.
+    pub open_sv2_jd_connection: Option<Duration>,
+    /// From the first `AllocateMiningJobToken` to its `AllocateMiningJobTokenSuccess`.
+    pub get_a_mining_token: Option<Duration>,
+}
+
+impl PoolSamples {
+    pub fn share_rtt(&self) -> Option<Duration> {
+        median(&self.share_rtt)
+    }
+
+    pub fn prev_hash_delay(&self) -> Option<Duration> {
+        median(&self.prev_hash_delay)
+    }
+
+    pub fn share_ack(&self) -> &LatencyHistogram {
+        &self.share_ack
+    }
+
+    pub fn stages(&self) -> LiveStages {
+        self.stages
+    }
+}
+
+/// Number of samples per latency bucket.
+#[derive(Clone, Debug, Default)]
+pub struct LatencyHistogram {
+    counts: [u64; HISTOGRAM_BOUNDS_MS.len() + 1],
+    sum: Duration,
+}
+
+impl LatencyHistogram {
+    fn add(&mut self, sample: Duration) {
+        let ms = sample.as_millis();
+        let bucket = HISTOGRAM_BOUNDS_MS
+            .iter()
+            .position(|bound| ms <= *bound as u128)
+            .unwrap_or(HISTOGRAM_BOUNDS_MS.len());
+        self.counts[bucket] += 1;
+        self.sum += sample;
+    }
+
+    /// Upper bound in ms and number of samples of every bucket, the last bucket has no bound.
+    pub fn buckets(&self) -> impl Iterator<Item = (Option<u64>, u64)> + '_ {
+        HISTOGRAM_BOUNDS_MS
+            .iter()
+            .map(|bound| Some(*bound))
+            .chain(std::iter::once(None))
+            .zip(self.counts.iter().copied())
+    }
+
+    pub fn count(&self) -> u64 {
+        self.counts.iter().sum()
+    }
+
+    pub fn mean(&self) -> Option<Duration> {
+        let count = self.count();
+        (count > 0).then(|| Duration::from_nanos((self.sum.as_nanos() / count as u128) as u64))
+    }
+}
+
+fn median(samples: &VecDeque<Duration>) -> Option<Duration> {
+    super::history::percentile(samples.iter().copied(), 50.0)
+}
+
+fn push_sample(samples: &mut VecDeque<Duration>, sample: Duration) {
+    if samples.len() == MAX_SAMPLES {
+        samples.pop_front();
+    }
+    samples.push_back(sample);
+}
+
+#[derive(Default)]
+struct PassiveLatency {
+    pools: HashMap<SocketAddr, PoolSamples>,
+    tp_prev_hashes: VecDeque<(Vec<u8>, Instant)>,
+    pool_prev_hashes: VecDeque<(Vec<u8>, Instant)>,
+    /// When the last channel was opened, until its first job and its first prev hash arrive.
+    waiting_first_job: Option<Instant>,
+    waiting_first_prev_hash: Option<Instant>,
+}
+
+impl PassiveLatency {
+    fn add_share_rtt(&mut self, pool: SocketAddr, rtt: Duration) {
+        let samples = self.pools.entry(pool).or_default();
+        push_sample(&mut samples.share_rtt, rtt);
+        samples.share_ack.add(rtt);
+    }
+
+    fn add_rejected_share_rtt(&mut self, pool: SocketAddr, rtt: Duration) {
+        self.pools.entry(pool).or_default().share_ack.add(rtt);
+    }
+
+    fn open_channel(&mut self, now: Instant) {
+        self.waiting_first_job = Some(now);
+        self.waiting_first_prev_hash = Some(now);
+    }
+
+    fn add_pool_job(&mut self, pool: Option<SocketAddr>, now: Instant) {
+        if let (Some(pool), Some(opened_at)) = (pool, self.waiting_first_job.take()) {
+            self.pools.entry(pool).or_default().stages.receive_first_job =
+                Some(now.saturating_duration_since(opened_at));
+        }
+    }
+
+    fn add_tp_prev_hash(&mut self, pool: Option<SocketAddr>, prev_hash: Vec<u8>, now: Instant) {
+        match take_prev_hash(&mut self.pool_prev_hashes, &prev_hash) {
+            // The pool was faster than the TP
+            Some(_) => self.add_prev_hash_delay(pool, Duration::ZERO),
+            None => push_prev_hash(&mut self.tp_prev_hashes, prev_hash, now),
+        }
+    }
+
+    fn add_pool_prev_hash(&mut self, pool: Option<SocketAddr>, prev_hash: Vec<u8>, now: Instant) {
+        if let (Some(pool), Some(opened_at)) = (pool, self.waiting_first_prev_hash.take()) {
+            self.pools
+                .entry(pool)
+                .or_default()
+                .stages
+                .receive_first_set_new_prev_hash = Some(now.saturating_duration_since(opened_at));
+        }
+        match take_prev_hash(&mut self.tp_prev_hashes, &prev_hash) {
+            Some(tp_received_at) => {
+                self.add_prev_hash_delay(pool, now.saturating_duration_since(tp_received_at))
+            }
+            None => push_prev_hash(&mut self.pool_prev_hashes, prev_hash, now),
+        }
+    }
+
+    fn add_prev_hash_delay(&mut self, pool: Option<SocketAddr>, delay: Duration) {
+        if let Some(pool) = pool {
+            push_sample(
+                &mut self.pools.entry(pool).or_default().prev_hash_delay,
+                delay,
+            );
+        }
+    }
+}
+
+fn take_prev_hash(pending: &mut VecDeque<(Vec<u8>, Instant)>, prev_hash: &[u8]) -> Option<Instant> {
+    let index = pending.iter().position(|(p, _)| p == prev_hash)?;
+    pending.remove(index).map(|(_, received_at)| received_at)
+}
+
+fn push_prev_hash(pending: &mut VecDeque<(Vec<u8>, Instant)>, prev_hash: Vec<u8>, now: Instant) {
+    if pending.len() == MAX_PENDING_PREV_HASHES {
+        pending.pop_front();
+    }
+    pending.push_back((prev_hash, now));
+}
+
+fn current_pool() -> Option<SocketAddr> {
+    crate::POOL_ADDRESS
+        .safe_lock(|pool_address| *pool_address)
+        .unwrap_or_else(|_| {
+            error!("Pool address Mutex corrupt");
+            None
+        })
+}
+
+/// Records the round trip of a share acknowledged by the current pool.
+pub fn on_share_acknowledged(rtt: Duration) {
+    if let Some(pool) = current_pool() {
+        if PASSIVE_LATENCY
+            .safe_lock(|l| l.add_share_rtt(pool, rtt))
+            .is_err()
+        {
+            error!("Passive latency Mutex corrupt");
+        }
+    }
+}
+
+/// Records the round trip of a share rejected by the current pool.
+pub fn on_share_rejected(rtt: Duration) {
+    if let Some(pool) = current_pool() {
+        if PASSIVE_LATENCY
+            .safe_lock(|l| l.add_rejected_share_rtt(pool, rtt))
+            .is_err()
+        {
+            error!("Passive latency Mutex corrupt");
+        }
+    }
+}
+
+/// Records a `SetNewPrevHash` received from the TP.
+pub fn on_tp_prev_hash(prev_hash: Vec<u8>) {
+    let pool = current_pool();
+    if PASSIVE_LATENCY
+        .safe_lock(|l| l.add_tp_prev_hash(pool, prev_hash, Instant::now()))
+        .is_err()
+    {
+        error!("Passive latency Mutex corrupt");
+    }
+}
+
+/// Records a `SetNewPrevHash` received from the current pool.
+pub fn on_pool_prev_hash(prev_hash: Vec<u8>) {
+    let pool = current_pool();
+    if PASSIVE_LATENCY
+        .safe_lock(|l| l.add_pool_prev_hash(pool, prev_hash, Instant::now()))
+        .is_err()
+    {
+        error!("Passive latency Mutex corrupt");
+    }
+}
+
+/// Records an `OpenExtendedMiningChannel` sent to the current pool.
+pub fn on_open_channel() {
+    if PASSIVE_LATENCY
+        .safe_lock(|l| l.open_channel(Instant::now()))
+        .is_err()
+    {
+        error!("Passive latency Mutex corrupt");
+    }
+}
+
+/// Records a `NewExtendedMiningJob` received from the current pool.
+pub fn on_pool_job() {
+    let pool = current_pool();
+    if PASSIVE_LATENCY
+        .safe_lock(|l| l.add_pool_job(pool, Instant::now()))
+        .is_err()
+    {
+        error!("Passive latency Mutex corrupt");
+    }
+}
+
+/// Records the time taken to open the JD connection with the current pool.
+pub fn on_jd_connected(elapsed: Duration) {
+    update_stages(|stages| stages.open_sv2_jd_connection = Some(elapsed));
+}
+
+/// Records the time taken by the current pool to allocate the first mining token.
+pub fn on_mining_token(elapsed: Duration) {
+    update_stages(|stages| stages.get_a_mining_token = Some(elapsed));
+}
+
+fn update_stages(update: impl FnOnce(&mut LiveStages)) {
+    if let Some(pool) = current_pool() {
+        if PASSIVE_LATENCY
+            .safe_lock(|l| update(&mut l.pools.entry(pool).or_default().stages))
+            .is_err()
+        {
+            error!("Passive latency Mutex corrupt");
+        }
+    }
+}
+
+/// Returns the samples measured on the connection with `pool`, if it has been used.
+pub fn samples(pool: SocketAddr) -> Option<PoolSamples> {
+    PASSIVE_LATENCY
+        .safe_lock(|l| l.pools.get(&pool).cloned())
+        .unwrap_or(None)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn prev_hash_delay_is_measured_from_the_tp() {
+        let pool = SocketAddr::from(([127, 0, 0, 1], 1));
+        let mut latency = PassiveLatency::default();
+        let start = Instant::now();
+
+        latency.add_tp_prev_hash(Some(pool), vec![1], start);
+        latency.add_pool_prev_hash(Some(pool), vec![1], start + Duration::from_millis(40));
+        // Pool first
+        latency.add_pool_prev_hash(Some(pool), vec![2], start);
+        latency.add_tp_prev_hash(Some(pool), vec![2], start + Duration::from_millis(10));
+        latency.add_tp_prev_hash(Some(pool), vec![3], start);
+        latency.add_pool_prev_hash(Some(pool), vec![3], start + Duration::from_millis(60));
+
+        let samples = &latency.pools[&pool];
+        assert_eq!(samples.prev_hash_delay.len(), 3);
+        assert_eq!(samples.prev_hash_delay(), Some(Duration::from_millis(40)));
+        assert!(latency.tp_prev_hashes.is_empty());
+        assert!(latency.pool_prev_hashes.is_empty());
+    }
+
+    #[test]
+    fn first_job_and_prev_hash_are_measured_from_the_channel_open() {
+        let pool = SocketAddr::from(([127, 0, 0, 1], 1));
+        let mut latency = PassiveLatency::default();
+        let start = Instant::now();
+
+        latency.open_channel(start);
+        latency.add_pool_job(Some(pool), start + Duration::from_millis(20));
+        latency.add_pool_prev_hash(Some(pool), vec![1], start + Duration::from_millis(30));
+        // Only the first job and prev hash after the open count
+        latency.add_pool_job(Some(pool), start + Duration::from_millis(90));
+        latency.add_pool_prev_hash(Some(pool), vec![2], start + Duration::from_millis(90));
+
+        let stages = latency.pools[&pool].stages();
+        assert_eq!(stages.receive_first_job, Some(Duration::from_millis(20)));
+        assert_eq!(
+            stages.receive_first_set_new_prev_hash,
+            Some(Duration::from_millis(30))
+        );
+        assert_eq!(stages.open_sv2_jd_connection, None);
+
+        // A new channel is measured again
+        latency.open_channel(start + Duration::from_millis(100));
+        latency.add_pool_job(Some(pool), start + Duration::from_millis(110));
+        let stages = latency.pools[&pool].stages();
+        assert_eq!(stages.receive_first_job, Some(Duration::from_millis(10)));
+    }
+
+    #[test]
+    fn keeps_a_bounded_number_of_samples() {
+        let pool = SocketAddr::from(([127, 0, 0, 1], 1));
+        let mut latency = PassiveLatency::default();
+        for ms in 0..(MAX_SAMPLES as u64 * 2) {
+            latency.add_share_rtt(pool, Duration::from_millis(ms));
+        }
+        let samples = &latency.pools[&pool];
+        assert_eq!(samples.share_rtt.len(), MAX_SAMPLES);
+        assert_eq!(samples.share_rtt(), Some(Duration::from_millis(150)));
+    }
+
+    #[test]
+    fn share_ack_histogram() {
+        let pool = SocketAddr::from(([127, 0, 0, 1], 1));
+        let mut latency = PassiveLatency::default();
+        latency.add_share_rtt(pool, Duration::from_millis(10));
+        latency.add_share_rtt(pool, Duration::from_millis(11));
+        latency.add_rejected_share_rtt(pool, Duration::from_millis(30));
+        latency.add_share_rtt(pool, Duration::from_secs(60));
+
+        let histogram = latency.pools[&pool].share_ack();
+        let buckets: Vec<_> = histogram.buckets().collect();
+        assert_eq!(buckets.len(), HISTOGRAM_BOUNDS_MS.len() + 1);
+        assert_eq!(buckets[0], (Some(10), 1));
+        assert_eq!(buckets[1], (Some(25), 1));
+        assert_eq!(buckets[2], (Some(50), 1));
+        assert_eq!(buckets[HISTOGRAM_BOUNDS_MS.len()], (None, 1));
+        assert_eq!(histogram.count(), 4);
+        assert_eq!(histogram.mean(), Some(Duration::from_micros(15_012_750)));
+        // Only accepted shares are round trip samples
+        assert_eq!(latency.pools[&pool].share_rtt.len(), 3);
+    }
+}
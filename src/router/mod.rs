@@ -1,45 +1,62 @@
 use std::{
     net::SocketAddr,
+    sync::Arc,
     time::{Duration, Instant},
 };
 
-use crate::jd_client::job_declarator::{setup_connection::SetupConnectionHandler, JobDeclarator};
-use codec_sv2::{buffer_sv2::Slice, HandshakeRole};
+use codec_sv2::HandshakeRole;
 use demand_share_accounting_ext::parser::PoolExtMessages;
 use demand_sv2_connection::noise_connection_tokio::Connection;
 use key_utils::Secp256k1PublicKey;
 use noise_sv2::Initiator;
-use roles_logic_sv2::{common_messages_sv2::SetupConnection, parsers::Mining};
-use tokio::{
-    net::TcpStream,
-    sync::{
-        mpsc::{Receiver, Sender},
-        watch,
-    },
-};
-use tracing::{error, info};
+use roles_logic_sv2::common_messages_sv2::SetupConnection;
+use tokio::{net::TcpStream, sync::watch};
+use tracing::{debug, error, info, warn};
 
 use crate::{
     minin_pool_connection::{self, get_mining_setup_connection_msg, mining_setup_connection},
     shared::utils::AbortOnDrop,
 };
 
+pub mod history;
+pub mod latency;
+mod standby;
+pub mod strategy;
+use history::{PoolHistory, PoolReport, SwitchReason, SwitchRecord};
+use roles_logic_sv2::utils::Mutex;
+use standby::StandbyConnection;
+use strategy::{PoolProbe, PoolSelectionConfig, PoolSelectionStrategy};
+
+// How often the standby connection is checked and, if needed, replaced
+const STANDBY_CHECK_INTERVAL: Duration = Duration::from_secs(30);
+
 /// Router handles connection to Multiple upstreams.
 #[derive(Clone)]
 pub struct Router {
-    pool_addresses: Vec<SocketAddr>,
+    // Pools as `host:port`, resolved again every time a pool is selected
+    pool_hosts: Vec<String>,
+    // Last address each host resolved to, in the same order as `pool_hosts`
+    pool_addresses: Arc<Mutex<Vec<Option<SocketAddr>>>>,
     pub current_pool: Option<SocketAddr>,
     auth_pub_k: Secp256k1PublicKey,
     setup_connection_msg: Option<SetupConnection<'static>>,
     timer: Option<Duration>,
     latency_tx: watch::Sender<Option<Duration>>,
     pub latency_rx: watch::Receiver<Option<Duration>>,
+    strategy: Arc<dyn PoolSelectionStrategy>,
+    // Minimum time to stay on a pool before the strategy is allowed to switch away from it
+    min_dwell_time: Duration,
+    connected_since: Option<Instant>,
+    hot_standby: bool,
+    // Warm connection to the next-best pool, promoted when the current pool fails
+    standby: Arc<Mutex<Option<StandbyConnection>>>,
+    history: Arc<Mutex<PoolHistory>>,
 }
 
 impl Router {
-    /// Creates a new `Router` instance with the specified upstream addresses.
+    /// Creates a new `Router` instance with the specified upstream addresses (`host:port`).
     pub fn new(
-        pool_addresses: Vec<SocketAddr>,
+        pool_hosts: Vec<String>,
         auth_pub_k: Secp256k1PublicKey,
         // Configuration msg used to setup connection between client and pool
         // If not, present `get_mining_setup_connection_msg()` is called to generated default values
@@ -47,49 +64,203 @@ impl Router {
         // Max duration for pool setup after which it times out.
         // If None, default time of 5s is used.
         timer: Option<Duration>,
+        // Strategy used to pick the pool and to decide when to switch to another one
+        pool_selection: &PoolSelectionConfig,
     ) -> Self {
+        info!("Pool selection: {:?}", pool_selection);
         let (latency_tx, latency_rx) = watch::channel(None);
         Self {
-            pool_addresses,
+            pool_addresses: Arc::new(Mutex::new(vec![None; pool_hosts.len()])),
+            pool_hosts,
             current_pool: None,
             auth_pub_k,
             setup_connection_msg,
             timer,
             latency_tx,
             latency_rx,
+            strategy: pool_selection.build_strategy(),
+            min_dwell_time: pool_selection.min_dwell_time(),
+            connected_since: None,
+            hot_standby: pool_selection.hot_standby,
+            standby: Arc::new(Mutex::new(None)),
+            history: Arc::new(Mutex::new(PoolHistory::default())),
         }
     }
 
-    /// Internal function to select pool with the least latency.
-    async fn select_pool(&self) -> Option<(SocketAddr, Duration)> {
-        let mut best_pool = None;
-        let mut least_latency = Duration::MAX;
+    /// Keeps a connection to the next-best pool open in the background, so that `connect_pool`
+    /// can fail over to it without reselecting and reconnecting. Returns `None` when hot standby
+    /// is disabled or when there is no other pool to fail over to.
+    pub fn start_standby(&self) -> Option<AbortOnDrop> {
+        if !self.hot_standby || self.pool_hosts.len() < 2 {
+            return None;
+        }
+        let router = self.clone();
+        let task = tokio::spawn(async move {
+            loop {
+                router.refresh_standby().await;
+                tokio::time::sleep(STANDBY_CHECK_INTERVAL).await;
+            }
+        });
+        Some(task.into())
+    }
+
+    /// Replaces the standby connection if it went down or if it points to the pool we are now
+    /// connected to.
+    async fn refresh_standby(&self) {
+        let current = match crate::POOL_ADDRESS.safe_lock(|pool_address| *pool_address) {
+            Ok(Some(current)) => current,
+            // Not connected yet, the standby is chosen once the first pool is picked
+            Ok(None) => return,
+            Err(_) => {
+                error!("Pool address Mutex corrupt");
+                return;
+            }
+        };
+        let usable = self
+            .standby
+            .safe_lock(|standby| {
+                standby
+                    .as_ref()
+                    .is_some_and(|s| s.pool != current && s.is_alive())
+            })
+            .unwrap_or(false);
+        if usable {
+            return;
+        }
+        let _ = self.standby.safe_lock(|standby| *standby = None);
+
+        let pool_addresses = self.pool_addresses();
+        let mut probes = Vec::with_capacity(pool_addresses.len());
+        for (index, pool) in pool_addresses.into_iter().filter(|(_, p)| *p != current) {
+            probes.push(PoolProbe {
+                index,
+                pool,
+                latency: self.get_latency(pool).await.ok(),
+            });
+        }
+        let Some(pool) = self.strategy.select(&probes) else {
+            warn!("No standby pool available");
+            return;
+        };
+        match minin_pool_connection::open_pool_connection(
+            pool,
+            self.auth_pub_k,
+            self.setup_connection_msg.clone(),
+            self.timer,
+        )
+        .await
+        {
+            Ok((receiver, sender)) => {
+                info!("Standby connection with Pool at {:?} ready", pool);
+                let standby = StandbyConnection::new(pool, receiver, sender);
+                if self.standby.safe_lock(|s| *s = Some(standby)).is_err() {
+                    error!("Standby pool Mutex corrupt");
+                }
+            }
+            Err(e) => warn!("Failed to open standby connection with {:?}: {}", pool, e),
+        }
+    }
+
+    /// Takes the standby connection if it can be used to connect to `pool_addr`, or to any pool
+    /// when `pool_addr` is `None`.
+    fn take_standby(&self, pool_addr: Option<SocketAddr>) -> Option<StandbyConnection> {
+        self.standby
+            .safe_lock(|standby| match standby.as_ref() {
+                Some(s) if s.is_alive() && pool_addr.map_or(true, |p| p == s.pool) => {
+                    standby.take()
+                }
+                _ => None,
+            })
+            .unwrap_or(None)
+    }
+
+    /// Addresses of the pools that could be resolved, with their position in the configured
+    /// order.
+    fn pool_addresses(&self) -> Vec<(usize, SocketAddr)> {
+        self.pool_addresses
+            .safe_lock(|addresses| {
+                addresses
+                    .iter()
+                    .enumerate()
+                    .filter_map(|(index, address)| address.map(|address| (index, address)))
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
 
-        for &pool_addr in &self.pool_addresses {
-            if let Ok(latency) = self.get_latency(pool_addr).await {
-                if latency < least_latency {
-                    least_latency = latency;
-                    best_pool = Some(pool_addr)
+    /// Resolves the pool hostnames again so that a pool whose address changed is reached at the
+    /// new one. A host that can not be resolved keeps its last address.
+    async fn resolve_pools(&self) {
+        let mut resolved = Vec::with_capacity(self.pool_hosts.len());
+        for host in &self.pool_hosts {
+            match tokio::net::lookup_host(host.as_str()).await {
+                Ok(mut addresses) => resolved.push(addresses.next()),
+                Err(e) => {
+                    error!("Failed to resolve pool address {}: {}", host, e);
+                    resolved.push(None);
+                }
+            }
+        }
+        let result = self.pool_addresses.safe_lock(|addresses| {
+            for (address, new) in addresses.iter_mut().zip(resolved) {
+                if new.is_some() {
+                    *address = new;
                 }
             }
+        });
+        if result.is_err() {
+            error!("Pool addresses Mutex corrupt");
         }
+    }
 
-        best_pool.map(|pool| (pool, least_latency))
+    /// Measures the latency of every pool, in the configured order. The pool we are connected to
+    /// is probed too, so that the strategy compares handshake round trips with each other. The
+    /// share round trip measured on the live connection is only reported.
+    async fn probe_pools(&self) -> Vec<PoolProbe> {
+        if let Some(share_rtt) = self
+            .current_pool
+            .and_then(latency::samples)
+            .and_then(|samples| samples.share_rtt())
+        {
+            self.latency_tx.send_replace(Some(share_rtt));
+        }
+        let pool_addresses = self.pool_addresses();
+        let mut probes = Vec::with_capacity(pool_addresses.len());
+        for (index, pool) in pool_addresses {
+            let latency = self.get_latency(pool).await.ok();
+            probes.push(PoolProbe {
+                index,
+                pool,
+                latency,
+            });
+        }
+        probes
+    }
+
+    /// Internal function to select pool according to the configured strategy.
+    async fn select_pool(&self) -> Option<(SocketAddr, Duration)> {
+        let probes = self.probe_pools().await;
+        let pool = self.strategy.select(&probes)?;
+        probes
+            .iter()
+            .find(|p| p.pool == pool)
+            .and_then(|p| p.latency)
+            .map(|latency| (pool, latency))
     }
 
     /// Select the best pool for connection
     pub async fn select_pool_connect(&self) -> Option<SocketAddr> {
         info!("Selecting best Pool for connection");
-        if self.pool_addresses.is_empty() {
+        self.resolve_pools().await;
+        let pool_addresses = self.pool_addresses();
+        if pool_addresses.is_empty() {
             error!("No pool addresses provided");
             return None;
         }
-        if self.pool_addresses.len() == 1 {
-            info!(
-                "Only one pool address available, using: {:?}",
-                self.pool_addresses[0]
-            );
-            return Some(self.pool_addresses[0]);
+        if pool_addresses.len() == 1 {
+            let (_, pool) = pool_addresses[0];
+            info!("Only one pool address available, using: {:?}", pool);
+            return Some(pool);
         }
         if let Some((pool, latency)) = self.select_pool().await {
             info!("Latency for Pool {:?} is {:?}", pool, latency);
@@ -101,34 +272,27 @@ impl Router {
     }
 
     /// Select the best pool for monitoring
-    async fn select_pool_monitor(&self, epsilon: Duration) -> Option<SocketAddr> {
-        if let Some((best_pool, best_pool_latency)) = self.select_pool().await {
-            if let Some(current_pool) = self.current_pool {
-                if best_pool == current_pool {
-                    return None;
-                }
-                let current_latency = match self.get_latency(current_pool).await {
-                    Ok(latency) => latency,
-                    Err(e) => {
-                        error!("Failed to get latency: {:?}", e);
-                        return None;
-                    }
-                };
-                // saturating_sub is used to avoid panic on negative duration result
-                if best_pool_latency < current_latency.saturating_sub(epsilon) {
-                    info!(
-                        "Found faster pool: {:?} with latency {:?}",
-                        best_pool, best_pool_latency
-                    );
-                    return Some(best_pool);
-                } else {
-                    return None;
-                }
-            } else {
-                return Some(best_pool);
+    async fn select_pool_monitor(&self) -> Option<SocketAddr> {
+        if !self.strategy.monitors() {
+            return None;
+        }
+        if let Some(connected_since) = self.connected_since {
+            if connected_since.elapsed() < self.min_dwell_time {
+                return None;
             }
         }
-        None
+        let probes = self.probe_pools().await;
+        match self.current_pool {
+            Some(current_pool) => {
+                let new_pool = self.strategy.switch_from(current_pool, &probes)?;
+                info!(
+                    "Pool selection strategy prefers {:?} over {:?}. Probes: {:?}",
+                    new_pool, current_pool, probes
+                );
+                Some(new_pool)
+            }
+            None => self.strategy.select(&probes),
+        }
     }
 
     /// Selects the best upstream and connects to.
@@ -144,6 +308,18 @@ impl Router {
         ),
         minin_pool_connection::errors::Error,
     > {
+        if let Some(standby) = self.take_standby(pool_addr) {
+            let pool = standby.pool;
+            info!("Promoting standby connection with Pool at {:?}", pool);
+            match standby.promote().await {
+                Ok(connection) => {
+                    self.on_connected(pool, pool_addr, true);
+                    return Ok(connection);
+                }
+                Err(e) => error!("Failed to promote standby connection: {}", e),
+            }
+        }
+
         let pool = match pool_addr {
             Some(addr) => addr,
             None => match self.select_pool_connect().await {
@@ -155,86 +331,114 @@ impl Router {
                 }
             },
         };
-        self.current_pool = Some(pool);
 
         info!("Trying to connect to Pool {:?}", pool);
 
-        match minin_pool_connection::connect_pool(
+        let connection = minin_pool_connection::connect_pool(
             pool,
             self.auth_pub_k,
             self.setup_connection_msg.clone(),
             self.timer,
         )
-        .await
-        {
-            Ok((send_to_pool, recv_from_pool, pool_connection_abortable)) => {
-                crate::POOL_ADDRESS
-                    .safe_lock(|pool_address| {
-                        *pool_address = Some(pool);
-                    })
-                    .unwrap_or_else(|_| {
-                        error!("Pool address Mutex corrupt");
-                        crate::proxy_state::ProxyState::update_inconsistency(Some(1));
-                    });
-                info!(
-                    "Completed Handshake And SetupConnection with Pool at {:?}",
-                    pool
-                );
-
-                Ok((send_to_pool, recv_from_pool, pool_connection_abortable))
-            }
+        .await?;
+        self.on_connected(pool, pool_addr, false);
+        Ok(connection)
+    }
 
-            Err(e) => Err(e),
+    /// `requested` is the pool `connect_pool` was called with: `None` means that we are
+    /// reconnecting because the previous connection went down.
+    fn on_connected(&mut self, pool: SocketAddr, requested: Option<SocketAddr>, standby: bool) {
+        let reason = match (self.current_pool, requested) {
+            (None, _) => SwitchReason::Initial,
+            (Some(_), Some(_)) => SwitchReason::Strategy,
+            (Some(_), None) => SwitchReason::Failover,
+        };
+        let switch = SwitchRecord {
+            timestamp: history::unix_timestamp(),
+            from: self.current_pool,
+            to: pool,
+            reason,
+            standby,
+        };
+        if self.history.safe_lock(|h| h.add_switch(switch)).is_err() {
+            error!("Pool history Mutex corrupt");
         }
+        self.current_pool = Some(pool);
+        self.connected_since = Some(Instant::now());
+        crate::POOL_ADDRESS
+            .safe_lock(|pool_address| {
+                *pool_address = Some(pool);
+            })
+            .unwrap_or_else(|_| {
+                error!("Pool address Mutex corrupt");
+                crate::proxy_state::ProxyState::update_inconsistency(Some(1));
+            });
+        info!(
+            "Completed Handshake And SetupConnection with Pool at {:?}",
+            pool
+        );
     }
 
-    /// Returns the sum all the latencies for a given upstream
+    /// Measures the latency of a pool with a handshake-only connection: no channel is opened, so
+    /// the pool does not see any mining activity. Returns the `SetupConnection` round trip, the
+    /// closest to what is measured passively on the live connection with share acknowledgements.
     async fn get_latency(&self, pool_address: SocketAddr) -> Result<Duration, ()> {
         let mut pool = PoolLatency::new(pool_address);
-        let setup_connection_msg = self.setup_connection_msg.as_ref();
-        let timer = self.timer.as_ref();
-        let auth_pub_key = self.auth_pub_k;
-
-        tokio::time::timeout(
+        let latency = match tokio::time::timeout(
             Duration::from_secs(8),
-            PoolLatency::get_mining_setup_latencies(
-                &mut pool,
-                setup_connection_msg.cloned(),
-                timer.cloned(),
-                auth_pub_key,
+            pool.probe(
+                self.setup_connection_msg.clone(),
+                self.timer,
+                self.auth_pub_k,
             ),
         )
         .await
-        .map_err(|_| {
-            error!(
-                "Failed to get mining setup latencies for {:?}: Timeout",
-                pool_address
-            );
-        })??;
-
-        if (PoolLatency::get_jd_latencies(&mut pool, auth_pub_key).await).is_err() {
-            error!("Failed to get jd setup latencies for: {:?}", pool_address);
-            return Err(());
+        {
+            Ok(Ok(())) => pool.setup_a_channel,
+            Ok(Err(())) => None,
+            Err(_) => {
+                error!("Failed to probe pool {:?}: Timeout", pool_address);
+                None
+            }
+        };
+        debug!("Probe of pool {:?}: {:?}", pool_address, pool);
+        if self
+            .history
+            .safe_lock(|h| h.add_probe(&pool, latency))
+            .is_err()
+        {
+            error!("Pool history Mutex corrupt");
         }
+        latency.ok_or(())
+    }
+
+    /// Latency report of every configured pool, in the configured order.
+    pub fn pool_reports(&self) -> Vec<PoolReport> {
+        self.history
+            .safe_lock(|h| {
+                self.pool_addresses()
+                    .into_iter()
+                    .map(|(_, pool)| h.report(pool))
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// Pool switches, oldest first.
+    pub fn switch_history(&self) -> Vec<SwitchRecord> {
+        self.history.safe_lock(|h| h.switches()).unwrap_or_default()
+    }
 
-        let latencies = [
-            pool.open_sv2_mining_connection,
-            pool.setup_a_channel,
-            pool.receive_first_job,
-            pool.receive_first_set_new_prev_hash,
-            pool.open_sv2_jd_connection,
-            pool.get_a_mining_token,
-        ];
-        // Get sum of all latencies for pool
-        let sum_of_latencies: Duration = latencies.iter().flatten().sum();
-        Ok(sum_of_latencies)
+    /// How long we have been connected to the current pool.
+    pub fn connected_for(&self) -> Option<Duration> {
+        self.connected_since.map(|since| since.elapsed())
     }
 
-    /// Checks for faster upstream switch to it if found
-    pub async fn monitor_upstream(&mut self, epsilon: Duration) -> Option<SocketAddr> {
-        if let Some(best_pool) = self.select_pool_monitor(epsilon).await {
+    /// Checks if the strategy prefers another upstream and returns it if so
+    pub async fn monitor_upstream(&mut self) -> Option<SocketAddr> {
+        if let Some(best_pool) = self.select_pool_monitor().await {
             if Some(best_pool) != self.current_pool {
-                info!("Switching to faster upstreamn {:?}", best_pool);
+                info!("Switching to upstream {:?}", best_pool);
                 return Some(best_pool);
             } else {
                 return None;
@@ -244,16 +448,16 @@ impl Router {
     }
 }
 
-/// Track latencies for various stages of pool connection setup.
+/// Track latencies for the stages of a handshake-only pool connection. The stages that need a
+/// channel or a JD connection (`receive_first_job`, `receive_first_set_new_prev_hash`,
+/// `open_sv2_jd_connection` and `get_a_mining_token`) are measured on the live connection, see
+/// `latency::LiveStages`.
 #[derive(Clone, Copy, Debug)]
 struct PoolLatency {
     pool: SocketAddr,
     open_sv2_mining_connection: Option<Duration>,
+    noise_handshake: Option<Duration>,
     setup_a_channel: Option<Duration>,
-    receive_first_job: Option<Duration>,
-    receive_first_set_new_prev_hash: Option<Duration>,
-    open_sv2_jd_connection: Option<Duration>,
-    get_a_mining_token: Option<Duration>,
 }
 
 impl PoolLatency {
@@ -262,227 +466,52 @@ impl PoolLatency {
         Self {
             pool,
             open_sv2_mining_connection: None,
+            noise_handshake: None,
             setup_a_channel: None,
-            receive_first_job: None,
-            receive_first_set_new_prev_hash: None,
-            open_sv2_jd_connection: None,
-            get_a_mining_token: None,
         }
     }
 
-    /// Sets the `PoolLatency`'s `open_sv2_mining_connection`, `setup_channel_timer`, `receive_first_job`,
-    /// and `receive_first_set_new_prev_hash`
-    async fn get_mining_setup_latencies(
+    /// Connects to the pool, performs the noise handshake and the `SetupConnection`, then drops
+    /// the connection.
+    async fn probe(
         &mut self,
         setup_connection_msg: Option<SetupConnection<'static>>,
         timer: Option<Duration>,
         authority_public_key: Secp256k1PublicKey,
     ) -> Result<(), ()> {
-        // Set open_sv2_mining_connection latency
         let open_sv2_mining_connection_timer = Instant::now();
-        match TcpStream::connect(self.pool).await {
-            Ok(stream) => {
-                self.open_sv2_mining_connection = Some(open_sv2_mining_connection_timer.elapsed());
-
-                let (mut receiver, mut sender, setup_connection_msg) =
-                    initialize_mining_connections(
-                        setup_connection_msg,
-                        stream,
-                        authority_public_key,
-                    )
-                    .await?;
-
-                // Set setup_channel latency
-                let setup_channel_timer = Instant::now();
-                let result = mining_setup_connection(
-                    &mut receiver,
-                    &mut sender,
-                    setup_connection_msg,
-                    timer.unwrap_or(Duration::from_secs(2)),
-                )
-                .await;
-                match result {
-                    Ok(_) => {
-                        self.setup_a_channel = Some(setup_channel_timer.elapsed());
-                        let (send_to_down, mut recv_from_down) = tokio::sync::mpsc::channel(10);
-                        let (send_from_down, recv_to_up) = tokio::sync::mpsc::channel(10);
-                        let channel = open_channel();
-                        if send_from_down
-                            .send(PoolExtMessages::Mining(channel))
-                            .await
-                            .is_err()
-                        {
-                            error!("Failed to send channel to pool");
-                            return Err(());
-                        }
-
-                        let relay_up_task = minin_pool_connection::relay_up(recv_to_up, sender);
-                        let relay_down_task =
-                            minin_pool_connection::relay_down(receiver, send_to_down);
-
-                        let timer = Instant::now();
-                        let mut received_new_job = false;
-                        let mut received_prev_hash = false;
-
-                        while let Some(message) = recv_from_down.recv().await {
-                            if let PoolExtMessages::Mining(Mining::NewExtendedMiningJob(
-                                _new_ext_job,
-                            )) = message.clone()
-                            {
-                                // Set receive_first_job latency
-                                self.receive_first_job = Some(timer.elapsed());
-                                received_new_job = true;
-                            }
-                            if let PoolExtMessages::Mining(Mining::SetNewPrevHash(_new_prev_hash)) =
-                                message.clone()
-                            {
-                                // Set receive_first_set_new_prev_hash latency
-                                self.receive_first_set_new_prev_hash = Some(timer.elapsed());
-                                received_prev_hash = true;
-                            }
-                            // Both latencies have been set so we break the loop
-                            if received_new_job && received_prev_hash {
-                                break;
-                            }
-                        }
-                        drop(relay_up_task);
-                        drop(relay_down_task);
-
-                        Ok(())
-                    }
-                    Err(e) => {
-                        error!(
-                            "Failed to get mining setup latency for pool {}: {:?}",
-                            self.pool, e
-                        );
-                        Err(())
-                    }
-                }
-            }
-            _ => {
-                error!("Failed to get mining setup latencies for: {:?}", self.pool);
-                Err(())
-            }
-        }
-    }
-
-    /// Sets the `PoolLatency`'s `open_sv2_jd_connection` and `get_a_mining_token`
-    async fn get_jd_latencies(
-        &mut self,
-        authority_public_key: Secp256k1PublicKey,
-    ) -> Result<(), ()> {
-        let address = self.pool;
-
-        // Set open_sv2_jd_connection latency
-        let open_sv2_jd_connection_timer = Instant::now();
-
-        match tokio::time::timeout(Duration::from_secs(2), TcpStream::connect(address)).await {
-            Ok(Ok(stream)) => {
-                let tp = crate::TP_ADDRESS
-                    .safe_lock(|tp| tp.clone())
-                    .map_err(|_| error!(" TP_ADDRESS Mutex Corrupted"))?;
-                if let Some(_tp_addr) = tp {
-                    let initiator = Initiator::from_raw_k(authority_public_key.into_bytes())
-                        // Safe expect Key is a constant and must be right
-                        .expect("Unable to create initialtor");
-                    let (mut receiver, mut sender, _, _) =
-                        match Connection::new(stream, HandshakeRole::Initiator(initiator)).await {
-                            Ok(connection) => connection,
-                            Err(e) => {
-                                error!("Failed to create jd connection: {:?}", e);
-                                return Err(());
-                            }
-                        };
-                    if let Err(e) =
-                        SetupConnectionHandler::setup(&mut receiver, &mut sender, address).await
-                    {
-                        error!("Failed to setup connection: {:?}", e);
-                        return Err(());
-                    }
-
-                    self.open_sv2_jd_connection = Some(open_sv2_jd_connection_timer.elapsed());
-
-                    let (sender, mut _receiver) = tokio::sync::mpsc::channel(10);
-                    let upstream =
-                        match crate::jd_client::mining_upstream::Upstream::new(0, sender).await {
-                            Ok(upstream) => upstream,
-                            Err(e) => {
-                                error!("Failed to create upstream: {:?}", e);
-                                return Err(());
-                            }
-                        };
-
-                    let (job_declarator, _aborter) = match JobDeclarator::new(
-                        address,
-                        authority_public_key.into_bytes(),
-                        upstream,
-                        false,
-                    )
-                    .await
-                    {
-                        Ok(new) => new,
-                        Err(e) => {
-                            error!("Failed to create job declarator: {:?}", e);
-                            return Err(());
-                        }
-                    };
-
-                    // Set get_a_mining_token latency
-                    let get_a_mining_token_timer = Instant::now();
-                    let _token = JobDeclarator::get_last_token(&job_declarator).await;
-                    self.get_a_mining_token = Some(get_a_mining_token_timer.elapsed());
-                } else {
-                    self.open_sv2_jd_connection = Some(Duration::from_millis(0));
-                    self.get_a_mining_token = Some(Duration::from_millis(0));
-                }
-                Ok(())
-            }
-            _ => Err(()),
-        }
+        let stream = TcpStream::connect(self.pool).await.map_err(|e| {
+            error!("Failed to connect to pool {:?}: {}", self.pool, e);
+        })?;
+        self.open_sv2_mining_connection = Some(open_sv2_mining_connection_timer.elapsed());
+
+        let noise_handshake_timer = Instant::now();
+        let initiator =
+            // Safe expect Key is a constant and must be right
+            Initiator::from_raw_k(authority_public_key.into_bytes()).expect("Invalid authority key");
+        let (mut receiver, mut sender, _, _) =
+            Connection::new(stream, HandshakeRole::Initiator(initiator))
+                .await
+                .map_err(|e| {
+                    error!("Failed to create mining connection: {:?}", e);
+                })?;
+        self.noise_handshake = Some(noise_handshake_timer.elapsed());
+
+        let setup_a_channel_timer = Instant::now();
+        mining_setup_connection(
+            &mut receiver,
+            &mut sender,
+            setup_connection_msg.unwrap_or(get_mining_setup_connection_msg(true)),
+            timer.unwrap_or(Duration::from_secs(2)),
+        )
+        .await
+        .map_err(|e| {
+            error!(
+                "Failed to get mining setup latency for pool {}: {:?}",
+                self.pool, e
+            );
+        })?;
+        self.setup_a_channel = Some(setup_a_channel_timer.elapsed());
+        Ok(())
     }
 }
-
-// Helper functions
-fn open_channel() -> Mining<'static> {
-    roles_logic_sv2::parsers::Mining::OpenExtendedMiningChannel(
-        roles_logic_sv2::mining_sv2::OpenExtendedMiningChannel {
-            request_id: 0,
-            max_target: binary_sv2::u256_from_int(u64::MAX),
-            min_extranonce_size: 8,
-            user_identity: "ABC"
-                .to_string()
-                .try_into()
-                // This can never fail
-                .expect("Failed to convert user identity to string"),
-            nominal_hash_rate: 0.0,
-        },
-    )
-}
-
-async fn initialize_mining_connections(
-    setup_connection_msg: Option<SetupConnection<'static>>,
-    stream: TcpStream,
-    authority_public_key: Secp256k1PublicKey,
-) -> Result<
-    (
-        Receiver<codec_sv2::Frame<PoolExtMessages<'static>, Slice>>,
-        Sender<codec_sv2::Frame<PoolExtMessages<'static>, Slice>>,
-        SetupConnection<'static>,
-    ),
-    (),
-> {
-    let initiator =
-        // Safe expect Key is a constant and must be right
-        Initiator::from_raw_k(authority_public_key.into_bytes()).expect("Invalid authority key");
-    let (receiver, sender, _, _) =
-        match Connection::new(stream, HandshakeRole::Initiator(initiator)).await {
-            Ok(connection) => connection,
-            Err(e) => {
-                error!("Failed to create mining connection: {:?}", e);
-                return Err(());
-            }
-        };
-    let setup_connection_msg =
-        setup_connection_msg.unwrap_or(get_mining_setup_connection_msg(true));
-    Ok((receiver, sender, setup_connection_msg))
-}
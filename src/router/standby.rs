@@ -0,0 +1,91 @@
+use std::net::SocketAddr;
+
+use demand_share_accounting_ext::parser::PoolExtMessages;
+use tokio::{
+    sync::mpsc::{Receiver, Sender},
+    task::JoinHandle,
+};
+use tracing::warn;
+
+use crate::{
+    minin_pool_connection::{self, errors::Error, EitherFrame},
+    shared::utils::AbortOnDrop,
+};
+
+/// A pool connection that completed the noise handshake and the `SetupConnection` but that is not
+/// used yet. It is kept warm so that it can replace the current pool connection right away.
+///
+/// Frames coming from the pool are forwarded by a task that only watches the connection, so that
+/// a standby going down is noticed without touching the `ProxyState`.
+pub struct StandbyConnection {
+    pub pool: SocketAddr,
+    receiver: Receiver<EitherFrame>,
+    sender: Sender<EitherFrame>,
+    forward: Option<JoinHandle<()>>,
+}
+
+impl StandbyConnection {
+    pub fn new(
+        pool: SocketAddr,
+        mut from_pool: Receiver<EitherFrame>,
+        sender: Sender<EitherFrame>,
+    ) -> Self {
+        let (forward_tx, receiver) = tokio::sync::mpsc::channel(10);
+        let forward = tokio::spawn(async move {
+            while let Some(frame) = from_pool.recv().await {
+                if forward_tx.send(frame).await.is_err() {
+                    return;
+                }
+            }
+            warn!("Standby connection with Pool at {} closed", pool);
+        });
+        Self {
+            pool,
+            receiver,
+            sender,
+            forward: Some(forward),
+        }
+    }
+
+    pub fn is_alive(&self) -> bool {
+        self.forward
+            .as_ref()
+            .is_some_and(|forward| !forward.is_finished())
+            && !self.sender.is_closed()
+    }
+
+    /// Starts the relays on the standby connection. It is now the pool connection, and a failure
+    /// sets the pool down as for any other pool connection.
+    pub async fn promote(
+        mut self,
+    ) -> Result<
+        (
+            Sender<PoolExtMessages<'static>>,
+            Receiver<PoolExtMessages<'static>>,
+            AbortOnDrop,
+        ),
+        Error,
+    > {
+        let forward = self.forward.take().ok_or(Error::Unrecoverable)?;
+        let (receiver, _) = tokio::sync::mpsc::channel(1);
+        let receiver = std::mem::replace(&mut self.receiver, receiver);
+        match minin_pool_connection::start_relays(receiver, self.sender.clone()).await {
+            Ok((send_to_pool, recv_from_pool, mut abortable)) => {
+                abortable.add_task(forward);
+                Ok((send_to_pool, recv_from_pool, abortable))
+            }
+            Err(e) => {
+                forward.abort();
+                Err(e)
+            }
+        }
+    }
+}
+
+impl Drop for StandbyConnection {
+    fn drop(&mut self) {
+        if let Some(forward) = self.forward.take() {
+            forward.abort();
+        }
+    }
+}
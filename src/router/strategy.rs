@@ -0,0 +1,302 @@
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::{net::SocketAddr, sync::Arc, time::Duration};
+
+/// Result of probing a pool. `latency` is `None` when the pool could not be reached.
+#[derive(Clone, Copy, Debug)]
+pub struct PoolProbe {
+    /// Position of the pool in the configured pool list, pools that could not be resolved are
+    /// not probed.
+    pub index: usize,
+    pub pool: SocketAddr,
+    pub latency: Option<Duration>,
+}
+
+/// Decides which pool the `Router` connects to. Probes are passed in the same order as the
+/// configured pool list, so that order can be used as a priority.
+pub trait PoolSelectionStrategy: Send + Sync + std::fmt::Debug {
+    /// Picks the pool to connect to when there is no current connection, at startup or after the
+    /// current pool failed.
+    fn select(&self, probes: &[PoolProbe]) -> Option<SocketAddr>;
+
+    /// Called periodically while connected to `current`. Returns the pool to switch to, if any.
+    fn switch_from(&self, current: SocketAddr, probes: &[PoolProbe]) -> Option<SocketAddr>;
+
+    /// Whether the pools must be probed while connected. Strategies that only switch on failure
+    /// return false so that no probe connections are opened.
+    fn monitors(&self) -> bool {
+        true
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StrategyKind {
+    #[default]
+    LowestLatency,
+    Priority,
+    WeightedRandom,
+    Sticky,
+}
+
+impl std::str::FromStr for StrategyKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().replace('-', "_").as_str() {
+            "lowest_latency" | "latency" => Ok(Self::LowestLatency),
+            "priority" | "failover" => Ok(Self::Priority),
+            "weighted_random" | "weighted" => Ok(Self::WeightedRandom),
+            "sticky" => Ok(Self::Sticky),
+            _ => Err(format!(
+                "Invalid pool selection strategy '{}'. Expected 'lowest_latency', 'priority', 'weighted_random' or 'sticky'",
+                s
+            )),
+        }
+    }
+}
+
+/// `[pool_selection]` section of the config file.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PoolSelectionConfig {
+    pub strategy: StrategyKind,
+    /// A pool must be faster than the current one by at least this much before switching to it.
+    pub hysteresis_ms: u64,
+    /// Minimum time to stay connected to a pool before switching away from it (failures excluded).
+    pub min_dwell_secs: u64,
+    /// Weights used by `weighted_random`, in the same order as the pool list. Missing weights
+    /// default to 1.
+    pub weights: Vec<u32>,
+    /// Keep a connection to the next-best pool open so that failover does not need a reconnect.
+    pub hot_standby: bool,
+}
+
+impl Default for PoolSelectionConfig {
+    fn default() -> Self {
+        Self {
+            strategy: StrategyKind::default(),
+            hysteresis_ms: 30_000,
+            min_dwell_secs: 0,
+            weights: vec![],
+            hot_standby: true,
+        }
+    }
+}
+
+impl PoolSelectionConfig {
+    pub fn min_dwell_time(&self) -> Duration {
+        Duration::from_secs(self.min_dwell_secs)
+    }
+
+    pub fn build_strategy(&self) -> Arc<dyn PoolSelectionStrategy> {
+        let hysteresis = Duration::from_millis(self.hysteresis_ms);
+        match self.strategy {
+            StrategyKind::LowestLatency => Arc::new(LowestLatency { hysteresis }),
+            StrategyKind::Priority => Arc::new(Priority),
+            StrategyKind::WeightedRandom => Arc::new(WeightedRandom {
+                weights: self.weights.clone(),
+            }),
+            StrategyKind::Sticky => Arc::new(Sticky),
+        }
+    }
+}
+
+fn fastest(probes: &[PoolProbe]) -> Option<(SocketAddr, Duration)> {
+    probes
+        .iter()
+        .filter_map(|p| p.latency.map(|l| (p.pool, l)))
+        .min_by_key(|(_, latency)| *latency)
+}
+
+fn latency_of(pool: SocketAddr, probes: &[PoolProbe]) -> Option<Duration> {
+    probes
+        .iter()
+        .find(|p| p.pool == pool)
+        .and_then(|p| p.latency)
+}
+
+/// Connects to the pool with the lowest latency and switches when another pool is faster by more
+/// than `hysteresis`.
+#[derive(Debug)]
+pub struct LowestLatency {
+    pub hysteresis: Duration,
+}
+
+impl PoolSelectionStrategy for LowestLatency {
+    fn select(&self, probes: &[PoolProbe]) -> Option<SocketAddr> {
+        fastest(probes).map(|(pool, _)| pool)
+    }
+
+    fn switch_from(&self, current: SocketAddr, probes: &[PoolProbe]) -> Option<SocketAddr> {
+        let (best, best_latency) = fastest(probes)?;
+        if best == current {
+            return None;
+        }
+        match latency_of(current, probes) {
+            // saturating_sub is used to avoid panic on negative duration result
+            Some(current_latency)
+                if best_latency >= current_latency.saturating_sub(self.hysteresis) =>
+            {
+                None
+            }
+            _ => Some(best),
+        }
+    }
+}
+
+/// Connects to the first reachable pool in the configured order and goes back to a pool with
+/// higher priority as soon as it is reachable again.
+#[derive(Debug)]
+pub struct Priority;
+
+impl PoolSelectionStrategy for Priority {
+    fn select(&self, probes: &[PoolProbe]) -> Option<SocketAddr> {
+        probes.iter().find(|p| p.latency.is_some()).map(|p| p.pool)
+    }
+
+    fn switch_from(&self, current: SocketAddr, probes: &[PoolProbe]) -> Option<SocketAddr> {
+        self.select(probes).filter(|best| *best != current)
+    }
+}
+
+/// Picks a reachable pool at random, proportionally to its weight. Only switches on failure.
+#[derive(Debug)]
+pub struct WeightedRandom {
+    pub weights: Vec<u32>,
+}
+
+impl PoolSelectionStrategy for WeightedRandom {
+    fn select(&self, probes: &[PoolProbe]) -> Option<SocketAddr> {
+        let candidates: Vec<(SocketAddr, u32)> = probes
+            .iter()
+            .filter(|p| p.latency.is_some())
+            .map(|p| (p.pool, self.weights.get(p.index).copied().unwrap_or(1)))
+            .filter(|(_, weight)| *weight > 0)
+            .collect();
+        let total: u64 = candidates.iter().map(|(_, w)| *w as u64).sum();
+        if total == 0 {
+            return None;
+        }
+        let mut pick = rand::thread_rng().gen_range(0..total);
+        for (pool, weight) in candidates {
+            if pick < weight as u64 {
+                return Some(pool);
+            }
+            pick -= weight as u64;
+        }
+        None
+    }
+
+    fn switch_from(&self, _current: SocketAddr, _probes: &[PoolProbe]) -> Option<SocketAddr> {
+        None
+    }
+
+    fn monitors(&self) -> bool {
+        false
+    }
+}
+
+/// Connects to the pool with the lowest latency and stays there until it fails.
+#[derive(Debug)]
+pub struct Sticky;
+
+impl PoolSelectionStrategy for Sticky {
+    fn select(&self, probes: &[PoolProbe]) -> Option<SocketAddr> {
+        fastest(probes).map(|(pool, _)| pool)
+    }
+
+    fn switch_from(&self, _current: SocketAddr, _probes: &[PoolProbe]) -> Option<SocketAddr> {
+        None
+    }
+
+    fn monitors(&self) -> bool {
+        false
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn probe(port: u16, latency_ms: Option<u64>) -> PoolProbe {
+        PoolProbe {
+            index: port as usize - 1,
+            pool: SocketAddr::from(([127, 0, 0, 1], port)),
+            latency: latency_ms.map(Duration::from_millis),
+        }
+    }
+
+    #[test]
+    fn lowest_latency_respects_hysteresis() {
+        let strategy = LowestLatency {
+            hysteresis: Duration::from_millis(50),
+        };
+        let current = probe(1, Some(100));
+        let slightly_faster = [current, probe(2, Some(80))];
+        assert_eq!(strategy.select(&slightly_faster), Some(probe(2, None).pool));
+        assert_eq!(strategy.switch_from(current.pool, &slightly_faster), None);
+
+        let much_faster = [current, probe(2, Some(20))];
+        assert_eq!(
+            strategy.switch_from(current.pool, &much_faster),
+            Some(probe(2, None).pool)
+        );
+
+        let current_down = [probe(1, None), probe(2, Some(80))];
+        assert_eq!(
+            strategy.switch_from(current.pool, &current_down),
+            Some(probe(2, None).pool)
+        );
+    }
+
+    #[test]
+    fn priority_fails_over_and_back() {
+        let strategy = Priority;
+        let primary_down = [probe(1, None), probe(2, Some(300)), probe(3, Some(10))];
+        assert_eq!(strategy.select(&primary_down), Some(probe(2, None).pool));
+
+        let primary_up = [probe(1, Some(200)), probe(2, Some(300)), probe(3, Some(10))];
+        assert_eq!(
+            strategy.switch_from(probe(2, None).pool, &primary_up),
+            Some(probe(1, None).pool)
+        );
+        assert_eq!(strategy.switch_from(probe(1, None).pool, &primary_up), None);
+    }
+
+    #[test]
+    fn weighted_random_skips_unreachable_and_zero_weight() {
+        let strategy = WeightedRandom {
+            weights: vec![5, 0, 1],
+        };
+        let probes = [probe(1, None), probe(2, Some(10)), probe(3, Some(10))];
+        for _ in 0..100 {
+            assert_eq!(strategy.select(&probes), Some(probe(3, None).pool));
+        }
+        assert_eq!(strategy.select(&[probe(1, None)]), None);
+
+        // The first pool could not be resolved, the weights still follow the pool list
+        let probes = [probe(2, Some(10)), probe(3, Some(10))];
+        for _ in 0..100 {
+            assert_eq!(strategy.select(&probes), Some(probe(3, None).pool));
+        }
+    }
+
+    #[test]
+    fn sticky_never_switches_while_connected() {
+        let strategy = Sticky;
+        let probes = [probe(1, Some(500)), probe(2, Some(1))];
+        assert_eq!(strategy.select(&probes), Some(probe(2, None).pool));
+        assert_eq!(strategy.switch_from(probe(1, None).pool, &probes), None);
+    }
+
+    #[test]
+    fn parses_strategy_names() {
+        assert_eq!(
+            "weighted-random".parse::<StrategyKind>(),
+            Ok(StrategyKind::WeightedRandom)
+        );
+        assert_eq!("Sticky".parse::<StrategyKind>(), Ok(StrategyKind::Sticky));
+        assert!("fastest".parse::<StrategyKind>().is_err());
+    }
+}
@@ -0,0 +1,131 @@
+use roles_logic_sv2::mining_sv2::SubmitSharesSuccess;
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+/// Shares without a verdict for longer than this are dropped from the table.
+pub const SHARE_EXPIRY: Duration = Duration::from_secs(120);
+
+#[derive(Debug, Clone, Copy)]
+pub struct ShareSentUp {
+    pub channel_id: u32,
+    pub sequence_number: u32,
+    pub job_id: u32,
+    pub sent_at: Instant,
+}
+
+impl ShareSentUp {
+    /// `SubmitSharesSuccess` that accepts this share alone, for the translator.
+    pub fn accepted(&self) -> SubmitSharesSuccess<'static> {
+        SubmitSharesSuccess {
+            channel_id: self.channel_id,
+            last_sequence_number: self.sequence_number,
+            new_submits_accepted_count: 1,
+            new_shares_sum: 1,
+        }
+    }
+}
+
+/// Shares sent to the pool that are waiting for a `ShareOk`, a `SubmitSharesSuccess` or a
+/// `SubmitSharesError`, by channel and sequence number.
+#[derive(Debug, Default)]
+pub struct InFlightShares {
+    shares: HashMap<(u32, u32), ShareSentUp>,
+}
+
+impl InFlightShares {
+    /// Adds a share and drops the ones that expired.
+    pub fn insert(&mut self, share: ShareSentUp) -> usize {
+        let expired = self.expire(share.sent_at);
+        self.shares
+            .insert((share.channel_id, share.sequence_number), share);
+        expired
+    }
+
+    /// A `ShareOk` only names the job, it acknowledges the oldest pending share of the job. Shares
+    /// already rejected by sequence number are not pending anymore.
+    pub fn ack_job(&mut self, job_id: u32) -> Option<ShareSentUp> {
+        let key = self
+            .shares
+            .iter()
+            .filter(|(_, share)| share.job_id == job_id)
+            .min_by_key(|(_, share)| share.sent_at)
+            .map(|(key, _)| *key)?;
+        self.shares.remove(&key)
+    }
+
+    /// A `SubmitSharesSuccess` acknowledges every share of the channel up to
+    /// `last_sequence_number`.
+    pub fn ack_up_to(&mut self, channel_id: u32, last_sequence_number: u32) -> Vec<ShareSentUp> {
+        // Sequence numbers wrap around
+        let keys: Vec<(u32, u32)> = self
+            .shares
+            .keys()
+            .filter(|(channel, sequence_number)| {
+                *channel == channel_id
+                    && last_sequence_number.wrapping_sub(*sequence_number) < u32::MAX / 2
+            })
+            .copied()
+            .collect();
+        keys.iter()
+            .filter_map(|key| self.shares.remove(key))
+            .collect()
+    }
+
+    pub fn reject(&mut self, channel_id: u32, sequence_number: u32) -> Option<ShareSentUp> {
+        self.shares.remove(&(channel_id, sequence_number))
+    }
+
+    fn expire(&mut self, now: Instant) -> usize {
+        let before = self.shares.len();
+        self.shares
+            .retain(|_, share| now.saturating_duration_since(share.sent_at) < SHARE_EXPIRY);
+        before - self.shares.len()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn share(sequence_number: u32, job_id: u32, sent_at: Instant) -> ShareSentUp {
+        ShareSentUp {
+            channel_id: 1,
+            sequence_number,
+            job_id,
+            sent_at,
+        }
+    }
+
+    #[test]
+    fn shares_of_one_job_do_not_overwrite_each_other() {
+        let start = Instant::now();
+        let mut shares = InFlightShares::default();
+        shares.insert(share(1, 7, start));
+        shares.insert(share(2, 7, start + Duration::from_millis(1)));
+        shares.insert(share(3, 8, start + Duration::from_millis(2)));
+
+        assert_eq!(shares.ack_job(7).unwrap().sequence_number, 1);
+        assert_eq!(shares.reject(1, 3).unwrap().job_id, 8);
+        assert_eq!(shares.ack_job(7).unwrap().sequence_number, 2);
+        assert!(shares.ack_job(7).is_none());
+        assert!(shares.shares.is_empty());
+    }
+
+    #[test]
+    fn acks_up_to_a_sequence_number_and_expires() {
+        let start = Instant::now();
+        let mut shares = InFlightShares::default();
+        for sequence_number in [u32::MAX, 0, 1, 2] {
+            shares.insert(share(sequence_number, 7, start));
+        }
+        assert_eq!(shares.ack_up_to(2, 1).len(), 0);
+        assert_eq!(shares.ack_up_to(1, 1).len(), 3);
+        assert_eq!(shares.shares.len(), 1);
+
+        let expired = shares.insert(share(3, 7, start + SHARE_EXPIRY));
+        assert_eq!(expired, 1);
+        assert_eq!(shares.shares.len(), 1);
+    }
+}
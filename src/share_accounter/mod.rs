@@ -1,14 +1,16 @@
 mod errors;
+pub mod in_flight;
 mod task_manager;
 
 use errors::Error;
-use std::sync::Arc;
-use tracing::error;
+use in_flight::{InFlightShares, ShareSentUp};
+use roles_logic_sv2::utils::Mutex;
+use std::{sync::Arc, time::Instant};
+use tracing::{error, warn};
 
-use dashmap::DashMap;
 use demand_share_accounting_ext::*;
 use parser::{PoolExtMessages, ShareAccountingMessages};
-use roles_logic_sv2::{mining_sv2::SubmitSharesSuccess, parsers::Mining};
+use roles_logic_sv2::parsers::Mining;
 use task_manager::TaskManager;
 
 use crate::{
@@ -24,7 +26,7 @@ pub async fn start(
     up_sender: tokio::sync::mpsc::Sender<PoolExtMessages<'static>>,
 ) -> Result<AbortOnDrop, Error> {
     let task_manager = TaskManager::initialize();
-    let shares_sent_up = Arc::new(DashMap::with_capacity(100));
+    let shares_sent_up = Arc::new(Mutex::new(InFlightShares::default()));
     let abortable = task_manager
         .safe_lock(|t| t.get_aborter())
         .map_err(|_| Error::ShareAccounterTaskManagerMutexCorrupted)?
@@ -42,26 +44,36 @@ pub async fn start(
     Ok(abortable)
 }
 
-struct ShareSentUp {
-    channel_id: u32,
-    sequence_number: u32,
-}
-
 fn relay_up(
     mut receiver: tokio::sync::mpsc::Receiver<Mining<'static>>,
     up_sender: tokio::sync::mpsc::Sender<PoolExtMessages<'static>>,
-    shares_sent_up: Arc<DashMap<u32, ShareSentUp>>,
+    shares_sent_up: Arc<Mutex<InFlightShares>>,
 ) -> AbortOnDrop {
     let task = tokio::spawn(async move {
         while let Some(msg) = receiver.recv().await {
+            if let Mining::OpenExtendedMiningChannel(_) = &msg {
+                crate::router::latency::on_open_channel();
+            }
             if let Mining::SubmitSharesExtended(m) = &msg {
-                shares_sent_up.insert(
-                    m.job_id,
-                    ShareSentUp {
-                        channel_id: m.channel_id,
-                        sequence_number: m.sequence_number,
-                    },
-                );
+                let share = ShareSentUp {
+                    channel_id: m.channel_id,
+                    sequence_number: m.sequence_number,
+                    job_id: m.job_id,
+                    sent_at: Instant::now(),
+                };
+                match shares_sent_up.safe_lock(|s| s.insert(share)) {
+                    Ok(0) => (),
+                    Ok(expired) => warn!(
+                        "{} shares got no answer from the pool in {}s",
+                        expired,
+                        in_flight::SHARE_EXPIRY.as_secs()
+                    ),
+                    Err(e) => {
+                        error!("In flight shares Mutex corrupt: {e}");
+                        ProxyState::update_share_accounter_state(ShareAccounterState::Down);
+                        break;
+                    }
+                }
             };
             let msg = PoolExtMessages::Mining(msg);
             if up_sender.send(msg).await.is_err() {
@@ -75,7 +87,7 @@ fn relay_up(
 fn relay_down(
     mut up_receiver: tokio::sync::mpsc::Receiver<PoolExtMessages<'static>>,
     sender: tokio::sync::mpsc::Sender<Mining<'static>>,
-    shares_sent_up: Arc<DashMap<u32, ShareSentUp>>,
+    shares_sent_up: Arc<Mutex<InFlightShares>>,
 ) -> AbortOnDrop {
     let task = tokio::spawn(async move {
         while let Some(msg) = up_receiver.recv().await {
@@ -84,23 +96,25 @@ fn relay_down(
                     if let ShareAccountingMessages::ShareOk(msg) = msg {
                         let job_id_bytes = msg.ref_job_id.to_le_bytes();
                         let job_id = u32::from_le_bytes(job_id_bytes[4..8].try_into().expect("Internal error: job_id_bytes[4..8] can always be convertible into a u32"));
-                        let share_sent_up = match shares_sent_up.remove(&job_id) {
-                            Some(shares) => shares.1,
-                            // job_id doesn't exist
-                            None => {
-                                error!("Pool sent invalid share success");
-                                // Set global pool state to Down
-                                ProxyState::update_pool_state(PoolState::Down);
-                                return;
+                        let share_sent_up = match shares_sent_up.safe_lock(|s| s.ack_job(job_id)) {
+                            Ok(Some(share)) => share,
+                            // The share expired, or was never sent
+                            Ok(None) => {
+                                warn!("Pool acknowledged an unknown share of job {}", job_id);
+                                continue;
+                            }
+                            Err(e) => {
+                                error!("In flight shares Mutex corrupt: {e}");
+                                ProxyState::update_share_accounter_state(ShareAccounterState::Down);
+                                break;
                             }
                         };
+                        crate::router::latency::on_share_acknowledged(
+                            share_sent_up.sent_at.elapsed(),
+                        );
 
-                        let success = Mining::SubmitSharesSuccess(SubmitSharesSuccess {
-                            channel_id: share_sent_up.channel_id,
-                            last_sequence_number: share_sent_up.sequence_number,
-                            new_submits_accepted_count: 1,
-                            new_shares_sum: 1,
-                        });
+                        // Verdict on that share only, strict mode miners wait for it
+                        let success = Mining::SubmitSharesSuccess(share_sent_up.accepted());
                         if let Err(e) = sender.send(success).await {
                             error!("{e:?}");
                             ProxyState::update_share_accounter_state(ShareAccounterState::Down);
@@ -109,6 +123,41 @@ fn relay_down(
                     };
                 }
                 PoolExtMessages::Mining(msg) => {
+                    let answered = match &msg {
+                        Mining::SetNewPrevHash(m) => {
+                            crate::router::latency::on_pool_prev_hash(m.prev_hash.to_vec());
+                            Ok(vec![])
+                        }
+                        Mining::NewExtendedMiningJob(_) => {
+                            crate::router::latency::on_pool_job();
+                            Ok(vec![])
+                        }
+                        Mining::SubmitSharesSuccess(m) => shares_sent_up
+                            .safe_lock(|s| s.ack_up_to(m.channel_id, m.last_sequence_number)),
+                        Mining::SubmitSharesError(m) => shares_sent_up.safe_lock(|s| {
+                            s.reject(m.channel_id, m.sequence_number)
+                                .into_iter()
+                                .collect()
+                        }),
+                        _ => Ok(vec![]),
+                    };
+                    match answered {
+                        Ok(shares) => {
+                            for share in shares {
+                                let rtt = share.sent_at.elapsed();
+                                if let Mining::SubmitSharesError(_) = &msg {
+                                    crate::router::latency::on_share_rejected(rtt);
+                                } else {
+                                    crate::router::latency::on_share_acknowledged(rtt);
+                                }
+                            }
+                        }
+                        Err(e) => {
+                            error!("In flight shares Mutex corrupt: {e}");
+                            ProxyState::update_share_accounter_state(ShareAccounterState::Down);
+                            break;
+                        }
+                    }
                     if let Err(e) = sender.send(msg).await {
                         error!("{e}");
                         ProxyState::update_share_accounter_state(ShareAccounterState::Down);
@@ -0,0 +1,143 @@
+use crate::config::{parse_hashrate, Configuration, WorkerConfig};
+
+/// Starting difficulty asked for by a miner, or set for its worker in the config.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DifficultyHint {
+    Difficulty(f32),
+    /// Hashrate in h/s.
+    Hashrate(f32),
+}
+
+impl DifficultyHint {
+    /// Difficulty at which a miner sends `share_per_min` shares per minute.
+    pub fn difficulty(&self, share_per_min: f32) -> f32 {
+        match self {
+            Self::Difficulty(difficulty) => *difficulty,
+            Self::Hashrate(hashrate) => hashrate / (share_per_min / 60.0 * 2f32.powi(32)),
+        }
+    }
+
+    /// Parses the `d=<diff>`, `h=<hashrate>` and `hr=<hashrate>` tokens of a `mining.authorize`
+    /// password, e.g. `x,d=65536` or `hr=200T`. Tokens are separated by commas, semicolons or
+    /// spaces, the last valid one wins.
+    pub fn from_password(password: &str) -> Option<Self> {
+        password
+            .split([',', ';', ' '])
+            .filter_map(|token| {
+                let (key, value) = token.split_once('=')?;
+                match key.trim().to_lowercase().as_str() {
+                    "d" | "diff" => difficulty(value).map(Self::Difficulty),
+                    "h" | "hr" => hashrate(value).map(Self::Hashrate),
+                    _ => None,
+                }
+            })
+            .last()
+    }
+
+    /// Value of `mining.suggest_difficulty`.
+    pub fn suggested(value: f64) -> Option<Self> {
+        Some(value as f32)
+            .filter(|d| d.is_finite() && *d > 0.0)
+            .map(Self::Difficulty)
+    }
+
+    /// Hint of the `[[workers]]` entry of the config that matches the worker `name`.
+    pub fn for_worker(name: &str) -> Option<Self> {
+        from_workers(Configuration::workers(), name)
+    }
+}
+
+/// Entries match the full user name or the worker part after the last dot. A difficulty wins
+/// over a hashrate.
+fn from_workers(workers: &[WorkerConfig], name: &str) -> Option<DifficultyHint> {
+    let worker = name.rsplit('.').next().unwrap_or(name);
+    let entry = workers
+        .iter()
+        .find(|w| w.name == name)
+        .or_else(|| workers.iter().find(|w| w.name == worker))?;
+    entry
+        .difficulty
+        .and_then(|d| DifficultyHint::suggested(d as f64))
+        .or_else(|| {
+            entry
+                .hashrate
+                .as_deref()
+                .and_then(hashrate)
+                .map(DifficultyHint::Hashrate)
+        })
+}
+
+fn difficulty(value: &str) -> Option<f32> {
+    value
+        .trim()
+        .parse::<f32>()
+        .ok()
+        .filter(|d| d.is_finite() && *d > 0.0)
+}
+
+/// Plain h/s or with a unit, e.g. `200T`.
+fn hashrate(value: &str) -> Option<f32> {
+    let value = value.trim();
+    value
+        .parse::<f32>()
+        .ok()
+        .or_else(|| parse_hashrate(value).ok())
+        .filter(|h| h.is_finite() && *h > 0.0)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn is_hashrate(hint: Option<DifficultyHint>, expected: f32) -> bool {
+        matches!(hint, Some(DifficultyHint::Hashrate(h)) if (h - expected).abs() / expected < 1e-6)
+    }
+
+    #[test]
+    fn password_tokens() {
+        assert_eq!(
+            DifficultyHint::from_password("x,d=65536"),
+            Some(DifficultyHint::Difficulty(65536.0))
+        );
+        assert!(is_hashrate(
+            DifficultyHint::from_password("hr=200T"),
+            200e12
+        ));
+        assert_eq!(
+            DifficultyHint::from_password("h=1000000; d=512"),
+            Some(DifficultyHint::Difficulty(512.0))
+        );
+        assert_eq!(DifficultyHint::from_password("x"), None);
+        assert_eq!(DifficultyHint::from_password("d=0,hr=abc"), None);
+    }
+
+    #[test]
+    fn difficulty_from_hashrate() {
+        let hint = DifficultyHint::Hashrate(60.0 * 2f32.powi(32));
+        assert_eq!(hint.difficulty(60.0), 60.0);
+        assert_eq!(DifficultyHint::Difficulty(1024.0).difficulty(6.0), 1024.0);
+        assert_eq!(DifficultyHint::suggested(-1.0), None);
+    }
+
+    #[test]
+    fn worker_overrides() {
+        let workers = [
+            WorkerConfig {
+                name: "rig1".to_string(),
+                difficulty: None,
+                hashrate: Some("10T".to_string()),
+            },
+            WorkerConfig {
+                name: "account.rig1".to_string(),
+                difficulty: Some(2048.0),
+                hashrate: Some("10T".to_string()),
+            },
+        ];
+        assert_eq!(
+            from_workers(&workers, "account.rig1"),
+            Some(DifficultyHint::Difficulty(2048.0))
+        );
+        assert!(is_hashrate(from_workers(&workers, "other.rig1"), 10e12));
+        assert_eq!(from_workers(&workers, "rig2"), None);
+    }
+}
@@ -0,0 +1,306 @@
+use lazy_static::lazy_static;
+use serde::Serialize;
+use serde_json::Value;
+use tracing::warn;
+
+use crate::config::{Configuration, FirmwareQuirksConfig};
+
+lazy_static! {
+    static ref REGISTRY: QuirkRegistry = QuirkRegistry::new(Configuration::firmware_quirks());
+}
+
+/// Firmware specific behaviour, matched on the agent signature of `mining.subscribe`.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct FirmwareQuirks {
+    /// Firmware family, used in the logs and to override a built in entry from the config.
+    pub name: String,
+    /// Case insensitive substring of the agent signature.
+    pub agent: String,
+    /// Adds `"id":null` to the messages that have no id, the firmware drops them otherwise.
+    pub null_id: bool,
+    /// Sends `mining.set_difficulty` with an integer, the firmware can not parse floats.
+    pub integer_difficulty: bool,
+    /// Sends the last `mining.notify` again after every `mining.set_difficulty`, the firmware
+    /// only applies a new difficulty from the next job.
+    pub notify_after_difficulty: bool,
+    /// The firmware handles `mining.set_extranonce` once it sent `mining.extranonce.subscribe`.
+    /// When false, or when the miner did not subscribe, the miner is disconnected instead of
+    /// being sent a new extranonce.
+    pub set_extranonce: bool,
+    /// The firmware rolls the version bits it negotiated with `mining.configure`. When false the
+    /// miner is granted no version rolling mask, a mask negotiated before `mining.subscribe` named
+    /// the firmware is cleared with `mining.set_version_mask` before the first job.
+    pub version_rolling: bool,
+}
+
+impl FirmwareQuirks {
+    /// Quirks of the firmwares that are not in the registry.
+    fn generic() -> Self {
+        Self {
+            name: "generic".to_string(),
+            agent: String::new(),
+            null_id: false,
+            integer_difficulty: false,
+            notify_after_difficulty: false,
+            set_extranonce: true,
+            version_rolling: true,
+        }
+    }
+
+    /// Only the firmwares whose quirks were seen on the proxy are built in, the others are added
+    /// with `[[firmware]]` entries of the config.
+    fn builtin() -> Vec<Self> {
+        vec![Self {
+            name: "luxminer".to_string(),
+            agent: "luxminer".to_string(),
+            null_id: true,
+            ..Self::generic()
+        }]
+    }
+
+    fn apply(&mut self, config: &FirmwareQuirksConfig) {
+        if let Some(agent) = &config.agent {
+            self.agent = agent.to_lowercase();
+        }
+        let flags = [
+            (&mut self.null_id, config.null_id),
+            (&mut self.integer_difficulty, config.integer_difficulty),
+            (
+                &mut self.notify_after_difficulty,
+                config.notify_after_difficulty,
+            ),
+            (&mut self.set_extranonce, config.set_extranonce),
+            (&mut self.version_rolling, config.version_rolling),
+        ];
+        for (flag, value) in flags {
+            if let Some(value) = value {
+                *flag = value;
+            }
+        }
+    }
+
+    fn matches(&self, agent: &str) -> bool {
+        !self.agent.is_empty() && agent.to_lowercase().contains(&self.agent)
+    }
+}
+
+struct QuirkRegistry {
+    entries: Vec<FirmwareQuirks>,
+    generic: FirmwareQuirks,
+}
+
+impl QuirkRegistry {
+    /// Entries of the config with the name of a built in firmware change it, the others are new
+    /// firmwares and are matched before the built in ones.
+    fn new(overrides: &[FirmwareQuirksConfig]) -> Self {
+        let mut entries = FirmwareQuirks::builtin();
+        let mut generic = FirmwareQuirks::generic();
+        let mut added = vec![];
+        for config in overrides {
+            if config.name == generic.name {
+                generic.apply(config);
+                generic.agent = String::new();
+            } else if let Some(entry) = entries.iter_mut().find(|e| e.name == config.name) {
+                entry.apply(config);
+            } else if config.agent.as_ref().is_some_and(|a| !a.is_empty()) {
+                let mut entry = FirmwareQuirks {
+                    name: config.name.clone(),
+                    ..FirmwareQuirks::generic()
+                };
+                entry.apply(config);
+                added.push(entry);
+            } else {
+                warn!("Firmware quirks {} ignored: no agent to match", config.name);
+            }
+        }
+        added.append(&mut entries);
+        Self {
+            entries: added,
+            generic,
+        }
+    }
+
+    fn lookup(&self, agent: &str) -> &FirmwareQuirks {
+        self.entries
+            .iter()
+            .find(|e| e.matches(agent))
+            .unwrap_or(&self.generic)
+    }
+}
+
+/// Returns the quirks of the firmware with the `mining.subscribe` agent signature `agent`.
+pub fn quirks_for(agent: &str) -> &'static FirmwareQuirks {
+    REGISTRY.lookup(agent)
+}
+
+/// Applies the rewrites of a firmware to the messages sent to the miner.
+#[derive(Debug)]
+pub struct MessageRewriter {
+    quirks: &'static FirmwareQuirks,
+    last_notify: Option<String>,
+}
+
+impl MessageRewriter {
+    pub fn new(quirks: &'static FirmwareQuirks) -> Self {
+        Self {
+            quirks,
+            last_notify: None,
+        }
+    }
+
+    /// Returns the messages to send to the miner in place of `message`.
+    pub fn rewrite(&mut self, mut message: String) -> Vec<String> {
+        let quirks = self.quirks;
+        let method = if quirks.integer_difficulty || quirks.notify_after_difficulty {
+            method(&message)
+        } else {
+            None
+        };
+        if quirks.integer_difficulty && method.as_deref() == Some("mining.set_difficulty") {
+            message = integer_difficulty(message);
+        }
+        if quirks.null_id && !message.contains("\"id\"") {
+            if let Some(pos) = message.find('{') {
+                message.insert_str(pos + 1, r#""id":null,"#);
+            }
+        }
+        match method.as_deref() {
+            Some("mining.notify") if quirks.notify_after_difficulty => {
+                self.last_notify = Some(message.clone());
+                vec![message]
+            }
+            Some("mining.set_difficulty") if quirks.notify_after_difficulty => {
+                match self.last_notify.clone() {
+                    Some(notify) => vec![message, notify],
+                    None => vec![message],
+                }
+            }
+            _ => vec![message],
+        }
+    }
+}
+
+fn method(message: &str) -> Option<String> {
+    let value: Value = serde_json::from_str(message).ok()?;
+    value.get("method")?.as_str().map(|m| m.to_string())
+}
+
+fn integer_difficulty(message: String) -> String {
+    let Ok(mut value) = serde_json::from_str::<Value>(&message) else {
+        return message;
+    };
+    let Some(difficulty) = value
+        .get_mut("params")
+        .and_then(|p| p.get_mut(0))
+        .filter(|d| d.is_f64())
+    else {
+        return message;
+    };
+    // Rounded up so that the shares of the miner still meet the target
+    let rounded = difficulty.as_f64().unwrap_or(1.0).ceil().max(1.0) as u64;
+    *difficulty = Value::from(rounded);
+    serde_json::to_string(&value).unwrap_or(message)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const NOTIFY: &str = r#"{"method":"mining.notify","params":["1"]}"#;
+    const SET_DIFFICULTY: &str = r#"{"method":"mining.set_difficulty","params":[1024.5]}"#;
+
+    fn registry() -> QuirkRegistry {
+        QuirkRegistry::new(&[])
+    }
+
+    fn rewrite(quirks: &FirmwareQuirks, messages: &[&str]) -> Vec<String> {
+        // Leaked to get the 'static lifetime of the registry entries
+        let quirks: &'static FirmwareQuirks = Box::leak(Box::new(quirks.clone()));
+        let mut rewriter = MessageRewriter::new(quirks);
+        messages
+            .iter()
+            .flat_map(|m| rewriter.rewrite(m.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn generic_firmware() {
+        let registry = registry();
+        let quirks = registry.lookup("Antminer S21/Sat Jan 1 2024");
+        assert_eq!(quirks.name, "generic");
+        assert!(quirks.set_extranonce);
+        assert_eq!(
+            rewrite(quirks, &[SET_DIFFICULTY, NOTIFY]),
+            vec![SET_DIFFICULTY, NOTIFY]
+        );
+    }
+
+    #[test]
+    fn luxminer() {
+        let registry = registry();
+        let quirks = registry.lookup("LUXminer/2024.5.1");
+        assert_eq!(quirks.name, "luxminer");
+        assert_eq!(
+            rewrite(quirks, &[NOTIFY, r#"{"id":4,"result":true}"#]),
+            vec![
+                r#"{"id":null,"method":"mining.notify","params":["1"]}"#,
+                r#"{"id":4,"result":true}"#
+            ]
+        );
+    }
+
+    #[test]
+    fn unknown_firmwares_are_generic() {
+        let registry = registry();
+        for agent in ["cgminer/4.12.1", "NerdMinerV2/1.6.3", ""] {
+            let quirks = registry.lookup(agent);
+            assert_eq!(quirks.name, "generic");
+            assert!(quirks.set_extranonce);
+            assert!(quirks.version_rolling);
+        }
+    }
+
+    #[test]
+    fn difficulty_rewrites() {
+        let quirks = FirmwareQuirks {
+            integer_difficulty: true,
+            notify_after_difficulty: true,
+            ..FirmwareQuirks::generic()
+        };
+        let set_difficulty = r#"{"method":"mining.set_difficulty","params":[1025]}"#;
+        assert_eq!(
+            rewrite(&quirks, &[SET_DIFFICULTY, NOTIFY, SET_DIFFICULTY]),
+            vec![set_difficulty, NOTIFY, set_difficulty, NOTIFY]
+        );
+    }
+
+    #[test]
+    fn config_overrides() {
+        let registry = QuirkRegistry::new(&[
+            FirmwareQuirksConfig {
+                name: "luxminer".to_string(),
+                null_id: Some(false),
+                ..Default::default()
+            },
+            FirmwareQuirksConfig {
+                name: "custom".to_string(),
+                agent: Some("LUXminer/2025".to_string()),
+                set_extranonce: Some(false),
+                version_rolling: Some(false),
+                ..Default::default()
+            },
+            FirmwareQuirksConfig {
+                name: "no agent".to_string(),
+                ..Default::default()
+            },
+        ]);
+        let custom = registry.lookup("luxminer/2025.1");
+        assert_eq!(custom.name, "custom");
+        assert!(!custom.set_extranonce);
+        assert!(!custom.version_rolling);
+        let luxminer = registry.lookup("LUXminer/2024.5.1");
+        assert_eq!(luxminer.name, "luxminer");
+        assert!(!luxminer.null_id);
+        assert_eq!(registry.entries.len(), FirmwareQuirks::builtin().len() + 1);
+    }
+}
@@ -0,0 +1,250 @@
+use serde::Serialize;
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+/// Buckets of every window, the oldest one is dropped when a new one starts so a window covers
+/// between `BUCKETS - 1` and `BUCKETS` bucket lengths.
+const BUCKETS: u32 = 60;
+
+/// A meter reports no hashrate before it has been measuring for this long.
+const MIN_SPAN: Duration = Duration::from_secs(60);
+
+/// Averaging windows of `HashrateMeter`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HashrateWindow {
+    OneMinute,
+    FiveMinutes,
+    FifteenMinutes,
+    OneHour,
+    OneDay,
+}
+
+impl HashrateWindow {
+    const ALL: [Self; 5] = [
+        Self::OneMinute,
+        Self::FiveMinutes,
+        Self::FifteenMinutes,
+        Self::OneHour,
+        Self::OneDay,
+    ];
+
+    fn duration(self) -> Duration {
+        Duration::from_secs(match self {
+            Self::OneMinute => 60,
+            Self::FiveMinutes => 5 * 60,
+            Self::FifteenMinutes => 15 * 60,
+            Self::OneHour => 60 * 60,
+            Self::OneDay => 24 * 60 * 60,
+        })
+    }
+}
+
+/// Hashrate in h/s averaged over each window.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize)]
+pub struct HashrateAverages {
+    #[serde(rename = "1m")]
+    pub one_minute: f32,
+    #[serde(rename = "5m")]
+    pub five_minutes: f32,
+    #[serde(rename = "15m")]
+    pub fifteen_minutes: f32,
+    #[serde(rename = "1h")]
+    pub one_hour: f32,
+    #[serde(rename = "24h")]
+    pub one_day: f32,
+}
+
+/// Measures a hashrate from the difficulty of the shares found, over sliding windows of 1m, 5m,
+/// 15m, 1h and 24h. A share of difficulty `d` is `d * 2^32` hashes on average.
+#[derive(Clone, Debug)]
+pub struct HashrateMeter {
+    started: Instant,
+    windows: [Window; 5],
+}
+
+impl Default for HashrateMeter {
+    fn default() -> Self {
+        Self::new(Instant::now())
+    }
+}
+
+impl HashrateMeter {
+    pub fn new(now: Instant) -> Self {
+        Self {
+            started: now,
+            windows: HashrateWindow::ALL.map(|window| Window::new(window.duration(), now)),
+        }
+    }
+
+    pub fn add_share(&mut self, now: Instant, difficulty: f32) {
+        for window in &mut self.windows {
+            window.add(now, difficulty as f64);
+        }
+    }
+
+    /// Hashrate in h/s over `window`, or over the time since the meter started if shorter.
+    /// `None` during the first minute.
+    pub fn hashrate(&mut self, now: Instant, window: HashrateWindow) -> Option<f32> {
+        let measured = now.saturating_duration_since(self.started);
+        if measured < MIN_SPAN {
+            return None;
+        }
+        let index = HashrateWindow::ALL
+            .iter()
+            .position(|w| *w == window)
+            .expect("Internal error: every window is in ALL");
+        Some(self.windows[index].hashrate(now, measured))
+    }
+
+    /// Hashrate over every window, 0 during the first minute.
+    pub fn averages(&mut self, now: Instant) -> HashrateAverages {
+        let mut hashrate = |window| self.hashrate(now, window).unwrap_or(0.0);
+        HashrateAverages {
+            one_minute: hashrate(HashrateWindow::OneMinute),
+            five_minutes: hashrate(HashrateWindow::FiveMinutes),
+            fifteen_minutes: hashrate(HashrateWindow::FifteenMinutes),
+            one_hour: hashrate(HashrateWindow::OneHour),
+            one_day: hashrate(HashrateWindow::OneDay),
+        }
+    }
+}
+
+/// Sum of the share difficulties of each bucket, the last bucket started at `current_start`.
+#[derive(Clone, Debug)]
+struct Window {
+    bucket: Duration,
+    sums: VecDeque<f64>,
+    current_start: Instant,
+}
+
+impl Window {
+    fn new(length: Duration, now: Instant) -> Self {
+        let mut sums = VecDeque::with_capacity(BUCKETS as usize);
+        sums.push_back(0.0);
+        Self {
+            bucket: length / BUCKETS,
+            sums,
+            current_start: now,
+        }
+    }
+
+    fn advance(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.current_start);
+        let steps = (elapsed.as_nanos() / self.bucket.as_nanos()) as u32;
+        if steps == 0 {
+            return;
+        }
+        if steps >= BUCKETS {
+            self.sums.clear();
+            self.sums.push_back(0.0);
+        } else {
+            for _ in 0..steps {
+                if self.sums.len() == BUCKETS as usize {
+                    self.sums.pop_front();
+                }
+                self.sums.push_back(0.0);
+            }
+        }
+        self.current_start += self.bucket * steps;
+    }
+
+    fn add(&mut self, now: Instant, difficulty: f64) {
+        self.advance(now);
+        if let Some(sum) = self.sums.back_mut() {
+            *sum += difficulty;
+        }
+    }
+
+    /// `measured` caps the span for meters younger than the window.
+    fn hashrate(&mut self, now: Instant, measured: Duration) -> f32 {
+        self.advance(now);
+        let span = (self.bucket * (self.sums.len() as u32 - 1)
+            + now.saturating_duration_since(self.current_start))
+        .min(measured);
+        if span.is_zero() {
+            return 0.0;
+        }
+        let difficulty: f64 = self.sums.iter().sum();
+        (difficulty * 2f64.powi(32) / span.as_secs_f64()) as f32
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Shares of `difficulty` every `interval` from `start` for `duration`.
+    fn mine(
+        meter: &mut HashrateMeter,
+        start: Instant,
+        duration: Duration,
+        interval: Duration,
+        difficulty: f32,
+    ) -> Instant {
+        let mut now = start;
+        while now < start + duration {
+            now += interval;
+            meter.add_share(now, difficulty);
+        }
+        now
+    }
+
+    fn assert_close(measured: f32, expected: f32) {
+        assert!(
+            (measured - expected).abs() / expected < 0.05,
+            "measured {} expected {}",
+            measured,
+            expected
+        );
+    }
+
+    #[test]
+    fn steady_miner() {
+        let start = Instant::now();
+        let mut meter = HashrateMeter::new(start);
+        // 1000 difficulty every 6 seconds
+        let expected = 1000.0 * 2f32.powi(32) / 6.0;
+        let now = mine(
+            &mut meter,
+            start,
+            Duration::from_secs(2 * 3600),
+            Duration::from_secs(6),
+            1000.0,
+        );
+        let averages = meter.averages(now);
+        assert_close(averages.one_minute, expected);
+        assert_close(averages.five_minutes, expected);
+        assert_close(averages.fifteen_minutes, expected);
+        assert_close(averages.one_hour, expected);
+        // Only mined for two hours
+        assert_close(averages.one_day, expected);
+    }
+
+    #[test]
+    fn windows_forget_old_shares() {
+        let start = Instant::now();
+        let mut meter = HashrateMeter::new(start);
+        assert_eq!(meter.hashrate(start, HashrateWindow::OneMinute), None);
+        let now = mine(
+            &mut meter,
+            start,
+            Duration::from_secs(3600),
+            Duration::from_secs(6),
+            1000.0,
+        );
+        // The miner stops for 10 minutes
+        let now = now + Duration::from_secs(600);
+        let averages = meter.averages(now);
+        assert_eq!(averages.one_minute, 0.0);
+        assert_eq!(averages.five_minutes, 0.0);
+        let expected = 1000.0 * 2f32.powi(32) / 6.0;
+        assert_close(averages.fifteen_minutes, expected / 3.0);
+        assert_close(averages.one_hour, expected * 5.0 / 6.0);
+
+        // Back after a day
+        let now = now + Duration::from_secs(24 * 3600);
+        assert_eq!(meter.averages(now), HashrateAverages::default());
+    }
+}
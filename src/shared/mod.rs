@@ -2,5 +2,9 @@
 //!
 //!
 
+pub mod difficulty_hint;
 pub mod error;
+pub mod firmware;
+pub mod hashrate;
 pub mod utils;
+pub mod version_rolling;
@@ -0,0 +1,110 @@
+use std::fmt;
+
+/// Version bits miners may roll when the config does not set a mask, the 16 bits of BIP320.
+pub const DEFAULT_MASK: u32 = 0x1FFFE000;
+
+/// Why the version rolling asked for in a `mining.configure` can not be granted.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum VersionRollingError {
+    /// The pool does not allow rolling the version of its jobs.
+    NotAllowed,
+    /// Fewer bits than the `min-bit-count` of the miner are left in the mask.
+    NotEnoughBits { available: u32, required: u32 },
+}
+
+impl fmt::Display for VersionRollingError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NotAllowed => write!(f, "Version rolling is not allowed"),
+            Self::NotEnoughBits {
+                available,
+                required,
+            } => write!(
+                f,
+                "Version rolling mask has {} bits, {} are required",
+                available, required
+            ),
+        }
+    }
+}
+
+/// Negotiates version rolling as in BIP310. The miner gets the bits of its `mask` that are also
+/// in `allowed`, as long as there are at least `min_bit_count` of them. `allowed` is zero when the
+/// pool does not allow version rolling. A miner that did not ask for version rolling gets an
+/// empty mask.
+///
+/// Returns the mask and the minimum bit count.
+pub fn negotiate(
+    mask: Option<u32>,
+    min_bit_count: Option<u32>,
+    allowed: u32,
+) -> Result<(u32, u32), VersionRollingError> {
+    let Some(mask) = mask else {
+        return Ok((0, 0));
+    };
+    let min_bit_count = min_bit_count.unwrap_or(0);
+    let mask = mask & allowed;
+    if mask.count_ones() >= min_bit_count {
+        Ok((mask, min_bit_count))
+    } else if allowed == 0 {
+        Err(VersionRollingError::NotAllowed)
+    } else {
+        Err(VersionRollingError::NotEnoughBits {
+            available: mask.count_ones(),
+            required: min_bit_count,
+        })
+    }
+}
+
+/// Parses a mask of the config, in hex with or without `0x`.
+pub fn parse_mask(mask: &str) -> Result<u32, String> {
+    let mask = mask.trim();
+    let digits = mask
+        .strip_prefix("0x")
+        .or_else(|| mask.strip_prefix("0X"))
+        .unwrap_or(mask);
+    u32::from_str_radix(digits, 16).map_err(|e| format!("Invalid version rolling mask {mask}: {e}"))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn negotiates_the_common_bits() {
+        assert_eq!(
+            negotiate(Some(0xffffffff), Some(2), DEFAULT_MASK),
+            Ok((DEFAULT_MASK, 2))
+        );
+        assert_eq!(
+            negotiate(Some(0x00ffe000), None, 0x1fe00000),
+            Ok((0x00e00000, 0))
+        );
+        assert_eq!(negotiate(None, Some(16), DEFAULT_MASK), Ok((0, 0)));
+        // No bits needed, no bits granted
+        assert_eq!(negotiate(Some(DEFAULT_MASK), Some(0), 0), Ok((0, 0)));
+    }
+
+    #[test]
+    fn unsatisfiable_requests() {
+        assert_eq!(
+            negotiate(Some(DEFAULT_MASK), Some(2), 0),
+            Err(VersionRollingError::NotAllowed)
+        );
+        assert_eq!(
+            negotiate(Some(0x00006000), Some(4), DEFAULT_MASK),
+            Err(VersionRollingError::NotEnoughBits {
+                available: 2,
+                required: 4
+            })
+        );
+    }
+
+    #[test]
+    fn masks_of_the_config() {
+        assert_eq!(parse_mask("1fffe000"), Ok(DEFAULT_MASK));
+        assert_eq!(parse_mask(" 0x1FFFE000 "), Ok(DEFAULT_MASK));
+        assert!(parse_mask("0xzz").is_err());
+        assert!(parse_mask("1ffffe0000").is_err());
+    }
+}
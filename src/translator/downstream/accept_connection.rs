@@ -1,28 +1,20 @@
 use crate::{
+    config::PortProfile,
     proxy_state::{DownstreamType, ProxyState},
-    translator::{
-        error::Error, proxy::Bridge, upstream::diff_management::UpstreamDifficultyConfig,
-    },
+    translator::{error::Error, proxy::Bridge},
 };
 
-use super::{downstream::Downstream, task_manager::TaskManager, DownstreamMessages};
+use super::{downstream::Downstream, sessions::DownstreamSessions, task_manager::TaskManager};
 use roles_logic_sv2::utils::Mutex;
 use std::{net::IpAddr, sync::Arc};
-use sv1_api::server_to_client;
-use tokio::sync::{
-    broadcast,
-    mpsc::{Receiver, Sender},
-};
+use tokio::sync::mpsc::{Receiver, Sender};
 use tokio::task;
 use tracing::{debug, error, info};
 
 pub async fn start_accept_connection(
     task_manager: Arc<Mutex<TaskManager>>,
-    tx_sv1_submit: Sender<DownstreamMessages>,
-    tx_mining_notify: broadcast::Sender<server_to_client::Notify<'static>>,
-    bridge: Arc<Mutex<super::super::proxy::Bridge>>,
-    upstream_difficulty_config: Arc<Mutex<UpstreamDifficultyConfig>>,
-    mut downstreams: Receiver<(Sender<String>, Receiver<String>, IpAddr)>,
+    sessions: DownstreamSessions,
+    mut downstreams: Receiver<(Sender<String>, Receiver<String>, IpAddr, Arc<PortProfile>)>,
     stats_sender: crate::api::stats::StatsSender,
 ) -> Result<(), Error<'static>> {
     let handle = {
@@ -30,37 +22,42 @@ pub async fn start_accept_connection(
         task::spawn(async move {
             // This is needed. When bridge want to send a notification if no downstream is
             // available at least one receiver must be around.
+            let tx_mining_notify = sessions.tx_sv1_notify();
             let _s = tx_mining_notify.subscribe();
-            while let Some((send, recv, addr)) = downstreams.recv().await {
+            while let Some((send, recv, addr, profile)) = downstreams.recv().await {
                 info!("Translator opening connection for ip {}", addr);
                 // The initial difficulty is derived from the formula: difficulty = hash_rate / (shares_per_second * 2^32)
-                let initial_hash_rate = *crate::EXPECTED_SV1_HASHPOWER;
+                let initial_hash_rate = profile.expected_hashrate;
                 info!(
                     "Translator initial hash rate for ip {} is {} H/s",
                     addr, initial_hash_rate
                 );
-                let share_per_second = *crate::SHARE_PER_MIN / 60.0;
+                let share_per_second = profile.share_per_min / 60.0;
                 info!(
                     "Translator share per second for ip {} is {} shares/s",
                     addr, share_per_second
                 );
-                let initial_difficulty = initial_hash_rate / (share_per_second * 2f32.powf(32.0));
                 let initial_difficulty =
-                    crate::translator::downstream::diff_management::nearest_power_of_10(
-                        initial_difficulty,
-                    );
+                    crate::translator::downstream::diff_management::initial_difficulty(&profile);
                 info!(
                     "Translator initial difficulty for ip {} is {}",
                     addr, initial_difficulty
                 );
-                // Formula: expected_hash_rate = (shares_per_second) * initial_difficulty * 2^32, where shares_per_second = SHARE_PER_MIN / 60
-                let expected_hash_rate =
-                    (*crate::SHARE_PER_MIN / 60.0) * initial_difficulty * 2f32.powf(32.0);
+                // Formula: expected_hash_rate = (shares_per_second) * initial_difficulty * 2^32, where shares_per_second = share_per_min / 60
+                let expected_hash_rate = share_per_second * initial_difficulty * 2f32.powf(32.0);
                 info!(
                     "Translator expected hash rate for ip {} is {} H/s",
                     addr, expected_hash_rate
                 );
 
+                // If the upstream is being replaced the miner waits here for the new `Bridge`
+                let (bridge, tx_sv1_submit) = match sessions.wait_for_bridge().await {
+                    Ok(bridge) => bridge,
+                    Err(e) => {
+                        error!("{e}");
+                        break;
+                    }
+                };
                 match Bridge::ready(&bridge).await {
                     Ok(_) => {
                         debug!("Bridge is ready, proceeding with connection");
@@ -81,26 +78,42 @@ pub async fn start_accept_connection(
 
                 match open_sv1_downstream {
                     Ok(opened) => {
+                        let connection_id = match sessions.next_connection_id() {
+                            Ok(id) => id,
+                            Err(e) => {
+                                error!("{e}");
+                                break;
+                            }
+                        };
                         info!(
-                            "Translator opening connection for ip {} with id {}",
-                            addr, opened.channel_id
+                            "Translator opening connection for ip {} with id {} on channel {}",
+                            addr, connection_id, opened.channel_id
                         );
-                        Downstream::new_downstream(
+                        let downstream = Downstream::new_downstream(
+                            connection_id,
                             opened.channel_id,
-                            tx_sv1_submit.clone(),
+                            opened.upstream_channel_id,
+                            tx_sv1_submit,
                             tx_mining_notify.subscribe(),
                             opened.extranonce,
                             opened.last_notify,
                             opened.extranonce2_len as usize,
                             addr.to_string(),
-                            upstream_difficulty_config.clone(),
+                            sessions.clone(),
                             send,
                             recv,
                             task_manager.clone(),
                             initial_difficulty,
+                            profile.clone(),
                             stats_sender.clone(),
                         )
-                        .await
+                        .await;
+                        if let Err(e) = sessions
+                            .add_downstream(connection_id, downstream, &bridge)
+                            .await
+                        {
+                            error!("Failed to register downstream {}: {e}", connection_id);
+                        }
                     }
                     Err(e) => {
                         error!("{e:?}");
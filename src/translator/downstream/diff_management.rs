@@ -1,17 +1,23 @@
 use super::{Downstream, DownstreamMessages, SetDownstreamTarget};
 use pid::Pid;
 use roles_logic_sv2::{self, utils::from_u128_to_u256};
+use serde::{Deserialize, Serialize};
 use sv1_api::{self, methods::server_to_client::SetDifficulty};
 
-use super::super::error::{Error, ProxyResult};
+use super::super::{
+    error::{Error, ProxyResult},
+    upstream::diff_management::UpstreamDifficultyConfig,
+};
+use crate::{config::PortProfile, shared::difficulty_hint::DifficultyHint};
 use primitive_types::U256;
 use roles_logic_sv2::utils::Mutex;
+use std::collections::VecDeque;
 use std::ops::{Div, Mul};
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use sv1_api::json_rpc;
 
-use tracing::{error, info};
+use tracing::info;
 
 impl Downstream {
     /// Initializes difficult managment.
@@ -50,13 +56,60 @@ impl Downstream {
             }
         });
         stats_sender.update_diff(connection_id, diff);
-        stats_sender.update_hashrate(connection_id, estimated_hashrate);
+        stats_sender.update_estimated_hashrate(connection_id, estimated_hashrate);
         let downstream = self_.clone();
         tokio::spawn(crate::translator::utils::check_share_rate_limit(downstream));
 
         Ok(())
     }
 
+    /// Restarts the difficulty management of the miner from a difficulty hint, bounded by its
+    /// port profile. A miner that is already authorized is sent the new difficulty right away.
+    pub(super) async fn apply_difficulty_hint(
+        self_: &Arc<Mutex<Self>>,
+        hint: DifficultyHint,
+    ) -> ProxyResult<'static, ()> {
+        let (difficulty, share_per_min, is_authorized, channel_id, connection_id) = self_
+            .safe_lock(|d| {
+                let share_per_min = d.profile.share_per_min;
+                let difficulty = d.profile.clamp_difficulty(
+                    d.profile
+                        .vardiff
+                        .fixed_difficulty()
+                        .unwrap_or_else(|| hint.difficulty(share_per_min)),
+                );
+                d.difficulty_mgmt.initial_difficulty = difficulty;
+                (
+                    difficulty,
+                    share_per_min,
+                    !d.authorized_names.is_empty(),
+                    d.channel_id,
+                    d.connection_id,
+                )
+            })?;
+        info!(
+            "Downstream {} starts at difficulty {} from {:?}",
+            connection_id, difficulty, hint
+        );
+        let new_estimation = Self::estimate_hash_rate_from_difficulty(difficulty, share_per_min);
+        Self::update_self_with_new_hash_rate(self_, new_estimation, difficulty)?;
+        if is_authorized {
+            Self::update_diff_setting(self_, channel_id, difficulty.into()).await?;
+        } else {
+            // The miner gets it with its first job
+            let (_, target) = diff_to_sv1_message(difficulty.into())?;
+            Downstream::send_message_upstream(
+                self_,
+                DownstreamMessages::SetDownstreamTarget(SetDownstreamTarget {
+                    channel_id,
+                    new_target: target.into(),
+                }),
+            )
+            .await;
+        }
+        Ok(())
+    }
+
     /// Called before a miner disconnects so we can remove the miner's hashrate from the
     /// aggregated channel hashrate.
     pub fn remove_downstream_hashrate_from_channel(
@@ -80,6 +133,26 @@ impl Downstream {
         Ok(())
     }
 
+    /// Moves the hashrate of the downstream to the upstream channel of `difficulty_config`, when
+    /// the downstream is moved to another channel.
+    pub fn move_hashrate_to_channel(
+        self_: &Arc<Mutex<Self>>,
+        difficulty_config: &Arc<Mutex<UpstreamDifficultyConfig>>,
+    ) -> ProxyResult<'static, ()> {
+        let current = self_.safe_lock(|d| d.upstream_difficulty_config.clone())?;
+        if Arc::ptr_eq(&current, difficulty_config) {
+            return Ok(());
+        }
+        Self::remove_downstream_hashrate_from_channel(self_)?;
+        let estimated_downstream_hash_rate = self_.safe_lock(|d| {
+            d.upstream_difficulty_config = difficulty_config.clone();
+            d.difficulty_mgmt.estimated_downstream_hash_rate
+        })?;
+        difficulty_config
+            .safe_lock(|c| c.channel_nominal_hashrate += estimated_downstream_hash_rate)?;
+        Ok(())
+    }
+
     /// Checks the downstream's difficulty based on recent share submissions. And if is worth an update, update the
     /// downstream and the bridge.
     pub async fn try_update_difficulty_settings(
@@ -87,7 +160,7 @@ impl Downstream {
     ) -> ProxyResult<'static, ()> {
         let channel_id = self_
             .clone()
-            .safe_lock(|d| (d.connection_id))
+            .safe_lock(|d| (d.channel_id))
             .map_err(|_e| Error::TranslatorDiffConfigMutexPoisoned)?;
 
         if let Some(new_diff) = Self::update_difficulty_and_hashrate(self_)? {
@@ -134,7 +207,8 @@ impl Downstream {
     /// Increments the number of shares since the last difficulty update.
     pub(super) fn save_share(self_: Arc<Mutex<Self>>) -> ProxyResult<'static, ()> {
         self_.safe_lock(|d| {
-            d.difficulty_mgmt.on_new_valid_share();
+            let now = Instant::now();
+            d.difficulty_mgmt.on_new_valid_share(now);
         })?;
         Ok(())
     }
@@ -171,9 +245,8 @@ impl Downstream {
         target
     }
 
-    /// 1. Calculates the realized share rate since the last update.
-    /// 2. Adjusts difficulty using a PID controller, with aggressive tuning for zero-share cases over 5 secs.
-    /// 3. Estimates a new hash rate and updates the miner’s state if a change is needed.
+    /// Asks the vardiff algorithm of the miner for a new difficulty and, if it changed, estimates
+    /// a new hash rate and updates the miner’s state.
     ///
     /// Returns `Some(new_difficulty)` if updated, or `None` if no update is needed.
     pub fn update_difficulty_and_hashrate(
@@ -183,75 +256,30 @@ impl Downstream {
             .duration_since(std::time::UNIX_EPOCH)
             .expect("time went backwards")
             .as_millis();
+        let now = Instant::now();
 
-        let (mut difficulty_mgmt, _last_call) =
-            self_.safe_lock(|d| (d.difficulty_mgmt.clone(), d.last_call_to_update_hr))?;
-
-        self_.safe_lock(|d| d.last_call_to_update_hr = timestamp_millis)?;
-
-        let realized_share_per_min = match difficulty_mgmt.share_count() {
-            Some(value) => value,
-            // we need at least 2 seconds of data
-            None => return Ok(None),
-        };
-
-        if realized_share_per_min.is_sign_negative() {
-            error!("realized_share_per_min should not be negative");
-            return Err(Error::Unrecoverable);
-        }
-        if realized_share_per_min.is_nan() {
-            error!("realized_share_per_min should not be nan");
-            return Err(Error::Unrecoverable);
-        }
-
-        let (mut pid, latest_difficulty, initial_difficulty) = self_.safe_lock(|d| {
+        let (new_difficulty, profile) = self_.safe_lock(|d| {
+            d.last_call_to_update_hr = timestamp_millis;
             (
-                d.difficulty_mgmt.pid_controller,
-                d.difficulty_mgmt
-                    .current_difficulties
-                    .back()
-                    .copied()
-                    .unwrap_or(d.difficulty_mgmt.initial_difficulty),
-                d.difficulty_mgmt.initial_difficulty,
+                d.difficulty_mgmt.retarget(now, &d.profile),
+                d.profile.clone(),
             )
         })?;
 
-        let pid_output = pid.next_control_output(realized_share_per_min).output;
-        let new_difficulty = (latest_difficulty + pid_output).max(initial_difficulty * 0.1);
-        let nearest = nearest_power_of_10(new_difficulty);
-        if nearest != initial_difficulty {
-            let mut pid: Pid<f32> = Pid::new(*crate::SHARE_PER_MIN, nearest * 10.0);
-            let pk = -nearest * 0.01;
-            //let pi = initial_difficulty * 0.1;
-            //let pd = initial_difficulty * 0.01;
-            pid.p(pk, f32::MAX).i(0.0, f32::MAX).d(0.0, f32::MAX);
-            self_.safe_lock(|d| {
-                d.difficulty_mgmt.initial_difficulty = nearest;
-                d.difficulty_mgmt.pid_controller = pid;
-            })?;
-
-            let new_estimation =
-                Self::estimate_hash_rate_from_difficulty(nearest, *crate::SHARE_PER_MIN);
-            Self::update_self_with_new_hash_rate(self_, new_estimation, nearest)?;
-            Ok(Some(nearest))
-        } else {
-            // TODO check if we can improve stale share with a threshold here
-            let threshold = 0.0;
-            let change = (new_difficulty - latest_difficulty).abs() / latest_difficulty;
-            if change > threshold {
+        match new_difficulty {
+            Some(new_difficulty) => {
                 let new_estimation =
-                    Self::estimate_hash_rate_from_difficulty(new_difficulty, *crate::SHARE_PER_MIN);
+                    Self::estimate_hash_rate_from_difficulty(new_difficulty, profile.share_per_min);
                 Self::update_self_with_new_hash_rate(self_, new_estimation, new_difficulty)?;
                 Ok(Some(new_difficulty))
-            } else {
-                Ok(None)
             }
+            None => Ok(None),
         }
     }
 
     /// Estimates a miner's hash rate from its difficulty and share submission rate.
     /// Uses the formula: hash_rate = shares_per_second * difficulty * 2^32.
-    fn estimate_hash_rate_from_difficulty(difficulty: f32, share_per_min: f32) -> f32 {
+    pub(super) fn estimate_hash_rate_from_difficulty(difficulty: f32, share_per_min: f32) -> f32 {
         let share_per_second = share_per_min / 60.0;
         share_per_second * difficulty * 2f32.powi(32)
     }
@@ -266,8 +294,9 @@ impl Downstream {
             .safe_lock(|d| {
                 let old_estimation = d.difficulty_mgmt.estimated_downstream_hash_rate;
                 d.difficulty_mgmt.estimated_downstream_hash_rate = new_estimation;
-                d.difficulty_mgmt.reset();
-                d.difficulty_mgmt.add_difficulty(current_diff);
+                let now = Instant::now();
+                d.difficulty_mgmt.on_new_difficulty(now, current_diff);
+                d.health.borrow_mut().on_new_difficulty(now);
 
                 (
                     d.upstream_difficulty_config.clone(),
@@ -276,7 +305,7 @@ impl Downstream {
                     d.stats_sender.clone(),
                 )
             })?;
-        stats_sender.update_hashrate(connection_id, new_estimation);
+        stats_sender.update_estimated_hashrate(connection_id, new_estimation);
         stats_sender.update_diff(connection_id, current_diff);
         let hash_rate_delta = new_estimation - old_estimation;
         upstream_difficulty_config.safe_lock(|c| {
@@ -292,13 +321,42 @@ impl Downstream {
 
 // Converts difficulty to SV1 `SetDifficulty` message and corresponding target.
 /// Returns JSON-RPC message and the target.
-fn diff_to_sv1_message(diff: f64) -> ProxyResult<'static, (json_rpc::Message, [u8; 32])> {
+pub(super) fn diff_to_sv1_message(
+    diff: f64,
+) -> ProxyResult<'static, (json_rpc::Message, [u8; 32])> {
     let set_difficulty = SetDifficulty { value: diff };
     let message: json_rpc::Message = set_difficulty.into();
     let target = Downstream::difficulty_to_target(diff as f32);
     Ok((message, target))
 }
 
+/// Difficulty of a SV2 target, the bytes of SV2 targets are little endian.
+pub(super) fn sv2_target_to_difficulty(target: &[u8]) -> f32 {
+    let target = target
+        .iter()
+        .rev()
+        .fold(0.0, |target, byte| target * 256.0 + *byte as f64);
+    if target == 0.0 {
+        return f32::MAX;
+    }
+    // Max target of difficulty 1
+    (0xffff as f64 * 2f64.powi(208) / target) as f32
+}
+
+/// Starting difficulty of the miners of `profile`: the power of 10 closest to the one at which
+/// the expected hashrate sends the shares per minute of the profile, or the fixed difficulty.
+/// The formula is difficulty = hash_rate / (shares_per_second * 2^32).
+pub fn initial_difficulty(profile: &PortProfile) -> f32 {
+    let share_per_second = profile.share_per_min / 60.0;
+    let difficulty = profile.expected_hashrate / (share_per_second * 2f32.powf(32.0));
+    profile.clamp_difficulty(
+        profile
+            .vardiff
+            .fixed_difficulty()
+            .unwrap_or_else(|| nearest_power_of_10(difficulty)),
+    )
+}
+
 pub fn nearest_power_of_10(x: f32) -> f32 {
     if x <= 0.0 {
         return 0.001;
@@ -307,12 +365,449 @@ pub fn nearest_power_of_10(x: f32) -> f32 {
     10f32.powi(exponent)
 }
 
+/// Retunes the difficulty of a miner from the rate of its valid shares. The algorithm of every
+/// port is selected by the `vardiff` section of its profile.
+pub trait Vardiff: std::fmt::Debug + Send {
+    /// Called for every valid share of the miner.
+    fn on_share(&mut self, now: Instant);
+
+    /// Returns the difficulty the miner should switch to, if any. `difficulty` is the current
+    /// one.
+    fn retarget(&mut self, now: Instant, difficulty: f32) -> Option<f32>;
+
+    /// Called when the miner switches to `difficulty`, after a retarget or a difficulty hint.
+    fn on_new_difficulty(&mut self, now: Instant, difficulty: f32);
+
+    fn clone_box(&self) -> Box<dyn Vardiff>;
+}
+
+impl Clone for Box<dyn Vardiff> {
+    fn clone(&self) -> Self {
+        self.clone_box()
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum VardiffKind {
+    #[default]
+    Pid,
+    Ema,
+    Retarget,
+    Fixed,
+}
+
+impl std::str::FromStr for VardiffKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "pid" => Ok(Self::Pid),
+            "ema" => Ok(Self::Ema),
+            "retarget" | "window" => Ok(Self::Retarget),
+            "fixed" => Ok(Self::Fixed),
+            _ => Err(format!(
+                "Invalid vardiff algorithm '{}'. Expected 'pid', 'ema', 'retarget' or 'fixed'",
+                s
+            )),
+        }
+    }
+}
+
+/// `[vardiff]` section of the config file, also accepted by every listener.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct VardiffConfig {
+    pub algorithm: VardiffKind,
+    /// Proportional gain of `pid`, relative to the difficulty.
+    pub pid_gain: f32,
+    /// Longest span of shares looked at by `pid` and `retarget`, and time constant of the `ema`
+    /// average, in seconds.
+    pub window_secs: u64,
+    /// Shortest span of shares `pid` and `retarget` need before retargeting, in seconds.
+    pub min_window_secs: u64,
+    /// Relative distance from the share rate of the profile tolerated by `ema` and `retarget`.
+    pub tolerance: f32,
+    /// Largest factor by which a single retarget of `ema` and `retarget` changes the difficulty.
+    pub max_step: f32,
+    /// Minimum time between two retargets, in seconds.
+    pub cooldown_secs: u64,
+    /// Difficulty of `fixed`. When unset miners keep their starting difficulty.
+    pub difficulty: Option<f32>,
+}
+
+impl Default for VardiffConfig {
+    fn default() -> Self {
+        Self {
+            algorithm: VardiffKind::default(),
+            pid_gain: 0.01,
+            window_secs: 60,
+            min_window_secs: 20,
+            tolerance: 0.3,
+            max_step: 4.0,
+            cooldown_secs: 0,
+            difficulty: None,
+        }
+    }
+}
+
+impl VardiffConfig {
+    /// Difficulty every miner is kept at, if any.
+    pub fn fixed_difficulty(&self) -> Option<f32> {
+        match self.algorithm {
+            VardiffKind::Fixed => self.difficulty,
+            _ => None,
+        }
+    }
+}
+
+/// Builds the vardiff algorithm of `profile` for a miner starting at `difficulty`.
+pub fn new_vardiff(profile: &Arc<PortProfile>, difficulty: f32) -> Box<dyn Vardiff> {
+    let config = &profile.vardiff;
+    let now = Instant::now();
+    let window = ShareWindow::new(
+        now,
+        Duration::from_secs(config.min_window_secs),
+        Duration::from_secs(config.window_secs),
+    );
+    let cooldown = Duration::from_secs(config.cooldown_secs);
+    match config.algorithm {
+        VardiffKind::Pid => Box::new(PidVardiff::new(
+            profile.clone(),
+            config.pid_gain,
+            window,
+            cooldown,
+            difficulty,
+        )),
+        VardiffKind::Ema => Box::new(EmaVardiff {
+            profile: profile.clone(),
+            time_constant: Duration::from_secs(config.window_secs),
+            tolerance: config.tolerance,
+            max_step: config.max_step,
+            cooldown,
+            rate: profile.share_per_min,
+            shares: 0,
+            last_update: now,
+            last_change: now,
+            difficulty,
+        }),
+        VardiffKind::Retarget => Box::new(RetargetVardiff {
+            profile: profile.clone(),
+            window,
+            tolerance: config.tolerance,
+            max_step: config.max_step,
+            cooldown,
+        }),
+        VardiffKind::Fixed => Box::new(FixedVardiff {
+            difficulty: config.difficulty,
+        }),
+    }
+}
+
+/// Valid shares of a miner since its last difficulty change, up to `max` old.
+#[derive(Debug, Clone)]
+struct ShareWindow {
+    submits: VecDeque<Instant>,
+    /// Last difficulty change.
+    since: Instant,
+    min: Duration,
+    max: Duration,
+}
+
+impl ShareWindow {
+    fn new(now: Instant, min: Duration, max: Duration) -> Self {
+        Self {
+            submits: VecDeque::new(),
+            since: now,
+            min,
+            max,
+        }
+    }
+
+    fn push(&mut self, now: Instant) {
+        self.submits.push_back(now);
+    }
+
+    fn restart(&mut self, now: Instant) {
+        self.submits.clear();
+        self.since = now;
+    }
+
+    fn since_restart(&self, now: Instant) -> Duration {
+        now.saturating_duration_since(self.since)
+    }
+
+    fn prune(&mut self, now: Instant) {
+        while let Some(oldest) = self.submits.front() {
+            if now.saturating_duration_since(*oldest) >= self.max {
+                self.submits.pop_front();
+            } else {
+                break;
+            }
+        }
+    }
+
+    /// Shares per minute from the oldest share in the window, `None` while it spans less than
+    /// `min`. A window without shares is 0 right away.
+    fn share_per_min_from_oldest(&mut self, now: Instant) -> Option<f32> {
+        self.prune(now);
+        let Some(oldest) = self.submits.front() else {
+            return Some(0.0);
+        };
+        let elapsed = now.saturating_duration_since(*oldest);
+        if elapsed < self.min {
+            return None;
+        }
+        Some(self.submits.len() as f32 / (elapsed.as_secs_f32() / 60.0))
+    }
+
+    /// Shares per minute since the last difficulty change, `None` while that is less than `min`
+    /// ago.
+    fn share_per_min_since_restart(&mut self, now: Instant) -> Option<f32> {
+        self.prune(now);
+        let elapsed = self.since_restart(now).min(self.max);
+        if elapsed < self.min || elapsed.is_zero() {
+            return None;
+        }
+        Some(self.submits.len() as f32 / (elapsed.as_secs_f32() / 60.0))
+    }
+}
+
+/// Moves `difficulty` towards `wanted` by at most a factor of `max_step`, within the bounds of
+/// the profile.
+fn bounded_step(profile: &PortProfile, difficulty: f32, wanted: f32, max_step: f32) -> Option<f32> {
+    let max_step = max_step.max(1.0);
+    let wanted = wanted.clamp(difficulty / max_step, difficulty * max_step);
+    Some(profile.clamp_difficulty(wanted)).filter(|d| *d != difficulty)
+}
+
+/// Proportional controller on the share rate. The difficulty snaps to the nearest power of 10
+/// when it moves away from it, and the controller is then rebuilt around the new value.
+#[derive(Debug, Clone)]
+pub struct PidVardiff {
+    profile: Arc<PortProfile>,
+    gain: f32,
+    window: ShareWindow,
+    cooldown: Duration,
+    /// Power of 10 the controller is tuned for.
+    base: f32,
+    pid: Pid<f32>,
+}
+
+impl PidVardiff {
+    fn new(
+        profile: Arc<PortProfile>,
+        gain: f32,
+        window: ShareWindow,
+        cooldown: Duration,
+        difficulty: f32,
+    ) -> Self {
+        let base = profile.clamp_difficulty(nearest_power_of_10(difficulty));
+        let mut vardiff = Self {
+            pid: Pid::new(profile.share_per_min, base * 10.0),
+            profile,
+            gain,
+            window,
+            cooldown,
+            base,
+        };
+        vardiff.rebase(base);
+        vardiff
+    }
+
+    // The controller uses a negative proportional gain: a share rate under the target of the
+    // profile means that the difficulty is too high and must go down.
+    fn rebase(&mut self, base: f32) {
+        let mut pid: Pid<f32> = Pid::new(self.profile.share_per_min, base * 10.0);
+        pid.p(-base * self.gain, f32::MAX)
+            .i(0.0, f32::MAX)
+            .d(0.0, f32::MAX);
+        self.base = base;
+        self.pid = pid;
+    }
+}
+
+impl Vardiff for PidVardiff {
+    fn on_share(&mut self, now: Instant) {
+        self.window.push(now);
+    }
+
+    fn retarget(&mut self, now: Instant, difficulty: f32) -> Option<f32> {
+        if self.window.since_restart(now) < self.cooldown {
+            return None;
+        }
+        let realized_share_per_min = self.window.share_per_min_from_oldest(now)?;
+        let output = self.pid.next_control_output(realized_share_per_min).output;
+        let new_difficulty = self
+            .profile
+            .clamp_difficulty((difficulty + output).max(self.base * 0.1));
+        let nearest = self
+            .profile
+            .clamp_difficulty(nearest_power_of_10(new_difficulty));
+        if nearest != self.base {
+            Some(nearest)
+        } else {
+            Some(new_difficulty).filter(|d| *d != difficulty)
+        }
+    }
+
+    fn on_new_difficulty(&mut self, now: Instant, difficulty: f32) {
+        self.window.restart(now);
+        let nearest = self
+            .profile
+            .clamp_difficulty(nearest_power_of_10(difficulty));
+        if nearest != self.base {
+            self.rebase(nearest);
+        }
+    }
+
+    fn clone_box(&self) -> Box<dyn Vardiff> {
+        Box::new(self.clone())
+    }
+}
+
+/// Exponential moving average of the share rate, as used by ckpool-like SV1 pools. The
+/// difficulty is scaled by the ratio between the average and the target rate once they are
+/// further apart than `tolerance`.
+#[derive(Debug, Clone)]
+pub struct EmaVardiff {
+    profile: Arc<PortProfile>,
+    time_constant: Duration,
+    tolerance: f32,
+    max_step: f32,
+    cooldown: Duration,
+    /// Average shares per minute at `difficulty`.
+    rate: f32,
+    /// Shares since `last_update`.
+    shares: u32,
+    last_update: Instant,
+    last_change: Instant,
+    difficulty: f32,
+}
+
+impl EmaVardiff {
+    fn update(&mut self, now: Instant) {
+        let elapsed = now
+            .saturating_duration_since(self.last_update)
+            .as_secs_f32();
+        if elapsed <= 0.0 {
+            return;
+        }
+        let sample = self.shares as f32 * 60.0 / elapsed;
+        let weight = 1.0 - (-elapsed / self.time_constant.as_secs_f32().max(1.0)).exp();
+        self.rate += weight * (sample - self.rate);
+        self.shares = 0;
+        self.last_update = now;
+    }
+}
+
+impl Vardiff for EmaVardiff {
+    fn on_share(&mut self, _now: Instant) {
+        self.shares += 1;
+    }
+
+    fn retarget(&mut self, now: Instant, difficulty: f32) -> Option<f32> {
+        self.update(now);
+        if now.saturating_duration_since(self.last_change) < self.cooldown {
+            return None;
+        }
+        let target = self.profile.share_per_min;
+        if (self.rate - target).abs() <= target * self.tolerance {
+            return None;
+        }
+        bounded_step(
+            &self.profile,
+            difficulty,
+            difficulty * self.rate / target,
+            self.max_step,
+        )
+    }
+
+    fn on_new_difficulty(&mut self, now: Instant, difficulty: f32) {
+        self.update(now);
+        // The miner finds shares at a rate inversely proportional to the difficulty
+        if self.difficulty > 0.0 && difficulty > 0.0 {
+            self.rate *= self.difficulty / difficulty;
+        }
+        self.difficulty = difficulty;
+        self.last_change = now;
+    }
+
+    fn clone_box(&self) -> Box<dyn Vardiff> {
+        Box::new(self.clone())
+    }
+}
+
+/// Retarget window of the classic SV1 pools: once `min` has passed since the last change, the
+/// share rate since then is compared with the target and the difficulty is scaled when they are
+/// further apart than `tolerance`.
+#[derive(Debug, Clone)]
+pub struct RetargetVardiff {
+    profile: Arc<PortProfile>,
+    window: ShareWindow,
+    tolerance: f32,
+    max_step: f32,
+    cooldown: Duration,
+}
+
+impl Vardiff for RetargetVardiff {
+    fn on_share(&mut self, now: Instant) {
+        self.window.push(now);
+    }
+
+    fn retarget(&mut self, now: Instant, difficulty: f32) -> Option<f32> {
+        if self.window.since_restart(now) < self.cooldown {
+            return None;
+        }
+        let rate = self.window.share_per_min_since_restart(now)?;
+        let target = self.profile.share_per_min;
+        if (rate - target).abs() <= target * self.tolerance {
+            return None;
+        }
+        bounded_step(
+            &self.profile,
+            difficulty,
+            difficulty * rate / target,
+            self.max_step,
+        )
+    }
+
+    fn on_new_difficulty(&mut self, now: Instant, _difficulty: f32) {
+        self.window.restart(now);
+    }
+
+    fn clone_box(&self) -> Box<dyn Vardiff> {
+        Box::new(self.clone())
+    }
+}
+
+/// Keeps every miner at the configured difficulty, or at its starting one.
+#[derive(Debug, Clone)]
+pub struct FixedVardiff {
+    difficulty: Option<f32>,
+}
+
+impl Vardiff for FixedVardiff {
+    fn on_share(&mut self, _now: Instant) {}
+
+    fn retarget(&mut self, _now: Instant, difficulty: f32) -> Option<f32> {
+        self.difficulty.filter(|d| *d != difficulty)
+    }
+
+    fn on_new_difficulty(&mut self, _now: Instant, _difficulty: f32) {}
+
+    fn clone_box(&self) -> Box<dyn Vardiff> {
+        Box::new(self.clone())
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::super::super::upstream::diff_management::UpstreamDifficultyConfig;
+    use super::{new_vardiff, Vardiff, VardiffConfig, VardiffKind};
+    use crate::config::PortProfile;
     use crate::translator::downstream::{downstream::DownstreamDifficultyConfig, Downstream};
     use binary_sv2::U256;
-    use pid::Pid;
     use rand::{thread_rng, Rng};
     use roles_logic_sv2::{mining_sv2::Target, utils::Mutex};
     use sha2::{Digest, Sha256};
@@ -433,14 +928,14 @@ mod test {
         diff.push_back(10_000_000_000.0);
         let downstream_conf = DownstreamDifficultyConfig {
             estimated_downstream_hash_rate: 0.0, // updated below
-            pid_controller: Pid::new(10.0, 100_000_000.0),
+            vardiff: new_vardiff(&profile(VardiffConfig::default()), 10_000_000_000.0),
             current_difficulties: diff,
-            submits: VecDeque::new(),
             initial_difficulty: 10_000_000_000.0,
         };
         let upstream_config = UpstreamDifficultyConfig {
             channel_diff_update_interval: 60,
             channel_nominal_hashrate: 0.0,
+            hashrate: crate::shared::hashrate::HashrateMeter::default(),
         };
         let (tx_sv1_submit, _rx_sv1_submit) = tokio::sync::mpsc::channel(10);
         let (tx_outgoing, _rx_outgoing) = channel(10);
@@ -509,6 +1004,152 @@ mod test {
         }
         ret
     }
+
+    #[test]
+    fn difficulty_of_sv2_target() {
+        for difficulty in [0.5, 1.0, 512.0, 1e6] {
+            let mut target = Downstream::difficulty_to_target(difficulty);
+            target.reverse();
+            let measured = sv2_target_to_difficulty(&target);
+            assert!((measured - difficulty).abs() / difficulty < 0.001);
+        }
+    }
+
+    fn profile(vardiff: VardiffConfig) -> Arc<PortProfile> {
+        Arc::new(PortProfile {
+            expected_hashrate: 0.0,
+            share_per_min: 10.0,
+            min_difficulty: None,
+            max_difficulty: None,
+            worker_prefix: None,
+            vardiff,
+            idle_timeout: None,
+        })
+    }
+
+    fn algorithm(algorithm: VardiffKind) -> VardiffConfig {
+        VardiffConfig {
+            algorithm,
+            ..Default::default()
+        }
+    }
+
+    /// Difficulty at which a miner of `hashrate` h/s sends the 10 shares per minute of `profile`.
+    fn ideal_difficulty(hashrate: f32) -> f32 {
+        hashrate * 60.0 / (10.0 * 2f32.powi(32))
+    }
+
+    /// A miner of `hashrate` h/s finds a share every `difficulty * 2^32 / hashrate` seconds, the
+    /// vardiff is asked for a retarget every `tick` seconds for `secs` seconds. Returns the time
+    /// and the value of every difficulty change.
+    fn simulate(
+        vardiff: &mut dyn Vardiff,
+        hashrate: f32,
+        mut difficulty: f32,
+        tick: u64,
+        secs: u64,
+    ) -> Vec<(u64, f32)> {
+        let start = Instant::now();
+        let mut changes = vec![];
+        let mut interval = difficulty * 2f32.powi(32) / hashrate;
+        let mut next_share = interval;
+        for t in (tick..=secs).step_by(tick as usize) {
+            while next_share <= t as f32 {
+                vardiff.on_share(start + Duration::from_secs_f32(next_share));
+                next_share += interval;
+            }
+            let now = start + Duration::from_secs(t);
+            if let Some(new_difficulty) = vardiff.retarget(now, difficulty) {
+                difficulty = new_difficulty;
+                vardiff.on_new_difficulty(now, difficulty);
+                changes.push((t, difficulty));
+                interval = difficulty * 2f32.powi(32) / hashrate;
+                next_share = t as f32 + interval;
+            }
+        }
+        changes
+    }
+
+    #[test]
+    fn vardiff_converges_to_share_rate() {
+        let hashrate = 100e12;
+        let ideal = ideal_difficulty(hashrate);
+        for kind in [VardiffKind::Pid, VardiffKind::Ema, VardiffKind::Retarget] {
+            for start in [ideal / 100.0, ideal * 100.0] {
+                let profile = profile(algorithm(kind));
+                let mut vardiff = new_vardiff(&profile, start);
+                let changes = simulate(vardiff.as_mut(), hashrate, start, 60, 3 * 3600);
+                let (_, last) = *changes.last().expect("the difficulty must change");
+                assert!(
+                    last / ideal > 0.7 && last / ideal < 1.4,
+                    "{:?} from {} ended at {}, expected {}",
+                    kind,
+                    start,
+                    last,
+                    ideal
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn vardiff_settles_within_tolerance() {
+        let hashrate = 100e12;
+        let ideal = ideal_difficulty(hashrate);
+        for kind in [VardiffKind::Ema, VardiffKind::Retarget] {
+            let profile = profile(algorithm(kind));
+            let mut vardiff = new_vardiff(&profile, ideal / 100.0);
+            let changes = simulate(vardiff.as_mut(), hashrate, ideal / 100.0, 60, 3 * 3600);
+            let (at, _) = *changes.last().expect("the difficulty must change");
+            assert!(at <= 3600, "{:?} still retargets after {}s", kind, at);
+
+            let mut vardiff = new_vardiff(&profile, ideal);
+            assert!(simulate(vardiff.as_mut(), hashrate, ideal, 60, 3600).is_empty());
+        }
+    }
+
+    #[test]
+    fn vardiff_cooldown_and_bounds() {
+        let hashrate = 100e12;
+        let ideal = ideal_difficulty(hashrate);
+        let mut profile = profile(VardiffConfig {
+            algorithm: VardiffKind::Retarget,
+            cooldown_secs: 300,
+            max_step: 4.0,
+            ..Default::default()
+        });
+        Arc::get_mut(&mut profile).unwrap().max_difficulty = Some(ideal / 2.0);
+        let start = ideal / 1000.0;
+        let mut vardiff = new_vardiff(&profile, start);
+        let changes = simulate(vardiff.as_mut(), hashrate, start, 10, 3600);
+
+        let mut last = (0, start);
+        for (at, difficulty) in &changes {
+            assert!(at - last.0 >= 300);
+            assert!(difficulty / last.1 <= 4.0);
+            last = (*at, *difficulty);
+        }
+        assert_eq!(last.1, ideal / 2.0);
+    }
+
+    #[test]
+    fn fixed_vardiff() {
+        let hashrate = 100e12;
+        let fixed = profile(VardiffConfig {
+            algorithm: VardiffKind::Fixed,
+            difficulty: Some(512.0),
+            ..Default::default()
+        });
+        let mut vardiff = new_vardiff(&fixed, 1.0);
+        assert_eq!(
+            simulate(vardiff.as_mut(), hashrate, 1.0, 10, 3600),
+            [(10, 512.0)]
+        );
+
+        let mut vardiff = new_vardiff(&profile(algorithm(VardiffKind::Fixed)), 1.0);
+        assert!(simulate(vardiff.as_mut(), hashrate, 1.0, 10, 3600).is_empty());
+    }
+
     // TODO make a test where unknown donwstream is simulated and we do not wait for it to produce
     // a share but we try to updated the estimated hash power every 2 seconds and updated the
     // target consequentially this shuold start to provide shares within a normal amount of time
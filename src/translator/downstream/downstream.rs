@@ -1,27 +1,46 @@
 use crate::{
     api::stats::StatsSender,
+    config::{
+        AckFallback, Configuration, ExtranonceFallback, JobRetentionConfig, PortProfile,
+        ShareAckConfig,
+    },
     monitor::{
         shares::{RejectionReason, ShareInfo, SharesMonitor},
         worker_activity::{WorkerActivity, WorkerActivityType},
     },
     proxy_state::{DownstreamType, ProxyState},
-    shared::utils::AbortOnDrop,
-    translator::{error::Error, utils::validate_share},
+    shared::{
+        difficulty_hint::DifficultyHint,
+        firmware::{self, FirmwareQuirks},
+        version_rolling::{self, VersionRollingError},
+    },
+    translator::{
+        error::Error,
+        proxy::Bridge,
+        utils::{validate_share, ShareRateLimiter, ShareVerdict},
+    },
 };
 
 use super::{
-    super::upstream::diff_management::UpstreamDifficultyConfig, task_manager::TaskManager,
+    super::upstream::{
+        diff_management::UpstreamDifficultyConfig, upstream::VERSION_ROLLING_ALLOWED,
+    },
+    task_manager::TaskManager,
 };
-use pid::Pid;
 use tokio::sync::{
     broadcast,
     mpsc::{channel, Receiver, Sender},
 };
 
 use super::{
-    accept_connection::start_accept_connection, notify::start_notify,
+    diff_management::{diff_to_sv1_message, new_vardiff, Vardiff},
+    health::{MinerHealth, MinerState},
+    notify::start_notify,
     receive_from_downstream::start_receive_downstream,
-    send_to_downstream::start_send_to_downstream, DownstreamMessages, SubmitShareWithChannelId,
+    send_to_downstream::start_send_to_downstream,
+    sessions::DownstreamSessions,
+    validation::{self, MalformedSubmit},
+    ChannelNotify, DownstreamMessages, SetDownstreamTarget, SubmitShareWithChannelId,
 };
 
 use roles_logic_sv2::{
@@ -32,9 +51,9 @@ use roles_logic_sv2::{
 use rand::Rng;
 use server_to_client::Notify;
 use std::{
-    collections::{hash_map::Entry, HashMap, VecDeque},
-    net::IpAddr,
+    collections::{HashMap, HashSet, VecDeque},
     sync::Arc,
+    time::Instant,
 };
 use sv1_api::{
     client_to_server, json_rpc, server_to_client,
@@ -43,39 +62,53 @@ use sv1_api::{
 };
 use tracing::{error, info, warn};
 
+/// How often a miner is checked for being degraded or idle.
+const HEALTH_CHECK_INTERVAL: std::time::Duration = std::time::Duration::from_secs(10);
+
 #[derive(Debug, Clone)]
 pub struct DownstreamDifficultyConfig {
     pub estimated_downstream_hash_rate: f32,
-    pub submits: VecDeque<std::time::Instant>,
-    pub pid_controller: Pid<f32>,
+    /// Algorithm of the port profile that retunes the difficulty.
+    pub vardiff: Box<dyn Vardiff>,
     pub current_difficulties: VecDeque<f32>,
     pub initial_difficulty: f32,
 }
 
 impl DownstreamDifficultyConfig {
-    pub fn share_count(&mut self) -> Option<f32> {
-        let now = std::time::Instant::now();
-        if self.submits.is_empty() {
-            return Some(0.0);
-        }
-        let oldest = self.submits[0];
-        if now - oldest < std::time::Duration::from_secs(20) {
-            return None;
-        }
-        if now - oldest < std::time::Duration::from_secs(60) {
-            let elapsed = now - oldest;
-            Some(self.submits.len() as f32 / (elapsed.as_millis() as f32 / (60.0 * 1000.0)))
-        } else {
-            self.submits.pop_front();
-            self.share_count()
+    /// Difficulty management of a miner of `profile` that starts at `initial_difficulty`.
+    pub fn new(profile: &Arc<PortProfile>, initial_difficulty: f32) -> Self {
+        let mut current_difficulties = VecDeque::with_capacity(3);
+        current_difficulties.push_back(initial_difficulty);
+        Self {
+            estimated_downstream_hash_rate: profile.expected_hashrate,
+            vardiff: new_vardiff(profile, initial_difficulty),
+            current_difficulties,
+            initial_difficulty,
         }
     }
-    pub fn on_new_valid_share(&mut self) {
-        self.submits.push_back(std::time::Instant::now());
+
+    pub fn on_new_valid_share(&mut self, now: std::time::Instant) {
+        self.vardiff.on_share(now);
+    }
+
+    /// Asks the vardiff for a new difficulty within the bounds of `profile`. Returns `None` if
+    /// the difficulty does not change.
+    pub fn retarget(&mut self, now: std::time::Instant, profile: &PortProfile) -> Option<f32> {
+        let latest_difficulty = self
+            .current_difficulties
+            .back()
+            .copied()
+            .unwrap_or(self.initial_difficulty);
+        self.vardiff
+            .retarget(now, latest_difficulty)
+            .map(|difficulty| profile.clamp_difficulty(difficulty))
+            .filter(|difficulty| *difficulty != latest_difficulty)
     }
 
-    pub fn reset(&mut self) {
-        self.submits.clear();
+    /// Records the new difficulty of the miner and restarts the vardiff from it.
+    pub fn on_new_difficulty(&mut self, now: std::time::Instant, new_diff: f32) {
+        self.vardiff.on_new_difficulty(now, new_diff);
+        self.add_difficulty(new_diff);
     }
 
     pub fn add_difficulty(&mut self, new_diff: f32) {
@@ -99,6 +132,15 @@ impl PartialEq for DownstreamDifficultyConfig {
 pub struct Downstream {
     /// List of authorized Downstream Mining Devices.
     pub(super) connection_id: u32,
+    /// Id of the channel opened for this miner in the current `Bridge`'s channel factory. Unlike
+    /// `connection_id` it changes every time the miner is moved to a new upstream.
+    pub(super) channel_id: u32,
+    /// Id of the channel with the pool of the `Bridge` the miner is on, it only takes the jobs of
+    /// that channel.
+    pub(super) upstream_channel_id: u32,
+    /// Set while the upstream is being replaced. Jobs received in the meantime are not sent to
+    /// the miner since they would not match its extranonce.
+    pub(super) waiting_for_upstream: bool,
     pub(super) authorized_names: Vec<String>,
     extranonce1: Vec<u8>,
     /// `extranonce1` to be sent to the Downstream in the SV1 `mining.subscribe` message response.
@@ -114,6 +156,8 @@ pub struct Downstream {
     tx_outgoing: Sender<json_rpc::Message>,
     extranonce2_len: usize,
     pub(super) difficulty_mgmt: DownstreamDifficultyConfig,
+    /// Profile of the port the miner connected on.
+    pub(super) profile: Arc<PortProfile>,
     pub(super) upstream_difficulty_config: Arc<Mutex<UpstreamDifficultyConfig>>,
     pub last_call_to_update_hr: u128,
     pub(super) stats_sender: StatsSender,
@@ -121,6 +165,30 @@ pub struct Downstream {
     pub first_job: Notify<'static>,
     pub share_monitor: SharesMonitor,
     pub user_agent: std::cell::RefCell<String>, // RefCell is used here because `handle_subscribe` and `handle_authorize` take &self not &mut self and we need to mutate user_agent
+    /// Quirks of the miner firmware, set when it subscribes.
+    pub(super) quirks: std::cell::Cell<Option<&'static FirmwareQuirks>>,
+    /// Set when the miner sends `mining.extranonce.subscribe`.
+    pub(super) extranonce_subscribed: std::cell::Cell<bool>,
+    /// Budget of the shares the miner can send upstream.
+    pub(super) share_limiter: std::cell::RefCell<ShareRateLimiter>,
+    /// Why `handle_submit` rejected the last share, reported to the miner with its SV1 error code.
+    pub(super) submit_rejection: std::cell::Cell<Option<RejectionReason>>,
+    /// Why `handle_configure` could not grant the version rolling the miner asked for.
+    pub(super) configure_error: std::cell::Cell<Option<VersionRollingError>>,
+    pub(super) share_ack: ShareAckConfig,
+    /// Share sent by `handle_submit` in strict mode, answered once the pool verdict arrives.
+    pub(super) pending_share: std::cell::RefCell<Option<PendingShare>>,
+    /// Notified to close the connection with the miner.
+    pub(super) disconnect: Arc<tokio::sync::Notify>,
+    /// Why the proxy closed the connection, reported in the `Disconnected` worker activity.
+    pub(super) disconnect_reason: Option<&'static str>,
+    /// The starting difficulty comes from the `[[workers]]` entry of the worker, later
+    /// `mining.suggest_difficulty` of the miner are ignored.
+    pub(super) worker_difficulty: bool,
+    /// Updated by `handle_submit` with the shares that pass validation.
+    pub(super) health: std::cell::RefCell<MinerHealth>,
+    /// Tasks of the miner, `None` for the downstreams built by `Downstream::new`.
+    pub(super) task_manager: Option<Arc<Mutex<TaskManager>>>,
 }
 
 impl Downstream {
@@ -128,65 +196,34 @@ impl Downstream {
     #[allow(clippy::too_many_arguments)]
     pub async fn new_downstream(
         connection_id: u32,
+        channel_id: u32,
+        upstream_channel_id: u32,
         tx_sv1_bridge: Sender<DownstreamMessages>,
-        rx_sv1_notify: broadcast::Receiver<server_to_client::Notify<'static>>,
+        rx_sv1_notify: broadcast::Receiver<ChannelNotify>,
         extranonce1: Vec<u8>,
         last_notify: Option<server_to_client::Notify<'static>>,
         extranonce2_len: usize,
         host: String,
-        upstream_difficulty_config: Arc<Mutex<UpstreamDifficultyConfig>>,
+        sessions: DownstreamSessions,
         send_to_down: Sender<String>,
         recv_from_down: Receiver<String>,
         task_manager: Arc<Mutex<TaskManager>>,
         initial_difficulty: f32,
+        profile: Arc<PortProfile>,
         stats_sender: StatsSender,
-    ) {
+    ) -> Arc<Mutex<Self>> {
         assert!(last_notify.is_some());
+        let upstream_difficulty_config = sessions.upstream_difficulty_config();
 
         let (tx_outgoing, receiver_outgoing) = channel(crate::TRANSLATOR_BUFFER_SIZE);
 
-        // The PID controller uses negative proportional (P) and integral (I) gains to reduce difficulty
-        // when the actual share rate falls below the target rate (SHARE_PER_MIN). Negative gains are chosen
-        // because a lower share rate indicates the difficulty is too high for the miner, requiring a downward
-        // adjustment to make mining easier.
-        //
-        // // Example:
-        // - Target share rate (SHARE_PER_MIN) = 10 shares/min.
-        // - Case 1: Actual share rate = 5 shares/min (less than target):
-        //   - Error = 10 - 5 = 5 (positive).
-        //   - P output = -3.0 * 5 = -15 (reduces difficulty by 15).
-        //   - I output (assuming error persists 5 intervals) = -0.5 * (-15 * 5) = -37.5 (further reduction).
-        //   - Difficulty decreases, making mining easier to increase share rate.
-        //
-        // - Case 2: Actual share rate = 12 shares/min (greater than target):
-        //   - Error = 10 - 12 = -2 (negative).
-        //   - P output = -3.0 * -2 = 6 (increases difficulty by 6).
-        //   - I output (5 intervals) = -0.5 * (-2 * 5) = 5 (further increase).
-        //   - Difficulty increases, slows share rate.
-        //
-        // The positive D gain (0.05) dampens rapid changes, e.g., if share rate jumps from 8 to 12, D might
-        // add a small positive adjustment to prevent overshooting.
-
-        let mut pid: Pid<f32> = Pid::new(*crate::SHARE_PER_MIN, initial_difficulty * 10.0);
-        let pk = -initial_difficulty * 0.01;
-        //let pi = initial_difficulty * 0.1;
-        //let pd = initial_difficulty * 0.01;
-        pid.p(pk, f32::MAX).i(0.0, f32::MAX).d(0.0, f32::MAX);
-
-        let estimated_downstream_hash_rate = *crate::EXPECTED_SV1_HASHPOWER;
-        let mut current_difficulties = VecDeque::with_capacity(3);
-        current_difficulties.push_back(initial_difficulty);
-
-        let difficulty_mgmt = DownstreamDifficultyConfig {
-            estimated_downstream_hash_rate,
-            submits: vec![].into(),
-            pid_controller: pid,
-            current_difficulties,
-            initial_difficulty,
-        };
+        let difficulty_mgmt = DownstreamDifficultyConfig::new(&profile, initial_difficulty);
 
         let downstream = Arc::new(Mutex::new(Downstream {
             connection_id,
+            channel_id,
+            upstream_channel_id,
+            waiting_for_upstream: false,
             authorized_names: vec![],
             extranonce1,
             version_rolling_mask: None,
@@ -195,13 +232,28 @@ impl Downstream {
             tx_outgoing,
             extranonce2_len,
             difficulty_mgmt,
+            profile,
             upstream_difficulty_config,
             last_call_to_update_hr: 0,
             stats_sender,
-            recent_jobs: RecentJobs::new(),
+            recent_jobs: RecentJobs::new(Configuration::job_retention().clone()),
             first_job: last_notify.expect("we have an assertion at the beginning of this function"),
             share_monitor: SharesMonitor::new(),
             user_agent: std::cell::RefCell::new(String::new()),
+            quirks: std::cell::Cell::new(None),
+            extranonce_subscribed: std::cell::Cell::new(false),
+            share_limiter: std::cell::RefCell::new(
+                Configuration::share_rate_limit().miner_limiter(),
+            ),
+            submit_rejection: std::cell::Cell::new(None),
+            configure_error: std::cell::Cell::new(None),
+            share_ack: Configuration::share_ack().clone(),
+            pending_share: std::cell::RefCell::new(None),
+            disconnect: Arc::new(tokio::sync::Notify::new()),
+            disconnect_reason: None,
+            worker_difficulty: false,
+            health: std::cell::RefCell::new(MinerHealth::new(std::time::Instant::now())),
+            task_manager: Some(task_manager.clone()),
         }));
 
         if let Err(e) = start_receive_downstream(
@@ -209,6 +261,7 @@ impl Downstream {
             downstream.clone(),
             recv_from_down,
             connection_id,
+            sessions,
         )
         .await
         {
@@ -246,6 +299,71 @@ impl Downstream {
             error!("Failed to start share monitor task: {e}");
             ProxyState::update_downstream_state(DownstreamType::TranslatorDownstream);
         }
+
+        if let Err(e) =
+            Self::start_health_monitor(task_manager.clone(), downstream.clone(), connection_id)
+                .await
+        {
+            error!("Failed to start health monitor task: {e}");
+            ProxyState::update_downstream_state(DownstreamType::TranslatorDownstream);
+        }
+        downstream
+    }
+
+    /// Starts the task that reports whether the miner is degraded and disconnects it once it is
+    /// idle.
+    async fn start_health_monitor(
+        task_manager: Arc<Mutex<TaskManager>>,
+        downstream: Arc<Mutex<Self>>,
+        connection_id: u32,
+    ) -> Result<(), Error<'static>> {
+        let handle = tokio::spawn(async move {
+            let mut interval = tokio::time::interval(HEALTH_CHECK_INTERVAL);
+            let mut last_state = MinerState::Active;
+            loop {
+                interval.tick().await;
+                let checked = downstream.safe_lock(|d| {
+                    let state = d
+                        .health
+                        .borrow_mut()
+                        .state(std::time::Instant::now(), &d.profile);
+                    if state == MinerState::Idle {
+                        d.disconnect_reason = Some("idle");
+                        d.disconnect.notify_one();
+                    }
+                    (state, d.stats_sender.clone())
+                });
+                let (state, stats_sender) = match checked {
+                    Ok(checked) => checked,
+                    Err(e) => {
+                        error!("{e}");
+                        ProxyState::update_downstream_state(DownstreamType::TranslatorDownstream);
+                        return;
+                    }
+                };
+                if state == last_state {
+                    continue;
+                }
+                match state {
+                    MinerState::Idle => {
+                        warn!("Downstream {} is idle, disconnecting it", connection_id);
+                        return;
+                    }
+                    MinerState::Degraded => warn!(
+                        "Downstream {} sends shares far slower than its difficulty implies",
+                        connection_id
+                    ),
+                    MinerState::Active => {
+                        info!("Downstream {} is sending shares again", connection_id)
+                    }
+                }
+                stats_sender.update_state(connection_id, state);
+                last_state = state;
+            }
+        });
+        TaskManager::add_health_monitor(task_manager, handle.into(), connection_id)
+            .await
+            .map_err(|_| Error::TranslatorTaskManagerFailed)
     }
 
     /// Starts the shares monitor task.
@@ -263,42 +381,158 @@ impl Downstream {
         });
 
         // Register the task with the task manager so it can be aborted when needed
-        TaskManager::add_shares_monitor(task_manager, abortable.into())
+        // Aborted with the other tasks of the miner when it disconnects
+        TaskManager::add_shares_monitor(task_manager, abortable.into(), connection_id)
             .await
             .map_err(|_| Error::TranslatorTaskManagerFailed)
     }
 
-    /// Accept connections from one or more SV1 Downstream roles (SV1 Mining Devices) and create a
-    /// new `Downstream` for each connection.
-    pub async fn accept_connections(
-        tx_sv1_submit: Sender<DownstreamMessages>,
-        tx_mining_notify: broadcast::Sender<server_to_client::Notify<'static>>,
-        bridge: Arc<Mutex<super::super::proxy::Bridge>>,
-        upstream_difficulty_config: Arc<Mutex<UpstreamDifficultyConfig>>,
-        downstreams: Receiver<(Sender<String>, Receiver<String>, IpAddr)>,
-        stats_sender: StatsSender,
-    ) -> Result<AbortOnDrop, Error<'static>> {
-        let task_manager = TaskManager::initialize();
-        let abortable = task_manager
-            .safe_lock(|t| t.get_aborter())
-            .map_err(|_| Error::TranslatorTaskManagerMutexPoisoned)?
-            .ok_or(Error::TranslatorTaskManagerFailed)?;
-        if let Err(e) = start_accept_connection(
-            task_manager.clone(),
-            tx_sv1_submit,
-            tx_mining_notify,
-            bridge,
-            upstream_difficulty_config,
-            downstreams,
-            stats_sender,
-        )
-        .await
-        {
-            error!("Translator downstream failed to accept: {e}");
-            ProxyState::update_downstream_state(DownstreamType::TranslatorDownstream);
-            return Err(e);
+    /// Moves the miner to a new upstream, or to a new extranonce prefix of the current one,
+    /// without disconnecting it. A channel is opened in the `Bridge`, then the miner receives the
+    /// new extranonce with `mining.set_extranonce`, its current difficulty and a clean
+    /// `mining.notify`. Until then it keeps hashing on its current job. Miners that did not
+    /// subscribe to `mining.set_extranonce` are dropped as the `extranonce_fallback` config says.
+    pub(super) async fn switch_upstream(
+        self_: &Arc<Mutex<Self>>,
+        bridge: &Arc<Mutex<Bridge>>,
+        tx_sv1_bridge: Sender<DownstreamMessages>,
+    ) -> Result<(), Error<'static>> {
+        let (connection_id, supports_set_extranonce) =
+            self_.safe_lock(|d| (d.connection_id, d.supports_set_extranonce()))?;
+        if !supports_set_extranonce {
+            // It reconnects and gets a channel on the new upstream like any new miner
+            info!(
+                "Downstream {} can not change extranonce, disconnecting it",
+                connection_id
+            );
+            return Self::drop_for_new_extranonce(self_, Configuration::extranonce_fallback())
+                .await;
+        }
+        let hash_rate = self_.safe_lock(|d| d.difficulty_mgmt.estimated_downstream_hash_rate)?;
+        let opened = bridge
+            .safe_lock(|b| b.on_new_sv1_connection(hash_rate))
+            .map_err(|_| Error::BridgeMutexPoisoned)??;
+        let last_notify = opened.last_notify.ok_or(Error::Unrecoverable)?;
+        let extranonce1: Extranonce<'static> = opened
+            .extranonce
+            .clone()
+            .try_into()
+            .map_err(|_| Error::InvalidExtranonce(format!("{:?}", opened.extranonce)))?;
+
+        let (connection_id, is_authorized, difficulty, mask) = self_.safe_lock(|d| {
+            d.channel_id = opened.channel_id;
+            d.upstream_channel_id = opened.upstream_channel_id;
+            d.waiting_for_upstream = false;
+            d.extranonce1 = opened.extranonce.clone();
+            d.extranonce2_len = opened.extranonce2_len as usize;
+            d.tx_sv1_bridge = tx_sv1_bridge;
+            // Jobs of the old upstream can not be submitted to the new one
+            d.recent_jobs = RecentJobs::new(Configuration::job_retention().clone());
+            d.first_job = last_notify.clone();
+            (
+                d.connection_id,
+                !d.authorized_names.is_empty(),
+                d.difficulty_mgmt
+                    .current_difficulties
+                    .back()
+                    .copied()
+                    .unwrap_or(d.difficulty_mgmt.initial_difficulty),
+                d.version_rolling_mask.clone(),
+            )
+        })?;
+        info!(
+            "Downstream {} moved to channel {} of the upstream",
+            connection_id, opened.channel_id
+        );
+
+        let set_extranonce = server_to_client::SetExtranonce {
+            extra_nonce1: extranonce1,
+            extra_nonce2_size: opened.extranonce2_len as usize,
         };
-        Ok(abortable)
+        Self::send_message_downstream(self_.clone(), set_extranonce.into()).await;
+
+        // A miner that is not authorized yet gets difficulty and first job from the notify task
+        if is_authorized {
+            let (set_difficulty, target) = diff_to_sv1_message(difficulty as f64)?;
+            Self::send_message_downstream(self_.clone(), set_difficulty).await;
+            Self::send_message_upstream(
+                self_,
+                DownstreamMessages::SetDownstreamTarget(SetDownstreamTarget {
+                    channel_id: opened.channel_id,
+                    new_target: target.into(),
+                }),
+            )
+            .await;
+
+            let mut notify = last_notify;
+            notify.clean_jobs = true;
+            self_.safe_lock(|d| d.recent_jobs.add_job(&mut notify, mask))?;
+            Self::send_message_downstream(self_.clone(), notify.into()).await;
+        }
+        Ok(())
+    }
+
+    /// Closes the connection of a miner that can not be sent its new extranonce, after telling it
+    /// to reconnect with `client.reconnect` if `fallback` says so. The messages already queued for
+    /// the miner are still written.
+    pub(super) async fn drop_for_new_extranonce(
+        self_: &Arc<Mutex<Self>>,
+        fallback: ExtranonceFallback,
+    ) -> Result<(), Error<'static>> {
+        if fallback == ExtranonceFallback::Reconnect {
+            // Without params the miner reconnects to the same host and port
+            let reconnect = json_rpc::Message::Notification(json_rpc::Notification {
+                method: "client.reconnect".to_string(),
+                params: serde_json::json!([]),
+            });
+            Self::send_message_downstream(self_.clone(), reconnect).await;
+        }
+        self_.safe_lock(|d| {
+            d.disconnect_reason = Some("extranonce changed");
+            d.disconnect.notify_one()
+        })?;
+        Ok(())
+    }
+
+    /// Takes the version rolling mask back from a miner that negotiated one, once the pool stopped
+    /// allowing version rolling or its firmware turned out not to roll. The miner gets
+    /// `mining.set_version_mask` with an empty mask before its next job. Returns the mask of the
+    /// next jobs.
+    pub(super) async fn revoke_version_rolling(
+        self_: &Arc<Mutex<Self>>,
+    ) -> Result<Option<HexU32Be>, Error<'static>> {
+        let (connection_id, revoked) = self_.safe_lock(|d| {
+            let revoked = d.version_rolling_mask.as_ref().is_some_and(|m| m.0 != 0);
+            if revoked {
+                d.version_rolling_mask = Some(HexU32Be(0));
+            }
+            (d.connection_id, revoked)
+        })?;
+        if revoked {
+            info!(
+                "Downstream {}: version rolling not allowed, clearing its mask",
+                connection_id
+            );
+            // `server_to_client::SetVersionMask` can not be built outside of `sv1_api`
+            let set_version_mask = json_rpc::Message::Notification(json_rpc::Notification {
+                method: "mining.set_version_mask".to_string(),
+                params: serde_json::json!([format!("{:08x}", 0)]),
+            });
+            Self::send_message_downstream(self_.clone(), set_version_mask).await;
+        }
+        Ok(self_.safe_lock(|d| d.version_rolling_mask.clone())?)
+    }
+
+    /// Whether the miner can be granted a version rolling mask, its firmware is not known to
+    /// mishandle it.
+    pub(super) fn supports_version_rolling(&self) -> bool {
+        self.quirks.get().is_none_or(|q| q.version_rolling)
+    }
+
+    /// Whether the miner can be sent `mining.set_extranonce`: it subscribed to it and its firmware
+    /// is not known to mishandle it.
+    pub(super) fn supports_set_extranonce(&self) -> bool {
+        self.extranonce_subscribed.get() && self.quirks.get().is_none_or(|q| q.set_extranonce)
     }
 
     /// As SV1 messages come in, determines if the message response needs to be translated to SV2
@@ -311,10 +545,107 @@ impl Downstream {
         // `handle_message` in `IsServer` trait + calls `handle_request`
         // TODO: Map err from V1Error to Error::V1Error
 
+        let extranonce_subscribe = match &message_sv1 {
+            json_rpc::Message::StandardRequest(request)
+                if request.method == "mining.extranonce.subscribe" =>
+            {
+                Some(request.id)
+            }
+            _ => None,
+        };
+        if let json_rpc::Message::StandardRequest(request) = &message_sv1 {
+            let (is_authorized, worker_difficulty) =
+                self_.safe_lock(|d| (!d.authorized_names.is_empty(), d.worker_difficulty))?;
+            // The hints of an authorize only apply to the first worker of the connection, the
+            // `[[workers]]` entry of the worker wins over any later hint of the miner
+            if let Some((hint, from_worker_config)) = difficulty_hint(request).filter(|_| {
+                if request.method == "mining.suggest_difficulty" {
+                    !worker_difficulty
+                } else {
+                    !is_authorized
+                }
+            }) {
+                self_.safe_lock(|d| d.worker_difficulty = from_worker_config)?;
+                Self::apply_difficulty_hint(&self_, hint).await?;
+            }
+            if request.method == "mining.submit" {
+                if let Err(reason) = self_.safe_lock(|d| d.check_submit(request))? {
+                    let response = json_rpc::Response {
+                        id: request.id,
+                        error: Some(sv1_error(&reason)),
+                        result: serde_json::Value::Null,
+                    };
+                    Self::send_message_downstream(
+                        self_,
+                        json_rpc::Message::ErrorResponse(response),
+                    )
+                    .await;
+                    return Ok(());
+                }
+            }
+            if request.method == "mining.suggest_difficulty" {
+                // Not handled by `IsServer`
+                let response = json_rpc::Response {
+                    id: request.id,
+                    error: None,
+                    result: serde_json::Value::Bool(true),
+                };
+                Self::send_message_downstream(self_, json_rpc::Message::OkResponse(response)).await;
+                return Ok(());
+            }
+        }
+        let is_submit = matches!(
+            &message_sv1,
+            json_rpc::Message::StandardRequest(request) if request.method == "mining.submit"
+        );
         let response = self_.safe_lock(|s| s.handle_message(message_sv1.clone()))?;
         match response {
             Ok(res) => {
-                if let Some(r) = res {
+                if let Some(mut r) = res {
+                    if let Some(e) = self_.safe_lock(|d| d.configure_error.take())? {
+                        warn!("Version rolling refused to downstream: {e}");
+                        r.error = Some(json_rpc::JsonRpcError {
+                            code: 20,
+                            message: e.to_string(),
+                            data: None,
+                        });
+                        r.result = serde_json::Value::Null;
+                        Self::send_message_downstream(self_, json_rpc::Message::ErrorResponse(r))
+                            .await;
+                        return Ok(());
+                    }
+                    if is_submit {
+                        if let Some(reason) = self_.safe_lock(|d| d.submit_rejection.take())? {
+                            // Miners tell stale, duplicate and low difficulty shares apart by
+                            // the error code
+                            r.error = Some(sv1_error(&reason));
+                            r.result = serde_json::Value::Null;
+                            Self::send_message_downstream(
+                                self_,
+                                json_rpc::Message::ErrorResponse(r),
+                            )
+                            .await;
+                            return Ok(());
+                        }
+                        let pending = self_.safe_lock(|d| d.pending_share.borrow_mut().take())?;
+                        if let Some(pending) = pending {
+                            // Other requests of the miner must not wait for the pool
+                            let (task_manager, connection_id) =
+                                self_.safe_lock(|d| (d.task_manager.clone(), d.connection_id))?;
+                            let handle =
+                                tokio::spawn(Self::answer_after_verdict(self_.clone(), r, pending));
+                            if let Some(task_manager) = task_manager {
+                                TaskManager::add_share_verdict(
+                                    task_manager,
+                                    handle.into(),
+                                    connection_id,
+                                )
+                                .await
+                                .map_err(|_| Error::TranslatorTaskManagerFailed)?;
+                            }
+                            return Ok(());
+                        }
+                    }
                     // If some response is received, indicates no messages translation is needed
                     // and response should be sent directly to the SV1 Downstream. Otherwise,
                     // message will be sent to the upstream Translator to be translated to SV2 and
@@ -322,6 +653,16 @@ impl Downstream {
                     // let sender = self_.safe_lock(|s| s.connection.sender_upstream)
                     Self::send_message_downstream(self_, r.into()).await;
                     Ok(())
+                } else if let Some(id) = extranonce_subscribe {
+                    // `IsServer` has no response for it but miners wait for one
+                    let response = json_rpc::Response {
+                        id,
+                        error: None,
+                        result: serde_json::Value::Bool(true),
+                    };
+                    Self::send_message_downstream(self_, json_rpc::Message::OkResponse(response))
+                        .await;
+                    Ok(())
                 } else {
                     // If None response is received, indicates this SV1 message received from the
                     // Downstream MD is passed to the `Translator` for translation into SV2
@@ -329,12 +670,141 @@ impl Downstream {
                 }
             }
             Err(e) => {
-                error!("{e}");
-                Err(Error::V1Protocol(Box::new(e)))
+                // A request the miner got wrong only fails that request, not the session
+                warn!("Invalid message from downstream: {e}");
+                if let json_rpc::Message::StandardRequest(request) = message_sv1 {
+                    let response = json_rpc::Response {
+                        id: request.id,
+                        error: Some(json_rpc::JsonRpcError {
+                            code: 20,
+                            message: e.to_string(),
+                            data: None,
+                        }),
+                        result: serde_json::Value::Null,
+                    };
+                    Self::send_message_downstream(
+                        self_,
+                        json_rpc::Message::ErrorResponse(response),
+                    )
+                    .await;
+                }
+                Ok(())
             }
         }
     }
 
+    /// Checks the fields of a `mining.submit` before it is parsed, a malformed share is counted as
+    /// rejected.
+    fn check_submit(&self, request: &json_rpc::StandardRequest) -> Result<(), RejectionReason> {
+        let Err(e) = validation::check_submit(&request.params, self.extranonce2_len) else {
+            return Ok(());
+        };
+        warn!(
+            "Share rejected: malformed share from downstream {}: {}",
+            self.connection_id, e
+        );
+        let param = |i: usize| request.params.get(i).and_then(|p| p.as_str());
+        let reason = match e {
+            MalformedSubmit::JobId(_) => RejectionReason::InvalidJobIdFormat,
+            e => RejectionReason::Malformed(e.to_string()),
+        };
+        let share = ShareInfo::new(
+            self.profile.worker_name(param(0).unwrap_or_default()),
+            None,
+            param(1).and_then(|id| id.parse().ok()).unwrap_or_default(),
+            Some(reason.clone()),
+        );
+        self.share_monitor.insert_share(share);
+        self.stats_sender.update_rejected_shares(self.connection_id);
+        Err(reason)
+    }
+
+    /// Answers a share sent in strict mode with the pool verdict, or as `ShareAckConfig::on_timeout`
+    /// says when the verdict does not arrive in time.
+    async fn answer_after_verdict(
+        self_: Arc<Mutex<Self>>,
+        mut response: json_rpc::Response,
+        mut pending: PendingShare,
+    ) {
+        let (share_ack, stats_sender, share_monitor, connection_id, upstream_difficulty_config) =
+            match self_.safe_lock(|d| {
+                (
+                    d.share_ack.clone(),
+                    d.stats_sender.clone(),
+                    d.share_monitor.clone(),
+                    d.connection_id,
+                    d.upstream_difficulty_config.clone(),
+                )
+            }) {
+                Ok(downstream) => downstream,
+                Err(e) => {
+                    error!("{e}");
+                    ProxyState::update_downstream_state(DownstreamType::TranslatorDownstream);
+                    return;
+                }
+            };
+        let timeout = std::time::Duration::from_secs(share_ack.timeout_secs);
+        let verdict = match tokio::time::timeout(timeout, pending.verdict.recv()).await {
+            Ok(Some(verdict)) => verdict,
+            // Timed out, or the upstream is gone with the share
+            _ => {
+                warn!(
+                    "No pool verdict for share {} of downstream {}",
+                    response.id, connection_id
+                );
+                match share_ack.on_timeout {
+                    AckFallback::Accept => None,
+                    AckFallback::Reject => Some(RejectionReason::NotAcknowledged),
+                }
+            }
+        };
+        let message = match verdict {
+            None => {
+                stats_sender.update_accepted_shares(connection_id, pending.difficulty);
+                if pending.late {
+                    stats_sender.update_late_shares(connection_id);
+                }
+                if upstream_difficulty_config
+                    .safe_lock(|c| c.on_accepted_share(pending.difficulty))
+                    .is_err()
+                {
+                    error!("{}", Error::TranslatorDiffConfigMutexPoisoned);
+                }
+                share_monitor.insert_share(ShareInfo::new(
+                    pending.worker_name,
+                    Some(pending.difficulty),
+                    pending.job_id,
+                    None,
+                ));
+                response.into()
+            }
+            Some(reason) => {
+                warn!(
+                    "Share {} of downstream {} rejected: {}",
+                    response.id, connection_id, reason
+                );
+                stats_sender.update_rejected_shares(connection_id);
+                match reason {
+                    RejectionReason::Stale => stats_sender.update_stale_shares(connection_id),
+                    RejectionReason::Duplicate => {
+                        stats_sender.update_duplicate_shares(connection_id)
+                    }
+                    _ => (),
+                }
+                response.error = Some(sv1_error(&reason));
+                response.result = serde_json::Value::Null;
+                share_monitor.insert_share(ShareInfo::new(
+                    pending.worker_name,
+                    None,
+                    pending.job_id,
+                    Some(reason),
+                ));
+                json_rpc::Message::ErrorResponse(response)
+            }
+        };
+        Self::send_message_downstream(self_, message).await;
+    }
+
     /// Send SV1 response message that is generated by `Downstream` (as opposed to being received
     /// by `Bridge`) to be written to the SV1 Downstream role.
     pub(super) async fn send_message_downstream(
@@ -388,8 +858,20 @@ impl Downstream {
     ) -> Self {
         use crate::monitor::shares::SharesMonitor;
 
+        let profile = Arc::new(PortProfile {
+            expected_hashrate: difficulty_mgmt.estimated_downstream_hash_rate,
+            share_per_min: *crate::SHARE_PER_MIN,
+            min_difficulty: None,
+            max_difficulty: None,
+            worker_prefix: None,
+            vardiff: super::diff_management::VardiffConfig::default(),
+            idle_timeout: None,
+        });
         Downstream {
             connection_id,
+            channel_id: connection_id,
+            upstream_channel_id: 0,
+            waiting_for_upstream: false,
             authorized_names,
             extranonce1,
             version_rolling_mask,
@@ -398,17 +880,54 @@ impl Downstream {
             tx_outgoing,
             extranonce2_len,
             difficulty_mgmt,
+            profile,
             upstream_difficulty_config,
             last_call_to_update_hr: 0,
             first_job,
             stats_sender,
-            recent_jobs: RecentJobs::new(),
+            recent_jobs: RecentJobs::default(),
             share_monitor: SharesMonitor::new(),
             user_agent: std::cell::RefCell::new(String::new()),
+            quirks: std::cell::Cell::new(None),
+            extranonce_subscribed: std::cell::Cell::new(false),
+            share_limiter: std::cell::RefCell::new(ShareRateLimiter::new(70.0, 70.0)),
+            submit_rejection: std::cell::Cell::new(None),
+            configure_error: std::cell::Cell::new(None),
+            share_ack: ShareAckConfig::default(),
+            pending_share: std::cell::RefCell::new(None),
+            disconnect: Arc::new(tokio::sync::Notify::new()),
+            disconnect_reason: None,
+            worker_difficulty: false,
+            health: std::cell::RefCell::new(MinerHealth::new(std::time::Instant::now())),
+            task_manager: None,
         }
     }
 }
 
+/// Difficulty hint of a `mining.suggest_difficulty`, or of a `mining.authorize` from the config
+/// of the worker or its password. The flag is set when it comes from the config of the worker.
+fn difficulty_hint(request: &json_rpc::StandardRequest) -> Option<(DifficultyHint, bool)> {
+    let params = request.params.as_array()?;
+    match request.method.as_str() {
+        "mining.suggest_difficulty" => {
+            DifficultyHint::suggested(params.first()?.as_f64()?).map(|hint| (hint, false))
+        }
+        "mining.authorize" => {
+            let name = params.first()?.as_str()?;
+            DifficultyHint::for_worker(name)
+                .map(|hint| (hint, true))
+                .or_else(|| {
+                    params
+                        .get(1)
+                        .and_then(|p| p.as_str())
+                        .and_then(DifficultyHint::from_password)
+                        .map(|hint| (hint, false))
+                })
+        }
+        _ => None,
+    }
+}
+
 /// Implements `IsServer` for `Downstream` to handle the SV1 messages.
 impl IsServer<'static> for Downstream {
     /// Handle the incoming `mining.configure` message which is received after a Downstream role is
@@ -418,22 +937,47 @@ impl IsServer<'static> for Downstream {
         request: &client_to_server::Configure,
     ) -> (Option<server_to_client::VersionRollingParams>, Option<bool>) {
         info!("Down: Handling mining.configure: {:?}", &request);
-        let (version_rolling_mask, version_rolling_min_bit_count) =
-            crate::shared::utils::sv1_rolling(request);
+        let allowed = if VERSION_ROLLING_ALLOWED.load(std::sync::atomic::Ordering::SeqCst)
+            && self.supports_version_rolling()
+        {
+            Configuration::version_rolling_mask()
+        } else {
+            0
+        };
+        let negotiated = version_rolling::negotiate(
+            request.version_rolling_mask().map(|mask| mask.0),
+            request.version_rolling_min_bit_count().map(|count| count.0),
+            allowed,
+        )
+        .and_then(|(mask, min_bit_count)| {
+            server_to_client::VersionRollingParams::new(HexU32Be(mask), HexU32Be(min_bit_count))
+                .map(|params| (mask, min_bit_count, params))
+                .map_err(|_| VersionRollingError::NotEnoughBits {
+                    available: mask.count_ones(),
+                    required: min_bit_count,
+                })
+        });
+        let (mask, min_bit_count, params) = match negotiated {
+            Ok((mask, min_bit_count, params)) => (
+                Some(HexU32Be(mask)),
+                Some(HexU32Be(min_bit_count)),
+                Some(params),
+            ),
+            Err(e) => {
+                // Answered with an error by `handle_incoming_sv1`, the miner does not roll
+                self.configure_error.set(Some(e));
+                (None, None, None)
+            }
+        };
 
-        self.version_rolling_mask = Some(version_rolling_mask.clone());
-        self.version_rolling_min_bit = Some(version_rolling_min_bit_count.clone());
+        self.version_rolling_mask = mask;
+        self.version_rolling_min_bit = min_bit_count;
         let mut first_job = self.first_job.clone();
         self.recent_jobs
             .add_job(&mut first_job, self.version_rolling_mask.clone());
         self.first_job = first_job;
 
-        (
-            Some(server_to_client::VersionRollingParams::new(
-                    version_rolling_mask,version_rolling_min_bit_count
-            ).expect("Version mask invalid, automatic version mask selection not supported, please change it in carte::downstream_sv1::mod.rs")),
-            Some(false),
-        )
+        (params, Some(false))
     }
 
     /// Handle the response to a `mining.subscribe` message received from the client.
@@ -453,6 +997,8 @@ impl IsServer<'static> for Downstream {
             "ae6812eb4cd7735a302a8a9dd95cf71f".to_string(),
         );
         self.user_agent.replace(request.agent_signature.clone());
+        self.quirks
+            .set(Some(firmware::quirks_for(&request.agent_signature)));
         vec![set_difficulty_sub, notify_sub]
     }
 
@@ -461,7 +1007,7 @@ impl IsServer<'static> for Downstream {
             let user_agent = self.user_agent.borrow().clone();
             let worker_activity = WorkerActivity::new(
                 user_agent,
-                request.name.clone(),
+                self.profile.worker_name(&request.name),
                 WorkerActivityType::Connected,
             );
 
@@ -501,14 +1047,17 @@ impl IsServer<'static> for Downstream {
             );
 
             let share = ShareInfo::new(
-                request.user_name.clone(),
+                self.profile.worker_name(&request.user_name),
                 None,
-                job_id_as_number.expect("checked above") as i64,
+                // Not a number
+                0,
                 Some(RejectionReason::InvalidJobIdFormat),
             );
             self.share_monitor.insert_share(share);
 
             self.stats_sender.update_rejected_shares(self.connection_id);
+            self.submit_rejection
+                .set(Some(RejectionReason::InvalidJobIdFormat));
             return false;
         }
         let job_id = job_id_as_number.clone().expect("checked above") as i64;
@@ -518,6 +1067,36 @@ impl IsServer<'static> for Downstream {
             .get_matching_job(job_id_as_number.expect("checked above"))
         {
             request.job_id = job.job_id.clone();
+            // A kept job the miner did not switch away from yet
+            let late = !self.recent_jobs.is_latest(&job);
+            if self.recent_jobs.is_stale(&job) {
+                let share = ShareInfo::new(
+                    self.profile.worker_name(&request.user_name),
+                    None,
+                    job_id,
+                    Some(RejectionReason::Stale),
+                );
+                self.share_monitor.insert_share(share);
+                error!("Share rejected: job {} is stale", request.job_id);
+                self.stats_sender.update_rejected_shares(self.connection_id);
+                self.stats_sender.update_stale_shares(self.connection_id);
+                self.submit_rejection.set(Some(RejectionReason::Stale));
+                return false;
+            }
+            if let Err(e) = validation::check_ntime(request.time.0, job.time.0) {
+                let reason = RejectionReason::Malformed(e.to_string());
+                let share = ShareInfo::new(
+                    self.profile.worker_name(&request.user_name),
+                    None,
+                    job_id,
+                    Some(reason.clone()),
+                );
+                self.share_monitor.insert_share(share);
+                error!("Share rejected: {}", e);
+                self.stats_sender.update_rejected_shares(self.connection_id);
+                self.submit_rejection.set(Some(reason));
+                return false;
+            }
             //check share is valid
             if let Some(met_difficulty) = validate_share(
                 &request,
@@ -526,28 +1105,83 @@ impl IsServer<'static> for Downstream {
                 self.extranonce1.clone(),
                 self.version_rolling_mask.clone(),
             ) {
+                if self.recent_jobs.is_duplicate(&request) {
+                    let share = ShareInfo::new(
+                        self.profile.worker_name(&request.user_name),
+                        None,
+                        job_id,
+                        Some(RejectionReason::Duplicate),
+                    );
+                    self.share_monitor.insert_share(share);
+                    error!("Share rejected: duplicate of job {}", request.job_id);
+                    self.stats_sender.update_rejected_shares(self.connection_id);
+                    self.stats_sender
+                        .update_duplicate_shares(self.connection_id);
+                    self.submit_rejection.set(Some(RejectionReason::Duplicate));
+                    return false;
+                }
+                // Invalid shares do not keep a broken miner from being idle or degraded
+                self.health.borrow_mut().on_share(std::time::Instant::now());
                 // Only forward upstream if the share meets the latest difficulty
                 if let Some(latest_difficulty) = self.difficulty_mgmt.current_difficulties.back() {
                     if met_difficulty == *latest_difficulty {
+                        if !self.share_limiter.borrow_mut().try_acquire() {
+                            warn!(
+                                "Share of downstream {} dropped: share rate limit exceeded",
+                                self.connection_id
+                            );
+                            self.stats_sender.update_dropped_shares(self.connection_id);
+                            self.recent_jobs.forget(&request);
+                            let share = ShareInfo::new(
+                                self.profile.worker_name(&request.user_name),
+                                None,
+                                job_id,
+                                Some(RejectionReason::RateLimited),
+                            );
+                            self.share_monitor.insert_share(share);
+                            // Answered with an error so the miner does not count it as accepted
+                            self.submit_rejection
+                                .set(Some(RejectionReason::RateLimited));
+                            return false;
+                        }
+                        let ack = if self.share_ack.strict {
+                            let (ack, verdict) = channel(1);
+                            *self.pending_share.borrow_mut() = Some(PendingShare {
+                                verdict,
+                                worker_name: self.profile.worker_name(&request.user_name),
+                                difficulty: met_difficulty,
+                                job_id,
+                                late,
+                            });
+                            Some(ack)
+                        } else {
+                            None
+                        };
                         let to_send = SubmitShareWithChannelId {
-                            channel_id: self.connection_id,
+                            channel_id: self.channel_id,
                             share: request.clone(),
                             extranonce: self.extranonce1.clone(),
                             extranonce2_len: self.extranonce2_len,
                             version_rolling_mask: self.version_rolling_mask.clone(),
+                            ack,
                         };
                         if let Err(e) = self
                             .tx_sv1_bridge
                             .try_send(DownstreamMessages::SubmitShares(to_send))
                         {
                             error!("Failed to start receive downstream task: {e:?}");
+                            self.pending_share.borrow_mut().take();
                             self.stats_sender.update_rejected_shares(self.connection_id);
                             // Return false because submit was not properly handled
                             return false;
                         }
+                        if self.pending_share.borrow().is_some() {
+                            // Counted once the pool verdict arrives
+                            return true;
+                        }
                         // Share is accepted here
                         let share = ShareInfo::new(
-                            request.user_name.clone(),
+                            self.profile.worker_name(&request.user_name),
                             Some(met_difficulty),
                             job_id,
                             None,
@@ -556,7 +1190,7 @@ impl IsServer<'static> for Downstream {
                     } else {
                         // met_difficulty is not latest difficulty, so we mark it as rejected
                         let share = ShareInfo::new(
-                            request.user_name.clone(),
+                            self.profile.worker_name(&request.user_name),
                             None,
                             job_id, // rejected because it was not sent upstream
                             Some(RejectionReason::DifficultyMismatch),
@@ -564,7 +1198,18 @@ impl IsServer<'static> for Downstream {
                         self.share_monitor.insert_share(share);
                     }
                 }
-                self.stats_sender.update_accepted_shares(self.connection_id);
+                self.stats_sender
+                    .update_accepted_shares(self.connection_id, met_difficulty);
+                if late {
+                    self.stats_sender.update_late_shares(self.connection_id);
+                }
+                if self
+                    .upstream_difficulty_config
+                    .safe_lock(|c| c.on_accepted_share(met_difficulty))
+                    .is_err()
+                {
+                    error!("{}", Error::TranslatorDiffConfigMutexPoisoned);
+                }
                 info!(
                     "Share for Job {} and difficulty {} is accepted",
                     request.job_id, met_difficulty
@@ -572,7 +1217,7 @@ impl IsServer<'static> for Downstream {
                 true
             } else {
                 let share = ShareInfo::new(
-                    request.user_name.clone(),
+                    self.profile.worker_name(&request.user_name),
                     None,
                     job_id,
                     Some(RejectionReason::InvalidShare),
@@ -580,11 +1225,13 @@ impl IsServer<'static> for Downstream {
                 self.share_monitor.insert_share(share);
                 error!("Share rejected: Invalid share");
                 self.stats_sender.update_rejected_shares(self.connection_id);
+                self.submit_rejection
+                    .set(Some(RejectionReason::InvalidShare));
                 false
             }
         } else {
             let event = ShareInfo::new(
-                request.user_name.clone(),
+                self.profile.worker_name(&request.user_name),
                 None,
                 job_id,
                 Some(RejectionReason::JobIdNotFound),
@@ -595,12 +1242,20 @@ impl IsServer<'static> for Downstream {
                 request.job_id
             );
             self.stats_sender.update_rejected_shares(self.connection_id);
+            self.submit_rejection
+                .set(Some(RejectionReason::JobIdNotFound));
             false
         }
     }
 
     /// Indicates to the server that the client supports the mining.set_extranonce method.
-    fn handle_extranonce_subscribe(&self) {}
+    fn handle_extranonce_subscribe(&self) {
+        info!(
+            "Downstream {} subscribed to mining.set_extranonce",
+            self.connection_id
+        );
+        self.extranonce_subscribed.set(true);
+    }
 
     /// Checks if a Downstream role is authorized.
     fn is_authorized(&self, name: &str) -> bool {
@@ -667,13 +1322,60 @@ impl IsDownstream for Downstream {
     }
 }
 
+/// Share sent to the pool in strict mode.
+#[derive(Debug)]
+pub(super) struct PendingShare {
+    verdict: Receiver<ShareVerdict>,
+    worker_name: String,
+    difficulty: f32,
+    job_id: i64,
+    /// Of a job that was not the latest one.
+    late: bool,
+}
+
+/// Standard SV1 error of a rejected `mining.submit`.
+fn sv1_error(reason: &RejectionReason) -> json_rpc::JsonRpcError {
+    let (code, message) = match reason {
+        RejectionReason::JobIdNotFound | RejectionReason::InvalidJobIdFormat => {
+            (21, "Job not found".to_string())
+        }
+        RejectionReason::Stale => (21, "Stale share".to_string()),
+        RejectionReason::Duplicate => (22, "Duplicate share".to_string()),
+        RejectionReason::InvalidShare => (23, "Low difficulty share".to_string()),
+        reason => (20, reason.to_string()),
+    };
+    json_rpc::JsonRpcError {
+        code,
+        message,
+        data: None,
+    }
+}
+
+/// Job (v2 id), extranonce2, ntime, nonce and version bits of a submitted share.
+type ShareKey = (String, Vec<u8>, u32, u32, Option<u32>);
+
+fn share_key(share: &client_to_server::Submit<'static>) -> ShareKey {
+    (
+        share.job_id.clone(),
+        share.extra_nonce2.0.to_vec(),
+        share.time.0,
+        share.nonce.0,
+        share.version_bits.as_ref().map(|v| v.0),
+    )
+}
+
+/// Jobs sent to a miner, by their v2 id, with the v1 ids the miner knows them by. Which jobs are
+/// kept is set by the `[job_retention]` section of the config.
 #[derive(Debug)]
 pub struct RecentJobs {
     v1_to_v2: HashMap<u32, u32>,
     v2_to_v1: HashMap<u32, Vec<u32>>,
-    jobs: VecDeque<Notify<'static>>,
-    last_v2s: CircularBuffer<u32, 3>,
-    tracked_jobs: usize,
+    /// Jobs with the time they were received, the latest at the back.
+    jobs: VecDeque<(Notify<'static>, Instant)>,
+    retention: JobRetentionConfig,
+    /// Shares submitted for the jobs a share can still be accepted for. A `RefCell` because
+    /// `handle_submit` takes &self.
+    seen_shares: std::cell::RefCell<HashSet<ShareKey>>,
 }
 fn apply_mask(mask: Option<HexU32Be>, message: &mut server_to_client::Notify<'static>) {
     if let Some(mask) = mask {
@@ -683,18 +1385,17 @@ fn apply_mask(mask: Option<HexU32Be>, message: &mut server_to_client::Notify<'st
 impl RecentJobs {
     pub fn add_job(&mut self, notify: &mut Notify<'static>, mask: Option<HexU32Be>) {
         apply_mask(mask, notify);
+        let now = Instant::now();
         // save it with the v2 id
-        self.jobs.push_back(notify.clone());
+        self.jobs.push_back((notify.clone(), now));
         let new_id = self.new_v1(notify.job_id.parse::<u32>().unwrap());
         // send it with the v1 id
         notify.job_id = new_id.to_string();
-        if self.jobs.len() > self.tracked_jobs {
-            self.jobs.pop_front();
-        };
+        self.prune(now);
     }
 
     pub fn clone_last(&mut self) -> Option<Notify<'static>> {
-        if let Some(job) = self.jobs.back() {
+        if let Some((job, _)) = self.jobs.back() {
             let mut job = job.clone();
             let new_id = self.new_v1(job.job_id.parse::<u32>().unwrap());
             job.job_id = new_id.to_string();
@@ -704,16 +1405,100 @@ impl RecentJobs {
         }
     }
 
+    /// Whether `job` was built on a previous block than the latest job.
+    pub fn is_stale(&self, job: &Notify<'static>) -> bool {
+        self.jobs
+            .back()
+            .is_some_and(|(last, _)| !same_prev_hash(last, job))
+    }
+
+    /// Whether `job` is the last one sent to the miner.
+    pub fn is_latest(&self, job: &Notify<'static>) -> bool {
+        self.jobs
+            .back()
+            .is_some_and(|(last, _)| last.job_id == job.job_id)
+    }
+
+    /// Records the share, with its job id already translated to the v2 one. True if it was
+    /// already submitted for a job that is still kept.
+    pub fn is_duplicate(&self, share: &client_to_server::Submit<'static>) -> bool {
+        !self.seen_shares.borrow_mut().insert(share_key(share))
+    }
+
+    /// Forgets a share that was not forwarded, so that it is not a duplicate when resubmitted.
+    pub fn forget(&self, share: &client_to_server::Submit<'static>) {
+        self.seen_shares.borrow_mut().remove(&share_key(share));
+    }
+
     pub fn current_jobs(&self) -> VecDeque<Notify<'static>> {
-        self.jobs.clone()
+        self.jobs.iter().map(|(job, _)| job.clone()).collect()
     }
 
+    /// Job of a v1 id, `None` if it is unknown or older than its max age.
     pub fn get_matching_job(&self, v1_id: u32) -> Option<Notify<'static>> {
+        self.matching_job(v1_id, Instant::now())
+    }
+
+    fn matching_job(&self, v1_id: u32, now: Instant) -> Option<Notify<'static>> {
         let v2_id = self.get_v2(v1_id)?;
-        self.current_jobs()
+        let (last, _) = self.jobs.back()?;
+        self.jobs
+            .iter()
+            .find(|(job, _)| job.job_id == v2_id)
+            .filter(|(job, received)| {
+                job.job_id == last.job_id
+                    || now.saturating_duration_since(*received)
+                        <= self.retention.max_age(same_prev_hash(last, job))
+            })
+            .map(|(job, _)| job.clone())
+    }
+
+    /// Drops the jobs past the count or age limits of their prevhash, the latest job is always
+    /// kept, with their v1 ids and the shares seen for them.
+    fn prune(&mut self, now: Instant) {
+        let Some((last, _)) = self.jobs.back().cloned() else {
+            return;
+        };
+        let (mut current, mut previous) = (0, 0);
+        let mut kept = VecDeque::with_capacity(self.jobs.len());
+        let mut dropped = vec![];
+        // From the latest to the oldest
+        while let Some((job, received)) = self.jobs.pop_back() {
+            let is_current = same_prev_hash(&last, &job);
+            let count = if is_current {
+                &mut current
+            } else {
+                &mut previous
+            };
+            *count += 1;
+            let keep = kept.is_empty()
+                || (*count <= self.retention.max_jobs(is_current)
+                    && now.saturating_duration_since(received)
+                        <= self.retention.max_age(is_current));
+            if keep {
+                kept.push_front((job, received));
+            } else {
+                dropped.push(job.job_id);
+            }
+        }
+        self.jobs = kept;
+        for v2_id in dropped {
+            // The same job may have been added again
+            if !self.jobs.iter().any(|(job, _)| job.job_id == v2_id) {
+                if let Ok(v2_id) = v2_id.parse() {
+                    self.remove_v2(v2_id);
+                }
+            }
+        }
+        let accepted: HashSet<&str> = self
+            .jobs
             .iter()
-            .find(|notify| notify.job_id == v2_id)
-            .cloned()
+            .filter(|(job, _)| same_prev_hash(&last, job))
+            .map(|(job, _)| job.job_id.as_str())
+            .collect();
+        self.seen_shares
+            .borrow_mut()
+            .retain(|(job_id, ..)| accepted.contains(job_id.as_str()));
     }
 
     fn new_v1(&mut self, v2_id: u32) -> u32 {
@@ -721,17 +1506,7 @@ impl RecentJobs {
         while self.v1_to_v2.contains_key(&v1_id) {
             v1_id = rand::thread_rng().gen();
         }
-        match self.v2_to_v1.entry(v2_id) {
-            Entry::Occupied(mut v) => {
-                v.get_mut().push(v1_id);
-            }
-            Entry::Vacant(v) => {
-                v.insert(vec![v1_id]);
-                if let Some(first) = self.last_v2s.push_back(v2_id) {
-                    self.remove_v2(first);
-                }
-            }
-        }
+        self.v2_to_v1.entry(v2_id).or_default().push(v1_id);
         self.v1_to_v2.insert(v1_id, v2_id);
         v1_id
     }
@@ -745,52 +1520,26 @@ impl RecentJobs {
     fn get_v2(&self, v1_id: u32) -> Option<String> {
         self.v1_to_v2.get(&v1_id).cloned().map(|v| v.to_string())
     }
-    pub fn new() -> Self {
+    pub fn new(retention: JobRetentionConfig) -> Self {
         Self {
             v1_to_v2: HashMap::new(),
             v2_to_v1: HashMap::new(),
-            last_v2s: CircularBuffer::new(),
             jobs: VecDeque::new(),
-            tracked_jobs: 3,
+            retention,
+            seen_shares: std::cell::RefCell::new(HashSet::new()),
         }
     }
 }
 
-impl Default for RecentJobs {
-    fn default() -> Self {
-        Self::new()
-    }
+fn same_prev_hash(a: &Notify<'static>, b: &Notify<'static>) -> bool {
+    let a: Vec<u8> = a.prev_hash.clone().into();
+    let b: Vec<u8> = b.prev_hash.clone().into();
+    a == b
 }
 
-#[derive(Debug)]
-struct CircularBuffer<T, const N: usize> {
-    buffer: [Option<T>; N],
-    len: usize,
-    start: usize,
-}
-
-impl<T, const N: usize> CircularBuffer<T, N> {
-    pub fn new() -> Self {
-        Self {
-            buffer: std::array::from_fn(|_| None),
-            len: 0,
-            start: 0,
-        }
-    }
-
-    pub fn push_back(&mut self, value: T) -> Option<T> {
-        let end = (self.start + self.len) % N;
-
-        if self.len < N {
-            self.buffer[end] = Some(value);
-            self.len += 1;
-            None
-        } else {
-            let evicted = self.buffer[self.start].take();
-            self.buffer[self.start] = Some(value);
-            self.start = (self.start + 1) % N;
-            evicted
-        }
+impl Default for RecentJobs {
+    fn default() -> Self {
+        Self::new(JobRetentionConfig::default())
     }
 }
 
@@ -809,3 +1558,385 @@ impl<T, const N: usize> CircularBuffer<T, N> {
 //        assert_eq!(actual, expect);
 //    }
 //}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::translator::VardiffConfig;
+    use rand::{rngs::StdRng, SeedableRng};
+    use serde_json::json;
+    use std::time::Duration;
+    use sv1_api::utils::{MerkleNode, PrevHash};
+
+    fn notify(job_id: u32, prev_hash: char) -> Notify<'static> {
+        Notify {
+            job_id: job_id.to_string(),
+            prev_hash: PrevHash::try_from(prev_hash.to_string().repeat(64).as_str()).unwrap(),
+            coin_base1: "ffff".try_into().unwrap(),
+            coin_base2: "ffff".try_into().unwrap(),
+            merkle_branch: vec![MerkleNode::try_from(vec![0; 32]).unwrap()],
+            version: HexU32Be(0x20000000),
+            bits: HexU32Be(0x1703a30c),
+            time: HexU32Be(1700000000),
+            clean_jobs: true,
+        }
+    }
+
+    fn share(job_id: &str, nonce: u32) -> client_to_server::Submit<'static> {
+        client_to_server::Submit {
+            user_name: "account.rig1".to_string(),
+            job_id: job_id.to_string(),
+            extra_nonce2: vec![0; 4].try_into().unwrap(),
+            time: HexU32Be(1700000000),
+            nonce: HexU32Be(nonce),
+            version_bits: None,
+            id: 1,
+        }
+    }
+
+    #[test]
+    fn duplicates_and_stale_jobs() {
+        let mut jobs = RecentJobs::default();
+        let first = notify(1, '0');
+        jobs.add_job(&mut first.clone(), None);
+        assert!(!jobs.is_duplicate(&share("1", 7)));
+        assert!(jobs.is_duplicate(&share("1", 7)));
+        assert!(!jobs.is_duplicate(&share("1", 8)));
+
+        // Rate limited, then resubmitted
+        assert!(!jobs.is_duplicate(&share("1", 9)));
+        jobs.forget(&share("1", 9));
+        assert!(!jobs.is_duplicate(&share("1", 9)));
+        assert!(jobs.is_duplicate(&share("1", 9)));
+
+        // Same block, the filter is kept
+        jobs.add_job(&mut notify(2, '0'), None);
+        assert!(jobs.is_duplicate(&share("1", 7)));
+        assert!(!jobs.is_stale(&first));
+
+        // New block, the filter is reset and the first job is stale
+        jobs.add_job(&mut notify(3, '1'), None);
+        assert!(!jobs.is_duplicate(&share("1", 7)));
+        assert!(jobs.is_stale(&first));
+        assert!(!jobs.is_stale(&notify(3, '1')));
+    }
+
+    #[test]
+    fn retention_by_count_and_age() {
+        let mut jobs = RecentJobs::new(JobRetentionConfig {
+            current_max_jobs: 2,
+            current_max_age_secs: 300,
+            previous_max_jobs: 1,
+            previous_max_age_secs: 60,
+        });
+        let v1_id = |jobs: &mut RecentJobs, job_id, prev_hash| {
+            let mut job = notify(job_id, prev_hash);
+            jobs.add_job(&mut job, None);
+            job.job_id.parse::<u32>().unwrap()
+        };
+        let now = Instant::now();
+        let first = v1_id(&mut jobs, 1, '0');
+        let second = v1_id(&mut jobs, 2, '0');
+        let third = v1_id(&mut jobs, 3, '0');
+        // Only the two last jobs of the block are kept
+        assert!(jobs.matching_job(first, now).is_none());
+        let job = jobs.matching_job(second, now).unwrap();
+        assert!(!jobs.is_latest(&job) && !jobs.is_stale(&job));
+        assert!(jobs.is_latest(&jobs.matching_job(third, now).unwrap()));
+
+        // New block, only the last job of the previous one is kept
+        let fourth = v1_id(&mut jobs, 4, '1');
+        assert!(jobs.matching_job(second, now).is_none());
+        assert!(jobs.is_stale(&jobs.matching_job(third, now).unwrap()));
+
+        // Jobs of the previous block expire first, the latest job never does
+        let later = now + Duration::from_secs(120);
+        assert!(jobs.matching_job(third, later).is_none());
+        assert!(jobs.matching_job(fourth, later).is_some());
+        assert!(jobs
+            .matching_job(fourth, now + Duration::from_secs(900))
+            .is_some());
+        let fifth = v1_id(&mut jobs, 5, '1');
+        assert!(jobs.matching_job(fourth, later).is_some());
+        assert!(jobs
+            .matching_job(fourth, now + Duration::from_secs(900))
+            .is_none());
+        assert!(jobs.matching_job(fifth, later).is_some());
+    }
+
+    /// Downstream of `account.rig1` with a 4 bytes extranonce2 working on a job, with the receiver
+    /// of its messages to the miner and the id of the job.
+    fn downstream() -> (Arc<Mutex<Downstream>>, Receiver<json_rpc::Message>, String) {
+        let profile = Arc::new(PortProfile {
+            expected_hashrate: 0.0,
+            share_per_min: 10.0,
+            min_difficulty: None,
+            max_difficulty: None,
+            worker_prefix: None,
+            vardiff: VardiffConfig::default(),
+            idle_timeout: None,
+        });
+        let (tx_sv1_bridge, _) = channel(10);
+        let (tx_outgoing, rx_outgoing) = channel(10);
+        let mut job = notify(1, '0');
+        let mut downstream = Downstream::new(
+            1,
+            vec!["account.rig1".to_string()],
+            vec![0; 4],
+            None,
+            None,
+            tx_sv1_bridge,
+            tx_outgoing,
+            4,
+            DownstreamDifficultyConfig::new(&profile, 1.0),
+            Arc::new(Mutex::new(UpstreamDifficultyConfig {
+                channel_diff_update_interval: 60,
+                channel_nominal_hashrate: 0.0,
+                hashrate: crate::shared::hashrate::HashrateMeter::default(),
+            })),
+            StatsSender::new(),
+            job.clone(),
+        );
+        downstream.recent_jobs.add_job(&mut job, None);
+        (Arc::new(Mutex::new(downstream)), rx_outgoing, job.job_id)
+    }
+
+    fn submit_line(id: u64, params: serde_json::Value) -> json_rpc::Message {
+        serde_json::from_value(json!({ "id": id, "method": "mining.submit", "params": params }))
+            .unwrap()
+    }
+
+    /// Id and error code of the only message sent to the miner.
+    fn answer(rx: &mut Receiver<json_rpc::Message>) -> (u64, Option<i32>) {
+        let answer = match rx.try_recv().expect("no answer") {
+            json_rpc::Message::OkResponse(r) | json_rpc::Message::ErrorResponse(r) => {
+                (r.id, r.error.map(|e| e.code))
+            }
+            message => panic!("unexpected message {:?}", message),
+        };
+        assert!(rx.try_recv().is_err(), "more than one answer");
+        answer
+    }
+
+    /// Hex string of `len` chars, sometimes with a char that is not hex.
+    fn hex(rng: &mut StdRng, len: usize) -> String {
+        (0..len)
+            .map(|_| {
+                if rng.gen_ratio(1, 50) {
+                    'g'
+                } else {
+                    char::from_digit(rng.gen_range(0..16), 16).unwrap()
+                }
+            })
+            .collect()
+    }
+
+    /// A stratum line close to a `mining.submit` with random fields, sometimes cut short.
+    fn fuzzed_line(rng: &mut StdRng, job_id: &str) -> String {
+        let mut params = vec![
+            json!(["account.rig1", "account.rig2", ""][rng.gen_range(0..3)]),
+            json!([job_id, "7", "x1", "-1", "4294967296", ""][rng.gen_range(0..6)]),
+            json!(hex(rng, [8, 8, 8, 0, 6, 10][rng.gen_range(0..6)])),
+            json!(format!("{:08x}", 1700000000 + rng.gen_range(-10..8000))),
+            json!(hex(rng, [8, 8, 8, 7, 9][rng.gen_range(0..5)])),
+        ];
+        if rng.gen_ratio(1, 4) {
+            params.push(json!(hex(rng, 8)));
+        }
+        if rng.gen_ratio(1, 10) {
+            params.truncate(rng.gen_range(0..params.len()));
+        }
+        if rng.gen_ratio(1, 10) {
+            let i = rng.gen_range(0..params.len().max(1));
+            params.insert(i, json!(rng.gen::<u32>()));
+        }
+        let method = [
+            "mining.submit",
+            "mining.submit",
+            "mining.submit",
+            "mining.extranonce.subscribe",
+            "mining.unknown",
+            "",
+        ][rng.gen_range(0..6)];
+        let line =
+            json!({ "id": rng.gen_range(0..1000), "method": method, "params": params }).to_string();
+        if rng.gen_ratio(1, 5) {
+            line[..rng.gen_range(0..line.len())].to_string()
+        } else {
+            line
+        }
+    }
+
+    #[tokio::test]
+    async fn malformed_shares_get_an_error() {
+        let (downstream, mut rx, job_id) = downstream();
+        let cases = [
+            (
+                json!(["account.rig1", "x", "00000000", "6553f100", "00000000"]),
+                21,
+            ),
+            (
+                json!(["account.rig1", &job_id, "00", "6553f100", "00000000"]),
+                20,
+            ),
+            (
+                json!(["account.rig1", &job_id, "00000000", "6553f1", "00000000"]),
+                20,
+            ),
+            (json!(["account.rig1", &job_id, "00000000", "6553f100"]), 20),
+            // Two hours and one second after the job
+            (
+                json!(["account.rig1", &job_id, "00000000", "65540d21", "00000000"]),
+                20,
+            ),
+            (
+                json!(["account.rig1", "12345", "00000000", "6553f100", "00000000"]),
+                21,
+            ),
+        ];
+        for (id, (params, code)) in cases.into_iter().enumerate() {
+            let message = submit_line(id as u64, params);
+            Downstream::handle_incoming_sv1(downstream.clone(), message)
+                .await
+                .unwrap();
+            assert_eq!(answer(&mut rx), (id as u64, Some(code)));
+        }
+    }
+
+    #[tokio::test]
+    async fn fuzzed_lines_do_not_end_the_session() {
+        let mut rng = StdRng::seed_from_u64(21);
+        let (downstream, mut rx, job_id) = downstream();
+        for _ in 0..2000 {
+            let line = fuzzed_line(&mut rng, &job_id);
+            // Skipped by `start_receive_downstream`
+            let Ok(message) = serde_json::from_str::<json_rpc::Message>(&line) else {
+                continue;
+            };
+            Downstream::handle_incoming_sv1(downstream.clone(), message.clone())
+                .await
+                .unwrap_or_else(|e| panic!("{line} ended the session: {e:?}"));
+            match message {
+                json_rpc::Message::StandardRequest(request)
+                    if request.method == "mining.submit" =>
+                {
+                    assert_eq!(answer(&mut rx).0, request.id, "{line}");
+                }
+                _ => while rx.try_recv().is_ok() {},
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn version_rolling_is_revoked_once() {
+        let (downstream, mut rx, _) = downstream();
+        downstream
+            .safe_lock(|d| d.version_rolling_mask = Some(HexU32Be(0x1fffe000)))
+            .unwrap();
+
+        let mask = Downstream::revoke_version_rolling(&downstream)
+            .await
+            .unwrap();
+        assert_eq!(mask.map(|m| m.0), Some(0));
+        match rx.try_recv().expect("no set_version_mask") {
+            json_rpc::Message::Notification(n) => {
+                assert_eq!(n.method, "mining.set_version_mask");
+                assert_eq!(n.params, json!(["00000000"]));
+            }
+            message => panic!("unexpected message {:?}", message),
+        }
+
+        Downstream::revoke_version_rolling(&downstream)
+            .await
+            .unwrap();
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn miners_without_set_extranonce_are_told_to_reconnect() {
+        let (miner, mut rx, _) = downstream();
+        let disconnect = miner.safe_lock(|d| d.disconnect.clone()).unwrap();
+        Downstream::drop_for_new_extranonce(&miner, ExtranonceFallback::Reconnect)
+            .await
+            .unwrap();
+        match rx.try_recv().expect("no client.reconnect") {
+            json_rpc::Message::Notification(n) => assert_eq!(n.method, "client.reconnect"),
+            message => panic!("unexpected message {:?}", message),
+        }
+        tokio::time::timeout(Duration::from_secs(1), disconnect.notified())
+            .await
+            .expect("not disconnected");
+
+        let (miner, mut rx, _) = downstream();
+        let disconnect = miner.safe_lock(|d| d.disconnect.clone()).unwrap();
+        Downstream::drop_for_new_extranonce(&miner, ExtranonceFallback::Disconnect)
+            .await
+            .unwrap();
+        assert!(rx.try_recv().is_err());
+        tokio::time::timeout(Duration::from_secs(1), disconnect.notified())
+            .await
+            .expect("not disconnected");
+    }
+
+    #[tokio::test]
+    async fn invalid_shares_do_not_keep_a_miner_active() {
+        let (downstream, mut rx, job_id) = downstream();
+        let now = std::time::Instant::now();
+        let profile = PortProfile {
+            expected_hashrate: 0.0,
+            share_per_min: 10.0,
+            min_difficulty: None,
+            max_difficulty: None,
+            worker_prefix: None,
+            vardiff: VardiffConfig::default(),
+            idle_timeout: Some(Duration::from_secs(600)),
+        };
+        downstream
+            .safe_lock(|d| {
+                *d.health.borrow_mut() = MinerHealth::new(now - Duration::from_secs(700))
+            })
+            .unwrap();
+
+        // Does not meet the difficulty of the miner
+        let message = submit_line(
+            1,
+            json!(["account.rig1", &job_id, "00000000", "6553f100", "00000000"]),
+        );
+        Downstream::handle_incoming_sv1(downstream.clone(), message)
+            .await
+            .unwrap();
+        assert_eq!(answer(&mut rx), (1, Some(23)));
+        let state = downstream
+            .safe_lock(|d| d.health.borrow_mut().state(now, &profile))
+            .unwrap();
+        assert_eq!(state, MinerState::Idle);
+    }
+
+    #[tokio::test]
+    async fn suggest_difficulty_after_authorize_keeps_the_worker_config() {
+        let (downstream, mut rx, _) = downstream();
+        // Authorized with a `[[workers]]` entry
+        let initial_difficulty = downstream
+            .safe_lock(|d| {
+                d.worker_difficulty = true;
+                d.difficulty_mgmt.initial_difficulty
+            })
+            .unwrap();
+
+        let message = serde_json::from_value(
+            json!({ "id": 1, "method": "mining.suggest_difficulty", "params": [1024] }),
+        )
+        .unwrap();
+        Downstream::handle_incoming_sv1(downstream.clone(), message)
+            .await
+            .unwrap();
+        // Answered, with no new difficulty
+        assert_eq!(answer(&mut rx), (1, None));
+        assert_eq!(
+            downstream
+                .safe_lock(|d| d.difficulty_mgmt.initial_difficulty)
+                .unwrap(),
+            initial_difficulty
+        );
+    }
+}
@@ -0,0 +1,165 @@
+use crate::config::PortProfile;
+use serde::Serialize;
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+/// Span of shares the share rate of a miner is measured over.
+const RATE_WINDOW: Duration = Duration::from_secs(300);
+
+/// A miner is degraded when it sends less than this fraction of the shares per minute of its
+/// profile, at the difficulty it was given.
+const DEGRADED_RATIO: f32 = 0.25;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MinerState {
+    #[default]
+    Active,
+    /// Sends shares far slower than its difficulty implies, e.g. a failing hash board or a
+    /// difficulty that vardiff can not lower anymore.
+    Degraded,
+    /// Sent no valid share for longer than the idle timeout of its profile.
+    Idle,
+}
+
+/// Tracks whether a miner is still hashing from the valid `mining.submit` it sends.
+#[derive(Clone, Debug)]
+pub struct MinerHealth {
+    last_share: Instant,
+    /// Shares since the last difficulty change within `RATE_WINDOW`.
+    shares: VecDeque<Instant>,
+    /// Last difficulty change, the share rate is only measured at a single difficulty.
+    since: Instant,
+}
+
+impl MinerHealth {
+    pub fn new(now: Instant) -> Self {
+        Self {
+            last_share: now,
+            shares: VecDeque::new(),
+            since: now,
+        }
+    }
+
+    pub fn on_share(&mut self, now: Instant) {
+        self.last_share = now;
+        self.shares.push_back(now);
+        self.forget(now);
+    }
+
+    pub fn on_new_difficulty(&mut self, now: Instant) {
+        self.shares.clear();
+        self.since = now;
+    }
+
+    pub fn state(&mut self, now: Instant, profile: &PortProfile) -> MinerState {
+        let idle_for = now.saturating_duration_since(self.last_share);
+        if profile
+            .idle_timeout
+            .is_some_and(|timeout| idle_for >= timeout)
+        {
+            return MinerState::Idle;
+        }
+        // Until a full window was mined at the current difficulty
+        if now.saturating_duration_since(self.since) < RATE_WINDOW {
+            return MinerState::Active;
+        }
+        self.forget(now);
+        let share_per_min = self.shares.len() as f32 / (RATE_WINDOW.as_secs_f32() / 60.0);
+        if share_per_min < profile.share_per_min * DEGRADED_RATIO {
+            MinerState::Degraded
+        } else {
+            MinerState::Active
+        }
+    }
+
+    fn forget(&mut self, now: Instant) {
+        while self
+            .shares
+            .front()
+            .is_some_and(|share| now.saturating_duration_since(*share) > RATE_WINDOW)
+        {
+            self.shares.pop_front();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::translator::downstream::diff_management::VardiffConfig;
+
+    fn profile(idle_timeout: Option<Duration>) -> PortProfile {
+        PortProfile {
+            expected_hashrate: 1e12,
+            share_per_min: 10.0,
+            min_difficulty: None,
+            max_difficulty: None,
+            worker_prefix: None,
+            vardiff: VardiffConfig::default(),
+            idle_timeout,
+        }
+    }
+
+    /// Shares every `interval` for `duration` from `start`.
+    fn mine(health: &mut MinerHealth, start: Instant, duration: u64, interval: u64) -> Instant {
+        let mut now = start;
+        while now < start + Duration::from_secs(duration) {
+            now += Duration::from_secs(interval);
+            health.on_share(now);
+        }
+        now
+    }
+
+    #[test]
+    fn idle_after_timeout() {
+        let profile = profile(Some(Duration::from_secs(600)));
+        let start = Instant::now();
+        let mut health = MinerHealth::new(start);
+        // Never sent a share
+        assert_eq!(
+            health.state(start + Duration::from_secs(60), &profile),
+            MinerState::Active
+        );
+        assert_eq!(
+            health.state(start + Duration::from_secs(599), &profile),
+            MinerState::Degraded
+        );
+        assert_eq!(
+            health.state(start + Duration::from_secs(600), &profile),
+            MinerState::Idle
+        );
+
+        let now = mine(&mut health, start, 900, 6);
+        assert_eq!(health.state(now, &profile), MinerState::Active);
+        let now = now + Duration::from_secs(600);
+        assert_eq!(health.state(now, &profile), MinerState::Idle);
+        // Without a timeout it is only degraded
+        assert_eq!(
+            health.state(now, &self::profile(None)),
+            MinerState::Degraded
+        );
+    }
+
+    #[test]
+    fn degraded_below_share_rate() {
+        let profile = profile(None);
+        let start = Instant::now();
+        let mut health = MinerHealth::new(start);
+        // 10 shares per minute
+        let now = mine(&mut health, start, 600, 6);
+        assert_eq!(health.state(now, &profile), MinerState::Active);
+        // Then 2 per minute
+        let now = mine(&mut health, now, 600, 30);
+        assert_eq!(health.state(now, &profile), MinerState::Degraded);
+
+        // Measured again at the new difficulty
+        health.on_new_difficulty(now);
+        let now = mine(&mut health, now, 120, 30);
+        assert_eq!(health.state(now, &profile), MinerState::Active);
+        let now = mine(&mut health, now, 300, 6);
+        assert_eq!(health.state(now, &profile), MinerState::Active);
+    }
+}
@@ -1,14 +1,26 @@
-use roles_logic_sv2::mining_sv2::Target;
-use sv1_api::{client_to_server::Submit, utils::HexU32Be};
+use super::{upstream::diff_management::UpstreamDifficultyConfig, utils::ShareVerdict};
+use roles_logic_sv2::{
+    mining_sv2::{SubmitSharesExtended, Target},
+    parsers::Mining,
+    utils::Mutex,
+};
+use std::sync::Arc;
+use sv1_api::{client_to_server::Submit, server_to_client, utils::HexU32Be};
 pub mod diff_management;
 #[allow(clippy::module_inception)]
 pub mod downstream;
 pub use downstream::Downstream;
 mod accept_connection;
+pub mod health;
 mod notify;
 mod receive_from_downstream;
 mod send_to_downstream;
+mod sessions;
+mod sv2_downstream;
 mod task_manager;
+mod validation;
+pub mod vardiff_sim;
+pub use sessions::DownstreamSessions;
 
 /// This constant is used as a check to ensure clients
 /// do not send a mining.subscribe and never a mining.authorize
@@ -17,10 +29,15 @@ mod task_manager;
 /// `mining.subscribe` messages that init connections and take up compute
 const SUBSCRIBE_TIMEOUT_SECS: u64 = 10;
 
+/// `mining.notify` of a `Bridge` with the id of its upstream channel, the miners on the other
+/// channels skip it.
+pub type ChannelNotify = (u32, server_to_client::Notify<'static>);
+
 /// enum of messages sent to the Bridge
 #[derive(Debug, Clone)]
 pub enum DownstreamMessages {
     SubmitShares(SubmitShareWithChannelId),
+    SubmitSharesSv2(SubmitSv2Share),
     SetDownstreamTarget(SetDownstreamTarget),
 }
 
@@ -32,10 +49,24 @@ pub struct SubmitShareWithChannelId {
     pub share: Submit<'static>,
     // TODO why we need allow dead code here???
     #[allow(dead_code)]
-    extranonce: Vec<u8>,
+    pub extranonce: Vec<u8>,
     #[allow(dead_code)]
-    extranonce2_len: usize,
+    pub extranonce2_len: usize,
     pub version_rolling_mask: Option<HexU32Be>,
+    /// Set in strict mode, receives the pool verdict on the share.
+    pub ack: Option<tokio::sync::mpsc::Sender<ShareVerdict>>,
+}
+
+/// `SubmitSharesExtended` of a native SV2 downstream, already on the channel the Bridge knows.
+/// The Bridge answers the downstream on `reply`.
+#[derive(Debug, Clone)]
+pub struct SubmitSv2Share {
+    pub share: SubmitSharesExtended<'static>,
+    /// Difficulty of the target of the channel, the hashrate of `upstream_difficulty_config` is
+    /// measured from it once the share is accepted.
+    pub difficulty: f32,
+    pub upstream_difficulty_config: Arc<Mutex<UpstreamDifficultyConfig>>,
+    pub reply: tokio::sync::mpsc::Sender<Mining<'static>>,
 }
 
 /// message for notifying the bridge that a downstream target has updated
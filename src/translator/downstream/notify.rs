@@ -1,35 +1,36 @@
 use crate::proxy_state::{DownstreamType, ProxyState};
 use crate::translator::downstream::SUBSCRIBE_TIMEOUT_SECS;
 use crate::translator::error::Error;
+use crate::translator::upstream::upstream::VERSION_ROLLING_ALLOWED;
 
-use super::{downstream::Downstream, task_manager::TaskManager};
+use super::{downstream::Downstream, task_manager::TaskManager, ChannelNotify};
 use roles_logic_sv2::utils::Mutex;
-use std::sync::Arc;
+use std::sync::{atomic::Ordering, Arc};
 use sv1_api::json_rpc;
-use sv1_api::server_to_client;
-use tokio::sync::broadcast;
+use tokio::sync::broadcast::{self, error::RecvError};
 use tokio::task;
 use tracing::{debug, error, warn};
 
 pub async fn start_notify(
     task_manager: Arc<Mutex<TaskManager>>,
     downstream: Arc<Mutex<Downstream>>,
-    mut rx_sv1_notify: broadcast::Receiver<server_to_client::Notify<'static>>,
+    mut rx_sv1_notify: broadcast::Receiver<ChannelNotify>,
     host: String,
     connection_id: u32,
 ) -> Result<(), Error<'static>> {
     let handle = {
         let task_manager = task_manager.clone();
-        let (upstream_difficulty_config, stats_sender, latest_diff) =
-            downstream.safe_lock(|d| {
+        let (upstream_difficulty_config, stats_sender, latest_diff, expected_hashrate) = downstream
+            .safe_lock(|d| {
                 (
                     d.upstream_difficulty_config.clone(),
                     d.stats_sender.clone(),
                     d.difficulty_mgmt.current_difficulties.back().copied(),
+                    d.profile.expected_hashrate,
                 )
             })?;
         upstream_difficulty_config.safe_lock(|c| {
-            c.channel_nominal_hashrate += *crate::EXPECTED_SV1_HASHPOWER;
+            c.channel_nominal_hashrate += expected_hashrate;
         })?;
         stats_sender.setup_stats(connection_id);
         task::spawn(async move {
@@ -93,10 +94,60 @@ pub async fn start_notify(
                 warn!("Translator impossible to start update task: {e}");
             } else if authorized_in_time {
                 // Get the mask after initialization since is set by configure message
-                let mask = downstream
-                    .safe_lock(|d| d.version_rolling_mask.clone())
+                let (mut mask, firmware_rolls) = downstream
+                    .safe_lock(|d| (d.version_rolling_mask.clone(), d.supports_version_rolling()))
                     .unwrap();
-                while let Ok(mut sv1_mining_notify_msg) = rx_sv1_notify.recv().await {
+                // The mask may have been negotiated before `mining.subscribe` named the firmware
+                if !firmware_rolls && mask.as_ref().is_some_and(|m| m.0 != 0) {
+                    if let Ok(revoked) = Downstream::revoke_version_rolling(&downstream).await {
+                        mask = revoked;
+                    }
+                }
+                loop {
+                    let (upstream_channel_id, mut sv1_mining_notify_msg) =
+                        match rx_sv1_notify.recv().await {
+                            Ok(notify) => notify,
+                            // Every channel sends its jobs on the same broadcast, a burst of jobs
+                            // of other channels must not stop the miner
+                            Err(RecvError::Lagged(skipped)) => {
+                                warn!(
+                                    "Downstream {}: skipped {} jobs, notifier lagging",
+                                    connection_id, skipped
+                                );
+                                continue;
+                            }
+                            Err(RecvError::Closed) => break,
+                        };
+                    match downstream.safe_lock(|d| {
+                        d.waiting_for_upstream || d.upstream_channel_id != upstream_channel_id
+                    }) {
+                        // The clean job is sent when the downstream is moved to the new upstream,
+                        // the jobs of the other channels do not match its extranonce
+                        Ok(true) => continue,
+                        Ok(false) => (),
+                        Err(_) => {
+                            error!("Translator Downstream Mutex Poisoned");
+                            ProxyState::update_downstream_state(
+                                DownstreamType::TranslatorDownstream,
+                            );
+                            break;
+                        }
+                    }
+                    // Version bits rolled with the old mask would be rejected by the pool
+                    if !VERSION_ROLLING_ALLOWED.load(Ordering::SeqCst)
+                        && mask.as_ref().is_some_and(|m| m.0 != 0)
+                    {
+                        match Downstream::revoke_version_rolling(&downstream).await {
+                            Ok(revoked) => mask = revoked,
+                            Err(_) => {
+                                error!("Translator Downstream Mutex Poisoned");
+                                ProxyState::update_downstream_state(
+                                    DownstreamType::TranslatorDownstream,
+                                );
+                                break;
+                            }
+                        }
+                    }
                     if downstream
                         .safe_lock(|d| {
                             d.recent_jobs.add_job(&mut sv1_mining_notify_msg,mask.clone());
@@ -139,19 +190,19 @@ async fn start_update(
     downstream: Arc<Mutex<Downstream>>,
     connection_id: u32,
 ) -> Result<(), Error<'static>> {
+    let share_per_min = downstream.safe_lock(|d| d.profile.share_per_min)?;
     let handle = task::spawn(async move {
         // Prevent difficulty adjustments until after delay elapses
         tokio::time::sleep(std::time::Duration::from_secs(crate::Configuration::delay())).await;
         loop {
             let share_count = crate::translator::utils::get_share_count(connection_id);
-            let sleep_duration = if share_count >= *crate::SHARE_PER_MIN * 3.0
-                || share_count <= *crate::SHARE_PER_MIN / 3.0
-            {
-                // TODO: this should only apply when after the first share has been received
-                std::time::Duration::from_millis(crate::Configuration::adjustment_interval())
-            } else {
-                std::time::Duration::from_millis(crate::Configuration::adjustment_interval())
-            };
+            let sleep_duration =
+                if share_count >= share_per_min * 3.0 || share_count <= share_per_min / 3.0 {
+                    // TODO: this should only apply when after the first share has been received
+                    std::time::Duration::from_millis(crate::Configuration::adjustment_interval())
+                } else {
+                    std::time::Duration::from_millis(crate::Configuration::adjustment_interval())
+                };
 
             tokio::time::sleep(sleep_duration).await;
 
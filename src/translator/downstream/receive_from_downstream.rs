@@ -1,4 +1,4 @@
-use super::{downstream::Downstream, task_manager::TaskManager};
+use super::{downstream::Downstream, sessions::DownstreamSessions, task_manager::TaskManager};
 use crate::{
     monitor::worker_activity::{WorkerActivity, WorkerActivityType},
     proxy_state::ProxyState,
@@ -11,18 +11,36 @@ use tokio::sync::mpsc;
 use tokio::task;
 use tracing::{error, warn};
 
+/// Lines in a row that are not JSON-RPC after which a miner is disconnected, it is likely not
+/// speaking stratum at all.
+const MAX_MALFORMED_LINES: u32 = 10;
+
 pub async fn start_receive_downstream(
     task_manager: Arc<Mutex<TaskManager>>,
     downstream: Arc<Mutex<Downstream>>,
     mut recv_from_down: mpsc::Receiver<String>,
     connection_id: u32,
+    sessions: DownstreamSessions,
 ) -> Result<(), Error<'static>> {
     let handle = {
         let task_manager = task_manager.clone();
+        let disconnect = downstream.safe_lock(|d| d.disconnect.clone())?;
         task::spawn(async move {
-            while let Some(incoming) = recv_from_down.recv().await {
+            let mut malformed_lines = 0;
+            loop {
+                let incoming = tokio::select! {
+                    incoming = recv_from_down.recv() => incoming,
+                    _ = disconnect.notified() => {
+                        warn!("Disconnecting downstream {}", connection_id);
+                        None
+                    }
+                };
+                let Some(incoming) = incoming else {
+                    break;
+                };
                 let incoming: Result<json_rpc::Message, _> = serde_json::from_str(&incoming);
                 if let Ok(incoming) = incoming {
+                    malformed_lines = 0;
                     // if message is Submit Shares update difficulty management
                     if let sv1_api::Message::StandardRequest(standard_req) = incoming.clone() {
                         if let Ok(Submit { .. }) = standard_req.try_into() {
@@ -33,23 +51,47 @@ pub async fn start_receive_downstream(
                         }
                     }
 
+                    let is_authorize = matches!(
+                        &incoming,
+                        json_rpc::Message::StandardRequest(request)
+                            if request.method == "mining.authorize"
+                    );
                     if let Err(error) =
                         Downstream::handle_incoming_sv1(downstream.clone(), incoming).await
                     {
                         error!("Failed to handle incoming sv1 msg: {:?}", error);
                         break;
                     };
+                    if is_authorize {
+                        if let Err(e) = sessions.start_join_worker_channel(downstream.clone()).await
+                        {
+                            error!("{}", e);
+                            break;
+                        }
+                    }
                 } else {
-                    // Message received could not be converted to rpc message
-                    error!(
-                        "{}",
+                    // Message received could not be converted to rpc message, there is no id to
+                    // answer to
+                    malformed_lines += 1;
+                    warn!(
+                        "Downstream {}: {}",
+                        connection_id,
                         Error::V1Protocol(Box::new(
                             sv1_api::error::Error::InvalidJsonRpcMessageKind
                         ))
                     );
-                    return;
+                    if malformed_lines >= MAX_MALFORMED_LINES {
+                        error!(
+                            "Downstream {} sent {} malformed lines in a row",
+                            connection_id, malformed_lines
+                        );
+                        break;
+                    }
                 }
             }
+            if let Err(e) = sessions.remove_downstream(connection_id) {
+                error!("Failed to remove downstream {}: {}", connection_id, e);
+            }
             if let Ok(stats_sender) = downstream.safe_lock(|d| d.stats_sender.clone()) {
                 stats_sender.remove_stats(connection_id);
             }
@@ -63,21 +105,28 @@ pub async fn start_receive_downstream(
                 error!("Failed to remove downstream hashrate from channel: {}", e)
             };
 
-            let (worker_name, user_agent) = downstream
+            let (worker_name, user_agent, reason) = downstream
                 .safe_lock(|d| {
                     (
-                        d.authorized_names.first().cloned().unwrap_or_default(),
+                        d.authorized_names
+                            .first()
+                            .map(|name| d.profile.worker_name(name))
+                            .unwrap_or_default(),
                         d.user_agent.borrow().clone(),
+                        d.disconnect_reason,
                     )
                 })
                 .unwrap_or_else(|e| {
                     error!("Failed to lock downstream: {:?}", e);
                     ProxyState::update_inconsistency(Some(1));
-                    ("unknown".to_string(), "unknown".to_string())
+                    ("unknown".to_string(), "unknown".to_string(), None)
                 });
 
-            let worker_activity =
+            let mut worker_activity =
                 WorkerActivity::new(user_agent, worker_name, WorkerActivityType::Disconnected);
+            if let Some(reason) = reason {
+                worker_activity = worker_activity.with_reason(reason);
+            }
 
             worker_activity
                 .monitor_api()
@@ -0,0 +1,390 @@
+use super::{
+    accept_connection::start_accept_connection, downstream::Downstream,
+    sv2_downstream::start_accept_sv2_connection, task_manager::TaskManager, ChannelNotify,
+    DownstreamMessages,
+};
+use crate::{
+    config::PortProfile,
+    shared::{hashrate::HashrateMeter, utils::AbortOnDrop},
+    translator::{
+        error::{Error, ProxyResult},
+        proxy::Bridge,
+        upstream::diff_management::UpstreamDifficultyConfig,
+        worker_channels::WorkerChannels,
+    },
+};
+use roles_logic_sv2::{
+    parsers::Mining,
+    utils::{Id, Mutex},
+};
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::{Arc, Weak},
+};
+use tokio::sync::{
+    broadcast,
+    mpsc::{Receiver, Sender},
+    watch,
+};
+use tracing::{error, info, warn};
+
+type BridgeHandle = (Arc<Mutex<Bridge>>, Sender<DownstreamMessages>);
+
+/// Holds everything that belongs to the SV1 side of the translator: the task that accepts new
+/// miners, every live `Downstream` and the channels they listen on. It outlives the upstream
+/// connection so that when the pool (or the TP) changes, miners stay connected and are moved to
+/// the new `Bridge` with `mining.set_extranonce`, `mining.set_difficulty` and a clean
+/// `mining.notify` instead of being disconnected.
+///
+/// Native SV2 downstreams are accepted here too. They get their jobs from `tx_sv2_jobs` and are
+/// disconnected when the upstream changes.
+///
+/// With worker channels an authorized miner is moved from the shared channel to the channel of
+/// its worker name, miners that can not change extranonce stay on the shared channel.
+#[derive(Clone)]
+pub struct DownstreamSessions {
+    tx_sv1_notify: broadcast::Sender<ChannelNotify>,
+    tx_sv2_jobs: broadcast::Sender<Mining<'static>>,
+    /// Incremented every time the upstream goes away.
+    upstream_changes: Arc<watch::Sender<u64>>,
+    upstream_difficulty_config: Arc<Mutex<UpstreamDifficultyConfig>>,
+    bridge: Arc<Mutex<Option<BridgeHandle>>>,
+    /// Owned by the translator of the current upstream, they go away with it.
+    worker_channels: Arc<Mutex<Weak<WorkerChannels>>>,
+    downstreams: Arc<Mutex<HashMap<u32, Arc<Mutex<Downstream>>>>>,
+    connection_ids: Arc<Mutex<Id>>,
+    stats_sender: crate::api::stats::StatsSender,
+}
+
+impl DownstreamSessions {
+    /// Starts accepting SV1 and SV2 connections. Miners that connect before a `Bridge` is
+    /// available are held until `set_bridge` is called.
+    pub async fn start(
+        downstreams: Receiver<(Sender<String>, Receiver<String>, IpAddr, Arc<PortProfile>)>,
+        sv2_downstreams: Receiver<(
+            Sender<Mining<'static>>,
+            Receiver<Mining<'static>>,
+            IpAddr,
+            Arc<PortProfile>,
+        )>,
+        stats_sender: crate::api::stats::StatsSender,
+    ) -> Result<(Self, AbortOnDrop), Error<'static>> {
+        let task_manager = TaskManager::initialize();
+        let abortable = task_manager
+            .safe_lock(|t| t.get_aborter())
+            .map_err(|_| Error::TranslatorTaskManagerMutexPoisoned)?
+            .ok_or(Error::TranslatorTaskManagerFailed)?;
+
+        let (tx_sv1_notify, _) = broadcast::channel(crate::TRANSLATOR_BUFFER_SIZE);
+        let (tx_sv2_jobs, _) = broadcast::channel(crate::TRANSLATOR_BUFFER_SIZE);
+        let upstream_difficulty_config = Arc::new(Mutex::new(UpstreamDifficultyConfig {
+            channel_diff_update_interval: crate::CHANNEL_DIFF_UPDTATE_INTERVAL,
+            channel_nominal_hashrate: 0.0,
+            hashrate: HashrateMeter::default(),
+        }));
+        let sessions = Self {
+            tx_sv1_notify,
+            tx_sv2_jobs,
+            upstream_changes: Arc::new(watch::channel(0).0),
+            upstream_difficulty_config,
+            bridge: Arc::new(Mutex::new(None)),
+            worker_channels: Arc::new(Mutex::new(Weak::new())),
+            downstreams: Arc::new(Mutex::new(HashMap::new())),
+            connection_ids: Arc::new(Mutex::new(Id::new())),
+            stats_sender: stats_sender.clone(),
+        };
+        start_accept_connection(
+            task_manager.clone(),
+            sessions.clone(),
+            downstreams,
+            stats_sender,
+        )
+        .await?;
+        start_accept_sv2_connection(task_manager, sessions.clone(), sv2_downstreams).await?;
+        Ok((sessions, abortable))
+    }
+
+    pub fn tx_sv1_notify(&self) -> broadcast::Sender<ChannelNotify> {
+        self.tx_sv1_notify.clone()
+    }
+
+    pub fn tx_sv2_jobs(&self) -> broadcast::Sender<Mining<'static>> {
+        self.tx_sv2_jobs.clone()
+    }
+
+    pub fn stats_sender(&self) -> crate::api::stats::StatsSender {
+        self.stats_sender.clone()
+    }
+
+    pub(super) fn upstream_changes(&self) -> watch::Receiver<u64> {
+        self.upstream_changes.subscribe()
+    }
+
+    pub fn upstream_difficulty_config(&self) -> Arc<Mutex<UpstreamDifficultyConfig>> {
+        self.upstream_difficulty_config.clone()
+    }
+
+    pub(super) fn next_connection_id(&self) -> ProxyResult<'static, u32> {
+        Ok(self.connection_ids.safe_lock(|ids| ids.next())?)
+    }
+
+    /// Called when the upstream goes away. New miners wait for the next `Bridge`, connected ones
+    /// keep working on their current job.
+    pub fn clear_bridge(&self) -> ProxyResult<'static, ()> {
+        self.bridge.safe_lock(|b| *b = None)?;
+        self.worker_channels.safe_lock(|c| *c = Weak::new())?;
+        // SV2 channels can not be moved, their downstreams reconnect to the new upstream
+        self.upstream_changes.send_modify(|changes| *changes += 1);
+        let downstreams: Vec<Arc<Mutex<Downstream>>> = self
+            .downstreams
+            .safe_lock(|d| d.values().cloned().collect())?;
+        for downstream in downstreams {
+            downstream.safe_lock(|d| d.waiting_for_upstream = true)?;
+        }
+        Ok(())
+    }
+
+    /// Returns the current `Bridge`, waiting for one to be set if the upstream is being replaced.
+    pub(super) async fn wait_for_bridge(&self) -> ProxyResult<'static, BridgeHandle> {
+        loop {
+            if let Some(bridge) = self.bridge.safe_lock(|b| b.clone())? {
+                return Ok(bridge);
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        }
+    }
+
+    /// Sets the `Bridge` of a new upstream and moves every connected miner to it. Miners of a
+    /// worker channel then move on to the channel of the new upstream.
+    pub async fn set_bridge(
+        &self,
+        bridge: Arc<Mutex<Bridge>>,
+        tx_sv1_bridge: Sender<DownstreamMessages>,
+    ) -> ProxyResult<'static, ()> {
+        Bridge::ready(&bridge)
+            .await
+            .map_err(|_| Error::BridgeMutexPoisoned)?;
+        self.bridge
+            .safe_lock(|b| *b = Some((bridge.clone(), tx_sv1_bridge.clone())))?;
+        let downstreams: Vec<Arc<Mutex<Downstream>>> = self
+            .downstreams
+            .safe_lock(|d| d.values().cloned().collect())?;
+        self.move_downstreams(downstreams.clone(), &bridge, tx_sv1_bridge)
+            .await?;
+        for downstream in downstreams {
+            self.start_join_worker_channel(downstream).await?;
+        }
+        Ok(())
+    }
+
+    /// Called when the pool changed the extranonce prefix of a channel, after its `Bridge` got
+    /// the new extranonce. Every miner of the channel gets a channel with the new extranonce.
+    pub async fn on_new_extranonce(
+        &self,
+        bridge: &Arc<Mutex<Bridge>>,
+        tx_sv1_bridge: Sender<DownstreamMessages>,
+    ) -> ProxyResult<'static, ()> {
+        let is_shared = match self.bridge.safe_lock(|b| b.clone())? {
+            Some((shared, _)) => Arc::ptr_eq(&shared, bridge),
+            // Downstreams are moved when the `Bridge` is set
+            None => return Ok(()),
+        };
+        if is_shared {
+            // SV2 channels are not moved either, their downstreams reconnect
+            self.upstream_changes.send_modify(|changes| *changes += 1);
+        }
+        let channel_id = bridge
+            .safe_lock(|b| b.channel_id())
+            .map_err(|_| Error::BridgeMutexPoisoned)?;
+        let downstreams: Vec<Arc<Mutex<Downstream>>> = self.downstreams.safe_lock(|d| {
+            d.values()
+                .filter(|d| {
+                    d.safe_lock(|d| d.upstream_channel_id == channel_id)
+                        .unwrap_or(false)
+                })
+                .cloned()
+                .collect()
+        })?;
+        self.move_downstreams(downstreams, bridge, tx_sv1_bridge)
+            .await
+    }
+
+    async fn move_downstreams(
+        &self,
+        downstreams: Vec<Arc<Mutex<Downstream>>>,
+        bridge: &Arc<Mutex<Bridge>>,
+        tx_sv1_bridge: Sender<DownstreamMessages>,
+    ) -> ProxyResult<'static, ()> {
+        if !downstreams.is_empty() {
+            info!("Moving {} downstreams to a new channel", downstreams.len());
+        }
+        let is_shared = self.bridge.safe_lock(|b| {
+            b.as_ref()
+                .is_some_and(|(shared, _)| Arc::ptr_eq(shared, bridge))
+        })?;
+        for downstream in downstreams {
+            if is_shared {
+                // Back from the worker channel of the previous upstream
+                Downstream::move_hashrate_to_channel(
+                    &downstream,
+                    &self.upstream_difficulty_config,
+                )?;
+            }
+            if let Err(e) =
+                Downstream::switch_upstream(&downstream, bridge, tx_sv1_bridge.clone()).await
+            {
+                // Its shares would go to a channel that is gone
+                error!("Failed to move downstream to a new channel, disconnecting it: {e}");
+                downstream.safe_lock(|d| d.disconnect.notify_one())?;
+            }
+        }
+        Ok(())
+    }
+
+    pub(crate) fn set_worker_channels(
+        &self,
+        worker_channels: Weak<WorkerChannels>,
+    ) -> ProxyResult<'static, ()> {
+        self.worker_channels.safe_lock(|c| *c = worker_channels)?;
+        Ok(())
+    }
+
+    /// Moves an authorized miner to the channel of its worker name in the background, opening
+    /// the channel if it is the first miner of the worker. Meanwhile it mines on its current
+    /// channel.
+    pub(super) async fn start_join_worker_channel(
+        &self,
+        downstream: Arc<Mutex<Downstream>>,
+    ) -> ProxyResult<'static, ()> {
+        let (task_manager, connection_id) =
+            downstream.safe_lock(|d| (d.task_manager.clone(), d.connection_id))?;
+        let sessions = self.clone();
+        let handle = tokio::spawn(async move {
+            if let Err(e) = sessions.join_worker_channel(&downstream).await {
+                error!("Failed to move downstream to its worker channel: {e}");
+            }
+        });
+        if let Some(task_manager) = task_manager {
+            TaskManager::add_join_worker_channel(task_manager, handle.into(), connection_id)
+                .await
+                .map_err(|_| Error::TranslatorTaskManagerFailed)?;
+        }
+        Ok(())
+    }
+
+    /// Called when the pool closed the channel of a worker. Its miners go back to the shared
+    /// channel, they get a new worker channel when they authorize again.
+    pub(crate) async fn on_worker_channel_closed(
+        &self,
+        identity: &str,
+    ) -> ProxyResult<'static, ()> {
+        let Some(worker_channels) = self.worker_channels.safe_lock(|c| c.upgrade())? else {
+            return Ok(());
+        };
+        let Some((bridge, tx_sv1_bridge)) = self.bridge.safe_lock(|b| b.clone())? else {
+            // Moved when the `Bridge` is set
+            return Ok(());
+        };
+        let miners = worker_channels.miners(identity)?;
+        let downstreams: Vec<Arc<Mutex<Downstream>>> = self
+            .downstreams
+            .safe_lock(|d| miners.iter().filter_map(|id| d.get(id).cloned()).collect())?;
+        self.move_downstreams(downstreams, &bridge, tx_sv1_bridge)
+            .await?;
+        // Last, this task is one of the tasks of the channel
+        worker_channels.remove(identity)
+    }
+
+    async fn join_worker_channel(
+        &self,
+        downstream: &Arc<Mutex<Downstream>>,
+    ) -> ProxyResult<'static, ()> {
+        let Some(worker_channels) = self.worker_channels.safe_lock(|c| c.upgrade())? else {
+            return Ok(());
+        };
+        let (connection_id, worker_name, supports_set_extranonce) = downstream.safe_lock(|d| {
+            (
+                d.connection_id,
+                d.authorized_names
+                    .first()
+                    .map(|name| d.profile.worker_name(name)),
+                d.supports_set_extranonce(),
+            )
+        })?;
+        let Some(identity) = worker_name.and_then(|name| worker_channels.identity(&name)) else {
+            return Ok(());
+        };
+        if !supports_set_extranonce {
+            info!(
+                "Downstream {} can not change extranonce, it stays on the shared channel",
+                connection_id
+            );
+            return Ok(());
+        }
+
+        let channel = worker_channels.enter(&identity, connection_id)?;
+        // In the channel before it is opened, so that it is opened with the miner hashrate
+        Downstream::move_hashrate_to_channel(downstream, &channel.difficulty_config)?;
+        let (bridge, tx_sv1_bridge) = match worker_channels.open(&channel).await {
+            Ok(opened) => opened,
+            Err(e) => {
+                warn!(
+                    "Downstream {} stays on the shared channel, no channel for {}: {e}",
+                    connection_id, identity
+                );
+                Downstream::move_hashrate_to_channel(downstream, &self.upstream_difficulty_config)?;
+                worker_channels.leave(connection_id)?;
+                return Ok(());
+            }
+        };
+        if !self
+            .downstreams
+            .safe_lock(|d| d.contains_key(&connection_id))?
+        {
+            // Disconnected while the channel was being opened
+            worker_channels.leave(connection_id)?;
+            return Ok(());
+        }
+        let channel_id = bridge
+            .safe_lock(|b| b.channel_id())
+            .map_err(|_| Error::BridgeMutexPoisoned)?;
+        if downstream.safe_lock(|d| d.upstream_channel_id == channel_id)? {
+            // Authorized again with the same worker
+            return Ok(());
+        }
+        info!(
+            "Moving downstream {} to the channel of {}",
+            connection_id, identity
+        );
+        Downstream::switch_upstream(downstream, &bridge, tx_sv1_bridge).await
+    }
+
+    /// Registers a newly opened `Downstream`. If the `Bridge` it was opened on has been replaced
+    /// in the meantime it is moved to the current one.
+    pub(super) async fn add_downstream(
+        &self,
+        connection_id: u32,
+        downstream: Arc<Mutex<Downstream>>,
+        opened_on: &Arc<Mutex<Bridge>>,
+    ) -> ProxyResult<'static, ()> {
+        self.downstreams
+            .safe_lock(|d| d.insert(connection_id, downstream.clone()))?;
+        match self.bridge.safe_lock(|b| b.clone())? {
+            Some((bridge, tx_sv1_bridge)) if !Arc::ptr_eq(&bridge, opened_on) => {
+                Downstream::switch_upstream(&downstream, &bridge, tx_sv1_bridge).await?;
+            }
+            Some(_) => (),
+            None => downstream.safe_lock(|d| d.waiting_for_upstream = true)?,
+        }
+        Ok(())
+    }
+
+    pub(super) fn remove_downstream(&self, connection_id: u32) -> ProxyResult<'static, ()> {
+        self.downstreams.safe_lock(|d| d.remove(&connection_id))?;
+        if let Some(worker_channels) = self.worker_channels.safe_lock(|c| c.upgrade())? {
+            worker_channels.leave(connection_id)?;
+        }
+        Ok(())
+    }
+}
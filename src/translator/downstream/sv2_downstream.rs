@@ -0,0 +1,503 @@
+use super::{
+    diff_management::sv2_target_to_difficulty, sessions::DownstreamSessions,
+    task_manager::TaskManager, DownstreamMessages, SetDownstreamTarget, SubmitSv2Share,
+};
+use crate::{
+    config::PortProfile,
+    translator::{
+        error::{Error, ProxyResult},
+        proxy::{bridge::OpenSv2Channel, Bridge},
+        upstream::diff_management::UpstreamDifficultyConfig,
+    },
+};
+use binary_sv2::Sv2Option;
+use roles_logic_sv2::{
+    mining_sv2::{
+        NewExtendedMiningJob, NewMiningJob, OpenExtendedMiningChannelSuccess,
+        OpenMiningChannelError, OpenStandardMiningChannelSuccess, SetNewPrevHash, SetTarget,
+        SubmitSharesError, SubmitSharesExtended, UpdateChannel,
+    },
+    parsers::Mining,
+    utils::{hash_rate_to_target, merkle_root_from_path, Mutex},
+};
+use std::{collections::HashMap, net::IpAddr, sync::Arc};
+use tokio::{
+    sync::{
+        broadcast,
+        mpsc::{Receiver, Sender},
+        watch,
+    },
+    task,
+};
+use tracing::{debug, error, info, warn};
+
+/// Accepts the connections of the SV2 listener. Every connection waits for a `Bridge` and then
+/// lives in its own task until the downstream disconnects or the upstream changes.
+pub async fn start_accept_sv2_connection(
+    task_manager: Arc<Mutex<TaskManager>>,
+    sessions: DownstreamSessions,
+    mut downstreams: Receiver<(
+        Sender<Mining<'static>>,
+        Receiver<Mining<'static>>,
+        IpAddr,
+        Arc<PortProfile>,
+    )>,
+) -> Result<(), Error<'static>> {
+    let handle = {
+        let task_manager = task_manager.clone();
+        task::spawn(async move {
+            while let Some((send, recv, addr, profile)) = downstreams.recv().await {
+                let connection_id = match sessions.next_connection_id() {
+                    Ok(id) => id,
+                    Err(e) => {
+                        error!("{e}");
+                        break;
+                    }
+                };
+                info!(
+                    "Translator opening SV2 connection for ip {} with id {}",
+                    addr, connection_id
+                );
+                let kill_signal = match task_manager.safe_lock(|t| t.send_kill_signal.clone()) {
+                    Ok(kill_signal) => kill_signal,
+                    Err(e) => {
+                        error!("Translator task manager Mutex corrupted: {e}");
+                        break;
+                    }
+                };
+                let sessions = sessions.clone();
+                let handle = task::spawn(async move {
+                    Sv2Downstream::run(sessions, send, recv, addr, profile).await;
+                    // Drops the handle of this task, it is done anyway
+                    let _ = kill_signal.send(connection_id).await;
+                });
+                if TaskManager::add_receive_downstream(
+                    task_manager.clone(),
+                    handle.into(),
+                    connection_id,
+                )
+                .await
+                .is_err()
+                {
+                    error!("Failed to add SV2 downstream task {}", connection_id);
+                    break;
+                }
+            }
+        })
+    };
+    TaskManager::add_accept_connection(task_manager, handle.into())
+        .await
+        .map_err(|_| Error::TranslatorTaskManagerFailed)
+}
+
+/// Channel opened by a native SV2 downstream. Both kinds are extended channels in the channel
+/// factory: for a standard channel the proxy fills the extranonce with zeros and sends the
+/// merkle root of the resulting coinbase.
+#[derive(Debug)]
+struct Sv2Channel {
+    standard: bool,
+    extranonce_prefix: Vec<u8>,
+    extranonce_size: usize,
+    hash_rate: f32,
+    /// Last target sent to the downstream.
+    target: Vec<u8>,
+}
+
+impl Sv2Channel {
+    /// Extranonce used by the proxy on behalf of a standard channel.
+    fn standard_extranonce(&self) -> Vec<u8> {
+        vec![0; self.extranonce_size]
+    }
+
+    fn job(&self, channel_id: u32, job: &NewExtendedMiningJob<'static>) -> Option<Mining<'static>> {
+        let mut job = job.clone();
+        job.channel_id = channel_id;
+        if !self.standard {
+            return Some(Mining::NewExtendedMiningJob(job));
+        }
+        let mut extranonce = self.extranonce_prefix.clone();
+        extranonce.extend_from_slice(&self.standard_extranonce());
+        let merkle_root = merkle_root_from_path(
+            &job.coinbase_tx_prefix.to_vec(),
+            &job.coinbase_tx_suffix.to_vec(),
+            &extranonce,
+            &job.merkle_path.to_vec(),
+        )?;
+        Some(Mining::NewMiningJob(NewMiningJob {
+            channel_id,
+            job_id: job.job_id,
+            min_ntime: job.min_ntime.clone(),
+            version: job.version,
+            merkle_root: merkle_root.try_into().ok()?,
+        }))
+    }
+}
+
+struct Sv2Downstream {
+    address: IpAddr,
+    sender: Sender<Mining<'static>>,
+    channels: HashMap<u32, Sv2Channel>,
+    bridge: Arc<Mutex<Bridge>>,
+    tx_bridge: Sender<DownstreamMessages>,
+    upstream_difficulty_config: Arc<Mutex<UpstreamDifficultyConfig>>,
+    /// Profile of the SV2 listener, sets the share rate of the channels.
+    profile: Arc<PortProfile>,
+}
+
+impl Sv2Downstream {
+    async fn run(
+        sessions: DownstreamSessions,
+        sender: Sender<Mining<'static>>,
+        mut receiver: Receiver<Mining<'static>>,
+        address: IpAddr,
+        profile: Arc<PortProfile>,
+    ) {
+        // If the upstream is being replaced the downstream waits here for the new `Bridge`
+        let (bridge, tx_bridge) = match sessions.wait_for_bridge().await {
+            Ok(bridge) => bridge,
+            Err(e) => {
+                error!("{e}");
+                return;
+            }
+        };
+        let mut upstream_changes: watch::Receiver<u64> = sessions.upstream_changes();
+        let mut jobs = sessions.tx_sv2_jobs().subscribe();
+        let mut self_ = Self {
+            address,
+            sender,
+            channels: HashMap::new(),
+            bridge,
+            tx_bridge,
+            upstream_difficulty_config: sessions.upstream_difficulty_config(),
+            profile,
+        };
+        loop {
+            let result = tokio::select! {
+                message = receiver.recv() => match message {
+                    Some(message) => self_.on_downstream_message(message).await,
+                    None => break,
+                },
+                job = jobs.recv() => match job {
+                    Ok(job) => self_.on_upstream_job(job).await,
+                    Err(broadcast::error::RecvError::Lagged(n)) => {
+                        warn!("SV2 downstream {} missed {} jobs", address, n);
+                        Ok(())
+                    }
+                    Err(broadcast::error::RecvError::Closed) => break,
+                },
+                _ = upstream_changes.changed() => {
+                    info!(
+                        "Upstream changed, disconnecting SV2 downstream {} so that it reopens its channels",
+                        address
+                    );
+                    break;
+                }
+            };
+            if let Err(e) = result {
+                error!("SV2 downstream {} failed: {e}", address);
+                break;
+            }
+        }
+        if let Err(e) = self_.remove_hash_rate() {
+            error!("{e}");
+        }
+        info!("SV2 downstream {} closed", address);
+    }
+
+    async fn on_downstream_message(
+        &mut self,
+        message: Mining<'static>,
+    ) -> ProxyResult<'static, ()> {
+        match message {
+            Mining::OpenStandardMiningChannel(m) => {
+                let request_id = m.get_request_id_as_u32();
+                match self.open_channel(request_id, m.nominal_hash_rate, 0, true)? {
+                    Some((success, last_job)) => {
+                        let Ok(extranonce_prefix) = success.extranonce_prefix.to_vec().try_into()
+                        else {
+                            // A standard channel has no extranonce, the prefix must fit in 32 bytes
+                            warn!(
+                                "Extranonce prefix of channel {} too long for a standard channel",
+                                success.channel_id
+                            );
+                            self.close_channel(success.channel_id)?;
+                            return self.send_open_channel_error(request_id).await;
+                        };
+                        self.send(Mining::OpenStandardMiningChannelSuccess(
+                            OpenStandardMiningChannelSuccess {
+                                request_id: m.request_id,
+                                channel_id: success.channel_id,
+                                target: success.target.clone(),
+                                extranonce_prefix,
+                                group_channel_id: 0,
+                            },
+                        ))
+                        .await?;
+                        self.send_initial_job(success.channel_id, last_job).await
+                    }
+                    None => self.send_open_channel_error(request_id).await,
+                }
+            }
+            Mining::OpenExtendedMiningChannel(m) => {
+                let request_id = m.request_id;
+                match self.open_channel(
+                    request_id,
+                    m.nominal_hash_rate,
+                    m.min_extranonce_size,
+                    false,
+                )? {
+                    Some((success, last_job)) => {
+                        let channel_id = success.channel_id;
+                        self.send(Mining::OpenExtendedMiningChannelSuccess(success))
+                            .await?;
+                        self.send_initial_job(channel_id, last_job).await
+                    }
+                    None => self.send_open_channel_error(request_id).await,
+                }
+            }
+            Mining::SubmitSharesExtended(m) => match self.channels.get(&m.channel_id) {
+                Some(channel) if !channel.standard => self.submit(m).await,
+                _ => {
+                    self.send_share_error(m.channel_id, m.sequence_number, "invalid-channel-id")
+                        .await
+                }
+            },
+            Mining::SubmitSharesStandard(m) => match self.channels.get(&m.channel_id) {
+                Some(channel) if channel.standard => {
+                    let Ok(extranonce) = channel.standard_extranonce().try_into() else {
+                        return self
+                            .send_share_error(m.channel_id, m.sequence_number, "invalid-extranonce")
+                            .await;
+                    };
+                    let share = SubmitSharesExtended {
+                        channel_id: m.channel_id,
+                        sequence_number: m.sequence_number,
+                        job_id: m.job_id,
+                        nonce: m.nonce,
+                        ntime: m.ntime,
+                        version: m.version,
+                        extranonce,
+                    };
+                    self.submit(share).await
+                }
+                _ => {
+                    self.send_share_error(m.channel_id, m.sequence_number, "invalid-channel-id")
+                        .await
+                }
+            },
+            Mining::UpdateChannel(m) => self.update_channel(m).await,
+            Mining::CloseChannel(m) => {
+                info!(
+                    "SV2 downstream {} closed channel {}",
+                    self.address, m.channel_id
+                );
+                self.close_channel(m.channel_id)
+            }
+            message => {
+                warn!(
+                    "SV2 downstream {} sent an unsupported message: {:?}",
+                    self.address, message
+                );
+                Ok(())
+            }
+        }
+    }
+
+    /// Opens an extended channel in the factory of the `Bridge`. Returns `None` if the factory
+    /// can not open it.
+    fn open_channel(
+        &mut self,
+        request_id: u32,
+        hash_rate: f32,
+        min_extranonce_size: u16,
+        standard: bool,
+    ) -> ProxyResult<'static, Option<(OpenExtendedMiningChannelSuccess<'static>, Option<Job>)>>
+    {
+        let share_per_min = self.profile.share_per_min;
+        let opened = self
+            .bridge
+            .safe_lock(|b| {
+                b.on_new_sv2_channel(request_id, hash_rate, min_extranonce_size, share_per_min)
+            })
+            .map_err(|_| Error::BridgeMutexPoisoned)?;
+        let OpenSv2Channel {
+            success,
+            last_job,
+            last_p_hash,
+        } = match opened {
+            Ok(opened) => opened,
+            Err(e) => {
+                error!("Impossible to open channel for {}: {e}", self.address);
+                return Ok(None);
+            }
+        };
+        info!(
+            "SV2 downstream {} opened {} channel {} with hash rate {}",
+            self.address,
+            if standard { "standard" } else { "extended" },
+            success.channel_id,
+            hash_rate
+        );
+        self.channels.insert(
+            success.channel_id,
+            Sv2Channel {
+                standard,
+                extranonce_prefix: success.extranonce_prefix.to_vec(),
+                extranonce_size: success.extranonce_size as usize,
+                hash_rate,
+                target: success.target.to_vec(),
+            },
+        );
+        self.update_hash_rate(hash_rate)?;
+        Ok(Some((success, last_job.zip(last_p_hash))))
+    }
+
+    /// Forgets a channel and removes its hash rate from the upstream channel.
+    fn close_channel(&mut self, channel_id: u32) -> ProxyResult<'static, ()> {
+        match self.channels.remove(&channel_id) {
+            Some(channel) => self.update_hash_rate(-channel.hash_rate),
+            None => Ok(()),
+        }
+    }
+
+    /// Sends the job the upstream is mining on to a new channel, as a future job activated by a
+    /// `SetNewPrevHash`.
+    async fn send_initial_job(
+        &self,
+        channel_id: u32,
+        last_job: Option<Job>,
+    ) -> ProxyResult<'static, ()> {
+        let Some((mut job, mut prev_hash)) = last_job else {
+            debug!("No job yet for channel {}", channel_id);
+            return Ok(());
+        };
+        job.min_ntime = Sv2Option::new(None);
+        prev_hash.channel_id = channel_id;
+        prev_hash.job_id = job.job_id;
+        let Some(job) = self
+            .channels
+            .get(&channel_id)
+            .and_then(|c| c.job(channel_id, &job))
+        else {
+            error!("Impossible to create job for channel {}", channel_id);
+            return Ok(());
+        };
+        self.send(job).await?;
+        self.send(Mining::SetNewPrevHash(prev_hash)).await
+    }
+
+    async fn send_open_channel_error(&self, request_id: u32) -> ProxyResult<'static, ()> {
+        self.send(Mining::OpenMiningChannelError(OpenMiningChannelError {
+            request_id,
+            error_code: "unknown-user".to_string().try_into().expect("Internal error: this operation can not fail because the error code can always be converted into Inner"),
+        }))
+        .await
+    }
+
+    async fn send_share_error(
+        &self,
+        channel_id: u32,
+        sequence_number: u32,
+        error_code: &str,
+    ) -> ProxyResult<'static, ()> {
+        self.send(Mining::SubmitSharesError(SubmitSharesError {
+            channel_id,
+            sequence_number,
+            error_code: error_code.to_string().try_into().expect("Internal error: this operation can not fail because the error codes can always be converted into Inner"),
+        }))
+        .await
+    }
+
+    async fn submit(&self, share: SubmitSharesExtended<'static>) -> ProxyResult<'static, ()> {
+        // Counted in the hashrate of the channel by the `Bridge` if it is valid
+        let difficulty = self
+            .channels
+            .get(&share.channel_id)
+            .map_or(0.0, |c| sv2_target_to_difficulty(&c.target));
+        self.tx_bridge
+            .send(DownstreamMessages::SubmitSharesSv2(SubmitSv2Share {
+                share,
+                difficulty,
+                upstream_difficulty_config: self.upstream_difficulty_config.clone(),
+                reply: self.sender.clone(),
+            }))
+            .await?;
+        Ok(())
+    }
+
+    /// Sets the target of the channel from the new nominal hash rate of the downstream.
+    async fn update_channel(&mut self, m: UpdateChannel<'static>) -> ProxyResult<'static, ()> {
+        let Some(channel) = self.channels.get_mut(&m.channel_id) else {
+            warn!(
+                "SV2 downstream {} updated unknown channel {}",
+                self.address, m.channel_id
+            );
+            return Ok(());
+        };
+        let delta = m.nominal_hash_rate - channel.hash_rate;
+        channel.hash_rate = m.nominal_hash_rate;
+        let target = hash_rate_to_target(
+            m.nominal_hash_rate as f64,
+            self.profile.share_per_min as f64,
+        )?;
+        channel.target = target.to_vec();
+        self.update_hash_rate(delta)?;
+        self.tx_bridge
+            .send(DownstreamMessages::SetDownstreamTarget(
+                SetDownstreamTarget {
+                    channel_id: m.channel_id,
+                    new_target: target.clone().into(),
+                },
+            ))
+            .await?;
+        self.send(Mining::SetTarget(SetTarget {
+            channel_id: m.channel_id,
+            maximum_target: target,
+        }))
+        .await
+    }
+
+    /// Forwards a job or a prev hash of the upstream on every channel.
+    async fn on_upstream_job(&self, message: Mining<'static>) -> ProxyResult<'static, ()> {
+        for (channel_id, channel) in &self.channels {
+            let message = match &message {
+                Mining::NewExtendedMiningJob(job) => match channel.job(*channel_id, job) {
+                    Some(job) => job,
+                    None => {
+                        error!("Impossible to create job for channel {}", channel_id);
+                        continue;
+                    }
+                },
+                Mining::SetNewPrevHash(prev_hash) => {
+                    let mut prev_hash: SetNewPrevHash<'static> = prev_hash.clone();
+                    prev_hash.channel_id = *channel_id;
+                    Mining::SetNewPrevHash(prev_hash)
+                }
+                _ => continue,
+            };
+            self.send(message).await?;
+        }
+        Ok(())
+    }
+
+    async fn send(&self, message: Mining<'static>) -> ProxyResult<'static, ()> {
+        self.sender.send(message).await?;
+        Ok(())
+    }
+
+    /// The upstream channel is opened with the hash rate of every downstream, SV1 and SV2.
+    fn update_hash_rate(&self, delta: f32) -> ProxyResult<'static, ()> {
+        self.upstream_difficulty_config
+            .safe_lock(|c| {
+                c.channel_nominal_hashrate = f32::max(c.channel_nominal_hashrate + delta, 0.0);
+            })
+            .map_err(|_| Error::TranslatorDiffConfigMutexPoisoned)
+    }
+
+    fn remove_hash_rate(&self) -> ProxyResult<'static, ()> {
+        let hash_rate: f32 = self.channels.values().map(|c| c.hash_rate).sum();
+        self.update_hash_rate(-hash_rate)
+    }
+}
+
+type Job = (NewExtendedMiningJob<'static>, SetNewPrevHash<'static>);
@@ -13,6 +13,9 @@ enum Task {
     Notify(AbortOnDrop),
     Update(AbortOnDrop),
     SharesMonitor(AbortOnDrop),
+    HealthMonitor(AbortOnDrop),
+    ShareVerdict(AbortOnDrop),
+    JoinWorkerChannel(AbortOnDrop),
 }
 
 type TaskMessage = (Option<u32>, Task);
@@ -44,6 +47,8 @@ impl TaskManager {
                     .safe_lock(|tasks| {
                         let tasks_list: &mut Vec<AbortOnDrop> =
                             tasks.entry(connection_id).or_default();
+                        // Verdict tasks end long before the miner disconnects
+                        tasks_list.retain(|task| !task.is_finished());
                         tasks_list.push(task.into());
                     })
                     .is_err()
@@ -148,10 +153,47 @@ impl TaskManager {
     pub async fn add_shares_monitor(
         self_: Arc<Mutex<Self>>,
         abortable: AbortOnDrop,
+        connection_id: u32,
+    ) -> Result<(), ()> {
+        let send_task = self_.safe_lock(|s| s.send_task.clone()).unwrap();
+        send_task
+            .send((Some(connection_id), Task::SharesMonitor(abortable)))
+            .await
+            .map_err(|_| ())
+    }
+
+    pub async fn add_health_monitor(
+        self_: Arc<Mutex<Self>>,
+        abortable: AbortOnDrop,
+        connection_id: u32,
+    ) -> Result<(), ()> {
+        let send_task = self_.safe_lock(|s| s.send_task.clone()).unwrap();
+        send_task
+            .send((Some(connection_id), Task::HealthMonitor(abortable)))
+            .await
+            .map_err(|_| ())
+    }
+
+    pub async fn add_share_verdict(
+        self_: Arc<Mutex<Self>>,
+        abortable: AbortOnDrop,
+        connection_id: u32,
+    ) -> Result<(), ()> {
+        let send_task = self_.safe_lock(|s| s.send_task.clone()).unwrap();
+        send_task
+            .send((Some(connection_id), Task::ShareVerdict(abortable)))
+            .await
+            .map_err(|_| ())
+    }
+
+    pub async fn add_join_worker_channel(
+        self_: Arc<Mutex<Self>>,
+        abortable: AbortOnDrop,
+        connection_id: u32,
     ) -> Result<(), ()> {
         let send_task = self_.safe_lock(|s| s.send_task.clone()).unwrap();
         send_task
-            .send((None, Task::SharesMonitor(abortable)))
+            .send((Some(connection_id), Task::JoinWorkerChannel(abortable)))
             .await
             .map_err(|_| ())
     }
@@ -166,6 +208,9 @@ impl From<Task> for AbortOnDrop {
             Task::Notify(handle) => handle,
             Task::Update(handle) => handle,
             Task::SharesMonitor(handle) => handle,
+            Task::HealthMonitor(handle) => handle,
+            Task::ShareVerdict(handle) => handle,
+            Task::JoinWorkerChannel(handle) => handle,
         }
     }
 }
@@ -0,0 +1,158 @@
+use serde_json::Value;
+use std::fmt;
+
+/// How far past the ntime of its job a share can roll ntime, nodes accept blocks up to two hours
+/// in the future.
+pub const MAX_NTIME_ROLL: u32 = 7200;
+
+/// Field of a `mining.submit` that the miner got wrong.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MalformedSubmit {
+    /// The params are not 5 or 6 strings.
+    Params,
+    /// The job id is not one of the decimal ids sent in `mining.notify`.
+    JobId(String),
+    /// The extranonce2 is not `expected` bytes in hex.
+    Extranonce2 { expected: usize, value: String },
+    /// A field that must be 4 bytes in hex.
+    Hex { field: &'static str, value: String },
+    /// The ntime is before the ntime of the job or more than `MAX_NTIME_ROLL` after it.
+    Ntime { ntime: u32, job_ntime: u32 },
+}
+
+impl fmt::Display for MalformedSubmit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Params => write!(f, "Invalid mining.submit params"),
+            Self::JobId(job_id) => write!(f, "Invalid job id {}", job_id),
+            Self::Extranonce2 { expected, value } => {
+                write!(f, "Extranonce2 {} is not {} bytes of hex", value, expected)
+            }
+            Self::Hex { field, value } => write!(f, "Invalid {} {}", field, value),
+            Self::Ntime { ntime, job_ntime } => write!(
+                f,
+                "Ntime {:08x} out of range for job ntime {:08x}",
+                ntime, job_ntime
+            ),
+        }
+    }
+}
+
+/// Checks the fields of a `mining.submit` before `IsServer` parses them: job id, extranonce2
+/// length and the width of ntime, nonce and version bits.
+pub fn check_submit(params: &Value, extranonce2_len: usize) -> Result<(), MalformedSubmit> {
+    let params: Vec<&str> = params
+        .as_array()
+        .filter(|params| params.len() == 5 || params.len() == 6)
+        .and_then(|params| params.iter().map(|p| p.as_str()).collect())
+        .ok_or(MalformedSubmit::Params)?;
+
+    let job_id = params[1];
+    if job_id.parse::<u32>().is_err() {
+        return Err(MalformedSubmit::JobId(job_id.to_string()));
+    }
+    let extranonce2 = params[2];
+    if extranonce2.len() != extranonce2_len * 2 || !is_hex(extranonce2) {
+        return Err(MalformedSubmit::Extranonce2 {
+            expected: extranonce2_len,
+            value: extranonce2.to_string(),
+        });
+    }
+    let fields = [("ntime", params[3]), ("nonce", params[4])]
+        .into_iter()
+        .chain(
+            params
+                .get(5)
+                .map(|version_bits| ("version bits", *version_bits)),
+        );
+    for (field, value) in fields {
+        if value.len() != 8 || !is_hex(value) {
+            return Err(MalformedSubmit::Hex {
+                field,
+                value: value.to_string(),
+            });
+        }
+    }
+    Ok(())
+}
+
+/// Checks that a share rolls the ntime of its job forward, and not too far.
+pub fn check_ntime(ntime: u32, job_ntime: u32) -> Result<(), MalformedSubmit> {
+    match ntime.checked_sub(job_ntime) {
+        Some(roll) if roll <= MAX_NTIME_ROLL => Ok(()),
+        _ => Err(MalformedSubmit::Ntime { ntime, job_ntime }),
+    }
+}
+
+fn is_hex(value: &str) -> bool {
+    value.bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn submit_fields() {
+        let valid = json!(["account.rig1", "3", "0000abcd", "6553f100", "DEADBEEF"]);
+        assert_eq!(check_submit(&valid, 4), Ok(()));
+        let valid = json!([
+            "account.rig1",
+            "3",
+            "00",
+            "6553f100",
+            "deadbeef",
+            "1fffe000"
+        ]);
+        assert_eq!(check_submit(&valid, 1), Ok(()));
+
+        for params in [
+            json!(null),
+            json!(["account.rig1", "3", "0000abcd", "6553f100"]),
+            json!(["account.rig1", 3, "0000abcd", "6553f100", "deadbeef"]),
+        ] {
+            assert_eq!(check_submit(&params, 4), Err(MalformedSubmit::Params));
+        }
+        let params = json!(["account.rig1", "x3", "0000abcd", "6553f100", "deadbeef"]);
+        assert_eq!(
+            check_submit(&params, 4),
+            Err(MalformedSubmit::JobId("x3".to_string()))
+        );
+        for extranonce2 in ["0000ab", "0000abcd00", "0000abcg"] {
+            let params = json!(["account.rig1", "3", extranonce2, "6553f100", "deadbeef"]);
+            assert!(matches!(
+                check_submit(&params, 4),
+                Err(MalformedSubmit::Extranonce2 { expected: 4, .. })
+            ));
+        }
+        let params = json!(["account.rig1", "3", "0000abcd", "6553f1", "deadbeef"]);
+        assert!(matches!(
+            check_submit(&params, 4),
+            Err(MalformedSubmit::Hex { field: "ntime", .. })
+        ));
+        let params = json!([
+            "account.rig1",
+            "3",
+            "0000abcd",
+            "6553f100",
+            "deadbeef",
+            "1fffe0z0"
+        ]);
+        assert!(matches!(
+            check_submit(&params, 4),
+            Err(MalformedSubmit::Hex {
+                field: "version bits",
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn ntime_range() {
+        assert!(check_ntime(1700000000, 1700000000).is_ok());
+        assert!(check_ntime(1700000000 + MAX_NTIME_ROLL, 1700000000).is_ok());
+        assert!(check_ntime(1700000000 + MAX_NTIME_ROLL + 1, 1700000000).is_err());
+        assert!(check_ntime(1699999999, 1700000000).is_err());
+    }
+}
@@ -0,0 +1,557 @@
+//! Offline simulation of the vardiff of a port profile, to tune its parameters without real
+//! miners. Simulated miners send shares to a `DownstreamDifficultyConfig` which is retargeted
+//! like `Downstream::try_update_difficulty_settings` does, on a virtual clock.
+use super::{
+    diff_management::{initial_difficulty, VardiffConfig},
+    downstream::DownstreamDifficultyConfig,
+    Downstream,
+};
+use crate::config::{parse_hashrate, Configuration, PortProfile};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use serde::Deserialize;
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+/// Simulation file passed with `--simulate-vardiff`. Every miner is simulated with every
+/// parameter set.
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct Scenario {
+    pub duration_secs: u64,
+    /// Time between two retargets, defaults to the adjustment interval of the config.
+    pub interval_ms: u64,
+    /// Time before the first retarget, defaults to the delay of the config.
+    pub delay_secs: u64,
+    /// Runs of every miner, the Poisson share arrivals change at every run.
+    pub runs: u32,
+    pub seed: u64,
+    /// A miner converged once its difficulty stays within this relative distance of the ideal
+    /// one.
+    pub band: f32,
+    pub sets: Vec<ParameterSet>,
+    pub miners: Vec<SimulatedMiner>,
+}
+
+impl Default for Scenario {
+    fn default() -> Self {
+        Self {
+            duration_secs: 4 * 3600,
+            interval_ms: Configuration::adjustment_interval(),
+            delay_secs: Configuration::delay(),
+            runs: 10,
+            seed: 0,
+            band: 0.2,
+            sets: vec![],
+            miners: vec![],
+        }
+    }
+}
+
+/// Port profile to simulate. Unset values are taken from the main profile.
+#[derive(Debug, Deserialize)]
+pub struct ParameterSet {
+    pub name: String,
+    pub share_per_min: Option<f32>,
+    /// Hashrate the starting difficulty is computed from, e.g. "100T".
+    pub expected_hashrate: Option<String>,
+    pub min_difficulty: Option<f32>,
+    pub max_difficulty: Option<f32>,
+    pub vardiff: Option<VardiffConfig>,
+}
+
+impl ParameterSet {
+    fn profile(&self, main: &PortProfile) -> Result<PortProfile, String> {
+        Ok(PortProfile {
+            expected_hashrate: match &self.expected_hashrate {
+                Some(hashrate) => parse_rate(hashrate)?,
+                None => main.expected_hashrate,
+            },
+            share_per_min: self.share_per_min.unwrap_or(main.share_per_min),
+            min_difficulty: self.min_difficulty.or(main.min_difficulty),
+            max_difficulty: self.max_difficulty.or(main.max_difficulty),
+            worker_prefix: None,
+            vardiff: self.vardiff.clone().unwrap_or(main.vardiff.clone()),
+            idle_timeout: main.idle_timeout,
+        })
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SimulatedMiner {
+    pub name: String,
+    /// Hashrate at the start, e.g. "100T".
+    pub hashrate: Option<String>,
+    /// Hashrate changes during the run. A hashrate of 0 is a dropout.
+    #[serde(default)]
+    pub steps: Vec<HashrateStep>,
+    /// Recorded shares replayed instead of `hashrate`, one `<seconds> <difficulty>` per line.
+    pub shares_file: Option<PathBuf>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct HashrateStep {
+    pub at_secs: u64,
+    pub hashrate: String,
+}
+
+/// Plain h/s or with a unit, e.g. `200T`.
+fn parse_rate(value: &str) -> Result<f32, String> {
+    value
+        .trim()
+        .parse::<f32>()
+        .or_else(|_| parse_hashrate(value))
+}
+
+/// Where the shares of a simulated miner come from.
+enum ShareSource {
+    /// Poisson arrivals from `(start in seconds, hashrate)` segments.
+    Hashrate(Vec<(f32, f32)>),
+    /// Recorded `(seconds, difficulty)` shares. The work of every share is replayed, so a
+    /// miner at a different difficulty finds proportionally more or fewer shares.
+    Recorded(Vec<(f32, f32)>),
+}
+
+impl ShareSource {
+    fn new(miner: &SimulatedMiner, duration: f32) -> Result<Self, String> {
+        if let Some(path) = &miner.shares_file {
+            return Self::recorded(path);
+        }
+        let hashrate = miner
+            .hashrate
+            .as_deref()
+            .ok_or_else(|| format!("Miner {} needs a hashrate or a shares_file", miner.name))?;
+        let mut segments = vec![(0.0, parse_rate(hashrate)?)];
+        for step in &miner.steps {
+            segments.push((step.at_secs as f32, parse_rate(&step.hashrate)?));
+        }
+        segments.sort_by(|a, b| a.0.total_cmp(&b.0));
+        segments.retain(|(start, _)| *start < duration);
+        Ok(Self::Hashrate(segments))
+    }
+
+    fn recorded(path: &Path) -> Result<Self, String> {
+        let content = std::fs::read_to_string(path)
+            .map_err(|e| format!("Can not read {}: {}", path.display(), e))?;
+        let mut shares = vec![];
+        for line in content.lines().filter(|l| !l.trim().is_empty()) {
+            let mut fields = line.split_whitespace().map(str::parse::<f32>);
+            match (fields.next(), fields.next()) {
+                (Some(Ok(at)), Some(Ok(difficulty))) => shares.push((at, difficulty)),
+                _ => return Err(format!("Invalid share '{}' in {}", line, path.display())),
+            }
+        }
+        shares.sort_by(|a, b| a.0.total_cmp(&b.0));
+        Ok(Self::Recorded(shares))
+    }
+
+    /// `(start, end, hashrate)` of the periods with a constant hashrate.
+    fn segments(&self, duration: f32) -> Vec<(f32, f32, f32)> {
+        match self {
+            Self::Hashrate(segments) => segments
+                .iter()
+                .enumerate()
+                .map(|(i, (start, hashrate))| {
+                    let end = segments.get(i + 1).map_or(duration, |next| next.0);
+                    (*start, end, *hashrate)
+                })
+                .collect(),
+            Self::Recorded(shares) => {
+                let work: f32 = shares.iter().map(|(_, difficulty)| difficulty).sum();
+                vec![(0.0, duration, work * 2f32.powi(32) / duration)]
+            }
+        }
+    }
+}
+
+/// Sends the shares found until `until` at `difficulty`.
+struct Shares<'a> {
+    source: &'a ShareSource,
+    rng: StdRng,
+    /// Time up to which the shares were sent.
+    cursor: f32,
+    /// Work of the recorded shares not yet sent, in difficulty 1 shares.
+    work: f32,
+    next_recorded: usize,
+}
+
+impl Shares<'_> {
+    fn until(&mut self, until: f32, difficulty: f32, mut on_share: impl FnMut(f32)) {
+        match self.source {
+            ShareSource::Hashrate(segments) => {
+                while self.cursor < until {
+                    let current = segments
+                        .iter()
+                        .rposition(|(start, _)| *start <= self.cursor)
+                        .unwrap_or(0);
+                    let segment_end = segments
+                        .get(current + 1)
+                        .map_or(until, |next| next.0.min(until));
+                    let rate = segments[current].1 / (difficulty * 2f32.powi(32));
+                    if rate <= 0.0 {
+                        self.cursor = segment_end;
+                        continue;
+                    }
+                    // Time to the next share of a Poisson process, the arrivals have no memory so
+                    // the draw can be restarted at every change of hashrate or difficulty.
+                    let wait = -(1.0 - self.rng.gen::<f32>()).ln() / rate;
+                    if self.cursor + wait <= segment_end {
+                        self.cursor += wait;
+                        on_share(self.cursor);
+                    } else {
+                        self.cursor = segment_end;
+                    }
+                }
+            }
+            ShareSource::Recorded(shares) => {
+                while let Some((at, share_difficulty)) = shares.get(self.next_recorded) {
+                    if *at > until {
+                        break;
+                    }
+                    self.work += share_difficulty;
+                    while self.work >= difficulty {
+                        self.work -= difficulty;
+                        on_share(*at);
+                    }
+                    self.next_recorded += 1;
+                }
+                self.cursor = until;
+            }
+        }
+    }
+}
+
+/// Result of a simulated miner with a parameter set, averaged over the runs.
+#[derive(Debug, Default)]
+pub struct Report {
+    pub set: String,
+    pub miner: String,
+    /// Mean time to converge after a start or a hashrate step, in seconds.
+    pub convergence_secs: Option<f32>,
+    /// Share of the periods that did not converge.
+    pub not_converged: f32,
+    /// Mean of the largest relative move past the ideal difficulty.
+    pub overshoot: f32,
+    /// Shares sent every minute once the difficulty converged.
+    pub share_per_min: f32,
+    /// Variance of the shares sent every minute once the difficulty converged.
+    pub share_rate_variance: f32,
+    pub difficulty_changes: f32,
+}
+
+impl std::fmt::Display for Report {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{:<16} {:<16} {:>12} {:>10.1}% {:>10.1}% {:>10.2} {:>10.2} {:>8.1}",
+            self.set,
+            self.miner,
+            self.convergence_secs
+                .map_or("-".to_string(), |secs| format!("{:.0}s", secs)),
+            self.not_converged * 100.0,
+            self.overshoot * 100.0,
+            self.share_per_min,
+            self.share_rate_variance,
+            self.difficulty_changes,
+        )
+    }
+}
+
+/// Metrics of a single run.
+struct Run {
+    convergences: Vec<Option<f32>>,
+    overshoots: Vec<f32>,
+    share_per_min: f32,
+    share_rate_variance: f32,
+    difficulty_changes: usize,
+}
+
+fn simulate_run(
+    scenario: &Scenario,
+    profile: &Arc<PortProfile>,
+    source: &ShareSource,
+    seed: u64,
+) -> Run {
+    let duration = scenario.duration_secs as f32;
+    let start = Instant::now();
+    let at = |secs: f32| start + Duration::from_secs_f32(secs);
+    let initial = initial_difficulty(profile);
+    let mut difficulty_mgmt = DownstreamDifficultyConfig::new(profile, initial);
+    let mut shares = Shares {
+        source,
+        rng: StdRng::seed_from_u64(seed),
+        cursor: 0.0,
+        work: 0.0,
+        next_recorded: 0,
+    };
+
+    let interval = (scenario.interval_ms as f32 / 1000.0).max(0.001);
+    let mut difficulty = initial;
+    let mut difficulties = vec![(0.0, initial)];
+    let mut share_times = vec![];
+    let mut now = scenario.delay_secs as f32;
+    while now <= duration {
+        shares.until(now, difficulty, |t| {
+            difficulty_mgmt.on_new_valid_share(at(t));
+            share_times.push(t);
+        });
+        if let Some(new_difficulty) = difficulty_mgmt.retarget(at(now), profile) {
+            let estimation = Downstream::estimate_hash_rate_from_difficulty(
+                new_difficulty,
+                profile.share_per_min,
+            );
+            difficulty_mgmt.estimated_downstream_hash_rate = estimation;
+            difficulty_mgmt.on_new_difficulty(at(now), new_difficulty);
+            difficulty = new_difficulty;
+            difficulties.push((now, difficulty));
+        }
+        now += interval;
+    }
+    shares.until(duration, difficulty, |t| share_times.push(t));
+
+    let mut convergences = vec![];
+    let mut overshoots = vec![];
+    let mut minutes = vec![];
+    for (from, to, hashrate) in source.segments(duration) {
+        if hashrate <= 0.0 || to <= from {
+            continue;
+        }
+        let ideal = hashrate * 60.0 / (profile.share_per_min * 2f32.powi(32));
+        let ideal = profile.clamp_difficulty(ideal);
+        // Difficulty at the start of the period and its changes during it
+        let mut timeline = vec![(from, difficulty_at(&difficulties, from))];
+        timeline.extend(
+            difficulties
+                .iter()
+                .filter(|(t, _)| *t > from && *t < to)
+                .copied(),
+        );
+        let in_band = |d: f32| (d / ideal - 1.0).abs() <= scenario.band;
+        let converged_at = match timeline.iter().rposition(|(_, d)| !in_band(*d)) {
+            None => Some(from),
+            Some(last_out) => timeline.get(last_out + 1).map(|(t, _)| *t),
+        };
+        convergences.push(converged_at.map(|t| t - from));
+        let below = timeline[0].1 < ideal;
+        let overshoot = timeline
+            .iter()
+            .map(|(_, d)| {
+                if below {
+                    d / ideal - 1.0
+                } else {
+                    1.0 - d / ideal
+                }
+            })
+            .fold(0.0, f32::max);
+        overshoots.push(overshoot);
+
+        // The share rate is measured once the difficulty converged
+        let Some(mut minute) = converged_at else {
+            continue;
+        };
+        while minute + 60.0 <= to {
+            let count = share_times
+                .iter()
+                .filter(|t| **t >= minute && **t < minute + 60.0)
+                .count();
+            minutes.push(count as f32);
+            minute += 60.0;
+        }
+    }
+    let share_per_min = mean(&minutes);
+    let share_rate_variance = mean(
+        &minutes
+            .iter()
+            .map(|m| (m - share_per_min).powi(2))
+            .collect::<Vec<_>>(),
+    );
+    Run {
+        convergences,
+        overshoots,
+        share_per_min,
+        share_rate_variance,
+        difficulty_changes: difficulties.len() - 1,
+    }
+}
+
+fn difficulty_at(difficulties: &[(f32, f32)], at: f32) -> f32 {
+    difficulties
+        .iter()
+        .take_while(|(t, _)| *t <= at)
+        .last()
+        .map_or(difficulties[0].1, |(_, d)| *d)
+}
+
+fn mean(values: &[f32]) -> f32 {
+    if values.is_empty() {
+        0.0
+    } else {
+        values.iter().sum::<f32>() / values.len() as f32
+    }
+}
+
+/// Simulates every miner of the scenario with every parameter set, `main` is the profile of the
+/// unset parameters.
+pub fn simulate(scenario: &Scenario, main: &PortProfile) -> Result<Vec<Report>, String> {
+    let duration = scenario.duration_secs as f32;
+    let default_set;
+    let sets = if scenario.sets.is_empty() {
+        default_set = [ParameterSet {
+            name: "config".to_string(),
+            share_per_min: None,
+            expected_hashrate: None,
+            min_difficulty: None,
+            max_difficulty: None,
+            vardiff: None,
+        }];
+        &default_set[..]
+    } else {
+        &scenario.sets[..]
+    };
+    let mut reports = vec![];
+    for set in sets {
+        let profile = Arc::new(set.profile(main)?);
+        for miner in &scenario.miners {
+            let source = ShareSource::new(miner, duration)?;
+            let runs: Vec<Run> = (0..scenario.runs.max(1))
+                .map(|run| simulate_run(scenario, &profile, &source, scenario.seed + run as u64))
+                .collect();
+            let convergences: Vec<Option<f32>> = runs
+                .iter()
+                .flat_map(|r| r.convergences.iter().copied())
+                .collect();
+            let converged: Vec<f32> = convergences.iter().flatten().copied().collect();
+            let overshoots: Vec<f32> = runs
+                .iter()
+                .flat_map(|r| r.overshoots.iter().copied())
+                .collect();
+            let per_run = |f: fn(&Run) -> f32| mean(&runs.iter().map(f).collect::<Vec<_>>());
+            reports.push(Report {
+                set: set.name.clone(),
+                miner: miner.name.clone(),
+                convergence_secs: Some(mean(&converged)).filter(|_| !converged.is_empty()),
+                not_converged: 1.0 - converged.len() as f32 / convergences.len().max(1) as f32,
+                overshoot: mean(&overshoots),
+                share_per_min: per_run(|r| r.share_per_min),
+                share_rate_variance: per_run(|r| r.share_rate_variance),
+                difficulty_changes: per_run(|r| r.difficulty_changes as f32),
+            });
+        }
+    }
+    Ok(reports)
+}
+
+/// Runs the scenario at `path` and prints the report.
+pub fn run(path: &Path) -> Result<(), String> {
+    let content = std::fs::read_to_string(path)
+        .map_err(|e| format!("Can not read {}: {}", path.display(), e))?;
+    let scenario: Scenario =
+        toml::from_str(&content).map_err(|e| format!("Invalid scenario: {}", e))?;
+    let reports = simulate(&scenario, &PortProfile::main())?;
+    println!(
+        "{:<16} {:<16} {:>12} {:>11} {:>11} {:>10} {:>10} {:>8}",
+        "set",
+        "miner",
+        "convergence",
+        "unconverged",
+        "overshoot",
+        "shares/min",
+        "variance",
+        "changes"
+    );
+    for report in reports {
+        println!("{}", report);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::translator::downstream::diff_management::VardiffKind;
+
+    fn main_profile() -> PortProfile {
+        PortProfile {
+            expected_hashrate: 1e12,
+            share_per_min: 10.0,
+            min_difficulty: None,
+            max_difficulty: None,
+            worker_prefix: None,
+            vardiff: VardiffConfig::default(),
+            idle_timeout: None,
+        }
+    }
+
+    fn scenario(vardiff: VardiffConfig, steps: Vec<HashrateStep>) -> Scenario {
+        Scenario {
+            duration_secs: 4 * 3600,
+            interval_ms: 60_000,
+            delay_secs: 0,
+            runs: 3,
+            seed: 1,
+            band: 0.3,
+            sets: vec![ParameterSet {
+                name: "test".to_string(),
+                share_per_min: None,
+                expected_hashrate: None,
+                min_difficulty: None,
+                max_difficulty: None,
+                vardiff: Some(vardiff),
+            }],
+            miners: vec![SimulatedMiner {
+                name: "miner".to_string(),
+                hashrate: Some("100T".to_string()),
+                steps,
+                shares_file: None,
+            }],
+        }
+    }
+
+    #[test]
+    fn simulated_miner_converges() {
+        let vardiff = VardiffConfig {
+            algorithm: VardiffKind::Retarget,
+            window_secs: 900,
+            min_window_secs: 300,
+            ..Default::default()
+        };
+        let reports = simulate(&scenario(vardiff, vec![]), &main_profile()).unwrap();
+        let report = &reports[0];
+        assert_eq!(report.not_converged, 0.0);
+        assert!(report.convergence_secs.unwrap() < 3600.0);
+        assert!((report.share_per_min - 10.0).abs() < 2.0);
+    }
+
+    #[test]
+    fn simulated_dropout() {
+        let steps = vec![
+            HashrateStep {
+                at_secs: 3600,
+                hashrate: "0".to_string(),
+            },
+            HashrateStep {
+                at_secs: 5400,
+                hashrate: "50T".to_string(),
+            },
+        ];
+        let vardiff = VardiffConfig {
+            algorithm: VardiffKind::Fixed,
+            ..Default::default()
+        };
+        let scenario = scenario(vardiff, steps);
+        let source = ShareSource::new(&scenario.miners[0], 4.0 * 3600.0).unwrap();
+        let segments = source.segments(4.0 * 3600.0);
+        let bounds: Vec<(f32, f32)> = segments.iter().map(|(from, to, _)| (*from, *to)).collect();
+        assert_eq!(
+            bounds,
+            vec![(0.0, 3600.0), (3600.0, 5400.0), (5400.0, 14400.0)]
+        );
+        assert_eq!(segments[1].2, 0.0);
+        // A fixed difficulty never converges from 1T to 100T
+        let report = &simulate(&scenario, &main_profile()).unwrap()[0];
+        assert_eq!(report.difficulty_changes, 0.0);
+        assert_eq!(report.not_converged, 1.0);
+    }
+}
@@ -4,39 +4,63 @@ mod error;
 mod proxy;
 mod upstream;
 mod utils;
+mod worker_channels;
 
 use bitcoin::Address;
 use error::Error;
 
-use roles_logic_sv2::{parsers::Mining, utils::Mutex};
-use tracing::error;
-
-use std::{net::IpAddr, sync::Arc};
-use tokio::sync::mpsc::channel;
+use roles_logic_sv2::{
+    mining_sv2::{ExtendedExtranonce, NewExtendedMiningJob, SetNewPrevHash, SubmitSharesExtended},
+    parsers::Mining,
+    utils::Mutex,
+};
+use tracing::{error, warn};
 
-use sv1_api::server_to_client;
-use tokio::sync::broadcast;
+use std::sync::Arc;
+use tokio::sync::{broadcast, mpsc::channel};
 
 use crate::{
-    proxy_state::{ProxyState, TranslatorState},
+    config::{Configuration, WorkerChannelMode},
+    proxy_state::{ProxyState, TranslatorState, UpstreamType},
     shared::utils::AbortOnDrop,
 };
 use tokio::sync::mpsc::{Receiver as TReceiver, Sender as TSender};
 
-use self::upstream::diff_management::UpstreamDifficultyConfig;
+pub use downstream::{
+    diff_management::VardiffConfig, health::MinerState, vardiff_sim, DownstreamSessions,
+};
+pub use proxy::bridge::RETAINED_JOBS;
+pub use utils::ShareRateLimiter;
 mod task_manager;
+use downstream::{ChannelNotify, DownstreamMessages};
+use proxy::Bridge;
 use task_manager::TaskManager;
+use upstream::{channel_mux::ChannelMux, diff_management::UpstreamDifficultyConfig};
+use worker_channels::WorkerChannels;
+
+/// Connection to the pool, or to the JDC, requested by the translator.
+type PoolConnection = TSender<(
+    TSender<Mining<'static>>,
+    TReceiver<Mining<'static>>,
+    Option<Address>,
+)>;
 
+/// `user_identity` of the channel that the miners share.
+const SHARED_USER_IDENTITY: &str = "ABC";
+
+/// Starts the upstream side of the translator (`Upstream` and `Bridge`) for a new pool
+/// connection. The SV1 side lives in `DownstreamSessions` and is handed over to the new `Bridge`
+/// once it has its first job, so connected miners are not dropped.
 pub async fn start(
-    downstreams: TReceiver<(TSender<String>, TReceiver<String>, IpAddr)>,
-    pool_connection: TSender<(
-        TSender<Mining<'static>>,
-        TReceiver<Mining<'static>>,
-        Option<Address>,
-    )>,
-    stats_sender: crate::api::stats::StatsSender,
+    sessions: DownstreamSessions,
+    pool_connection: PoolConnection,
     signature: String,
 ) -> Result<AbortOnDrop, Error<'static>> {
+    // Until the new `Bridge` is ready new miners have to wait
+    sessions.clear_bridge()?;
+    // The previous upstream may have been dropped while a job was being handled
+    upstream::upstream::IS_NEW_JOB_HANDLED.store(true, std::sync::atomic::Ordering::SeqCst);
+
     let task_manager = TaskManager::initialize(pool_connection.clone());
     let abortable = task_manager
         .safe_lock(|t| t.get_aborter())
@@ -53,11 +77,114 @@ pub async fn start(
             Error::Unrecoverable // Propagate error to that caller. There, we will restart Proxy
         })?;
 
-    // `tx_sv1_bridge` sender is used by `Downstream` to send a `DownstreamMessages` message to
-    // `Bridge` via the `rx_sv1_downstream` receiver
-    // (Sender<downstream_sv1::DownstreamMessages>, Receiver<downstream_sv1::DownstreamMessages>)
-    let (tx_sv1_bridge, rx_sv1_bridge) = channel(crate::TRANSLATOR_BUFFER_SIZE);
+    let recv_from_up = match Configuration::worker_channels() {
+        WorkerChannelMode::Shared => recv_from_up,
+        // The JDC only knows the channel of the translator
+        _ if crate::TP_ADDRESS
+            .safe_lock(|tp| tp.is_some())
+            .map_err(|_| Error::PoisonLock)? =>
+        {
+            warn!("Worker channels are not available with a TP, miners share one channel");
+            recv_from_up
+        }
+        mode => {
+            // The worker channels are on the same connection, their messages are taken out
+            let (tx_shared, rx_shared) = channel(crate::TRANSLATOR_BUFFER_SIZE);
+            let (mux, mux_abortable) = ChannelMux::start(recv_from_up, tx_shared);
+            TaskManager::add_channel_mux(task_manager.clone(), mux_abortable)
+                .await
+                .map_err(|_| Error::TranslatorTaskManagerFailed)?;
+            let worker_channels = Arc::new(WorkerChannels::new(
+                mode,
+                mux,
+                pool_connection.clone(),
+                send_to_up.clone(),
+                signature.clone(),
+                sessions.clone(),
+            ));
+            sessions.set_worker_channels(Arc::downgrade(&worker_channels))?;
+            TaskManager::add_worker_channels(task_manager.clone(), worker_channels)
+                .await
+                .map_err(|_| Error::TranslatorTaskManagerFailed)?;
+            rx_shared
+        }
+    };
+
+    // Kept across upstream changes so that the new channel is opened with the hashrate of the
+    // miners that are already connected
+    let diff_config = sessions.upstream_difficulty_config();
+
+    let upstream = start_upstream(
+        task_manager.clone(),
+        send_to_up,
+        recv_from_up,
+        diff_config,
+        signature,
+        SHARED_USER_IDENTITY.to_string(),
+        0,
+    )
+    .await?;
+
+    let startup_task = {
+        let task_manager = task_manager.clone();
+        tokio::task::spawn(async move {
+            // Sender to send SV1 `mining.notify` message from the `Bridge` to the `Downstream`,
+            // shared by every `Bridge` so that downstreams keep receiving jobs across upstream
+            // changes. Same for the jobs forwarded to the native SV2 downstreams
+            let (b, tx_sv1_bridge, rx_sv2_extranonce) = match start_bridge(
+                task_manager,
+                upstream,
+                sessions.tx_sv1_notify(),
+                sessions.tx_sv2_jobs(),
+                sessions.stats_sender(),
+            )
+            .await
+            {
+                Ok(bridge) => bridge,
+                Err(e) => {
+                    error!("Failed to start bridge: {e}");
+                    ProxyState::update_translator_state(TranslatorState::Down);
+                    return;
+                }
+            };
+
+            if let Err(e) = sessions.set_bridge(b.clone(), tx_sv1_bridge.clone()).await {
+                error!("Failed to hand downstreams over to the new bridge: {e}");
+                ProxyState::update_translator_state(TranslatorState::Down);
+                return;
+            }
+
+            follow_extranonce_prefix(b, tx_sv1_bridge, rx_sv2_extranonce, sessions).await;
+        })
+    };
+    TaskManager::add_startup_task(task_manager.clone(), startup_task.into())
+        .await
+        .map_err(|_| Error::TranslatorTaskManagerFailed)?;
 
+    Ok(abortable)
+}
+
+/// Ends of the channels between an `Upstream` and its `Bridge`, handed over to the `Bridge`.
+struct UpstreamChannels {
+    rx_sv2_extranonce: TReceiver<(ExtendedExtranonce, u32)>,
+    rx_sv2_set_new_prev_hash: TReceiver<SetNewPrevHash<'static>>,
+    rx_sv2_new_ext_mining_job: TReceiver<NewExtendedMiningJob<'static>>,
+    tx_sv2_submit_shares_ext: TSender<SubmitSharesExtended<'static>>,
+    target: Arc<Mutex<Vec<u8>>>,
+    share_acks: Arc<Mutex<utils::PendingAcks>>,
+}
+
+/// Starts an `Upstream` that opens a channel for `user_identity` with the pool of `send_to_up`,
+/// and gets the messages of the pool for that channel from `recv_from_up`.
+async fn start_upstream(
+    task_manager: Arc<Mutex<TaskManager>>,
+    send_to_up: TSender<Mining<'static>>,
+    recv_from_up: TReceiver<Mining<'static>>,
+    diff_config: Arc<Mutex<UpstreamDifficultyConfig>>,
+    signature: String,
+    user_identity: String,
+    request_id: u32,
+) -> Result<UpstreamChannels, Error<'static>> {
     // Sender/Receiver to send a SV2 `SubmitSharesExtended` from the `Bridge` to the `Upstream`
     // (Sender<SubmitSharesExtended<'static>>, Receiver<SubmitSharesExtended<'static>>)
     let (tx_sv2_submit_shares_ext, rx_sv2_submit_shares_ext) =
@@ -74,25 +201,14 @@ pub async fn start(
     let (tx_sv2_new_ext_mining_job, rx_sv2_new_ext_mining_job) =
         channel(crate::TRANSLATOR_BUFFER_SIZE);
 
-    // Sender/Receiver to send a new extranonce from the `Upstream` to this `main` function to be
-    // passed to the `Downstream` upon a Downstream role connection
+    // Sender/Receiver to send a new extranonce from the `Upstream` to the `Bridge`, to be passed
+    // to the `Downstream` upon a Downstream role connection
     // (Sender<ExtendedExtranonce>, Receiver<ExtendedExtranonce>)
-    let (tx_sv2_extranonce, mut rx_sv2_extranonce) = channel(crate::TRANSLATOR_BUFFER_SIZE);
+    let (tx_sv2_extranonce, rx_sv2_extranonce) = channel(crate::TRANSLATOR_BUFFER_SIZE);
     let target = Arc::new(Mutex::new(vec![0; 32]));
 
-    // Sender/Receiver to send SV1 `mining.notify` message from the `Bridge` to the `Downstream`
-    let (tx_sv1_notify, _): (
-        broadcast::Sender<server_to_client::Notify>,
-        broadcast::Receiver<server_to_client::Notify>,
-    ) = broadcast::channel(crate::TRANSLATOR_BUFFER_SIZE);
-
-    let channel_nominal_hashrate = 0.0;
-
-    let upstream_diff = UpstreamDifficultyConfig {
-        channel_diff_update_interval: crate::CHANNEL_DIFF_UPDTATE_INTERVAL,
-        channel_nominal_hashrate,
-    };
-    let diff_config = Arc::new(Mutex::new(upstream_diff));
+    // Shares of strict mode miners sent by the `Bridge` and acknowledged to the `Upstream`
+    let share_acks = Arc::new(Mutex::new(utils::PendingAcks::default()));
 
     // Instantiate a new `Upstream` (SV2 Pool)
     let upstream = upstream::Upstream::new(
@@ -101,112 +217,126 @@ pub async fn start(
         crate::MIN_EXTRANONCE_SIZE - 1,
         tx_sv2_extranonce,
         target.clone(),
-        diff_config.clone(),
+        diff_config,
         send_to_up,
         signature,
+        share_acks.clone(),
+        user_identity,
+        request_id,
     )
     .await?;
 
     let upstream_abortable =
         upstream::Upstream::start(upstream, recv_from_up, rx_sv2_submit_shares_ext).await?;
-    TaskManager::add_upstream(task_manager.clone(), upstream_abortable)
+    TaskManager::add_upstream(task_manager, upstream_abortable)
         .await
         .map_err(|_| Error::TranslatorTaskManagerFailed)?;
 
-    let startup_task = {
-        let target = target.clone();
-        let task_manager = task_manager.clone();
-        tokio::task::spawn(async move {
-            let (extended_extranonce, up_id) = match rx_sv2_extranonce.recv().await {
-                Some((extended_extranonce, up_id)) => (extended_extranonce, up_id),
-                None => {
-                    error!("Failed to receive from rx_sv2_extranonce");
-                    ProxyState::update_translator_state(TranslatorState::Down);
-                    return;
-                }
-            };
-
-            loop {
-                let target: [u8; 32] =  match target.safe_lock(|t| t.clone()) {
-                    Ok(target) => target.try_into().expect("Internal error: this operation cannot fail because Vec<u8> can always be converted into [u8; 32]"),
-                    Err(e) => {
-                        error!("{}", Error::TargetError(roles_logic_sv2::Error::PoisonLock(e.to_string())));
-                        break
-                    }
-                };
-
-                if target != [0; 32] {
-                    break;
-                };
-                tokio::task::yield_now().await;
-            }
-
-            // Instantiate a new `Bridge` and begins handling incoming messages
-            let b = match proxy::Bridge::new(
-                tx_sv2_submit_shares_ext,
-                tx_sv1_notify.clone(),
-                extended_extranonce,
-                target,
-                up_id,
-            ) {
-                Ok(b) => b,
-                Err(e) => {
-                    error!("Failed to instantiate new Bridge: {e}");
-                    return;
-                }
-            };
+    Ok(UpstreamChannels {
+        rx_sv2_extranonce,
+        rx_sv2_set_new_prev_hash,
+        rx_sv2_new_ext_mining_job,
+        tx_sv2_submit_shares_ext,
+        target,
+        share_acks,
+    })
+}
 
-            let bridge_aborter = match proxy::Bridge::start(
-                b.clone(),
-                rx_sv2_set_new_prev_hash,
-                rx_sv2_new_ext_mining_job,
-                rx_sv1_bridge,
-            )
-            .await
-            {
-                Ok(abortable) => abortable,
-                Err(e) => {
-                    error!("Failed to start bridge: {e}");
-                    return;
-                }
-            };
+/// Starts the `Bridge` of an `Upstream` once the pool opened its channel. Returns the `Bridge`
+/// with the sender of its downstreams and the receiver of the extranonces of the later
+/// `SetExtranoncePrefix` of the pool.
+#[allow(clippy::type_complexity)]
+async fn start_bridge(
+    task_manager: Arc<Mutex<TaskManager>>,
+    upstream: UpstreamChannels,
+    tx_sv1_notify: broadcast::Sender<ChannelNotify>,
+    tx_sv2_jobs: broadcast::Sender<Mining<'static>>,
+    stats_sender: crate::api::stats::StatsSender,
+) -> Result<
+    (
+        Arc<Mutex<Bridge>>,
+        TSender<DownstreamMessages>,
+        TReceiver<(ExtendedExtranonce, u32)>,
+    ),
+    Error<'static>,
+> {
+    let UpstreamChannels {
+        mut rx_sv2_extranonce,
+        rx_sv2_set_new_prev_hash,
+        rx_sv2_new_ext_mining_job,
+        tx_sv2_submit_shares_ext,
+        target,
+        share_acks,
+    } = upstream;
+    let (extended_extranonce, up_id) = rx_sv2_extranonce.recv().await.ok_or_else(|| {
+        error!("Failed to receive from rx_sv2_extranonce");
+        Error::AsyncChannelError
+    })?;
 
-            let downstream_aborter = match downstream::Downstream::accept_connections(
-                tx_sv1_bridge,
-                tx_sv1_notify,
-                b,
-                diff_config,
-                downstreams,
-                stats_sender,
-            )
-            .await
-            {
-                Ok(abortable) => abortable,
-                Err(e) => {
-                    error!("Downstream failed to accept connection: {e}");
-                    return;
-                }
-            };
+    // The `Upstream` sets the target of the channel right after the extranonce
+    while target
+        .safe_lock(|t| t.iter().all(|b| *b == 0))
+        .map_err(|e| Error::TargetError(roles_logic_sv2::Error::PoisonLock(e.to_string())))?
+    {
+        tokio::task::yield_now().await;
+    }
 
-            if TaskManager::add_bridge(task_manager.clone(), bridge_aborter)
-                .await
-                .is_err()
-            {
-                error!("{}", Error::TranslatorTaskManagerFailed);
-                return;
-            };
+    // `tx_sv1_bridge` sender is used by `Downstream` to send a `DownstreamMessages` message to
+    // `Bridge` via the `rx_sv1_downstream` receiver
+    // (Sender<downstream_sv1::DownstreamMessages>, Receiver<downstream_sv1::DownstreamMessages>)
+    let (tx_sv1_bridge, rx_sv1_bridge) = channel(crate::TRANSLATOR_BUFFER_SIZE);
 
-            if TaskManager::add_downstream_listener(task_manager.clone(), downstream_aborter)
-                .await
-                .is_err()
-            {
-                error!("{}", Error::TranslatorTaskManagerFailed);
-            }
-        })
-    };
-    TaskManager::add_startup_task(task_manager.clone(), startup_task.into())
+    // Instantiate a new `Bridge` and begins handling incoming messages
+    let b = proxy::Bridge::new(
+        tx_sv2_submit_shares_ext,
+        tx_sv1_notify,
+        tx_sv2_jobs,
+        extended_extranonce,
+        target,
+        up_id,
+        Configuration::share_rate_limit().channel_limiter(),
+        share_acks,
+        stats_sender,
+    )?;
+    let bridge_aborter = proxy::Bridge::start(
+        b.clone(),
+        rx_sv2_set_new_prev_hash,
+        rx_sv2_new_ext_mining_job,
+        rx_sv1_bridge,
+    )
+    .await?;
+    TaskManager::add_bridge(task_manager, bridge_aborter)
         .await
         .map_err(|_| Error::TranslatorTaskManagerFailed)?;
+    Ok((b, tx_sv1_bridge, rx_sv2_extranonce))
+}
 
-    Ok(abortable)
+/// Any other extranonce of a channel comes from a `SetExtranoncePrefix` of the pool, its
+/// downstreams are moved to the new one.
+async fn follow_extranonce_prefix(
+    b: Arc<Mutex<Bridge>>,
+    tx_sv1_bridge: TSender<DownstreamMessages>,
+    mut rx_sv2_extranonce: TReceiver<(ExtendedExtranonce, u32)>,
+    sessions: DownstreamSessions,
+) {
+    while let Some((extended_extranonce, _)) = rx_sv2_extranonce.recv().await {
+        match b.safe_lock(|b| b.set_extranonces(extended_extranonce)) {
+            Ok(Ok(())) => (),
+            Ok(Err(e)) => {
+                error!("Failed to set the new extranonce: {e}");
+                ProxyState::update_upstream_state(UpstreamType::TranslatorUpstream);
+                return;
+            }
+            Err(_) => {
+                error!("{}", Error::BridgeMutexPoisoned);
+                ProxyState::update_translator_state(TranslatorState::Down);
+                return;
+            }
+        }
+        if let Err(e) = sessions.on_new_extranonce(&b, tx_sv1_bridge.clone()).await {
+            error!("Failed to move downstreams to the new extranonce: {e}");
+            ProxyState::update_translator_state(TranslatorState::Down);
+            return;
+        }
+    }
 }
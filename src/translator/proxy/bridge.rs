@@ -4,10 +4,11 @@ use tokio::task::JoinHandle;
 use roles_logic_sv2::{
     channel_logic::channel_factory::{ExtendedChannelKind, ProxyExtendedChannelFactory, Share},
     mining_sv2::{
-        ExtendedExtranonce, NewExtendedMiningJob, SetNewPrevHash, SubmitSharesExtended, Target,
+        ExtendedExtranonce, NewExtendedMiningJob, OpenExtendedMiningChannelSuccess, SetNewPrevHash,
+        SubmitSharesError, SubmitSharesExtended, SubmitSharesSuccess, Target,
     },
     parsers::Mining,
-    utils::{GroupId, Mutex},
+    utils::{hash_rate_to_target, GroupId, Mutex},
 };
 use std::sync::{
     atomic::{AtomicU32, Ordering},
@@ -18,20 +19,29 @@ use tokio::sync::broadcast;
 
 use super::{
     super::{
-        downstream::{DownstreamMessages, SetDownstreamTarget, SubmitShareWithChannelId},
+        downstream::{
+            ChannelNotify, DownstreamMessages, SetDownstreamTarget, SubmitShareWithChannelId,
+            SubmitSv2Share,
+        },
         error::{Error, ProxyResult},
     },
     task_manager::TaskManager,
 };
 use crate::{
+    api::stats::StatsSender,
+    monitor::shares::RejectionReason,
     proxy_state::{ProxyState, TranslatorState, UpstreamType},
     shared::utils::AbortOnDrop,
-    translator::utils::allow_submit_share,
+    translator::utils::{PendingAcks, ShareRateLimiter},
 };
 use lazy_static::lazy_static;
 use roles_logic_sv2::{channel_logic::channel_factory::OnNewShare, Error as RolesLogicError};
 use tracing::{debug, error, info, warn};
 
+/// Jobs the channel factory keeps, the latest included. Shares of older jobs, or of jobs of a
+/// previous prevhash, can not be translated anymore.
+pub const RETAINED_JOBS: usize = 3;
+
 lazy_static! {
     static ref SUBMIT_FAIL_COUNTER: AtomicU32 = AtomicU32::new(0);
 }
@@ -46,8 +56,12 @@ pub struct Bridge {
     /// the `Upstream`.
     tx_sv2_submit_shares_ext: tokio::sync::mpsc::Sender<SubmitSharesExtended<'static>>,
     /// Sends SV1 `mining.notify` message (translated from the SV2 `SetNewPrevHash` and
-    /// `NewExtendedMiningJob` messages stored in the `NextMiningNotify`) to the `Downstream`.
-    tx_sv1_notify: broadcast::Sender<server_to_client::Notify<'static>>,
+    /// `NewExtendedMiningJob` messages stored in the `NextMiningNotify`) to the `Downstream`,
+    /// tagged with `channel_id` since every `Bridge` sends on the same broadcast.
+    tx_sv1_notify: broadcast::Sender<ChannelNotify>,
+    /// Sends the SV2 `NewExtendedMiningJob` and `SetNewPrevHash` messages of the `Upstream` to the
+    /// native SV2 downstreams, which forward them on their own channels.
+    tx_sv2_jobs: broadcast::Sender<Mining<'static>>,
     /// Stores the most recent SV1 `mining.notify` values to be sent to the `Downstream` upon
     /// receiving a new SV2 `SetNewPrevHash` and `NewExtendedMiningJob` messages **before** any
     /// Downstream role connects to the proxy.
@@ -62,7 +76,19 @@ pub struct Bridge {
     pub(self) channel_factory: ProxyExtendedChannelFactory,
     future_jobs: Vec<NewExtendedMiningJob<'static>>,
     last_p_hash: Option<SetNewPrevHash<'static>>,
+    /// Job that is being mined on `last_p_hash`, sent to the SV2 channels when they are opened.
+    last_job: Option<NewExtendedMiningJob<'static>>,
     target: Arc<Mutex<Vec<u8>>>,
+    /// Id of the upstream channel.
+    channel_id: u32,
+    /// Budget of the shares sent to the `Upstream`, for all the downstreams.
+    share_limiter: ShareRateLimiter,
+    /// Sequence number of the last share sent to the `Upstream`.
+    sequence_number: u32,
+    /// Miners waiting for the pool verdict on their shares, answered by the `Upstream`.
+    share_acks: Arc<Mutex<PendingAcks>>,
+    /// Counts the shares dropped by `share_limiter`.
+    stats_sender: StatsSender,
 }
 
 impl Bridge {
@@ -80,10 +106,14 @@ impl Bridge {
     /// Instantiate a new `Bridge`.
     pub fn new(
         tx_sv2_submit_shares_ext: tokio::sync::mpsc::Sender<SubmitSharesExtended<'static>>,
-        tx_sv1_notify: broadcast::Sender<server_to_client::Notify<'static>>,
+        tx_sv1_notify: broadcast::Sender<ChannelNotify>,
+        tx_sv2_jobs: broadcast::Sender<Mining<'static>>,
         extranonces: ExtendedExtranonce,
         target: Arc<Mutex<Vec<u8>>>,
         channel_id: u32,
+        share_limiter: ShareRateLimiter,
+        share_acks: Arc<Mutex<PendingAcks>>,
+        stats_sender: StatsSender,
     ) -> Result<Arc<Mutex<Self>>, Error<'static>> {
         info!("Creating new bridge for channel_id {}:", channel_id);
         let ids = Arc::new(Mutex::new(GroupId::new()));
@@ -94,6 +124,7 @@ impl Bridge {
         Ok(Arc::new(Mutex::new(Self {
             tx_sv2_submit_shares_ext,
             tx_sv1_notify,
+            tx_sv2_jobs,
             last_notify: None,
             channel_factory: ProxyExtendedChannelFactory::new(
                 ids,
@@ -106,10 +137,67 @@ impl Bridge {
             ),
             future_jobs: vec![],
             last_p_hash: None,
+            last_job: None,
             target,
+            channel_id,
+            share_limiter,
+            sequence_number: 0,
+            share_acks,
+            stats_sender,
         })))
     }
 
+    /// Replaces the extranonce of the upstream channel after a `SetExtranoncePrefix`. The channel
+    /// factory is created again with the current job, the downstream channels opened on the
+    /// previous one are gone and have to be opened again.
+    #[allow(clippy::result_large_err)]
+    pub fn set_extranonces(&mut self, extranonces: ExtendedExtranonce) -> ProxyResult<'static, ()> {
+        info!(
+            "Replacing extranonce of bridge for channel_id {}",
+            self.channel_id
+        );
+        let upstream_target: [u8; 32] = self
+            .target
+            .safe_lock(|t| t.clone())
+            .map_err(|e| Error::TargetError(RolesLogicError::PoisonLock(e.to_string())))?
+            .try_into()
+            .map_err(|_| Error::ImpossibleToOpenChannnel)?;
+        let upstream_target: Target = upstream_target.into();
+        let mut channel_factory = ProxyExtendedChannelFactory::new(
+            Arc::new(Mutex::new(GroupId::new())),
+            extranonces,
+            None,
+            *crate::SHARE_PER_MIN,
+            ExtendedChannelKind::Proxy { upstream_target },
+            None,
+            self.channel_id,
+        );
+        if let Some(p_hash) = self.last_p_hash.clone() {
+            channel_factory.on_new_prev_hash(p_hash)?;
+        }
+        if let Some(job) = self.last_job.clone() {
+            channel_factory
+                .on_new_extended_mining_job(job)
+                .map_err(|_| {
+                    Error::RolesSv2Logic(RolesLogicError::JobIsNotFutureButPrevHashNotPresent)
+                })?;
+        }
+        for job in self.future_jobs.iter() {
+            channel_factory
+                .on_new_extended_mining_job(job.clone())
+                .map_err(|_| {
+                    Error::RolesSv2Logic(RolesLogicError::JobIsNotFutureButPrevHashNotPresent)
+                })?;
+        }
+        self.channel_factory = channel_factory;
+        Ok(())
+    }
+
+    /// Id of the upstream channel.
+    pub fn channel_id(&self) -> u32 {
+        self.channel_id
+    }
+
     #[allow(clippy::result_large_err)]
     pub fn on_new_sv1_connection(
         &mut self,
@@ -131,6 +219,7 @@ impl Bridge {
                         })?;
                     Ok(OpenSv1Downstream {
                         channel_id: success.channel_id,
+                        upstream_channel_id: self.channel_id,
                         last_notify: self.last_notify.clone(),
                         extranonce,
                         extranonce2_len,
@@ -151,6 +240,53 @@ impl Bridge {
         }
     }
 
+    /// Opens an extended channel for a native SV2 downstream. Standard channels of the downstream
+    /// are extended channels too, the proxy fills the extranonce for them. The target of the
+    /// channel is set for `share_per_min`, the share rate of the SV2 listener.
+    #[allow(clippy::result_large_err)]
+    pub fn on_new_sv2_channel(
+        &mut self,
+        request_id: u32,
+        hash_rate: f32,
+        min_extranonce_size: u16,
+        share_per_min: f32,
+    ) -> ProxyResult<'static, OpenSv2Channel> {
+        let messages = self
+            .channel_factory
+            .new_extended_channel(request_id, hash_rate, min_extranonce_size)
+            .map_err(|e| {
+                error!("{}", e);
+                Error::RolesSv2Logic(e)
+            })?;
+        let success = messages.into_iter().find_map(|m| match m {
+            Mining::OpenExtendedMiningChannelSuccess(success) => Some(success.into_static()),
+            _ => None,
+        });
+        match success {
+            Some(mut success) => {
+                info!(
+                    "New extended channel opened for SV2 downstream with id {}",
+                    success.channel_id
+                );
+                // The factory sets the target for the share rate of the main listener
+                let target = hash_rate_to_target(hash_rate as f64, share_per_min as f64)?;
+                self.channel_factory
+                    .update_target_for_channel(success.channel_id, target.clone().into());
+                success.target = target;
+                Ok(OpenSv2Channel {
+                    success,
+                    last_job: self.last_job.clone(),
+                    last_p_hash: self.last_p_hash.clone(),
+                })
+            }
+            None => {
+                let e = Error::ImpossibleToOpenChannnel;
+                error!("{}", e);
+                Err(e)
+            }
+        }
+    }
+
     /// Starts the tasks that receive SV1 and SV2 messages to be translated and sent to their
     /// respective roles.
     pub async fn start(
@@ -213,6 +349,13 @@ impl Bridge {
                             break;
                         }
                     }
+                    DownstreamMessages::SubmitSharesSv2(share) => {
+                        if let Err(e) = Self::handle_submit_sv2_shares(self_.clone(), share).await {
+                            error!("Failed to handle SubmitSharesSv2: {e}");
+                            ProxyState::update_translator_state(TranslatorState::Down);
+                            break;
+                        }
+                    }
                     DownstreamMessages::SetDownstreamTarget(new_target) => {
                         if let Err(e) =
                             Self::handle_update_downstream_target(self_.clone(), new_target)
@@ -250,6 +393,13 @@ impl Bridge {
         let channel_id = share.channel_id;
         let job_id = share.share.job_id.clone();
         let share_id = share.share.id;
+        // Only set in strict mode, the miner waits for the verdict
+        let ack = share.ack.clone();
+        let answer = |verdict| {
+            if let Some(ack) = &ack {
+                let _ = ack.try_send(verdict);
+            }
+        };
         info!(
             "Bridge received share {:?} for channel {:?} and job {:?}",
             &share_id, &channel_id, &job_id
@@ -269,9 +419,15 @@ impl Bridge {
         let mut upstream_target: Target = upstream_target.into();
         let res = self_
             .safe_lock(|s| {
-                let job_id = share.share.job_id.parse::<u32>().expect("Invalid job_id");
+                let Ok(job_id) = share.share.job_id.parse::<u32>() else {
+                    warn!("Share rejected: invalid job_id {}", share.share.job_id);
+                    return Err(roles_logic_sv2::Error::ShareDoNotMatchAnyJob);
+                };
                 if s.channel_factory.job(job_id).is_none() {
-                    warn!("Share rejected: job_id {} not in last three jobs", job_id);
+                    warn!(
+                        "Share rejected: job_id {} not in last {} jobs",
+                        job_id, RETAINED_JOBS
+                    );
                     return Err(roles_logic_sv2::Error::ShareDoNotMatchAnyJob); // rejected
                 }
                 s.channel_factory.set_target(&mut upstream_target);
@@ -300,31 +456,46 @@ impl Bridge {
                     "Submit share {} from channel {} and job {} error {}",
                     &share_id, &channel_id, &job_id, error_code
                 );
+                answer(Some(RejectionReason::from_error_code(&error_code)));
             }
             Ok(OnNewShare::SendSubmitShareUpstream((s, _))) => {
-                if let Ok(is_rate_limited) = allow_submit_share() {
-                    if !is_rate_limited {
-                        warn!("Share will not be sent upstream: Exceeded 70 shares/min limit");
-                        return Ok(());
-                    }
-                    info!(
-                        "Share with id {} meets upstream target from channel {} and job {}",
-                        &share_id, &channel_id, &job_id
-                    );
-                    match s {
-                        Share::Extended(share) => {
-                            if tx_sv2_submit_shares_ext.send(share).await.is_err() {
-                                error!("Failed to send SubmitShareExtended downstream");
-                                return Err(Error::AsyncChannelError);
-                            }
+                if !self_
+                    .safe_lock(|b| b.share_limiter.try_acquire())
+                    .map_err(|_| Error::BridgeMutexPoisoned)?
+                {
+                    warn!("Share will not be sent upstream: channel share budget exceeded");
+                    self_
+                        .safe_lock(|b| b.stats_sender.update_channel_dropped_shares())
+                        .map_err(|_| Error::BridgeMutexPoisoned)?;
+                    // Answered as without strict mode
+                    answer(None);
+                    return Ok(());
+                }
+                info!(
+                    "Share with id {} meets upstream target from channel {} and job {}",
+                    &share_id, &channel_id, &job_id
+                );
+                match s {
+                    Share::Extended(mut share) => {
+                        share.sequence_number = self_
+                            .safe_lock(|b| b.next_sequence_number())
+                            .map_err(|_| Error::BridgeMutexPoisoned)?;
+                        if let Some(ack) = &ack {
+                            // Registered before sending, the verdict can come back at any time
+                            let share_acks = self_
+                                .safe_lock(|b| b.share_acks.clone())
+                                .map_err(|_| Error::BridgeMutexPoisoned)?;
+                            share_acks
+                                .safe_lock(|a| a.insert(share.sequence_number, ack.clone()))
+                                .map_err(|_| Error::BridgeMutexPoisoned)?;
+                        }
+                        if tx_sv2_submit_shares_ext.send(share).await.is_err() {
+                            error!("Failed to send SubmitShareExtended downstream");
+                            return Err(Error::AsyncChannelError);
                         }
-                        // We are in an extended channel shares are extended
-                        Share::Standard(_) => unreachable!(),
                     }
-                } else {
-                    error!("Failed to record share: Bridge mutex poisoned");
-                    ProxyState::update_inconsistency(Some(1));
-                    return Err(Error::BridgeMutexPoisoned);
+                    // We are in an extended channel shares are extended
+                    Share::Standard(_) => unreachable!(),
                 }
             }
             // We are in an extended channel this variant is group channle only
@@ -334,6 +505,8 @@ impl Bridge {
                     "Share with id {} meets downstream target from channel {} and job {}",
                     &share_id, &channel_id, &job_id
                 );
+                // The pool never sees it
+                answer(None);
             }
             // Proxy do not have JD capabilities
             Ok(OnNewShare::ShareMeetBitcoinTarget(..)) => unreachable!(),
@@ -354,6 +527,7 @@ impl Bridge {
                     "Channel factory can not get this share's job_id: {}",
                     job_id
                 );
+                answer(Some(RejectionReason::JobIdNotFound));
             }
             Err(e) => {
                 return Err(Error::RolesSv2Logic(e));
@@ -362,6 +536,144 @@ impl Bridge {
         Ok(())
     }
 
+    /// Receives a `SubmitSharesExtended` of a native SV2 downstream, validates it against the
+    /// channel target and sends it to the `Upstream` if it meets the upstream target. The
+    /// downstream is answered with `SubmitSharesSuccess` or `SubmitSharesError`.
+    async fn handle_submit_sv2_shares(
+        self_: Arc<Mutex<Self>>,
+        share: SubmitSv2Share,
+    ) -> ProxyResult<'static, ()> {
+        let SubmitSv2Share {
+            share,
+            difficulty,
+            upstream_difficulty_config,
+            reply,
+        } = share;
+        let (channel_id, sequence_number, job_id) =
+            (share.channel_id, share.sequence_number, share.job_id);
+        info!(
+            "Bridge received SV2 share {} for channel {} and job {}",
+            sequence_number, channel_id, job_id
+        );
+        let (tx_sv2_submit_shares_ext, target_mutex) = self_
+            .safe_lock(|s| (s.tx_sv2_submit_shares_ext.clone(), s.target.clone()))
+            .map_err(|_| Error::BridgeMutexPoisoned)?;
+        let upstream_target: [u8; 32] = target_mutex
+            .safe_lock(|t| t.clone())
+            .map_err(|_| Error::BridgeMutexPoisoned)?
+            .try_into()
+            .expect("Internal error: this operation can not fail because the Vec<U8> can always be converted into Inner");
+        let mut upstream_target: Target = upstream_target.into();
+        let res = self_
+            .safe_lock(|s| {
+                if s.channel_factory.job(job_id).is_none() {
+                    warn!(
+                        "Share rejected: job_id {} not in last {} jobs",
+                        job_id, RETAINED_JOBS
+                    );
+                    return Err(roles_logic_sv2::Error::ShareDoNotMatchAnyJob);
+                }
+                s.channel_factory.set_target(&mut upstream_target);
+                s.channel_factory.on_submit_shares_extended(share)
+            })
+            .map_err(|_| Error::BridgeMutexPoisoned)?;
+
+        // Valid shares measure the hashrate of the channel
+        let mut accepted = false;
+        let error_code = match res {
+            Ok(OnNewShare::SendErrorDownstream(e)) => {
+                let error_code = std::str::from_utf8(&e.error_code.to_vec()[..])
+                    .unwrap_or("unparsable error code")
+                    .to_string();
+                error!(
+                    "Submit share {} from channel {} and job {} error {}",
+                    sequence_number, channel_id, job_id, error_code
+                );
+                Some(error_code)
+            }
+            Ok(OnNewShare::SendSubmitShareUpstream((s, _))) => {
+                if self_
+                    .safe_lock(|b| b.share_limiter.try_acquire())
+                    .map_err(|_| Error::BridgeMutexPoisoned)?
+                {
+                    info!(
+                        "Share {} meets upstream target from channel {} and job {}",
+                        sequence_number, channel_id, job_id
+                    );
+                    match s {
+                        Share::Extended(mut share) => {
+                            // Sequence numbers of the upstream channel, not of the downstream one
+                            share.sequence_number = self_
+                                .safe_lock(|b| b.next_sequence_number())
+                                .map_err(|_| Error::BridgeMutexPoisoned)?;
+                            if tx_sv2_submit_shares_ext.send(share).await.is_err() {
+                                error!("Failed to send SubmitShareExtended upstream");
+                                return Err(Error::AsyncChannelError);
+                            }
+                            accepted = true;
+                        }
+                        // We are in an extended channel shares are extended
+                        Share::Standard(_) => unreachable!(),
+                    }
+                } else {
+                    warn!("Share will not be sent upstream: channel share budget exceeded");
+                    self_
+                        .safe_lock(|b| b.stats_sender.update_channel_dropped_shares())
+                        .map_err(|_| Error::BridgeMutexPoisoned)?;
+                }
+                None
+            }
+            Ok(OnNewShare::ShareMeetDownstreamTarget) => {
+                accepted = true;
+                None
+            }
+            // We are in an extended channel this variant is group channle only
+            Ok(OnNewShare::RelaySubmitShareUpstream) => unreachable!(),
+            // Proxy do not have JD capabilities
+            Ok(OnNewShare::ShareMeetBitcoinTarget(..)) => unreachable!(),
+            Err(roles_logic_sv2::Error::ShareDoNotMatchAnyJob) => {
+                Some("invalid-job-id".to_string())
+            }
+            Err(e) => {
+                warn!(
+                    "Share {} from channel {} rejected: {}",
+                    sequence_number, channel_id, e
+                );
+                Some("invalid-share".to_string())
+            }
+        };
+        let response = match error_code {
+            None => Mining::SubmitSharesSuccess(SubmitSharesSuccess {
+                channel_id,
+                last_sequence_number: sequence_number,
+                new_submits_accepted_count: 1,
+                // Work of the share, in difficulty 1 units
+                new_shares_sum: (difficulty as u64).max(1),
+            }),
+            Some(error_code) => Mining::SubmitSharesError(SubmitSharesError {
+                channel_id,
+                sequence_number,
+                error_code: error_code.try_into().expect("Internal error: this operation can not fail because the error codes can always be converted into Inner"),
+            }),
+        };
+        if accepted
+            && upstream_difficulty_config
+                .safe_lock(|c| c.on_accepted_share(difficulty))
+                .is_err()
+        {
+            error!("{}", Error::TranslatorDiffConfigMutexPoisoned);
+        }
+        // The downstream may be gone already
+        let _ = reply.send(response).await;
+        Ok(())
+    }
+
+    /// Sequence numbers of the shares sent on the upstream channel of the `Bridge`.
+    fn next_sequence_number(&mut self) -> u32 {
+        self.sequence_number = self.sequence_number.wrapping_add(1);
+        self.sequence_number
+    }
+
     /// Translates a SV1 `mining.submit` message to a SV2 `SubmitSharesExtended` message.
     #[allow(clippy::result_large_err)]
     fn translate_submit(
@@ -409,22 +721,27 @@ impl Bridge {
         );
         let extranonce2 = mining_device_extranonce;
         debug!("Extranonce2: {}", extranonce2.to_vec().as_hex());
+        let invalid_submission =
+            |_| Error::V1Protocol(Box::new(sv1_api::error::Error::InvalidSubmission));
         Ok(SubmitSharesExtended {
             channel_id,
-            // I put 0 below cause sequence_number is not what should be TODO
+            // Set by `next_sequence_number` once the share is sent upstream
             sequence_number: 0,
-            job_id: sv1_submit.job_id.parse::<u32>().expect("Internal error: this operation can not fail because job_id can always be converted into U32"),
+            job_id: sv1_submit
+                .job_id
+                .parse::<u32>()
+                .map_err(invalid_submission)?,
             nonce: sv1_submit.nonce.0,
             ntime: sv1_submit.time.0,
             version,
-            extranonce: extranonce2.try_into().expect("Internal error: this operation can not fail because Vec<U8> can always be converted into Inner"),
+            extranonce: extranonce2.try_into().map_err(invalid_submission)?,
         })
     }
 
     async fn handle_new_prev_hash_(
         self_: Arc<Mutex<Self>>,
         sv2_set_new_prev_hash: SetNewPrevHash<'static>,
-        tx_sv1_notify: broadcast::Sender<server_to_client::Notify<'static>>,
+        tx_sv1_notify: broadcast::Sender<ChannelNotify>,
     ) -> Result<(), Error<'static>> {
         while !super::super::upstream::upstream::IS_NEW_JOB_HANDLED
             .load(std::sync::atomic::Ordering::SeqCst)
@@ -450,8 +767,14 @@ impl Bridge {
             })
             .map_err(|_| Error::BridgeMutexPoisoned)?;
 
-        let extranonce_len = self_
-            .safe_lock(|s| s.channel_factory.get_extranonce_len())
+        let (extranonce_len, tx_sv2_jobs, channel_id) = self_
+            .safe_lock(|s| {
+                (
+                    s.channel_factory.get_extranonce_len(),
+                    s.tx_sv2_jobs.clone(),
+                    s.channel_id,
+                )
+            })
             .map_err(|_| Error::BridgeMutexPoisoned)?;
 
         let mut match_a_future_job = false;
@@ -460,13 +783,13 @@ impl Bridge {
                 // Create the mining.notify to be sent to the Downstream.
                 let notify = super::super::proxy::next_mining_notify::create_notify(
                     sv2_set_new_prev_hash.clone(),
-                    job,
+                    job.clone(),
                     true,
                     extranonce_len,
                 );
 
                 // Get the sender to send the mining.notify to the Downstream
-                if tx_sv1_notify.send(notify.clone()).is_err() {
+                if tx_sv1_notify.send((channel_id, notify.clone())).is_err() {
                     error!("Failed to send mining.notify");
                     // Update translator state to down
                     ProxyState::update_translator_state(TranslatorState::Down);
@@ -475,6 +798,7 @@ impl Bridge {
                 self_
                     .safe_lock(|s| {
                         s.last_notify = Some(notify);
+                        s.last_job = Some(job);
                     })
                     .map_err(|_| Error::BridgeMutexPoisoned)?;
                 break;
@@ -483,6 +807,8 @@ impl Bridge {
         if !match_a_future_job {
             debug!("No future jobs for {:?}", sv2_set_new_prev_hash);
         }
+        // There are no SV2 downstreams when nobody is subscribed
+        let _ = tx_sv2_jobs.send(Mining::SetNewPrevHash(sv2_set_new_prev_hash));
         Ok(())
     }
 
@@ -538,7 +864,7 @@ impl Bridge {
     async fn handle_new_extended_mining_job_(
         self_: Arc<Mutex<Self>>,
         sv2_new_extended_mining_job: NewExtendedMiningJob<'static>,
-        tx_sv1_notify: broadcast::Sender<server_to_client::Notify<'static>>,
+        tx_sv1_notify: broadcast::Sender<ChannelNotify>,
     ) -> Result<(), Error<'static>> {
         // convert to non segwit jobs so we dont have to depend if miner's support segwit or not
         self_
@@ -551,7 +877,17 @@ impl Bridge {
                 Error::RolesSv2Logic(RolesLogicError::JobIsNotFutureButPrevHashNotPresent)
             })?;
 
-        let extranonce_len = self_.safe_lock(|s| s.channel_factory.get_extranonce_len())?;
+        let (extranonce_len, tx_sv2_jobs, channel_id) = self_.safe_lock(|s| {
+            (
+                s.channel_factory.get_extranonce_len(),
+                s.tx_sv2_jobs.clone(),
+                s.channel_id,
+            )
+        })?;
+        // There are no SV2 downstreams when nobody is subscribed
+        let _ = tx_sv2_jobs.send(Mining::NewExtendedMiningJob(
+            sv2_new_extended_mining_job.clone(),
+        ));
 
         // If future_job=true, this job is meant for a future SetNewPrevHash that the proxy
         // has yet to receive. Insert this new job into the job_mapper .
@@ -583,12 +919,13 @@ impl Bridge {
             );
             // Get the sender to send the mining.notify to the Downstream
             tx_sv1_notify
-                .send(notify.clone())
+                .send((channel_id, notify.clone()))
                 .map_err(|_| Error::AsyncChannelError)?;
 
             self_
                 .safe_lock(|s| {
                     s.last_notify = Some(notify);
+                    s.last_job = Some(sv2_new_extended_mining_job);
                 })
                 .map_err(|_| Error::BridgeMutexPoisoned)?;
             Ok(())
@@ -639,9 +976,19 @@ impl Bridge {
         }))
     }
 }
+/// Extended channel opened for a native SV2 downstream, with the job to start mining on.
+#[derive(Debug)]
+pub struct OpenSv2Channel {
+    pub success: OpenExtendedMiningChannelSuccess<'static>,
+    pub last_job: Option<NewExtendedMiningJob<'static>>,
+    pub last_p_hash: Option<SetNewPrevHash<'static>>,
+}
+
 #[derive(Debug)]
 pub struct OpenSv1Downstream {
     pub channel_id: u32,
+    /// Id of the upstream channel of the `Bridge`.
+    pub upstream_channel_id: u32,
     pub last_notify: Option<server_to_client::Notify<'static>>,
     pub extranonce: Vec<u8>,
     pub extranonce2_len: u16,
@@ -650,6 +997,7 @@ pub struct OpenSv1Downstream {
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::config::JobRetentionConfig;
     use tokio::sync::mpsc;
 
     pub mod test_utils {
@@ -658,6 +1006,7 @@ mod test {
         pub fn create_bridge(extranonces: ExtendedExtranonce) -> Result<Arc<Mutex<Bridge>>, ()> {
             let (tx_sv2_submit_shares_ext, _rx_sv2_submit_shares_ext) = mpsc::channel(1);
             let (tx_sv1_notify, _rx_sv1_notify) = broadcast::channel(1);
+            let (tx_sv2_jobs, _rx_sv2_jobs) = broadcast::channel(1);
             let upstream_target = vec![
                 0, 0, 0, 0, 255, 255, 255, 255, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
                 0, 0, 0, 0, 0, 0, 0,
@@ -666,9 +1015,13 @@ mod test {
             let b = Bridge::new(
                 tx_sv2_submit_shares_ext.clone(),
                 tx_sv1_notify,
+                tx_sv2_jobs,
                 extranonces,
                 Arc::new(Mutex::new(upstream_target)),
                 1,
+                ShareRateLimiter::new(70.0, 70.0),
+                Arc::new(Mutex::new(PendingAcks::default())),
+                StatsSender::new(),
             )
             .map_err(|_| ())?;
             Ok(b)
@@ -687,10 +1040,73 @@ mod test {
         }
     }
 
-    #[test]
-    fn test_version_bits_insert() {
+    /// Coinbase transaction the jobs of the tests are cut from.
+    fn coinbase_tx() -> Vec<u8> {
         use bitcoin::{blockdata::witness::Witness, hashes::Hash};
 
+        let out_id = bitcoin::hashes::sha256d::Hash::from_slice(&[
+            0_u8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+            0, 0, 0, 0,
+        ])
+        .unwrap();
+        let p_out = bitcoin::OutPoint {
+            txid: bitcoin::Txid::from_raw_hash(out_id),
+            vout: 0xffff_ffff,
+        };
+        let in_ = bitcoin::TxIn {
+            previous_output: p_out,
+            script_sig: vec![89_u8; 16].into(),
+            sequence: bitcoin::Sequence(0),
+            witness: Witness::new(),
+        };
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs() as u32;
+
+        let tx = bitcoin::Transaction {
+            version: bitcoin::transaction::Version(1),
+            lock_time: bitcoin::locktime::absolute::LockTime::from_time(now).unwrap(),
+            input: vec![in_],
+            output: vec![],
+        };
+        bitcoin::consensus::serialize(&tx)
+    }
+
+    fn prev_hash(channel_id: u32) -> SetNewPrevHash<'static> {
+        SetNewPrevHash {
+            channel_id,
+            job_id: 0,
+            prev_hash: [
+                3, 3, 3, 3, 3, 3, 3, 3, 3, 3, 3, 3, 3, 3, 3, 3, 3, 3, 3, 3, 3, 3, 3, 3, 3, 3, 3, 3,
+                3, 3, 3, 3,
+            ]
+            .into(),
+            min_ntime: 989898,
+            nbits: 9,
+        }
+    }
+
+    fn mining_job(channel_id: u32, job_id: u32) -> NewExtendedMiningJob<'static> {
+        let tx = coinbase_tx();
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs() as u32;
+        NewExtendedMiningJob {
+            channel_id,
+            job_id,
+            min_ntime: binary_sv2::Sv2Option::new(Some(now)),
+            version: 0b0000_0000_0000_0000,
+            version_rolling_allowed: false,
+            merkle_path: vec![].into(),
+            coinbase_tx_prefix: tx[0..42].to_vec().try_into().unwrap(),
+            coinbase_tx_suffix: tx[58..].to_vec().try_into().unwrap(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_version_bits_insert() {
         let extranonces = ExtendedExtranonce::new(0..6, 6..8, 8..16);
         let bridge = match test_utils::create_bridge(extranonces) {
             Ok(bridge) => bridge,
@@ -699,63 +1115,15 @@ mod test {
         bridge
             .safe_lock(|bridge| {
                 let channel_id = 1;
-                let out_id = bitcoin::hashes::sha256d::Hash::from_slice(&[
-                    0_u8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
-                    0, 0, 0, 0, 0, 0, 0,
-                ])
-                .unwrap();
-                let p_out = bitcoin::OutPoint {
-                    txid: bitcoin::Txid::from_raw_hash(out_id),
-                    vout: 0xffff_ffff,
-                };
-                let in_ = bitcoin::TxIn {
-                    previous_output: p_out,
-                    script_sig: vec![89_u8; 16].into(),
-                    sequence: bitcoin::Sequence(0),
-                    witness: Witness::new(),
-                };
-                let now = std::time::SystemTime::now()
-                    .duration_since(std::time::UNIX_EPOCH)
-                    .unwrap()
-                    .as_secs() as u32;
-
-                let tx = bitcoin::Transaction {
-                    version: bitcoin::transaction::Version(1),
-                    lock_time: bitcoin::locktime::absolute::LockTime::from_time(now).unwrap(),
-                    input: vec![in_],
-                    output: vec![],
-                };
-                let tx = bitcoin::consensus::serialize(&tx);
                 let _down = bridge
                     .channel_factory
                     .add_standard_channel(0, 10_000_000_000.0, true, 1)
                     .unwrap();
-                let prev_hash = SetNewPrevHash {
-                    channel_id,
-                    job_id: 0,
-                    prev_hash: [
-                        3, 3, 3, 3, 3, 3, 3, 3, 3, 3, 3, 3, 3, 3, 3, 3, 3, 3, 3, 3, 3, 3, 3, 3, 3,
-                        3, 3, 3, 3, 3, 3, 3,
-                    ]
-                    .into(),
-                    min_ntime: 989898,
-                    nbits: 9,
-                };
-                bridge.channel_factory.on_new_prev_hash(prev_hash).unwrap();
-                let now = std::time::SystemTime::now()
-                    .duration_since(std::time::UNIX_EPOCH)
-                    .unwrap()
-                    .as_secs() as u32;
-                let new_mining_job = NewExtendedMiningJob {
-                    channel_id,
-                    job_id: 0,
-                    min_ntime: binary_sv2::Sv2Option::new(Some(now)),
-                    version: 0b0000_0000_0000_0000,
-                    version_rolling_allowed: false,
-                    merkle_path: vec![].into(),
-                    coinbase_tx_prefix: tx[0..42].to_vec().try_into().unwrap(),
-                    coinbase_tx_suffix: tx[58..].to_vec().try_into().unwrap(),
-                };
+                bridge
+                    .channel_factory
+                    .on_new_prev_hash(prev_hash(channel_id))
+                    .unwrap();
+                let new_mining_job = mining_job(channel_id, 0);
                 bridge
                     .channel_factory
                     .on_new_extended_mining_job(new_mining_job.clone())
@@ -774,4 +1142,56 @@ mod test {
             })
             .unwrap();
     }
+
+    #[tokio::test]
+    async fn shares_of_jobs_older_than_retained_are_not_found() {
+        let extranonces = ExtendedExtranonce::new(0..6, 6..8, 8..16);
+        let bridge = test_utils::create_bridge(extranonces).unwrap();
+        let opened = bridge
+            .safe_lock(|bridge| {
+                bridge
+                    .channel_factory
+                    .on_new_prev_hash(prev_hash(1))
+                    .unwrap();
+                for job_id in 0..5 {
+                    bridge
+                        .channel_factory
+                        .on_new_extended_mining_job(mining_job(1, job_id))
+                        .unwrap();
+                }
+                bridge.on_new_sv1_connection(1e12).unwrap()
+            })
+            .unwrap();
+        let (ack, mut verdict) = mpsc::channel(1);
+        let submit = |job_id: u32| {
+            let mut share = test_utils::create_sv1_submit(job_id);
+            share.extra_nonce2 = vec![0; opened.extranonce2_len as usize].try_into().unwrap();
+            SubmitShareWithChannelId {
+                channel_id: opened.channel_id,
+                share,
+                extranonce: opened.extranonce.clone(),
+                extranonce2_len: opened.extranonce2_len as usize,
+                version_rolling_mask: None,
+                ack: Some(ack.clone()),
+            }
+        };
+
+        // The 5th newest job
+        Bridge::handle_submit_shares(bridge.clone(), submit(0))
+            .await
+            .unwrap();
+        assert!(matches!(
+            verdict.try_recv(),
+            Ok(Some(RejectionReason::JobIdNotFound))
+        ));
+
+        // The oldest job a downstream keeps, the share is checked against its target. The pool
+        // is gone so it may fail to be sent upstream.
+        let oldest = 5 - JobRetentionConfig::default().max_jobs(true) as u32;
+        let _ = Bridge::handle_submit_shares(bridge.clone(), submit(oldest)).await;
+        assert!(!matches!(
+            verdict.try_recv(),
+            Ok(Some(RejectionReason::JobIdNotFound))
+        ));
+    }
 }
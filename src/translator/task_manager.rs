@@ -1,5 +1,6 @@
 use std::sync::Arc;
 
+use super::worker_channels::WorkerChannels;
 use crate::shared::utils::AbortOnDrop;
 use bitcoin::Address;
 use roles_logic_sv2::parsers::Mining;
@@ -12,11 +13,13 @@ type Message = Mining<'static>;
 
 #[allow(dead_code)]
 enum Task {
-    DownstreamListener(AbortOnDrop),
     Upstream(AbortOnDrop),
     #[allow(clippy::enum_variant_names)]
     StartupTask(AbortOnDrop),
     Bridge(AbortOnDrop),
+    ChannelMux(AbortOnDrop),
+    /// Channels of the workers, closed with the connection they were opened on.
+    WorkerChannels(Arc<WorkerChannels>),
 }
 
 pub struct TaskManager {
@@ -53,37 +56,47 @@ impl TaskManager {
         self.abort.take()
     }
 
-    pub async fn add_downstream_listener(
+    pub async fn add_upstream(self_: Arc<Mutex<Self>>, abortable: AbortOnDrop) -> Result<(), ()> {
+        let send_task = self_.safe_lock(|s| s.send_task.clone()).unwrap();
+        send_task
+            .send(Task::Upstream(abortable))
+            .await
+            .map_err(|_| ())
+    }
+    pub async fn add_startup_task(
         self_: Arc<Mutex<Self>>,
         abortable: AbortOnDrop,
     ) -> Result<(), ()> {
         let send_task = self_.safe_lock(|s| s.send_task.clone()).unwrap();
         send_task
-            .send(Task::DownstreamListener(abortable))
+            .send(Task::StartupTask(abortable))
             .await
             .map_err(|_| ())
     }
-    pub async fn add_upstream(self_: Arc<Mutex<Self>>, abortable: AbortOnDrop) -> Result<(), ()> {
+    pub async fn add_bridge(self_: Arc<Mutex<Self>>, abortable: AbortOnDrop) -> Result<(), ()> {
         let send_task = self_.safe_lock(|s| s.send_task.clone()).unwrap();
         send_task
-            .send(Task::Upstream(abortable))
+            .send(Task::Bridge(abortable))
             .await
             .map_err(|_| ())
     }
-    pub async fn add_startup_task(
+    pub async fn add_channel_mux(
         self_: Arc<Mutex<Self>>,
         abortable: AbortOnDrop,
     ) -> Result<(), ()> {
         let send_task = self_.safe_lock(|s| s.send_task.clone()).unwrap();
         send_task
-            .send(Task::StartupTask(abortable))
+            .send(Task::ChannelMux(abortable))
             .await
             .map_err(|_| ())
     }
-    pub async fn add_bridge(self_: Arc<Mutex<Self>>, abortable: AbortOnDrop) -> Result<(), ()> {
+    pub async fn add_worker_channels(
+        self_: Arc<Mutex<Self>>,
+        worker_channels: Arc<WorkerChannels>,
+    ) -> Result<(), ()> {
         let send_task = self_.safe_lock(|s| s.send_task.clone()).unwrap();
         send_task
-            .send(Task::Bridge(abortable))
+            .send(Task::WorkerChannels(worker_channels))
             .await
             .map_err(|_| ())
     }
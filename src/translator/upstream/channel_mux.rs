@@ -0,0 +1,296 @@
+use crate::{
+    shared::utils::AbortOnDrop,
+    translator::error::{Error, ProxyResult},
+};
+use roles_logic_sv2::{parsers::Mining, utils::Mutex};
+use std::{collections::HashMap, sync::Arc};
+use tokio::sync::{
+    mpsc::{Receiver as TReceiver, Sender as TSender},
+    oneshot,
+};
+use tracing::{error, info, warn};
+
+/// Request id of the `OpenExtendedMiningChannel` of the shared `Upstream`.
+const SHARED_REQUEST_ID: u32 = 0;
+
+/// Pool verdict on a channel opened with `ChannelMux::open`: its id or the error code.
+pub type Opened = Result<u32, String>;
+
+/// Routes the messages of the pool to the `Upstream` of the channel they belong to, when the
+/// miners are split in more channels on the same connection. Messages of unknown channels, and
+/// the ones that do not belong to a channel, go to the shared `Upstream`.
+#[derive(Clone)]
+pub struct ChannelMux {
+    routes: Arc<Mutex<Routes>>,
+}
+
+impl ChannelMux {
+    /// Starts routing the messages received from the pool.
+    pub fn start(
+        mut from_pool: TReceiver<Mining<'static>>,
+        shared: TSender<Mining<'static>>,
+    ) -> (Self, AbortOnDrop) {
+        let routes = Arc::new(Mutex::new(Routes::default()));
+        let handle = {
+            let routes = routes.clone();
+            tokio::task::spawn(async move {
+                while let Some(message) = from_pool.recv().await {
+                    let route = match routes.safe_lock(|r| r.route(&message)) {
+                        Ok(route) => route,
+                        Err(e) => {
+                            error!("Channel mux mutex poisoned: {e}");
+                            return;
+                        }
+                    };
+                    match route {
+                        Route::Shared => {
+                            if shared.send(message).await.is_err() {
+                                error!("Failed to send message to the shared upstream");
+                                return;
+                            }
+                        }
+                        // A channel that is being closed can still get a message or two
+                        Route::Channel(upstream) => {
+                            if upstream.send(message).await.is_err() {
+                                warn!("Upstream of a worker channel is gone, message dropped");
+                            }
+                        }
+                        Route::Handled => (),
+                    }
+                }
+                error!("Failed to receive message from the pool");
+            })
+        };
+        (Self { routes }, handle.into())
+    }
+
+    /// Registers the `Upstream` of a new channel. Returns the request id of its
+    /// `OpenExtendedMiningChannel`, the receiver of the pool verdict and the receiver notified if
+    /// the pool closes the channel.
+    #[allow(clippy::type_complexity)]
+    pub fn open(
+        &self,
+        upstream: TSender<Mining<'static>>,
+    ) -> ProxyResult<'static, (u32, oneshot::Receiver<Opened>, oneshot::Receiver<()>)> {
+        let (tx_opened, rx_opened) = oneshot::channel();
+        let (tx_closed, rx_closed) = oneshot::channel();
+        let request_id = self
+            .routes
+            .safe_lock(|r| r.open(upstream, tx_opened, tx_closed))
+            .map_err(|_| Error::PoisonLock)?;
+        Ok((request_id, rx_opened, rx_closed))
+    }
+
+    /// Stops routing the messages of a closed channel, they go to the shared `Upstream`.
+    pub fn close(&self, channel_id: u32) {
+        if self
+            .routes
+            .safe_lock(|r| {
+                r.channels.remove(&channel_id);
+                r.closed.remove(&channel_id);
+            })
+            .is_err()
+        {
+            error!("Channel mux mutex poisoned");
+        }
+    }
+}
+
+enum Route {
+    Shared,
+    Channel(TSender<Mining<'static>>),
+    /// Answer to an open request or close of a channel, the requester already got it.
+    Handled,
+}
+
+#[derive(Default)]
+struct Routes {
+    channels: HashMap<u32, TSender<Mining<'static>>>,
+    /// Channels waiting for the pool to open them, by request id.
+    #[allow(clippy::type_complexity)]
+    opening: HashMap<
+        u32,
+        (
+            TSender<Mining<'static>>,
+            oneshot::Sender<Opened>,
+            oneshot::Sender<()>,
+        ),
+    >,
+    /// Notified when the pool closes the channel, by channel id.
+    closed: HashMap<u32, oneshot::Sender<()>>,
+    last_request_id: u32,
+}
+
+impl Routes {
+    fn open(
+        &mut self,
+        upstream: TSender<Mining<'static>>,
+        opened: oneshot::Sender<Opened>,
+        closed: oneshot::Sender<()>,
+    ) -> u32 {
+        self.last_request_id = self.last_request_id.wrapping_add(1);
+        if self.last_request_id == SHARED_REQUEST_ID {
+            self.last_request_id += 1;
+        }
+        self.opening
+            .insert(self.last_request_id, (upstream, opened, closed));
+        self.last_request_id
+    }
+
+    fn route(&mut self, message: &Mining<'static>) -> Route {
+        let channel_id = match message {
+            Mining::OpenExtendedMiningChannelSuccess(m) => {
+                return match self.opening.remove(&m.request_id) {
+                    Some((upstream, opened, closed)) => {
+                        info!(
+                            "Pool opened channel {} for request {}",
+                            m.channel_id, m.request_id
+                        );
+                        self.channels.insert(m.channel_id, upstream.clone());
+                        self.closed.insert(m.channel_id, closed);
+                        // The requester may have given up already
+                        let _ = opened.send(Ok(m.channel_id));
+                        Route::Channel(upstream)
+                    }
+                    None => Route::Shared,
+                };
+            }
+            Mining::OpenMiningChannelError(m) => {
+                return match self.opening.remove(&m.request_id) {
+                    Some((_, opened, _)) => {
+                        let error_code =
+                            String::from_utf8_lossy(&m.error_code.to_vec()).to_string();
+                        let _ = opened.send(Err(error_code));
+                        Route::Handled
+                    }
+                    None => Route::Shared,
+                };
+            }
+            Mining::CloseChannel(m) => {
+                // Its `Upstream` has nothing left to do, the miners are moved by the requester
+                return match self.channels.remove(&m.channel_id) {
+                    Some(_) => {
+                        info!("Pool closed channel {}", m.channel_id);
+                        if let Some(closed) = self.closed.remove(&m.channel_id) {
+                            let _ = closed.send(());
+                        }
+                        Route::Handled
+                    }
+                    None => Route::Shared,
+                };
+            }
+            Mining::NewExtendedMiningJob(m) => m.channel_id,
+            Mining::SetNewPrevHash(m) => m.channel_id,
+            Mining::SetTarget(m) => m.channel_id,
+            Mining::SetExtranoncePrefix(m) => m.channel_id,
+            Mining::SubmitSharesSuccess(m) => m.channel_id,
+            Mining::SubmitSharesError(m) => m.channel_id,
+            Mining::UpdateChannelError(m) => m.channel_id,
+            _ => return Route::Shared,
+        };
+        match self.channels.get(&channel_id) {
+            Some(upstream) => Route::Channel(upstream.clone()),
+            None => Route::Shared,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use roles_logic_sv2::mining_sv2::{
+        CloseChannel, OpenExtendedMiningChannelSuccess, OpenMiningChannelError, SetTarget,
+    };
+    use tokio::sync::mpsc::channel;
+
+    fn set_target(channel_id: u32) -> Mining<'static> {
+        Mining::SetTarget(SetTarget {
+            channel_id,
+            maximum_target: vec![255; 32].try_into().unwrap(),
+        })
+    }
+
+    fn routed_to(route: Route, upstream: &TSender<Mining<'static>>) -> bool {
+        matches!(route, Route::Channel(sender) if sender.same_channel(upstream))
+    }
+
+    #[test]
+    fn routes_by_channel_and_request_id() {
+        let mut routes = Routes::default();
+        let (worker, _rx_worker) = channel(1);
+        let (tx_opened, mut rx_opened) = oneshot::channel();
+        let (tx_closed, _rx_closed) = oneshot::channel();
+        let request_id = routes.open(worker.clone(), tx_opened, tx_closed);
+        assert_ne!(request_id, SHARED_REQUEST_ID);
+
+        // The shared channel is opened with its own request id
+        let success = |request_id, channel_id| {
+            Mining::OpenExtendedMiningChannelSuccess(OpenExtendedMiningChannelSuccess {
+                request_id,
+                channel_id,
+                target: vec![255; 32].try_into().unwrap(),
+                extranonce_size: 16,
+                extranonce_prefix: vec![0; 4].try_into().unwrap(),
+            })
+        };
+        assert!(matches!(
+            routes.route(&success(SHARED_REQUEST_ID, 1)),
+            Route::Shared
+        ));
+        assert!(routed_to(routes.route(&success(request_id, 2)), &worker));
+        assert_eq!(rx_opened.try_recv(), Ok(Ok(2)));
+
+        assert!(routed_to(routes.route(&set_target(2)), &worker));
+        assert!(matches!(routes.route(&set_target(1)), Route::Shared));
+        routes.channels.remove(&2);
+        assert!(matches!(routes.route(&set_target(2)), Route::Shared));
+    }
+
+    #[test]
+    fn refused_channels_are_answered() {
+        let mut routes = Routes::default();
+        let (worker, _rx_worker) = channel(1);
+        let (tx_opened, mut rx_opened) = oneshot::channel();
+        let (tx_closed, _rx_closed) = oneshot::channel();
+        let request_id = routes.open(worker, tx_opened, tx_closed);
+        let error = Mining::OpenMiningChannelError(OpenMiningChannelError {
+            request_id,
+            error_code: "unknown-user".to_string().try_into().unwrap(),
+        });
+        assert!(matches!(routes.route(&error), Route::Handled));
+        assert_eq!(rx_opened.try_recv(), Ok(Err("unknown-user".to_string())));
+        // Nothing is waiting for it anymore
+        assert!(matches!(routes.route(&error), Route::Shared));
+    }
+
+    #[test]
+    fn closed_channels_are_notified() {
+        let mut routes = Routes::default();
+        let (worker, _rx_worker) = channel(1);
+        let (tx_opened, _rx_opened) = oneshot::channel();
+        let (tx_closed, mut rx_closed) = oneshot::channel();
+        let request_id = routes.open(worker, tx_opened, tx_closed);
+        routes.route(&Mining::OpenExtendedMiningChannelSuccess(
+            OpenExtendedMiningChannelSuccess {
+                request_id,
+                channel_id: 2,
+                target: vec![255; 32].try_into().unwrap(),
+                extranonce_size: 16,
+                extranonce_prefix: vec![0; 4].try_into().unwrap(),
+            },
+        ));
+        let close = |channel_id| {
+            Mining::CloseChannel(CloseChannel {
+                channel_id,
+                reason_code: "shutdown".to_string().try_into().unwrap(),
+            })
+        };
+
+        // The `CloseChannel` of the shared channel goes to its `Upstream`
+        assert!(matches!(routes.route(&close(1)), Route::Shared));
+        assert!(rx_closed.try_recv().is_err());
+        assert!(matches!(routes.route(&close(2)), Route::Handled));
+        assert_eq!(rx_closed.try_recv(), Ok(()));
+        assert!(matches!(routes.route(&set_target(2)), Route::Shared));
+    }
+}
@@ -1,11 +1,33 @@
-use crate::translator::error::Error;
+use crate::{
+    shared::hashrate::{HashrateMeter, HashrateWindow},
+    translator::error::Error,
+};
 
 use super::Upstream;
 
 #[derive(Debug, Clone)]
 pub struct UpstreamDifficultyConfig {
     pub channel_diff_update_interval: u32,
+    /// Sum of the hashrates estimated from the difficulty of the miners of the channel.
     pub channel_nominal_hashrate: f32,
+    /// Measured from the accepted shares of the miners of the channel. Miners that leave the
+    /// channel fade out of it with the window.
+    pub hashrate: HashrateMeter,
+}
+
+impl UpstreamDifficultyConfig {
+    /// Hashrate sent to the pool: the measured one once the meter had a minute of shares, the
+    /// estimated one before that or when no share was found in the window.
+    pub fn nominal_hashrate(&mut self) -> f32 {
+        self.hashrate
+            .hashrate(Instant::now(), HashrateWindow::FiveMinutes)
+            .filter(|hashrate| *hashrate > 0.0)
+            .unwrap_or(self.channel_nominal_hashrate)
+    }
+
+    pub fn on_accepted_share(&mut self, difficulty: f32) {
+        self.hashrate.add_share(Instant::now(), difficulty);
+    }
 }
 
 use super::super::error::ProxyResult;
@@ -13,7 +35,10 @@ use binary_sv2::u256_from_int;
 use roles_logic_sv2::{
     mining_sv2::UpdateChannel, parsers::Mining, utils::Mutex, Error as RolesLogicError,
 };
-use std::{sync::Arc, time::Duration};
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
 use tracing::error;
 
 impl Upstream {
@@ -26,7 +51,7 @@ impl Upstream {
             RolesLogicError::NotFoundChannelId,
         ))?;
         let (timeout, new_hashrate) = diff_mgmt
-            .safe_lock(|d| (d.channel_diff_update_interval, d.channel_nominal_hashrate))
+            .safe_lock(|d| (d.channel_diff_update_interval, d.nominal_hashrate()))
             .map_err(|_| Error::TranslatorDiffConfigMutexPoisoned)?;
         // UPDATE CHANNEL
         let update_channel = UpdateChannel {
@@ -1,3 +1,4 @@
+pub mod channel_mux;
 pub mod diff_management;
 #[allow(clippy::module_inception)]
 pub mod upstream;
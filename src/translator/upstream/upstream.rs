@@ -33,12 +33,17 @@ use tracing::{error, info, warn};
 
 use super::task_manager::TaskManager;
 use crate::{
+    monitor::shares::RejectionReason,
     proxy_state::{ProxyState, UpstreamType},
     shared::utils::AbortOnDrop,
+    translator::utils::PendingAcks,
 };
 use bitcoin::BlockHash;
 
 pub static IS_NEW_JOB_HANDLED: AtomicBool = AtomicBool::new(true);
+/// Whether the last job of the pool allows rolling its version, miners that configure version
+/// rolling get no bits when it does not.
+pub static VERSION_ROLLING_ALLOWED: AtomicBool = AtomicBool::new(true);
 /// Represents the currently active `prevhash` of the mining job being worked on OR being submitted
 /// from the Downstream role.
 #[derive(Debug, Clone)]
@@ -80,6 +85,9 @@ pub struct Upstream {
     /// set by the SV2 Upstream via the SV2 `OpenExtendedMiningChannelSuccess` message.
     pub min_extranonce_size: u16,
     pub upstream_extranonce1_size: usize,
+    /// Size of the part of the extranonce after the prefix, it is the same for every prefix of
+    /// the channel.
+    extranonce_size: usize,
     // values used to update the channel with the correct nominal hashrate.
     // each Downstream instance will add and subtract their hashrates as needed
     // and the upstream just needs to occasionally check if it has changed more than
@@ -90,6 +98,12 @@ pub struct Upstream {
     sent_up: u32,
     rejected: u32,
     toa: Vec<std::time::Instant>,
+    /// Miners waiting for the pool verdict on their shares, registered by the `Bridge`.
+    share_acks: Arc<Mutex<PendingAcks>>,
+    /// Sent in `OpenExtendedMiningChannel`, the pool accounts the shares of the channel to it.
+    user_identity: String,
+    /// Id of the `OpenExtendedMiningChannel` request, the pool answers with the same id.
+    request_id: u32,
 }
 
 impl PartialEq for Upstream {
@@ -114,6 +128,9 @@ impl Upstream {
         difficulty_config: Arc<Mutex<UpstreamDifficultyConfig>>,
         sender: TSender<Mining<'static>>,
         signature: String,
+        share_acks: Arc<Mutex<PendingAcks>>,
+        user_identity: String,
+        request_id: u32,
     ) -> ProxyResult<'static, Arc<Mutex<Self>>> {
         Ok(Arc::new(Mutex::new(Self {
             extranonce_prefix: None,
@@ -124,6 +141,7 @@ impl Upstream {
             last_job_id: None,
             min_extranonce_size,
             upstream_extranonce1_size: crate::UPSTREAM_EXTRANONCE1_SIZE,
+            extranonce_size: 0,
             tx_sv2_extranonce,
             target,
             difficulty_config,
@@ -132,6 +150,9 @@ impl Upstream {
             sent_up: 0,
             rejected: 0,
             toa: Vec::new(),
+            share_acks,
+            user_identity,
+            request_id,
         })))
     }
 
@@ -168,22 +189,25 @@ impl Upstream {
 
     /// Setups the connection with the SV2 Upstream role (most typically a SV2 Pool).
     async fn connect(self_: Arc<Mutex<Self>>) -> ProxyResult<'static, ()> {
-        let sender = self_
-            .safe_lock(|s| s.sender.clone())
+        let (sender, user_identity, request_id) = self_
+            .safe_lock(|s| (s.sender.clone(), s.user_identity.clone(), s.request_id))
             .map_err(|_e| Error::TranslatorUpstreamMutexPoisoned)?;
 
         // Send open channel request
         let nominal_hash_rate = self_
             .safe_lock(|u| {
                 u.difficulty_config
-                    .safe_lock(|c| c.channel_nominal_hashrate)
+                    .safe_lock(|c| c.nominal_hashrate())
                     .map_err(|_e| Error::TranslatorDiffConfigMutexPoisoned)
             })
             .map_err(|_e| Error::TranslatorUpstreamMutexPoisoned)??;
-        let user_identity = "ABC".to_string().try_into().expect("Internal error: this operation can not fail because the string ABC can always be converted into Inner");
+        let user_identity = user_identity.clone().try_into().map_err(|_| {
+            error!("User identity {} is longer than 255 bytes", user_identity);
+            Error::ImpossibleToOpenChannnel
+        })?;
         let open_channel = Mining::OpenExtendedMiningChannel(OpenExtendedMiningChannel {
-            request_id: 0, // TODO
-            user_identity, // TODO
+            request_id,
+            user_identity,
             nominal_hash_rate,
             max_target: u256_max(),
             min_extranonce_size: crate::MIN_EXTRANONCE2_SIZE,
@@ -271,34 +295,18 @@ impl Upstream {
                                             return;
                                         }
                                     };
-                                    let extranonce_prefix: Extranonce = m.extranonce_prefix.into();
-                                    // Create the extended extranonce that will be saved in bridge and
-                                    // it will be used to open downstream (sv1) channels
-                                    // range 0 is the extranonce1 from upstream
-                                    // range 1 is the extranonce1 added by the tproxy
-                                    // range 2 is the extranonce2 used by the miner for rolling
-                                    // range 0 + range 1 is the extranonce1 sent to the miner
-                                    let tproxy_e1_len = proxy_extranonce1_len(
+                                    let extended = match extended_extranonce(
+                                        m.extranonce_prefix.to_vec(),
                                         m.extranonce_size as usize,
                                         miner_extranonce2_size,
-                                    );
-                                    let range_0 = 0..prefix_len; // upstream extranonce1
-                                    let range_1 = prefix_len..prefix_len + tproxy_e1_len; // downstream extranonce1
-                                    let range_2 = prefix_len + tproxy_e1_len
-                                        ..prefix_len + m.extranonce_size as usize; // extranonce2
-                                    let extended = match ExtendedExtranonce::from_upstream_extranonce(
-                                        extranonce_prefix.clone(), range_0.clone(), range_1.clone(), range_2.clone(),
-                                    ).ok_or( Error::InvalidExtranonce(format!("Impossible to create a valid extended extranonce from {:?} {:?} {:?} {:?}",
-                                        extranonce_prefix,range_0,range_1,range_2))) {
-                                            Ok(extended_extranounce) => extended_extranounce,
-                                            Err(e) => {
-                                                error!(
-                                                    "Failed to create a valid extended extranonce from {:?} {:?} {:?} {:?}: {:?}",
-                                                    extranonce_prefix, range_0, range_1, range_2, e
-                                                ); ProxyState::update_upstream_state(UpstreamType::TranslatorUpstream);
-                                                break;
-                                            }
-                                        };
+                                    ) {
+                                        Ok(extended_extranounce) => extended_extranounce,
+                                        Err(e) => {
+                                            error!("{e}");
+                                            ProxyState::update_upstream_state(UpstreamType::TranslatorUpstream);
+                                            break;
+                                        }
+                                    };
 
                                     if tx_sv2_extranonce
                                         .send((extended, m.channel_id))
@@ -309,6 +317,41 @@ impl Upstream {
                                         return;
                                     };
                                 }
+                                Mining::SetExtranoncePrefix(m) => {
+                                    // Same as a new channel: the `Bridge` opens new channels for
+                                    // the downstreams, which get the new extranonce
+                                    let sizes = self_.safe_lock(|u| {
+                                        u.upstream_extranonce1_size = m.extranonce_prefix.len();
+                                        (u.extranonce_size, u.min_extranonce_size as usize)
+                                    });
+                                    let (extranonce_size, miner_extranonce2_size) = match sizes {
+                                        Ok(sizes) => sizes,
+                                        Err(e) => {
+                                            error!("Translator upstream mutex poisoned: {e}");
+                                            return;
+                                        }
+                                    };
+                                    let extended = match extended_extranonce(
+                                        m.extranonce_prefix.to_vec(),
+                                        extranonce_size,
+                                        miner_extranonce2_size,
+                                    ) {
+                                        Ok(extended_extranounce) => extended_extranounce,
+                                        Err(e) => {
+                                            error!("{e}");
+                                            ProxyState::update_upstream_state(UpstreamType::TranslatorUpstream);
+                                            break;
+                                        }
+                                    };
+                                    if tx_sv2_extranonce
+                                        .send((extended, m.channel_id))
+                                        .await
+                                        .is_err()
+                                    {
+                                        error!("Failed to send extended extranounce");
+                                        return;
+                                    };
+                                }
                                 Mining::NewExtendedMiningJob(m) => {
                                     info!("Parsing incoming NewExtendedMiningJob message from Pool for Channel Id: {}", m.channel_id);
                                     if m.is_future() {
@@ -351,8 +394,12 @@ impl Upstream {
                                         return;
                                     }
                                 }
-                                Mining::CloseChannel(_m) => {
-                                    todo!()
+                                // The ones of worker channels are handled by the `ChannelMux`,
+                                // without its channel the translator needs a new connection
+                                Mining::CloseChannel(m) => {
+                                    error!("Pool closed channel {}", m.channel_id);
+                                    ProxyState::update_upstream_state(UpstreamType::TranslatorUpstream);
+                                    break;
                                 }
                                 Mining::OpenMiningChannelError(_)
                                 | Mining::UpdateChannelError(_)
@@ -594,6 +641,7 @@ impl ParseUpstreamMiningMessages<Downstream, NullDownstreamMiningSelector, NoRou
             .map_err(|e| RolesLogicError::PoisonLock(e.to_string()))?;
         self.channel_id = Some(m.channel_id);
         self.extranonce_prefix = Some(m.extranonce_prefix.to_vec());
+        self.extranonce_size = m.extranonce_size as usize;
         let m = Mining::OpenExtendedMiningChannelSuccess(m.into_static());
         Ok(SendTo::None(Some(m)))
     }
@@ -626,29 +674,78 @@ impl ParseUpstreamMiningMessages<Downstream, NullDownstreamMiningSelector, NoRou
         Ok(SendTo::None(Some(Mining::CloseChannel(m.as_static()))))
     }
 
-    /// Handles the SV2 `SetExtranoncePrefix` message (TODO).
+    /// Handles the SV2 `SetExtranoncePrefix` message. The signature is added to the new prefix as
+    /// in `OpenExtendedMiningChannelSuccess`, the downstreams are moved to it by the `Bridge`.
     fn handle_set_extranonce_prefix(
         &mut self,
-        _: roles_logic_sv2::mining_sv2::SetExtranoncePrefix,
+        mut m: roles_logic_sv2::mining_sv2::SetExtranoncePrefix,
     ) -> Result<roles_logic_sv2::handlers::mining::SendTo<Downstream>, RolesLogicError> {
-        todo!()
+        if self.channel_id != Some(m.channel_id) {
+            warn!(
+                "SetExtranoncePrefix for unknown Channel Id {}, ignoring it",
+                m.channel_id
+            );
+            return Ok(SendTo::None(None));
+        }
+        let mut prefix = m.extranonce_prefix.to_vec();
+        prefix.extend_from_slice(self.signature.as_bytes());
+        // The full extranonce has a fixed size, the part left to the proxy depends on the prefix
+        let full_len =
+            self.extranonce_prefix.as_ref().map_or(0, |p| p.len()) + self.extranonce_size;
+        let extranonce_size = full_len.saturating_sub(prefix.len());
+        if extranonce_size < self.min_extranonce_size as usize {
+            error!(
+                "Invalid extranonce prefix for Channel Id {}: {} bytes leave {} bytes of extranonce",
+                m.channel_id,
+                prefix.len(),
+                extranonce_size
+            );
+            return Err(RolesLogicError::InvalidExtranonceSize(
+                self.min_extranonce_size,
+                extranonce_size as u16,
+            ));
+        }
+        info!(
+            "New extranonce prefix for Channel Id {}: {:?}",
+            m.channel_id, prefix
+        );
+        self.extranonce_prefix = Some(prefix.clone());
+        self.extranonce_size = extranonce_size;
+        m.extranonce_prefix = prefix.try_into()?;
+        Ok(SendTo::None(Some(Mining::SetExtranoncePrefix(
+            m.into_static(),
+        ))))
     }
 
     /// Handles the SV2 `SubmitSharesSuccess` message.
     fn handle_submit_shares_success(
         &mut self,
-        _m: roles_logic_sv2::mining_sv2::SubmitSharesSuccess,
+        m: roles_logic_sv2::mining_sv2::SubmitSharesSuccess,
     ) -> Result<roles_logic_sv2::handlers::mining::SendTo<Downstream>, RolesLogicError> {
+        self.share_acks
+            .safe_lock(|a| a.on_success(&m))
+            .map_err(|e| RolesLogicError::PoisonLock(e.to_string()))?;
         Ok(SendTo::None(None))
     }
 
     /// Handles the SV2 `SubmitSharesError` message.
     fn handle_submit_shares_error(
         &mut self,
-        _m: roles_logic_sv2::mining_sv2::SubmitSharesError,
+        m: roles_logic_sv2::mining_sv2::SubmitSharesError,
     ) -> Result<roles_logic_sv2::handlers::mining::SendTo<Downstream>, RolesLogicError> {
         self.rejected += 1;
-        error!("Ops rejected share");
+        let error_code = std::str::from_utf8(&m.error_code.to_vec()[..])
+            .unwrap_or("unparsable error code")
+            .to_string();
+        error!("Ops rejected share: {}", error_code);
+        self.share_acks
+            .safe_lock(|a| {
+                a.reject(
+                    m.sequence_number,
+                    RejectionReason::from_error_code(&error_code),
+                )
+            })
+            .map_err(|e| RolesLogicError::PoisonLock(e.to_string()))?;
         Ok(SendTo::None(None))
     }
 
@@ -673,9 +770,13 @@ impl ParseUpstreamMiningMessages<Downstream, NullDownstreamMiningSelector, NoRou
             Ok(SendTo::None(None))
         } else {
             IS_NEW_JOB_HANDLED.store(false, std::sync::atomic::Ordering::SeqCst);
-            if !m.version_rolling_allowed {
-                warn!("VERSION ROLLING NOT ALLOWED IS A TODO");
-                // todo!()
+            let was_allowed = VERSION_ROLLING_ALLOWED.swap(
+                m.version_rolling_allowed,
+                std::sync::atomic::Ordering::SeqCst,
+            );
+            if was_allowed && !m.version_rolling_allowed {
+                // Miners that negotiated a mask get an empty one with their next job
+                warn!("Pool does not allow version rolling, miners get no version bits");
             }
 
             let message = Mining::NewExtendedMiningJob(m.into_static());
@@ -740,6 +841,37 @@ impl ParseUpstreamMiningMessages<Downstream, NullDownstreamMiningSelector, NoRou
     }
 }
 
+/// Splits the extranonce of the upstream channel between the upstream prefix, the part added by
+/// the proxy for every downstream channel and the part rolled by the miner.
+fn extended_extranonce(
+    prefix: Vec<u8>,
+    extranonce_size: usize,
+    miner_extranonce2_size: usize,
+) -> Result<ExtendedExtranonce, Error<'static>> {
+    let prefix_len = prefix.len();
+    let extranonce_prefix: Extranonce = prefix
+        .try_into()
+        .map_err(|e| Error::InvalidExtranonce(format!("{:?}", e)))?;
+    // range 0 is the extranonce1 from upstream
+    // range 1 is the extranonce1 added by the tproxy
+    // range 2 is the extranonce2 used by the miner for rolling
+    // range 0 + range 1 is the extranonce1 sent to the miner
+    let tproxy_e1_len = proxy_extranonce1_len(extranonce_size, miner_extranonce2_size);
+    let range_0 = 0..prefix_len; // upstream extranonce1
+    let range_1 = prefix_len..prefix_len + tproxy_e1_len; // downstream extranonce1
+    let range_2 = prefix_len + tproxy_e1_len..prefix_len + extranonce_size; // extranonce2
+    ExtendedExtranonce::from_upstream_extranonce(
+        extranonce_prefix.clone(),
+        range_0.clone(),
+        range_1.clone(),
+        range_2.clone(),
+    )
+    .ok_or(Error::InvalidExtranonce(format!(
+        "Impossible to create a valid extended extranonce from {:?} {:?} {:?} {:?}",
+        extranonce_prefix, range_0, range_1, range_2
+    )))
+}
+
 /// currently the pool only supports 16 bytes exactly for its channels
 /// to use but that may change
 pub fn proxy_extranonce1_len(
@@ -768,3 +900,16 @@ fn avg_seconds_between(instants: &[std::time::Instant]) -> f64 {
 
     total_secs / (instants.len() - 1) as f64
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_extended_extranonce_from_new_prefix() {
+        // 20 bytes of prefix, 7 added by the proxy and 5 rolled by the miner
+        assert!(extended_extranonce(vec![1; 20], 12, 5).is_ok());
+        // More than the 32 bytes of an extranonce
+        assert!(extended_extranonce(vec![1; 30], 12, 5).is_err());
+    }
+}
@@ -1,11 +1,12 @@
 use std::{
-    collections::VecDeque,
-    sync::{atomic::AtomicBool, Arc},
+    collections::{HashMap, VecDeque},
+    sync::Arc,
+    time::Instant,
 };
 
 use crate::{
+    monitor::shares::RejectionReason,
     proxy_state::{DownstreamType, ProxyState},
-    translator::error::Error,
 };
 use binary_sv2::Sv2DataType;
 use bitcoin::{
@@ -15,86 +16,147 @@ use bitcoin::{
     BlockHash, CompactTarget,
 };
 use lazy_static::lazy_static;
-use roles_logic_sv2::utils::Mutex;
+use roles_logic_sv2::{mining_sv2::SubmitSharesSuccess, utils::Mutex};
 use sv1_api::{client_to_server, server_to_client::Notify};
 use tracing::{debug, error, info};
 
 use super::downstream::Downstream;
 lazy_static! {
-    pub static ref SHARE_TIMESTAMPS: Arc<Mutex<VecDeque<tokio::time::Instant>>> =
-        Arc::new(Mutex::new(VecDeque::with_capacity(70)));
-    pub static ref IS_RATE_LIMITED: AtomicBool = AtomicBool::new(false);
     static ref SHARE_COUNTS: Arc<Mutex<std::collections::HashMap<u32, (u32, tokio::time::Instant)>>> =
         Arc::new(Mutex::new(std::collections::HashMap::new()));
 }
 
-/// Checks if a share can be sent upstream based on a rate limit of 70 shares per minute.
-/// Returns `true` if the share can be sent, `false` if the limit is exceeded.
-pub async fn check_share_rate_limit(downstream: Arc<Mutex<Downstream>>) {
-    let mut interval = tokio::time::interval(std::time::Duration::from_secs(1));
-    let mut last_update = tokio::time::Instant::now(); // Track last difficulty update
-    let mut rate_limit_hit_count = 0;
+/// Token bucket that limits the shares sent per minute. It holds up to `burst` shares and is
+/// refilled continuously, shares that find it empty are dropped.
+#[derive(Debug, Clone)]
+pub struct ShareRateLimiter {
+    burst: f32,
+    /// Tokens added per second.
+    refill: f32,
+    tokens: f32,
+    last_refill: Instant,
+    /// Shares dropped since the last call to `take_dropped`.
+    dropped: u64,
+}
 
-    loop {
-        interval.tick().await;
-        let now = tokio::time::Instant::now();
-        let count = SHARE_TIMESTAMPS
-            .safe_lock(|timestamps| {
-                while let Some(&front) = timestamps.front() {
-                    if now.duration_since(front).as_secs() >= 60 {
-                        timestamps.pop_front();
-                    } else {
-                        break;
-                    }
-                }
-                timestamps.len()
-            })
-            .unwrap_or_else(|e| {
-                error!("Failed to lock SHARE_TIMESTAMPS: {:?}", e);
-                ProxyState::update_downstream_state(DownstreamType::TranslatorDownstream);
-                0
-            });
+impl ShareRateLimiter {
+    pub fn new(shares_per_min: f32, burst: f32) -> Self {
+        Self {
+            burst,
+            refill: shares_per_min / 60.0,
+            tokens: burst,
+            last_refill: Instant::now(),
+            dropped: 0,
+        }
+    }
 
-        let is_limited = count >= 70;
-        IS_RATE_LIMITED.store(is_limited, std::sync::atomic::Ordering::SeqCst);
+    /// Returns `true` if a share can be sent, `false` if it must be dropped.
+    pub fn try_acquire(&mut self) -> bool {
+        self.try_acquire_at(Instant::now())
+    }
 
-        if is_limited {
-            rate_limit_hit_count += 1;
+    fn try_acquire_at(&mut self, now: Instant) -> bool {
+        let elapsed = now
+            .saturating_duration_since(self.last_refill)
+            .as_secs_f32();
+        self.tokens = (self.tokens + elapsed * self.refill).min(self.burst);
+        self.last_refill = now;
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
         } else {
-            rate_limit_hit_count = 0;
+            self.dropped += 1;
+            false
         }
+    }
 
-        if rate_limit_hit_count >= 5 && now.duration_since(last_update).as_secs() >= 2 {
-            debug!("Rate limited. Updating difficulty");
-            if let Err(e) = Downstream::try_update_difficulty_settings(&downstream).await {
-                error!("Failed to update difficulty: {e}");
-            }
-            last_update = now;
-            IS_RATE_LIMITED.store(false, std::sync::atomic::Ordering::SeqCst);
-            rate_limit_hit_count = 0;
-        }
+    /// Returns the shares dropped since the last call.
+    pub fn take_dropped(&mut self) -> u64 {
+        std::mem::take(&mut self.dropped)
     }
 }
 
-/// Checks if a share can be sent by checking if rate is limited
-pub fn allow_submit_share() -> crate::translator::error::ProxyResult<'static, bool> {
-    // Check if rate-limited
-    let is_rate_limited = IS_RATE_LIMITED.load(std::sync::atomic::Ordering::SeqCst);
+/// Verdict on a share sent to the pool, `None` if it was accepted.
+pub type ShareVerdict = Option<RejectionReason>;
 
-    if is_rate_limited {
-        return Ok(false); // Rate limit exceeded, don’t send
+/// Miners waiting for the pool verdict on their shares, by sequence number of the share on the
+/// upstream channel. Shared by the `Bridge`, that sends the shares, and the `Upstream`, that
+/// receives the verdicts. Miners that gave up waiting are dropped on the next insert.
+#[derive(Debug, Default)]
+pub struct PendingAcks {
+    acks: HashMap<u32, tokio::sync::mpsc::Sender<ShareVerdict>>,
+}
+
+impl PendingAcks {
+    pub fn insert(&mut self, sequence_number: u32, ack: tokio::sync::mpsc::Sender<ShareVerdict>) {
+        self.acks.retain(|_, ack| !ack.is_closed());
+        self.acks.insert(sequence_number, ack);
     }
 
-    SHARE_TIMESTAMPS
-        .safe_lock(|timestamps| {
-            timestamps.push_back(tokio::time::Instant::now());
-        })
-        .map_err(|e| {
-            error!("Failed to lock SHARE_TIMESTAMPS: {:?}", e);
-            Error::TranslatorDiffConfigMutexPoisoned
-        })?;
+    /// Accepts the shares of a `SubmitSharesSuccess`.
+    pub fn on_success(&mut self, success: &SubmitSharesSuccess) {
+        // Other shares up to it may still be rejected
+        if success.new_submits_accepted_count == 1 {
+            self.accept(success.last_sequence_number)
+        } else {
+            self.accept_up_to(success.last_sequence_number)
+        }
+    }
 
-    Ok(true) // Share can be sent
+    /// A `SubmitSharesSuccess` for a single share, like the ones made from the `ShareOk` of the
+    /// share accounter, accepts only that share.
+    pub fn accept(&mut self, sequence_number: u32) {
+        if let Some(ack) = self.acks.remove(&sequence_number) {
+            let _ = ack.try_send(None);
+        }
+    }
+
+    /// A `SubmitSharesSuccess` for a batch accepts every share up to `last_sequence_number`.
+    pub fn accept_up_to(&mut self, last_sequence_number: u32) {
+        // Sequence numbers wrap around
+        let is_acked = |sequence_number: u32| {
+            last_sequence_number.wrapping_sub(sequence_number) < u32::MAX / 2
+        };
+        let acked: Vec<u32> = self.acks.keys().copied().filter(|s| is_acked(*s)).collect();
+        for sequence_number in acked {
+            if let Some(ack) = self.acks.remove(&sequence_number) {
+                let _ = ack.try_send(None);
+            }
+        }
+    }
+
+    pub fn reject(&mut self, sequence_number: u32, reason: RejectionReason) {
+        if let Some(ack) = self.acks.remove(&sequence_number) {
+            let _ = ack.try_send(Some(reason));
+        }
+    }
+}
+
+/// Retunes the difficulty of a miner whose shares are being dropped by its rate limiter.
+pub async fn check_share_rate_limit(downstream: Arc<Mutex<Downstream>>) {
+    let mut interval = tokio::time::interval(std::time::Duration::from_secs(5));
+
+    loop {
+        interval.tick().await;
+        let dropped = match downstream.safe_lock(|d| d.share_limiter.borrow_mut().take_dropped()) {
+            Ok(dropped) => dropped,
+            Err(e) => {
+                error!("Failed to lock downstream: {:?}", e);
+                ProxyState::update_downstream_state(DownstreamType::TranslatorDownstream);
+                return;
+            }
+        };
+
+        if dropped > 0 {
+            debug!(
+                "Rate limited, {} shares dropped. Updating difficulty",
+                dropped
+            );
+            if let Err(e) = Downstream::try_update_difficulty_settings(&downstream).await {
+                error!("Failed to update difficulty: {e}");
+            }
+        }
+    }
 }
 
 pub fn validate_share(
@@ -197,7 +259,8 @@ pub fn get_hash(
 
     // Construct the block header
     let header = Header {
-        version: Version::from_consensus(version.try_into().unwrap()),
+        // The header has the bits of the version as an i32, rolled versions can set the top bit
+        version: Version::from_consensus(version as i32),
         prev_blockhash: prev_hash,
         merkle_root: bitcoin::TxMerkleNode::from_byte_array(merkle_root),
         time: ntime,
@@ -264,3 +327,95 @@ pub fn get_share_count(connection_id: u32) -> f32 {
 //     // full_extranonce_len - pool_extranonce1_len - miner_extranonce2 = tproxy_extranonce1_len
 //     channel_extranonce2_size - downstream_extranonce2_len
 // }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_share_rate_limiter() {
+        let mut limiter = ShareRateLimiter::new(60.0, 2.0);
+        let start = limiter.last_refill;
+        assert!(limiter.try_acquire_at(start));
+        assert!(limiter.try_acquire_at(start));
+        assert!(!limiter.try_acquire_at(start));
+        // One share per second
+        assert!(limiter.try_acquire_at(start + Duration::from_secs(1)));
+        assert!(!limiter.try_acquire_at(start + Duration::from_secs(1)));
+        assert_eq!(limiter.take_dropped(), 2);
+        assert_eq!(limiter.take_dropped(), 0);
+        // Never more than the burst
+        assert!(limiter.try_acquire_at(start + Duration::from_secs(60)));
+        assert!(limiter.try_acquire_at(start + Duration::from_secs(60)));
+        assert!(!limiter.try_acquire_at(start + Duration::from_secs(60)));
+    }
+
+    #[test]
+    fn pending_acks() {
+        let mut acks = PendingAcks::default();
+        let mut receivers = vec![];
+        for sequence_number in [u32::MAX, 0, 1, 2] {
+            let (tx, rx) = tokio::sync::mpsc::channel(1);
+            acks.insert(sequence_number, tx);
+            receivers.push(rx);
+        }
+        acks.reject(1, RejectionReason::Stale);
+        acks.accept_up_to(1);
+        assert_eq!(acks.acks.len(), 1);
+        let verdicts: Vec<_> = receivers.iter_mut().map(|rx| rx.try_recv().ok()).collect();
+        assert!(matches!(verdicts[0], Some(None)));
+        assert!(matches!(verdicts[1], Some(None)));
+        assert!(matches!(verdicts[2], Some(Some(RejectionReason::Stale))));
+        assert!(verdicts[3].is_none());
+
+        // A single share is accepted alone
+        let (tx, mut rx) = tokio::sync::mpsc::channel(1);
+        acks.insert(4, tx);
+        acks.accept(4);
+        assert!(matches!(rx.try_recv(), Ok(None)));
+        assert!(matches!(receivers[3].try_recv(), Err(_)));
+        assert_eq!(acks.acks.len(), 1);
+
+        // Miners that stopped waiting are forgotten
+        drop(receivers);
+        let (tx, _rx) = tokio::sync::mpsc::channel(1);
+        acks.insert(3, tx);
+        assert_eq!(acks.acks.len(), 1);
+    }
+
+    #[test]
+    fn share_ok_answers_strict_mode_miners() {
+        use crate::share_accounter::in_flight::{InFlightShares, ShareSentUp};
+
+        let start = std::time::Instant::now();
+        let mut in_flight = InFlightShares::default();
+        let mut acks = PendingAcks::default();
+        let mut receivers = vec![];
+        for sequence_number in [1, 2] {
+            in_flight.insert(ShareSentUp {
+                channel_id: 1,
+                sequence_number,
+                job_id: 7,
+                sent_at: start + Duration::from_millis(sequence_number as u64),
+            });
+            let (tx, rx) = tokio::sync::mpsc::channel(1);
+            acks.insert(sequence_number, tx);
+            receivers.push(rx);
+        }
+        // The pool rejected the first share of the job by sequence number
+        in_flight.reject(1, 1);
+        acks.reject(1, RejectionReason::Stale);
+
+        // Then sent a `ShareOk` for the job, it is the verdict on the second share
+        let success = in_flight.ack_job(7).unwrap().accepted();
+        assert_eq!(success.last_sequence_number, 2);
+        acks.on_success(&success);
+        assert!(matches!(
+            receivers[0].try_recv(),
+            Ok(Some(RejectionReason::Stale))
+        ));
+        assert!(matches!(receivers[1].try_recv(), Ok(None)));
+        assert!(acks.acks.is_empty());
+    }
+}
@@ -0,0 +1,315 @@
+use super::{
+    downstream::{DownstreamMessages, DownstreamSessions},
+    error::{Error, ProxyResult},
+    follow_extranonce_prefix,
+    proxy::Bridge,
+    start_bridge, start_upstream,
+    task_manager::TaskManager,
+    upstream::{channel_mux::ChannelMux, diff_management::UpstreamDifficultyConfig},
+    PoolConnection,
+};
+use crate::{
+    config::WorkerChannelMode,
+    shared::{hashrate::HashrateMeter, utils::AbortOnDrop},
+};
+use roles_logic_sv2::{mining_sv2::CloseChannel, parsers::Mining, utils::Mutex};
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use tokio::sync::{
+    broadcast,
+    mpsc::{channel, Sender as TSender},
+    OnceCell,
+};
+use tracing::{error, info, warn};
+
+/// How long the pool has to open a worker channel and send its first job, the miner stays on the
+/// shared channel otherwise.
+const OPEN_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Channels opened for single workers or groups of workers, on the connection of the shared
+/// channel, so that the pool accounts their shares to them. A channel is opened by its first
+/// miner and closed when its last miner leaves.
+pub struct WorkerChannels {
+    mode: WorkerChannelMode,
+    mux: ChannelMux,
+    pool_connection: PoolConnection,
+    send_to_up: TSender<Mining<'static>>,
+    signature: String,
+    sessions: DownstreamSessions,
+    channels: Mutex<Channels>,
+}
+
+#[derive(Default)]
+struct Channels {
+    by_identity: HashMap<String, Arc<WorkerChannel>>,
+    /// Identity of the channel of each miner, by connection id.
+    miners: HashMap<u32, String>,
+}
+
+/// Channel of a worker or of a group of workers.
+pub struct WorkerChannel {
+    identity: String,
+    /// Hashrate of the miners of the channel, the channel is opened with it.
+    pub difficulty_config: Arc<Mutex<UpstreamDifficultyConfig>>,
+    opened: OnceCell<OpenedChannel>,
+}
+
+struct OpenedChannel {
+    channel_id: u32,
+    /// `None` until the channel got its first job.
+    bridge: Option<(Arc<Mutex<Bridge>>, TSender<DownstreamMessages>)>,
+    send_to_up: TSender<Mining<'static>>,
+    mux: ChannelMux,
+    /// Set when the pool closed the channel, it is not closed again.
+    closed_by_pool: Arc<AtomicBool>,
+    _tasks: AbortOnDrop,
+}
+
+impl Drop for OpenedChannel {
+    fn drop(&mut self) {
+        self.mux.close(self.channel_id);
+        if self.closed_by_pool.load(Ordering::SeqCst) {
+            return;
+        }
+        let close = Mining::CloseChannel(CloseChannel {
+            channel_id: self.channel_id,
+            reason_code: "no miners"
+                .to_string()
+                .try_into()
+                .expect("Internal error: this operation can not fail because the string is short"),
+        });
+        // The connection may be gone already, then the pool closed the channel
+        if self.send_to_up.try_send(close).is_err() {
+            warn!("Failed to close channel {}", self.channel_id);
+        }
+    }
+}
+
+impl WorkerChannels {
+    pub fn new(
+        mode: WorkerChannelMode,
+        mux: ChannelMux,
+        pool_connection: PoolConnection,
+        send_to_up: TSender<Mining<'static>>,
+        signature: String,
+        sessions: DownstreamSessions,
+    ) -> Self {
+        Self {
+            mode,
+            mux,
+            pool_connection,
+            send_to_up,
+            signature,
+            sessions,
+            channels: Mutex::new(Channels::default()),
+        }
+    }
+
+    /// Identity of the channel of a worker, `None` if it mines on the shared channel.
+    pub fn identity(&self, worker_name: &str) -> Option<String> {
+        self.mode.identity(worker_name)
+    }
+
+    /// Adds a miner to the channel of `identity`, leaving the channel it was in before.
+    pub fn enter(
+        &self,
+        identity: &str,
+        connection_id: u32,
+    ) -> ProxyResult<'static, Arc<WorkerChannel>> {
+        // Authorized again with the same worker, leaving would close the channel
+        let current = self
+            .channels
+            .safe_lock(|c| match c.miners.get(&connection_id) {
+                Some(current) if current == identity => c.by_identity.get(identity).cloned(),
+                _ => None,
+            })?;
+        if let Some(channel) = current {
+            return Ok(channel);
+        }
+        self.leave(connection_id)?;
+        let channel = self.channels.safe_lock(|c| {
+            c.miners.insert(connection_id, identity.to_string());
+            c.by_identity
+                .entry(identity.to_string())
+                .or_insert_with(|| {
+                    Arc::new(WorkerChannel {
+                        identity: identity.to_string(),
+                        difficulty_config: Arc::new(Mutex::new(UpstreamDifficultyConfig {
+                            channel_diff_update_interval: crate::CHANNEL_DIFF_UPDTATE_INTERVAL,
+                            channel_nominal_hashrate: 0.0,
+                            hashrate: HashrateMeter::default(),
+                        })),
+                        opened: OnceCell::new(),
+                    })
+                })
+                .clone()
+        })?;
+        Ok(channel)
+    }
+
+    /// Removes a miner from its channel, the channel is closed if it was the last one.
+    pub fn leave(&self, connection_id: u32) -> ProxyResult<'static, ()> {
+        let closed = self.channels.safe_lock(|c| {
+            let identity = c.miners.remove(&connection_id)?;
+            if c.miners.values().any(|i| *i == identity) {
+                return None;
+            }
+            c.by_identity.remove(&identity)
+        })?;
+        if let Some(channel) = closed {
+            info!(
+                "Last miner of {} left, closing its channel",
+                channel.identity
+            );
+        }
+        Ok(())
+    }
+
+    /// Miners in the channel of `identity`, by connection id.
+    pub fn miners(&self, identity: &str) -> ProxyResult<'static, Vec<u32>> {
+        Ok(self.channels.safe_lock(|c| {
+            c.miners
+                .iter()
+                .filter(|(_, i)| *i == identity)
+                .map(|(connection_id, _)| *connection_id)
+                .collect()
+        })?)
+    }
+
+    /// Forgets a channel closed by the pool, with its miners. The tasks of the channel are
+    /// aborted once nothing uses it anymore.
+    pub fn remove(&self, identity: &str) -> ProxyResult<'static, ()> {
+        let removed = self.channels.safe_lock(|c| {
+            c.miners.retain(|_, i| i != identity);
+            c.by_identity.remove(identity)
+        })?;
+        drop(removed);
+        Ok(())
+    }
+
+    /// Returns the `Bridge` of a channel, opening the channel with the pool if needed.
+    pub async fn open(
+        &self,
+        channel: &WorkerChannel,
+    ) -> ProxyResult<'static, (Arc<Mutex<Bridge>>, TSender<DownstreamMessages>)> {
+        let opened = channel
+            .opened
+            .get_or_try_init(|| self.open_channel(channel))
+            .await?;
+        opened.bridge.clone().ok_or(Error::ImpossibleToOpenChannnel)
+    }
+
+    async fn open_channel(&self, worker: &WorkerChannel) -> ProxyResult<'static, OpenedChannel> {
+        info!("Opening channel for {}", worker.identity);
+        let task_manager = TaskManager::initialize(self.pool_connection.clone());
+        let tasks = task_manager
+            .safe_lock(|t| t.get_aborter())
+            .map_err(|_| Error::TranslatorTaskManagerMutexPoisoned)?
+            .ok_or(Error::TranslatorTaskManagerFailed)?;
+
+        let (send_to_here, recv_from_up) = channel(crate::TRANSLATOR_BUFFER_SIZE);
+        let (request_id, rx_opened, rx_closed) = self.mux.open(send_to_here)?;
+        let upstream = start_upstream(
+            task_manager.clone(),
+            self.send_to_up.clone(),
+            recv_from_up,
+            worker.difficulty_config.clone(),
+            self.signature.clone(),
+            worker.identity.clone(),
+            request_id,
+        )
+        .await?;
+
+        let channel_id = match tokio::time::timeout(OPEN_TIMEOUT, rx_opened).await {
+            Ok(Ok(Ok(channel_id))) => channel_id,
+            Ok(Ok(Err(error_code))) => {
+                error!(
+                    "Pool refused to open a channel for {}: {}",
+                    worker.identity, error_code
+                );
+                return Err(Error::ImpossibleToOpenChannnel);
+            }
+            Ok(Err(_)) | Err(_) => {
+                error!("Pool did not open a channel for {}", worker.identity);
+                return Err(Error::ImpossibleToOpenChannnel);
+            }
+        };
+        // Dropped, and so closed, if the channel does not get its first job
+        let closed_by_pool = Arc::new(AtomicBool::new(false));
+        let mut opened = OpenedChannel {
+            channel_id,
+            bridge: None,
+            send_to_up: self.send_to_up.clone(),
+            mux: self.mux.clone(),
+            closed_by_pool: closed_by_pool.clone(),
+            _tasks: tasks,
+        };
+
+        // Native SV2 downstreams only use the shared channel
+        let (tx_sv2_jobs, _) = broadcast::channel(1);
+        let ready = async {
+            let (bridge, tx_sv1_bridge, rx_sv2_extranonce) = start_bridge(
+                task_manager.clone(),
+                upstream,
+                self.sessions.tx_sv1_notify(),
+                tx_sv2_jobs,
+                self.sessions.stats_sender(),
+            )
+            .await?;
+            Bridge::ready(&bridge)
+                .await
+                .map_err(|_| Error::BridgeMutexPoisoned)?;
+            Ok::<_, Error<'static>>((bridge, tx_sv1_bridge, rx_sv2_extranonce))
+        };
+        let (bridge, tx_sv1_bridge, rx_sv2_extranonce) =
+            match tokio::time::timeout(OPEN_TIMEOUT, ready).await {
+                Ok(ready) => ready?,
+                Err(_) => {
+                    error!(
+                        "No job from the pool for the channel of {}",
+                        worker.identity
+                    );
+                    return Err(Error::ImpossibleToOpenChannnel);
+                }
+            };
+
+        let follow = tokio::task::spawn(follow_extranonce_prefix(
+            bridge.clone(),
+            tx_sv1_bridge.clone(),
+            rx_sv2_extranonce,
+            self.sessions.clone(),
+        ));
+        TaskManager::add_startup_task(task_manager.clone(), follow.into())
+            .await
+            .map_err(|_| Error::TranslatorTaskManagerFailed)?;
+
+        let on_close = {
+            let sessions = self.sessions.clone();
+            let identity = worker.identity.clone();
+            tokio::task::spawn(async move {
+                if rx_closed.await.is_err() {
+                    // Closed by the proxy
+                    return;
+                }
+                warn!("Pool closed the channel of {}", identity);
+                closed_by_pool.store(true, Ordering::SeqCst);
+                if let Err(e) = sessions.on_worker_channel_closed(&identity).await {
+                    error!("Failed to move the miners of {}: {e}", identity);
+                }
+            })
+        };
+        TaskManager::add_startup_task(task_manager, on_close.into())
+            .await
+            .map_err(|_| Error::TranslatorTaskManagerFailed)?;
+
+        info!("Channel {} opened for {}", channel_id, worker.identity);
+        opened.bridge = Some((bridge, tx_sv1_bridge));
+        Ok(opened)
+    }
+}